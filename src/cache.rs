@@ -0,0 +1,836 @@
+//! A handle to a cache directory, for callers that want to share per-cache
+//! state such as event subscriptions.
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+
+use ssri::Integrity;
+
+use crate::content::read;
+use crate::errors::{Error, Result};
+use crate::fdpool::{self, FdPool};
+use crate::flight::Flights;
+use crate::gc::{GcOpts, GcReport};
+use crate::index::{self, Metadata};
+use crate::memo::MemoryCache;
+#[cfg(feature = "metrics")]
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::quarantine;
+
+/// Something that happened to an entry in a [`Cache`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// An entry was written under `key`.
+    Written {
+        /// Key the entry was written under.
+        key: String,
+        /// Integrity hash of the written data.
+        integrity: Integrity,
+    },
+    /// The data for `key` was read.
+    Read {
+        /// Key that was read.
+        key: String,
+    },
+    /// The entry for `key` was explicitly removed.
+    Removed {
+        /// Key that was removed.
+        key: String,
+    },
+    /// The entry for `key` was dropped by the cache itself, as part of
+    /// maintenance, rather than by an explicit removal.
+    Evicted {
+        /// Key that was evicted.
+        key: String,
+    },
+}
+
+/// Identifies a callback registered with [`Cache::subscribe`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+type Hook = Arc<dyn Fn(&Event) + Send + Sync>;
+
+struct Inner {
+    path: PathBuf,
+    hooks: RwLock<(u64, Vec<(SubscriptionId, Hook)>)>,
+    #[cfg(feature = "metrics")]
+    metrics: Metrics,
+    memo: Option<MemoryCache>,
+    fd_pool: Option<FdPool>,
+    flights: Flights,
+    quarantine_corrupt: bool,
+    treat_expired_as_missing: bool,
+    /// Whether index writes left unfinished by exited processes have been
+    /// finished yet.
+    recovered: AtomicBool,
+    /// Held shared by writes and exclusively by garbage collection.
+    collecting: RwLock<()>,
+}
+
+/// Builder for options for a [`Cache`] handle.
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::CacheOpts;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     // Keep up to 4 MiB of entries of at most 64 KiB each in memory.
+///     let cache = CacheOpts::new()
+///         .memory_cache(4 * 1024 * 1024, 64 * 1024)
+///         .open("./my-cache");
+///     let data = cache.read("my-key")?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CacheOpts {
+    pub(crate) memory_cache: Option<(usize, usize)>,
+    pub(crate) fd_pool: Option<usize>,
+    pub(crate) quarantine_corrupt: bool,
+    pub(crate) treat_expired_as_missing: bool,
+}
+
+impl CacheOpts {
+    /// Creates a blank set of cache handle options.
+    pub fn new() -> CacheOpts {
+        Default::default()
+    }
+
+    /// Keeps recently read content of at most `max_entry_size` bytes in an
+    /// in-process LRU holding up to `capacity` bytes in total, so repeated
+    /// reads of the same small entries skip reading content from disk.
+    /// Content is immutable once written, so remembered data never goes
+    /// stale, though key lookups still go through the on-disk index.
+    pub fn memory_cache(mut self, capacity: usize, max_entry_size: usize) -> Self {
+        self.memory_cache = Some((capacity, max_entry_size));
+        self
+    }
+
+    /// Keeps up to `capacity` recently read content files open, so repeated
+    /// and ranged reads of the same objects skip opening them again. Files
+    /// are verified in full when they're first opened, and reads through the
+    /// pool trust them from then on.
+    pub fn fd_pool(mut self, capacity: usize) -> Self {
+        self.fd_pool = Some(capacity);
+        self
+    }
+
+    /// Moves content that fails its integrity check on read into quarantine,
+    /// like [`crate::quarantine_hash`], so it's kept around for investigation
+    /// and the next write of the same data can replace it. The read still
+    /// fails with the original error.
+    pub fn quarantine_corrupt(mut self, quarantine: bool) -> Self {
+        self.quarantine_corrupt = quarantine;
+        self
+    }
+
+    /// Treats entries whose [`WriteOpts::ttl`](crate::WriteOpts::ttl) has run
+    /// out as if they weren't there, for [`Cache::read`], [`Cache::metadata`]
+    /// and [`Cache::list`], so no call site can forget to check. Calls by
+    /// content address, like [`Cache::read_hash`] and [`Cache::exists`], still
+    /// find the content, since it's not the content that expires but the
+    /// entries for it, and others may still be live.
+    pub fn treat_expired_as_missing(mut self, treat: bool) -> Self {
+        self.treat_expired_as_missing = treat;
+        self
+    }
+
+    /// Creates a handle for the cache at `path` with these options.
+    pub fn open<P: AsRef<Path>>(self, path: P) -> Cache {
+        Cache {
+            inner: Arc::new(Inner {
+                path: path.as_ref().to_path_buf(),
+                hooks: RwLock::new((0, Vec::new())),
+                #[cfg(feature = "metrics")]
+                metrics: Metrics::default(),
+                memo: self
+                    .memory_cache
+                    .map(|(capacity, max_entry_size)| MemoryCache::new(capacity, max_entry_size)),
+                fd_pool: self.fd_pool.map(FdPool::new),
+                flights: Flights::default(),
+                quarantine_corrupt: self.quarantine_corrupt,
+                treat_expired_as_missing: self.treat_expired_as_missing,
+                recovered: AtomicBool::new(false),
+                collecting: RwLock::new(()),
+            }),
+        }
+    }
+}
+
+/// A handle to a cache directory. It has the same operations as the free
+/// functions in this crate, and additionally lets callers subscribe to events
+/// for the entries it touches.
+///
+/// Cloning a `Cache` is cheap, and all clones share the same subscriptions.
+/// Events are only emitted for operations made through a handle; writes made
+/// through the free functions or by other processes aren't observed.
+///
+/// ## Sharing between threads
+///
+/// `Cache` is `Send + Sync`, so one handle, or clones of it, can serve every
+/// thread in a process, behind an [`Arc`] or not. Everything it keeps in
+/// memory, like the [`memory_cache`](CacheOpts::memory_cache), the
+/// [`fd_pool`](CacheOpts::fd_pool), metrics and subscriptions, is
+/// synchronized internally. Reads and writes can run alongside each other,
+/// and reads alongside maintenance like [`Cache::gc`], which skips over
+/// content and directories removed while it runs, just as writers recreate
+/// directories that removals prune out from under them. Writes through the
+/// handle and its clones wait for a collection underway to finish, and
+/// collections wait for writes underway, since content written before its
+/// index entry could otherwise be collected in between. Writes through the
+/// free functions or by other processes aren't held back, so collection
+/// still shouldn't run alongside those. Content removed through the handle is
+/// never served from memory afterwards, though, like any read of a file
+/// that's being deleted, a read already underway may still return it.
+/// Concurrent writes to the same key each land in the index, and the last
+/// one wins, as with the free functions.
+///
+/// The first time a handle looks up or changes the index, it finishes any
+/// batches of index writes that processes which have since exited left
+/// unfinished, as [`crate::recover_index`] would for a cache configured with
+/// [`crate::CacheConfig::index_journal`].
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::{Cache, Event};
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let cache = Cache::new("./my-cache");
+///     cache.subscribe(|event| {
+///         if let Event::Written { key, .. } = event {
+///             println!("cached {}", key);
+///         }
+///     });
+///     cache.write("my-key", b"hello")?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Cache {
+    inner: Arc<Inner>,
+}
+
+impl Cache {
+    /// Creates a handle for the cache at `path` with the default options.
+    /// Nothing is created on disk until data is written.
+    pub fn new<P: AsRef<Path>>(path: P) -> Cache {
+        CacheOpts::new().open(path)
+    }
+
+    /// The directory this cache lives in.
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// Registers a callback to be called with every [`Event`] on this cache.
+    /// Callbacks run synchronously on the thread performing the operation,
+    /// after it has completed, so they should be quick.
+    pub fn subscribe<F>(&self, hook: F) -> SubscriptionId
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        let mut hooks = self
+            .inner
+            .hooks
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        hooks.0 += 1;
+        let id = SubscriptionId(hooks.0);
+        hooks.1.push((id, Arc::new(hook)));
+        id
+    }
+
+    /// Removes a callback registered with [`Cache::subscribe`]. Returns false
+    /// if it was already removed.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut hooks = self
+            .inner
+            .hooks
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let before = hooks.1.len();
+        hooks.1.retain(|(hook_id, _)| *hook_id != id);
+        hooks.1.len() != before
+    }
+
+    /// Returns the metrics collected for operations made through this cache
+    /// and its clones.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.inner.metrics.snapshot()
+    }
+
+    /// Finishes interrupted index writes, once per handle and its clones.
+    fn recover(&self) -> Result<()> {
+        if !self.inner.recovered.load(Ordering::Acquire) {
+            index::recover_stale(self.path())?;
+            self.inner.recovered.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        #[cfg(feature = "metrics")]
+        if let Event::Evicted { .. } = event {
+            self.inner.metrics.evicted();
+        }
+        // Call hooks outside the lock, so they can (un)subscribe themselves.
+        let hooks = self
+            .inner
+            .hooks
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .1
+            .iter()
+            .map(|(_, hook)| hook.clone())
+            .collect::<Vec<_>>();
+        for hook in hooks {
+            hook(&event);
+        }
+    }
+
+    /// Reads the data for `key`. See [`crate::read`].
+    pub fn read<K: AsRef<str>>(&self, key: K) -> Result<Vec<u8>> {
+        let result = match self.metadata(key.as_ref()) {
+            Ok(Some(entry)) if entry.external.is_none() && !entry.metadata_only => {
+                self.read_content(&entry.integrity)
+            }
+            Ok(Some(_)) => crate::read(self.path(), key.as_ref()),
+            Ok(None) => Err(Error::EntryNotFound(
+                self.path().to_path_buf(),
+                key.as_ref().into(),
+            )),
+            Err(err) => Err(err),
+        };
+        #[cfg(feature = "metrics")]
+        self.inner.metrics.read(&result);
+        let data = result?;
+        self.emit(Event::Read {
+            key: key.as_ref().into(),
+        });
+        Ok(data)
+    }
+
+    /// Reads data by its content address. See [`crate::read_hash`].
+    pub fn read_hash(&self, sri: &Integrity) -> Result<Vec<u8>> {
+        let result = self.read_content(sri);
+        #[cfg(feature = "metrics")]
+        self.inner.metrics.read(&result);
+        result
+    }
+
+    /// Reads up to `len` bytes of content starting at `offset`, returning
+    /// fewer if the content ends first. The whole object is verified against
+    /// `sri` before any of it is returned, so this is best combined with
+    /// [`CacheOpts::fd_pool`], which only does that once per object.
+    pub fn read_hash_range(&self, sri: &Integrity, offset: u64, len: usize) -> Result<Vec<u8>> {
+        match &self.inner.fd_pool {
+            Some(pool) => {
+                let file = pool.open(self.path(), sri)?;
+                fdpool::read_range(&file, offset, len)
+            }
+            None => fdpool::read_range(&read::open_verified(self.path(), sri)?, offset, len),
+        }
+    }
+
+    fn read_content(&self, sri: &Integrity) -> Result<Vec<u8>> {
+        if let Some(data) = self.inner.memo.as_ref().and_then(|memo| memo.get(sri)) {
+            return Ok(data);
+        }
+        let generation = self.inner.memo.as_ref().map(MemoryCache::generation);
+        let result = match &self.inner.fd_pool {
+            Some(pool) => pool
+                .open(self.path(), sri)
+                .and_then(|file| fdpool::read_range(&file, 0, usize::MAX)),
+            None => crate::read_hash(self.path(), sri),
+        };
+        if let (Err(Error::IntegrityError { .. }), true) = (&result, self.inner.quarantine_corrupt)
+        {
+            // The caller needs to hear about the corruption itself, not about
+            // any trouble setting it aside.
+            let _ = quarantine::quarantine_hash(self.path(), sri);
+        }
+        let data = result?;
+        if let (Some(memo), Some(generation)) = (&self.inner.memo, generation) {
+            memo.insert(sri, &data, generation);
+        }
+        Ok(data)
+    }
+
+    /// Gets the index entry for `key`. See [`crate::metadata`].
+    pub fn metadata<K: AsRef<str>>(&self, key: K) -> Result<Option<Metadata>> {
+        self.recover()?;
+        let entry = crate::metadata(self.path(), key)?;
+        if self.inner.treat_expired_as_missing {
+            Ok(entry.filter(|entry| !entry.is_expired()))
+        } else {
+            Ok(entry)
+        }
+    }
+
+    /// Returns true if the given hash exists in the cache. See
+    /// [`crate::exists`].
+    pub fn exists(&self, sri: &Integrity) -> bool {
+        crate::exists(self.path(), sri)
+    }
+
+    /// Writes `data`, indexing it under `key`. See [`crate::write`].
+    ///
+    /// Threads writing the same data to the same key through this handle or
+    /// its clones at the same time share a single write: one of them writes
+    /// it out, and the rest wait for it and return its result. The shared
+    /// write emits a single [`Event::Written`].
+    pub fn write<K: AsRef<str>, D: AsRef<[u8]>>(&self, key: K, data: D) -> Result<Integrity> {
+        let (key, data) = (key.as_ref(), data.as_ref());
+        self.recover()?;
+        let (sri, wrote) = self.inner.flights.write(key, data, || {
+            let _writing = self
+                .inner
+                .collecting
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            crate::write(self.path(), key, data)
+        })?;
+        // Writes that were shared were only made, and are only reported,
+        // once.
+        if wrote {
+            #[cfg(feature = "metrics")]
+            self.inner.metrics.written(data.len() as u64);
+            self.emit(Event::Written {
+                key: key.into(),
+                integrity: sri.clone(),
+            });
+        }
+        Ok(sri)
+    }
+
+    /// Writes `data` without indexing it under a key. See
+    /// [`crate::write_hash`].
+    pub fn write_hash<D: AsRef<[u8]>>(&self, data: D) -> Result<Integrity> {
+        let sri = {
+            let _writing = self
+                .inner
+                .collecting
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            crate::write_hash(self.path(), data.as_ref())?
+        };
+        #[cfg(feature = "metrics")]
+        self.inner.metrics.written(data.as_ref().len() as u64);
+        Ok(sri)
+    }
+
+    /// Removes the index entry for `key`. See [`crate::remove`].
+    pub fn remove<K: AsRef<str>>(&self, key: K) -> Result<()> {
+        self.recover()?;
+        crate::remove(self.path(), key.as_ref())?;
+        self.emit(Event::Removed {
+            key: key.as_ref().into(),
+        });
+        Ok(())
+    }
+
+    /// Removes a content entry. See [`crate::remove_hash`].
+    pub fn remove_hash(&self, sri: &Integrity) -> Result<()> {
+        // Forgotten only once it's gone from disk, so reads can't bring it
+        // back in between.
+        let result = crate::remove_hash(self.path(), sri);
+        if let Some(memo) = &self.inner.memo {
+            memo.remove(sri);
+        }
+        if let Some(pool) = &self.inner.fd_pool {
+            pool.remove(sri);
+        }
+        result
+    }
+
+    /// Garbage collects the cache. See [`GcOpts::run`]. Index entries removed
+    /// by sweeping the index are reported as [`Event::Evicted`].
+    pub fn gc(&self, opts: GcOpts) -> Result<GcReport> {
+        self.recover()?;
+        let report = {
+            let _collecting = self
+                .inner
+                .collecting
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            opts.run(self.path())?
+        };
+        if report.content_objects > 0 {
+            if let Some(memo) = &self.inner.memo {
+                memo.clear();
+            }
+            if let Some(pool) = &self.inner.fd_pool {
+                pool.clear();
+            }
+        }
+        for key in &report.removed_keys {
+            self.emit(Event::Evicted { key: key.clone() });
+        }
+        Ok(report)
+    }
+
+    /// Lists all index entries. See [`crate::list`].
+    pub fn list(&self) -> impl Iterator<Item = Result<Metadata>> {
+        let treat_expired_as_missing = self.inner.treat_expired_as_missing;
+        self.recover()
+            .err()
+            .map(Err)
+            .into_iter()
+            .chain(crate::list(self.inner.path.clone()))
+            .filter(move |entry| {
+                !matches!(entry, Ok(entry) if treat_expired_as_missing && entry.is_expired())
+            })
+    }
+
+    /// Removes the entire contents of the cache. See [`crate::clear`].
+    pub fn clear(&self) -> Result<()> {
+        // As with `remove_hash`, disk first.
+        let result = crate::clear(self.path());
+        if let Some(memo) = &self.inner.memo {
+            memo.clear();
+        }
+        if let Some(pool) = &self.inner.fd_pool {
+            pool.clear();
+        }
+        result
+    }
+}
+
+// Handles are meant to be shared between threads, so make sure they stay
+// shareable.
+const _: fn() = || {
+    fn shareable<T: Send + Sync>() {}
+    shareable::<Cache>();
+};
+
+impl std::fmt::Debug for Cache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cache")
+            .field("path", &self.inner.path)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn events() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(tmp.path());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = seen.clone();
+        let id = cache.subscribe(move |event| seen_clone.lock().unwrap().push(event.clone()));
+
+        let sri = cache.write("key", b"hello").unwrap();
+        assert_eq!(cache.read("key").unwrap(), b"hello");
+        assert!(cache.read("missing").is_err());
+        cache.remove("key").unwrap();
+
+        assert!(cache.unsubscribe(id));
+        assert!(!cache.unsubscribe(id));
+        cache.write("key", b"hello").unwrap();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                Event::Written {
+                    key: "key".into(),
+                    integrity: sri
+                },
+                Event::Read { key: "key".into() },
+                Event::Removed { key: "key".into() },
+            ]
+        );
+    }
+
+    #[test]
+    fn gc_evictions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = CacheOpts::new().memory_cache(1024, 16).open(tmp.path());
+        let sri = cache.write("dropped", b"hello").unwrap();
+        cache.write("kept", b"world").unwrap();
+        assert_eq!(cache.read("dropped").unwrap(), b"hello");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = seen.clone();
+        cache.subscribe(move |event| seen_clone.lock().unwrap().push(event.clone()));
+
+        cache
+            .gc(GcOpts::new().keep_key("kept").sweep_index(true))
+            .unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![Event::Evicted {
+                key: "dropped".into()
+            }]
+        );
+        assert!(cache.read_hash(&sri).is_err());
+    }
+
+    #[test]
+    fn writes_racing_gc() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(tmp.path());
+        let writers = (0..4)
+            .map(|thread| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let key = format!("{}-{}", thread, i);
+                        cache.write(&key, key.as_bytes()).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        while !writers.iter().all(|writer| writer.is_finished()) {
+            cache.gc(GcOpts::new()).unwrap();
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        for thread in 0..4 {
+            for i in 0..50 {
+                let key = format!("{}-{}", thread, i);
+                assert_eq!(cache.read(&key).unwrap(), key.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn clones_share_subscriptions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(tmp.path());
+        let count = Arc::new(Mutex::new(0));
+        let count_clone = count.clone();
+        cache.subscribe(move |_| *count_clone.lock().unwrap() += 1);
+
+        let other = cache.clone();
+        other.write("key", b"hello").unwrap();
+        assert_eq!(*count.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn memory_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = CacheOpts::new().memory_cache(1024, 16).open(tmp.path());
+        let sri = cache.write("key", b"hello").unwrap();
+        assert_eq!(cache.read("key").unwrap(), b"hello");
+
+        // Served from memory, even though the file is gone.
+        crate::remove_hash(tmp.path(), &sri).unwrap();
+        assert_eq!(cache.read("key").unwrap(), b"hello");
+        assert_eq!(cache.read_hash(&sri).unwrap(), b"hello");
+
+        // But not after removing it through the handle.
+        cache.remove_hash(&sri).ok();
+        assert!(cache.read_hash(&sri).is_err());
+    }
+
+    #[test]
+    fn quarantine_corrupt() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = CacheOpts::new().quarantine_corrupt(true).open(tmp.path());
+        let sri = cache.write("key", b"hello").unwrap();
+        let cpath = crate::content::path::content_path(tmp.path(), &sri).unwrap();
+        std::fs::write(&cpath, b"jello").unwrap();
+
+        assert!(matches!(
+            cache.read("key"),
+            Err(Error::IntegrityError { .. })
+        ));
+        assert!(!cpath.exists());
+        assert!(tmp.path().join("quarantine").read_dir().unwrap().count() > 0);
+
+        // With the bad copy out of the way, the data can be written again.
+        cache.write("key", b"hello").unwrap();
+        assert_eq!(cache.read("key").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn treat_expired_as_missing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = CacheOpts::new()
+            .treat_expired_as_missing(true)
+            .open(tmp.path());
+        let write = |key, ttl| {
+            crate::WriteOpts::new()
+                .ttl(ttl)
+                .open(tmp.path(), key)
+                .unwrap()
+                .commit()
+                .unwrap()
+        };
+        write("fresh", std::time::Duration::from_secs(60));
+        write("stale", std::time::Duration::ZERO);
+        cache.write("forever", b"hello").unwrap();
+
+        assert!(cache.metadata("fresh").unwrap().is_some());
+        assert!(cache.read("fresh").is_ok());
+        assert!(cache.metadata("stale").unwrap().is_none());
+        assert!(matches!(cache.read("stale"), Err(Error::EntryNotFound(..))));
+        assert!(cache.metadata("forever").unwrap().is_some());
+        let mut keys = cache
+            .list()
+            .map(|entry| entry.unwrap().key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["forever", "fresh"]);
+        let sri = crate::metadata(tmp.path(), "stale")
+            .unwrap()
+            .unwrap()
+            .integrity;
+        assert!(cache.exists(&sri));
+        assert!(cache.read_hash(&sri).is_ok());
+
+        // The free functions, and other handles, still see it.
+        let entry = crate::metadata(tmp.path(), "stale").unwrap().unwrap();
+        assert!(entry.is_expired());
+        assert!(Cache::new(tmp.path()).read("stale").is_ok());
+    }
+
+    /// Run on WASI in CI, which has no temporary directory of its own, so it
+    /// uses one under the working directory the runner preopens instead.
+    #[test]
+    fn wasi_round_trip() {
+        #[cfg(target_os = "wasi")]
+        let tmp = tempfile::tempdir_in(".").unwrap();
+        #[cfg(not(target_os = "wasi"))]
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(tmp.path());
+
+        let sri = cache.write("key", b"hello").unwrap();
+        assert_eq!(cache.read("key").unwrap(), b"hello");
+        assert_eq!(cache.read_hash(&sri).unwrap(), b"hello");
+        assert_eq!(
+            cache
+                .list()
+                .map(|entry| entry.unwrap().key)
+                .collect::<Vec<_>>(),
+            ["key"]
+        );
+
+        cache.remove("key").unwrap();
+        assert!(cache.metadata("key").unwrap().is_none());
+        cache.gc(GcOpts::new().sweep_index(true)).unwrap();
+        assert!(!cache.exists(&sri));
+    }
+
+    #[test]
+    fn fd_pool() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = CacheOpts::new().fd_pool(4).open(tmp.path());
+        let sri = cache.write_hash(b"hello world").unwrap();
+        assert_eq!(cache.read_hash_range(&sri, 6, 5).unwrap(), b"world");
+        assert_eq!(cache.read_hash(&sri).unwrap(), b"hello world");
+
+        let unpooled = Cache::new(tmp.path());
+        assert_eq!(unpooled.read_hash_range(&sri, 0, 5).unwrap(), b"hello");
+        cache.remove_hash(&sri).unwrap();
+        assert!(cache.read_hash_range(&sri, 0, 5).is_err());
+    }
+
+    fn shared(dir: &Path) -> Arc<Cache> {
+        Arc::new(
+            CacheOpts::new()
+                .memory_cache(1024 * 1024, 1024)
+                .fd_pool(4)
+                .open(dir),
+        )
+    }
+
+    #[test]
+    fn shared_reads() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = shared(tmp.path());
+        let sris = (0..8)
+            .map(|i| cache.write(format!("key-{}", i), format!("data-{}", i)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        std::thread::scope(|scope| {
+            for t in 0..8 {
+                let (cache, sris) = (cache.clone(), &sris);
+                scope.spawn(move || {
+                    for i in (0..200).map(|i| (i + t) % 8) {
+                        let data = format!("data-{}", i).into_bytes();
+                        assert_eq!(cache.read(format!("key-{}", i)).unwrap(), data);
+                        assert_eq!(cache.read_hash(&sris[i]).unwrap(), data);
+                        assert_eq!(cache.read_hash_range(&sris[i], 5, 1).unwrap(), &data[5..]);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn shared_writes_to_one_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = shared(tmp.path());
+        std::thread::scope(|scope| {
+            for t in 0..8 {
+                let cache = cache.clone();
+                scope.spawn(move || {
+                    for i in 0..20 {
+                        // Half the threads write the same data, which gets
+                        // coalesced, and half write their own.
+                        let data = if t % 2 == 0 {
+                            format!("shared-{}", i)
+                        } else {
+                            format!("thread-{}-{}", t, i)
+                        };
+                        let sri = cache.write("key", &data).unwrap();
+                        assert_eq!(cache.read_hash(&sri).unwrap(), data.as_bytes());
+                    }
+                });
+            }
+        });
+        // The key ends up with a single entry, for data that's all there.
+        assert_eq!(crate::index::ls(tmp.path()).count(), 1);
+        let entry = cache.metadata("key").unwrap().unwrap();
+        assert_eq!(
+            cache.read("key").unwrap(),
+            cache.read_hash(&entry.integrity).unwrap()
+        );
+    }
+
+    #[test]
+    fn shared_with_maintenance() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = shared(tmp.path());
+        let kept = cache.write("kept", b"kept").unwrap();
+        let writing = std::sync::atomic::AtomicUsize::new(4);
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let (cache, writing) = (cache.clone(), &writing);
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        let data = format!("data-{}-{}", t, i);
+                        let sri = cache.write_hash(&data).unwrap();
+                        // Whatever's read is right, even if it's been
+                        // removed by then.
+                        if let Ok(read) = cache.read_hash(&sri) {
+                            assert_eq!(read, data.as_bytes());
+                        }
+                        // Unless gc got to it first.
+                        cache.remove_hash(&sri).ok();
+                        assert!(cache.read_hash(&sri).is_err());
+                    }
+                    writing.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                });
+            }
+            let (cache, writing) = (cache.clone(), &writing);
+            scope.spawn(move || {
+                // Collects for as long as the writers are going, so the two
+                // are sure to overlap.
+                while writing.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+                    cache.gc(GcOpts::new().keep_key("kept")).unwrap();
+                    assert_eq!(cache.read("kept").unwrap(), b"kept");
+                }
+            });
+        });
+        assert_eq!(cache.read_hash(&kept).unwrap(), b"kept");
+    }
+}
@@ -0,0 +1,638 @@
+//! Functions for configuring a cache.
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use walkdir::WalkDir;
+
+use crate::content::{filter, path, perms};
+use crate::errors::{Error, Internal, Result};
+use crate::index;
+use crate::retry::RetryPolicy;
+use crate::secondary;
+use crate::stats;
+
+pub(crate) const CONFIG_FILE: &str = "config.json";
+
+/// The most hex characters of a content hash that can be used up by fan-out
+/// directories.
+const MAX_FANOUT_CHARS: usize = 16;
+
+/// The most content roots a cache can be sharded across. Roots are selected by
+/// the first byte of the content hash.
+const MAX_CONTENT_ROOTS: usize = 256;
+
+/// Per-cache settings, stored in `{cache}/config.json`. Caches without a
+/// config file use the defaults.
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::CacheConfig;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     // Three levels of single-byte directories, for a very large cache.
+///     let config = CacheConfig::new().content_levels(3).content_width(2);
+///     cacache_sync::configure("./my-cache", config)?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Number of directory levels content files are fanned out into, below
+    /// `{cache}/content-v2/{algorithm}`. Defaults to 2.
+    pub content_levels: usize,
+    /// Number of hex characters of the content hash used to name each
+    /// fan-out directory. Defaults to 2.
+    pub content_width: usize,
+    /// Directories to spread content across, for example on different disks.
+    /// Each content object lives under exactly one of them, picked by the
+    /// first byte of its hash. Relative paths are resolved against the cache
+    /// directory. Defaults to empty, which keeps all content inside the cache
+    /// directory itself.
+    pub content_roots: Vec<PathBuf>,
+    /// Whether to keep a stats file up to date on every write and removal, so
+    /// that [`crate::stats`] can answer without walking the cache. Tracked
+    /// stats can drift when writers race each other, see [`crate::stats`].
+    /// Defaults to false.
+    pub track_stats: bool,
+    /// Whether to make content files read-only (`0444` on Unix) once they're
+    /// committed, so the OS rejects accidental writes to them. Reads then
+    /// also refuse content files that have been made writable again. Defaults
+    /// to false.
+    pub read_only_content: bool,
+    /// How to retry filesystem operations that fail transiently. Defaults to
+    /// never retrying.
+    pub retry: RetryPolicy,
+    /// What happens when a key that already has an entry is written again.
+    /// Defaults to [`KeyConflict::LastWins`].
+    pub key_conflict: KeyConflict,
+    /// How index entries are stored. Defaults to [`IndexFormat::Json`]. Like
+    /// the content layout, this can only be changed while the index is
+    /// empty.
+    pub index_format: IndexFormat,
+    /// Top-level metadata fields to keep secondary indexes for, so entries
+    /// can be looked up by their values with [`crate::find_by`]. Fields added
+    /// to a cache that already has entries are indexed for those too.
+    /// Defaults to none. Fixed-format indexes don't store metadata, so they
+    /// can't index any.
+    pub indexed_fields: Vec<String>,
+    /// Whether to keep a sorted directory of keys alongside the index, so
+    /// [`crate::list_prefix`] and [`crate::keys`] take time proportional to
+    /// what they return instead of reading every bucket. Writes append to a
+    /// journal that [`crate::repack`] folds back into the directory. Defaults
+    /// to false. Fixed-format indexes don't store keys, so they can't keep
+    /// one.
+    pub prefix_index: bool,
+    /// Whether to keep a bloom filter of the content hashes in the cache, so
+    /// [`crate::exists`] can answer for most missing content without looking
+    /// for its file. Writes add to it, and [`crate::repack`] rebuilds it to
+    /// drop content that's gone and to make room for what's been added.
+    /// Content written by anything other than this crate isn't in the filter
+    /// until it's rebuilt. Defaults to false.
+    pub content_filter: bool,
+    /// Whether to journal each batch of index writes before making it, so a
+    /// crash part way through can't leave only some of its entries in the
+    /// index, such as a renamed key showing up under neither name or both.
+    /// Interrupted batches are finished by later writes, or straight away by
+    /// [`crate::recover_index`]. Journaled writes are always synced to disk,
+    /// which makes them noticeably slower. Defaults to false.
+    pub index_journal: bool,
+}
+
+/// How a cache handles writes to a key that already has an entry. Removals
+/// always go through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyConflict {
+    /// The newest write replaces the existing entry.
+    #[default]
+    LastWins,
+    /// The existing entry is kept, and the write returns its integrity
+    /// instead of the new one. The losing write's content has already been
+    /// stored by then, and is left for [`crate::GcOpts`] to remove, like any
+    /// other content no entry points to.
+    FirstWins,
+    /// The write fails with [`Error::KeyConflict`] if its data differs from
+    /// the existing entry's, so nondeterministic producers are caught rather
+    /// than silently overwriting each other. Rewriting identical data is
+    /// fine.
+    ErrorOnDifferent,
+}
+
+/// How a cache stores its index entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexFormat {
+    /// Each entry is a line of JSON holding everything it was written with.
+    #[default]
+    Json,
+    /// Each entry is a small fixed-size binary record holding only a hash of
+    /// its key, its integrity, time and size. Lookups and listings skip all
+    /// JSON parsing, which pays off for caches with millions of entries.
+    ///
+    /// Since keys themselves aren't stored, entries listed or watched in such
+    /// a cache carry the hex-encoded SHA-256 of their key in place of the key.
+    /// Writes with metadata, tags, a content type, a pointer, a signature or
+    /// a time to live fail with [`Error::InvalidConfig`], as do integrity
+    /// strings longer than a single SHA-512 hash.
+    Fixed,
+    /// Each entry holds everything it was written with, like
+    /// [`IndexFormat::Json`], but encoded in a compact length-prefixed binary
+    /// form that skips JSON parsing when listing and looking up entries.
+    Binary,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            content_levels: 2,
+            content_width: 2,
+            content_roots: Vec::new(),
+            track_stats: false,
+            read_only_content: false,
+            retry: RetryPolicy::default(),
+            key_conflict: KeyConflict::default(),
+            index_format: IndexFormat::default(),
+            indexed_fields: Vec::new(),
+            prefix_index: false,
+            content_filter: false,
+            index_journal: false,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Creates a config with all the default settings.
+    pub fn new() -> CacheConfig {
+        Default::default()
+    }
+
+    /// Sets the number of fan-out directory levels for content files.
+    pub fn content_levels(mut self, levels: usize) -> Self {
+        self.content_levels = levels;
+        self
+    }
+
+    /// Sets how many hex characters name each content fan-out directory.
+    pub fn content_width(mut self, width: usize) -> Self {
+        self.content_width = width;
+        self
+    }
+
+    /// Adds a directory to shard content into. See `content_roots`.
+    pub fn content_root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.content_roots.push(root.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets whether to incrementally maintain cache stats. See `track_stats`.
+    pub fn track_stats(mut self, track: bool) -> Self {
+        self.track_stats = track;
+        self
+    }
+
+    /// Sets whether committed content files are made read-only. See
+    /// `read_only_content`.
+    pub fn read_only_content(mut self, read_only: bool) -> Self {
+        self.read_only_content = read_only;
+        self
+    }
+
+    /// Sets how to retry transiently failing filesystem operations.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets how writes to keys that already have entries are handled. See
+    /// `key_conflict`.
+    pub fn key_conflict(mut self, policy: KeyConflict) -> Self {
+        self.key_conflict = policy;
+        self
+    }
+
+    /// Sets how index entries are stored. See `index_format`.
+    pub fn index_format(mut self, format: IndexFormat) -> Self {
+        self.index_format = format;
+        self
+    }
+
+    /// Adds a metadata field to keep a secondary index for. See
+    /// `indexed_fields`.
+    pub fn indexed_field<F: Into<String>>(mut self, field: F) -> Self {
+        self.indexed_fields.push(field.into());
+        self
+    }
+
+    /// Sets whether to keep a sorted directory of keys. See `prefix_index`.
+    pub fn prefix_index(mut self, prefix_index: bool) -> Self {
+        self.prefix_index = prefix_index;
+        self
+    }
+
+    /// Sets whether to keep a bloom filter of content hashes. See
+    /// `content_filter`.
+    pub fn content_filter(mut self, content_filter: bool) -> Self {
+        self.content_filter = content_filter;
+        self
+    }
+
+    /// Sets whether to journal index writes. See `index_journal`.
+    pub fn index_journal(mut self, index_journal: bool) -> Self {
+        self.index_journal = index_journal;
+        self
+    }
+
+    fn validate(&self, cache: &Path) -> Result<()> {
+        if self.content_levels > 0 && self.content_width == 0 {
+            return Err(Error::InvalidConfig(
+                cache.to_path_buf(),
+                "content_width must be at least 1".into(),
+            ));
+        }
+        if self.content_levels * self.content_width > MAX_FANOUT_CHARS {
+            return Err(Error::InvalidConfig(
+                cache.to_path_buf(),
+                format!(
+                    "content fan-out can use at most {} hex characters",
+                    MAX_FANOUT_CHARS
+                ),
+            ));
+        }
+        if self.content_roots.len() > MAX_CONTENT_ROOTS {
+            return Err(Error::InvalidConfig(
+                cache.to_path_buf(),
+                format!(
+                    "content can be sharded across at most {} roots",
+                    MAX_CONTENT_ROOTS
+                ),
+            ));
+        }
+        if self.index_format == IndexFormat::Fixed && !self.indexed_fields.is_empty() {
+            return Err(Error::InvalidConfig(
+                cache.to_path_buf(),
+                "a fixed-format index has no metadata to index".into(),
+            ));
+        }
+        if self.index_format == IndexFormat::Fixed && self.prefix_index {
+            return Err(Error::InvalidConfig(
+                cache.to_path_buf(),
+                "a fixed-format index has no keys to keep a directory of".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn same_layout(&self, other: &CacheConfig) -> bool {
+        self.content_levels == other.content_levels
+            && self.content_width == other.content_width
+            && self.content_roots == other.content_roots
+    }
+}
+
+/// Returns the configuration of a cache, or the default configuration if it
+/// has never been configured.
+pub fn cache_config<P: AsRef<Path>>(cache: P) -> Result<CacheConfig> {
+    load(cache.as_ref())
+}
+
+/// Writes the configuration for a cache. Settings that determine where data
+/// lives on disk, such as the content fan-out, can only be changed while the
+/// cache holds no content.
+pub fn configure<P: AsRef<Path>>(cache: P, config: CacheConfig) -> Result<()> {
+    let cache = cache.as_ref();
+    config.validate(cache)?;
+    let current = load(cache)?;
+    if !current.same_layout(&config) && has_any_content(&current, cache) {
+        return Err(Error::InvalidConfig(
+            cache.to_path_buf(),
+            "the content layout can't be changed once the cache has content".into(),
+        ));
+    }
+    if current.index_format != config.index_format && has_any_entries(cache) {
+        return Err(Error::InvalidConfig(
+            cache.to_path_buf(),
+            "the index format can't be changed once the cache has entries".into(),
+        ));
+    }
+    let tmp_path = cache.join("tmp");
+    DirBuilder::new()
+        .recursive(true)
+        .create(&tmp_path)
+        .with_context(|| format!("Failed to create tmp directory at {:?}", tmp_path))?;
+    let mut tmpfile = NamedTempFile::new_in(&tmp_path).to_internal()?;
+    serde_json::to_writer_pretty(tmpfile.as_file_mut(), &config)
+        .with_context(|| format!("Failed to serialize config for cache at {:?}", cache))?;
+    tmpfile
+        .persist(cache.join(CONFIG_FILE))
+        .with_context(|| format!("Failed to write config for cache at {:?}", cache))?;
+    if config.track_stats && !current.track_stats {
+        // Start from an accurate count of whatever is already in the cache.
+        stats::rebuild_stats(cache)?;
+    }
+    if config.read_only_content && !current.read_only_content {
+        // Content committed before now must pass the read-time check too.
+        make_content_read_only(&config, cache)?;
+    }
+    if config.indexed_fields != current.indexed_fields {
+        secondary::rebuild(cache)?;
+    }
+    if config.prefix_index != current.prefix_index {
+        index::rebuild_key_dir(cache)?;
+    }
+    if config.content_filter != current.content_filter {
+        filter::rebuild(cache)?;
+    }
+    Ok(())
+}
+
+/// Configs already read, by the path of their file, along with the stamp of
+/// the file they were read from.
+static LOADED: Mutex<Option<HashMap<PathBuf, (Stamp, CacheConfig)>>> = Mutex::new(None);
+
+/// How many caches' configs are kept in `LOADED` before starting over.
+const MAX_LOADED: usize = 64;
+
+/// Tells versions of a config file apart, short of reading it. Configs are
+/// written by renaming a new file into place, which changes the inode too,
+/// so even a rewrite within the resolution of the modification time is
+/// noticed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(not(unix), allow(dead_code))]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    ino: u64,
+}
+
+impl Stamp {
+    /// The stamp of the file with `meta`. There's none off unix, where
+    /// there's no inode to go by, and a rewrite within the resolution of the
+    /// modification time would go unnoticed, so configs aren't kept there.
+    #[cfg(unix)]
+    fn of(meta: &fs::Metadata) -> Option<Stamp> {
+        Some(Stamp {
+            modified: meta.modified().ok(),
+            len: meta.len(),
+            ino: std::os::unix::fs::MetadataExt::ino(meta),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn of(_meta: &fs::Metadata) -> Option<Stamp> {
+        None
+    }
+}
+
+/// Loads the config of `cache`. This happens at least once for nearly every
+/// operation, so where their file can be stamped, configs are only read and
+/// parsed again once it changes, and otherwise cost a `stat`.
+pub(crate) fn load(cache: &Path) -> Result<CacheConfig> {
+    let config_path = cache.join(CONFIG_FILE);
+    let stamp = match fs::metadata(&config_path) {
+        Ok(meta) => Stamp::of(&meta),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(CacheConfig::default()),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read cache config at {:?}", config_path))?
+        }
+    };
+    if let Some(stamp) = &stamp {
+        let loaded = LOADED.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, config)) = loaded
+            .as_ref()
+            .and_then(|loaded| loaded.get(&config_path))
+            .filter(|(loaded_stamp, _)| loaded_stamp == stamp)
+        {
+            return Ok(config.clone());
+        }
+    }
+    let config: CacheConfig = match fs::read(&config_path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("Failed to parse cache config at {:?}", config_path))?,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(CacheConfig::default()),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read cache config at {:?}", config_path))?
+        }
+    };
+    let stamp = match stamp {
+        Some(stamp) => stamp,
+        None => return Ok(config),
+    };
+    // If the file changed again since it was stamped, this is remembered
+    // under the older stamp, and the next load reads it again.
+    let mut loaded = LOADED.lock().unwrap_or_else(PoisonError::into_inner);
+    let loaded = loaded.get_or_insert_with(HashMap::new);
+    if loaded.len() >= MAX_LOADED {
+        loaded.clear();
+    }
+    loaded.insert(config_path, (stamp, config.clone()));
+    Ok(config)
+}
+
+fn make_content_read_only(config: &CacheConfig, cache: &Path) -> Result<()> {
+    for dir in path::content_dirs(config, cache) {
+        for entry in WalkDir::new(dir).into_iter().filter_map(|entry| entry.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let perms = perms::read_only(entry.metadata().to_internal()?.permissions());
+            fs::set_permissions(entry.path(), perms)
+                .with_context(|| format!("Failed to make {:?} read-only", entry.path()))?;
+        }
+    }
+    Ok(())
+}
+
+fn has_any_content(config: &CacheConfig, cache: &Path) -> bool {
+    path::content_dirs(config, cache).iter().any(|dir| {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.file_type().is_file())
+    })
+}
+
+fn has_any_entries(cache: &Path) -> bool {
+    WalkDir::new(index::index_dir(cache))
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .any(|entry| entry.file_type().is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        assert_eq!(cache_config(&dir).unwrap(), CacheConfig::default());
+    }
+
+    #[test]
+    fn reloads_changed_configs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        configure(dir, CacheConfig::new().track_stats(true)).unwrap();
+        assert!(load(dir).unwrap().track_stats);
+        configure(dir, CacheConfig::new()).unwrap();
+        assert!(!load(dir).unwrap().track_stats);
+
+        // Edits made some other way are picked up too.
+        let edited = CacheConfig::new().content_levels(1);
+        fs::write(dir.join(CONFIG_FILE), serde_json::to_vec(&edited).unwrap()).unwrap();
+        assert_eq!(load(dir).unwrap(), edited);
+        fs::remove_file(dir.join(CONFIG_FILE)).unwrap();
+        assert_eq!(load(dir).unwrap(), CacheConfig::default());
+    }
+
+    #[test]
+    fn configure_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let config = CacheConfig::new().content_levels(3).content_width(1);
+        configure(&dir, config.clone()).unwrap();
+        assert_eq!(cache_config(&dir).unwrap(), config);
+    }
+
+    #[test]
+    fn index_format_fixed_once_written() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let fixed = CacheConfig::new().index_format(IndexFormat::Fixed);
+        configure(&dir, fixed.clone()).unwrap();
+        configure(&dir, CacheConfig::new()).unwrap();
+        crate::write(&dir, "key", b"hello").unwrap();
+        assert!(matches!(
+            configure(&dir, fixed),
+            Err(Error::InvalidConfig(..))
+        ));
+    }
+
+    #[test]
+    fn configure_invalid() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let config = CacheConfig::new().content_levels(9).content_width(2);
+        assert!(configure(&dir, config).is_err());
+        let config = CacheConfig::new().content_levels(1).content_width(0);
+        assert!(configure(&dir, config).is_err());
+    }
+
+    #[test]
+    fn custom_fanout_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        configure(&dir, CacheConfig::new().content_levels(3).content_width(1)).unwrap();
+        let sri = crate::write(&dir, "hello", b"hello world").unwrap();
+        let (algo, hex) = sri.to_hex();
+        let expected = path::content_dir(&dir)
+            .join(algo.to_string())
+            .join(&hex[0..1])
+            .join(&hex[1..2])
+            .join(&hex[2..3])
+            .join(&hex[3..]);
+        assert!(expected.is_file());
+        assert_eq!(crate::read(&dir, "hello").unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn layout_locked_once_populated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "hello", b"hello world").unwrap();
+        let config = CacheConfig::new().content_levels(1);
+        assert!(configure(&dir, config).is_err());
+        // Re-applying the current layout is always fine.
+        configure(&dir, CacheConfig::new()).unwrap();
+    }
+
+    #[test]
+    fn sharded_content_roots() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        let disk_a = tempfile::tempdir().unwrap();
+        let disk_b = tempfile::tempdir().unwrap();
+        let config = CacheConfig::new()
+            .content_root(disk_a.path())
+            .content_root(disk_b.path());
+        configure(&dir, config).unwrap();
+
+        let sris = (0..20)
+            .map(|i| crate::write(&dir, format!("key-{}", i), format!("data-{}", i)).unwrap())
+            .collect::<Vec<_>>();
+        for (i, sri) in sris.iter().enumerate() {
+            assert_eq!(
+                crate::read(&dir, format!("key-{}", i)).unwrap(),
+                format!("data-{}", i).as_bytes()
+            );
+            assert!(crate::exists(&dir, sri));
+        }
+        assert!(!path::content_dir(&dir).exists());
+        assert!(path::content_dir(disk_a.path()).exists());
+        assert!(path::content_dir(disk_b.path()).exists());
+
+        crate::clear(&dir).unwrap();
+        assert!(!path::content_dir(disk_a.path()).exists());
+        assert!(!path::content_dir(disk_b.path()).exists());
+    }
+
+    #[test]
+    fn read_only_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let old = crate::write(&dir, "old", b"old data").unwrap();
+        configure(&dir, CacheConfig::new().read_only_content(true)).unwrap();
+        let new = crate::write(&dir, "new", b"new data").unwrap();
+
+        for sri in [&old, &new] {
+            let cpath = path::content_path(&dir, sri).unwrap();
+            let perms = fs::metadata(&cpath).unwrap().permissions();
+            assert!(perms.readonly());
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                assert_eq!(perms.mode() & 0o777, 0o444);
+            }
+        }
+        assert_eq!(crate::read(&dir, "new").unwrap(), b"new data");
+
+        // Writing the same data again leaves it read-only, and readable.
+        assert_eq!(crate::write(&dir, "again", b"new data").unwrap(), new);
+        let cpath = path::content_path(&dir, &new).unwrap();
+        assert!(fs::metadata(&cpath).unwrap().permissions().readonly());
+        assert_eq!(crate::read(&dir, "new").unwrap(), b"new data");
+        assert_eq!(crate::read(&dir, "again").unwrap(), b"new data");
+
+        // Content that has been made writable is no longer trusted.
+        let cpath = path::content_path(&dir, &old).unwrap();
+        let mut perms = fs::metadata(&cpath).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        perms.set_readonly(false);
+        fs::set_permissions(&cpath, perms).unwrap();
+        assert!(crate::read(&dir, "old").is_err());
+
+        crate::remove_hash(&dir, &new).unwrap();
+        assert!(!crate::exists(&dir, &new));
+    }
+
+    #[test]
+    fn clear_keeps_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let config = CacheConfig::new().content_levels(1);
+        configure(&dir, config.clone()).unwrap();
+        crate::write(&dir, "hello", b"hello world").unwrap();
+        crate::clear(&dir).unwrap();
+        assert_eq!(cache_config(&dir).unwrap(), config);
+    }
+}
@@ -0,0 +1,115 @@
+//! Sequential writes that bypass the page cache with `O_DIRECT`, for large
+//! content that isn't going to be read again any time soon.
+//!
+//! Direct writes have to come from aligned memory, in aligned lengths, at
+//! aligned offsets. Data is gathered into an aligned buffer and written out a
+//! whole buffer at a time, and whatever's left over at the end, which is
+//! likely a partial block, goes through the ordinary file handle instead.
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Alignment that satisfies the logical block size of any common device.
+const ALIGN: usize = 4096;
+/// How much data is gathered before each direct write.
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+pub struct DirectWriter {
+    fd: File,
+    // Over-allocated by `ALIGN`, so an aligned buffer can be carved out of it.
+    buf: Vec<u8>,
+    start: usize,
+    len: usize,
+    offset: u64,
+}
+
+impl DirectWriter {
+    /// Opens `path`, which `file` already has open, for direct writes too.
+    /// Returns `None` where that isn't supported, such as on tmpfs, or on
+    /// platforms other than Linux, so writes carry on through `file` as
+    /// usual.
+    pub fn open(path: &Path) -> Option<DirectWriter> {
+        let fd = open_direct(path).ok()?;
+        let buf = vec![0; BUFFER_SIZE + ALIGN];
+        let start = buf.as_ptr().align_offset(ALIGN);
+        Some(DirectWriter {
+            fd,
+            buf,
+            start,
+            len: 0,
+            offset: 0,
+        })
+    }
+
+    /// Writes all of `data`, through `file` if direct writes turn out to be
+    /// refused.
+    pub fn write(&mut self, file: &File, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let amt = data.len().min(BUFFER_SIZE - self.len);
+            let at = self.start + self.len;
+            self.buf[at..at + amt].copy_from_slice(&data[..amt]);
+            self.len += amt;
+            data = &data[amt..];
+            if self.len == BUFFER_SIZE {
+                self.write_buffer(file)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out whatever's still buffered through `file`, which leaves the
+    /// file complete.
+    pub fn finish(self, file: &File) -> io::Result<()> {
+        write_all_at(file, self.buffered(), self.offset)
+    }
+
+    fn buffered(&self) -> &[u8] {
+        &self.buf[self.start..self.start + self.len]
+    }
+
+    fn write_buffer(&mut self, file: &File) -> io::Result<()> {
+        match write_all_at(&self.fd, self.buffered(), self.offset) {
+            Ok(()) => {}
+            // Some filesystems accept `O_DIRECT` when opening, but not when
+            // writing.
+            Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+                write_all_at(file, self.buffered(), self.offset)?
+            }
+            Err(err) => return Err(err),
+        }
+        self.offset += self.len as u64;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_path: &Path) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct writes are only supported on Linux",
+    ))
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(not(unix))]
+fn write_all_at(mut file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
@@ -0,0 +1,211 @@
+//! The bloom filter of content hashes kept by caches configured with
+//! [`CacheConfig::content_filter`](crate::CacheConfig::content_filter).
+//!
+//! It lives in `{cache}/content-filter-v1`, as a little-endian count of
+//! blocks followed by that many 64-byte blocks. Each content hash sets a few
+//! bits, all within the one block it hashes to, so checking for it takes a
+//! single small read. Bits are only ever set, under a lock, until the whole
+//! filter is rebuilt from the content on disk, sized for however much of it
+//! there is by then.
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use digest::Digest;
+use sha1::Sha1;
+use ssri::Integrity;
+use walkdir::WalkDir;
+
+use crate::config;
+use crate::content::path;
+use crate::errors::{Internal, Result};
+use crate::lock::Lock;
+
+const FILTER_FILE: &str = "content-filter-v1";
+const FILTER_LOCK: &str = "content-filter-v1.lock";
+const HEADER_SIZE: u64 = 8;
+const BLOCK_SIZE: usize = 64;
+/// Bits set for each hash.
+const HASH_BITS: usize = 8;
+/// Content objects each block is sized for, which gives 16 bits per object
+/// and keeps false positives well under 1% until the cache outgrows the
+/// filter.
+const OBJECTS_PER_BLOCK: usize = 32;
+/// Small caches get a filter this size anyway, so they have room to grow.
+const MIN_BLOCKS: usize = 1024;
+
+/// Which block a hash belongs in, and which bits of it it sets.
+struct Position {
+    hash: u32,
+    bits: [usize; HASH_BITS],
+}
+
+impl Position {
+    fn new(algo: &str, hex: &str) -> Position {
+        let digest = Sha1::digest(format!("{}-{}", algo, hex).as_bytes());
+        let hash = u32::from_le_bytes(digest[0..4].try_into().unwrap());
+        let mut rest = u128::from_le_bytes(digest[4..20].try_into().unwrap());
+        let mut bits = [0; HASH_BITS];
+        for bit in &mut bits {
+            *bit = (rest % (BLOCK_SIZE as u128 * 8)) as usize;
+            rest /= BLOCK_SIZE as u128 * 8;
+        }
+        Position { hash, bits }
+    }
+
+    fn of(sri: &Integrity) -> Position {
+        let (algo, hex) = sri.to_hex();
+        Position::new(&algo.to_string(), &hex)
+    }
+
+    fn offset(&self, blocks: u64) -> u64 {
+        HEADER_SIZE + (self.hash as u64 % blocks) * BLOCK_SIZE as u64
+    }
+
+    fn set(&self, block: &mut [u8]) {
+        for bit in self.bits {
+            block[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    fn is_set(&self, block: &[u8]) -> bool {
+        self.bits
+            .iter()
+            .all(|bit| block[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
+
+/// Whether the filter says `sri` might be in the cache. `Some(false)` means
+/// it definitely isn't. `None` means there's no filter to ask, or it
+/// couldn't be read, and the content itself needs checking.
+pub fn contains(cache: &Path, sri: &Integrity) -> Option<bool> {
+    let mut fd = File::open(cache.join(FILTER_FILE)).ok()?;
+    let blocks = read_blocks(&mut fd).ok()?;
+    let position = Position::of(sri);
+    let mut block = [0; BLOCK_SIZE];
+    fd.seek(SeekFrom::Start(position.offset(blocks))).ok()?;
+    fd.read_exact(&mut block).ok()?;
+    Some(position.is_set(&block))
+}
+
+/// Adds `sri` to the filter, if the cache has one.
+pub fn record(cache: &Path, sri: &Integrity) -> Result<()> {
+    let _lock = Lock::acquire(&cache.join(FILTER_LOCK))?;
+    let filter_path = cache.join(FILTER_FILE);
+    let mut fd = match OpenOptions::new().read(true).write(true).open(&filter_path) {
+        Ok(fd) => fd,
+        // The cache doesn't keep one, or it's about to be rebuilt.
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to open content filter at {:?}", filter_path))?
+        }
+    };
+    let position = Position::of(sri);
+    let mut block = [0; BLOCK_SIZE];
+    read_blocks(&mut fd)
+        .and_then(|blocks| fd.seek(SeekFrom::Start(position.offset(blocks))))
+        .and_then(|offset| {
+            fd.read_exact(&mut block)?;
+            position.set(&mut block);
+            fd.seek(SeekFrom::Start(offset))?;
+            fd.write_all(&block)
+        })
+        .with_context(|| format!("Failed to update content filter at {:?}", filter_path))?;
+    Ok(())
+}
+
+/// Builds the filter again from the content on disk, or removes it if the
+/// cache no longer keeps one. Content written while this runs can be left
+/// out, so like [`crate::repack`], this shouldn't run while anything else is
+/// writing to the cache.
+pub fn rebuild(cache: &Path) -> Result<()> {
+    let _lock = Lock::acquire(&cache.join(FILTER_LOCK))?;
+    let filter_path = cache.join(FILTER_FILE);
+    let config = config::load(cache)?;
+    if !config.content_filter {
+        return match fs::remove_file(&filter_path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err)
+                .with_context(|| format!("Failed to remove content filter at {:?}", filter_path))?,
+        };
+    }
+    let mut positions = Vec::new();
+    for dir in path::content_dirs(&config, cache) {
+        for file in WalkDir::new(&dir) {
+            let file = match file {
+                Ok(file) if file.file_type().is_file() => file,
+                Ok(_) => continue,
+                // No content yet.
+                Err(err) if err.depth() == 0 => break,
+                Err(err) if path::vanished(&err) => continue,
+                Err(err) => return Err(err).to_internal()?,
+            };
+            if let Some((algo, hex)) = path::parse_content_path(&dir, file.path()) {
+                positions.push(Position::new(&algo, &hex));
+            }
+        }
+    }
+    let blocks = (positions.len() / OBJECTS_PER_BLOCK + 1).max(MIN_BLOCKS);
+    let mut data = vec![0; HEADER_SIZE as usize + blocks * BLOCK_SIZE];
+    data[..HEADER_SIZE as usize].copy_from_slice(&(blocks as u64).to_le_bytes());
+    for position in positions {
+        let offset = position.offset(blocks as u64) as usize;
+        position.set(&mut data[offset..offset + BLOCK_SIZE]);
+    }
+    let tmp_path = cache.join("tmp");
+    fs::create_dir_all(&tmp_path)
+        .with_context(|| format!("Failed to create tmp directory at {:?}", tmp_path))?;
+    let mut tmp = tempfile::NamedTempFile::new_in(&tmp_path).to_internal()?;
+    tmp.write_all(&data)
+        .with_context(|| format!("Failed to write content filter for {:?}", cache))?;
+    tmp.persist(&filter_path)
+        .with_context(|| format!("Failed to replace content filter at {:?}", filter_path))?;
+    Ok(())
+}
+
+fn read_blocks(fd: &mut File) -> std::io::Result<u64> {
+    let mut header = [0; HEADER_SIZE as usize];
+    fd.read_exact(&mut header)?;
+    match u64::from_le_bytes(header) {
+        0 => Err(ErrorKind::InvalidData.into()),
+        blocks => Ok(blocks),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CacheConfig;
+
+    #[test]
+    fn filters_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let before = crate::write_hash(dir, b"before").unwrap();
+        let missing = Integrity::from(b"missing");
+        assert_eq!(contains(dir, &before), None);
+
+        crate::configure(dir, CacheConfig::new().content_filter(true)).unwrap();
+        assert_eq!(contains(dir, &before), Some(true));
+        assert_eq!(contains(dir, &missing), Some(false));
+        let after = crate::write_hash(dir, b"after").unwrap();
+        assert_eq!(contains(dir, &after), Some(true));
+        assert!(crate::exists(dir, &after));
+        assert!(!crate::exists(dir, &missing));
+
+        // Content that's gone is only dropped from the filter by a rebuild,
+        // and is checked on disk until then.
+        crate::remove_hash(dir, &after).unwrap();
+        assert_eq!(contains(dir, &after), Some(true));
+        assert!(!crate::exists(dir, &after));
+        rebuild(dir).unwrap();
+        assert_eq!(contains(dir, &after), Some(false));
+        assert_eq!(contains(dir, &before), Some(true));
+
+        crate::configure(dir, CacheConfig::new()).unwrap();
+        assert_eq!(contains(dir, &before), None);
+        assert!(!dir.join(FILTER_FILE).exists());
+    }
+}
@@ -1,4 +1,10 @@
+pub mod direct;
+pub mod filter;
 pub mod path;
+pub mod perms;
 pub mod read;
 pub mod rm;
+pub mod sparse;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
 pub mod write;
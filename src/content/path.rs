@@ -1,35 +1,136 @@
 use ssri::Integrity;
 use std::path::{Path, PathBuf};
 
+use crate::config::{self, CacheConfig};
+use crate::errors::{Internal, Result};
+use crate::index;
+
 const CONTENT_VERSION: &str = "2";
 
-// Current format of content file path:
+// Current format of content file path, with the default fan-out of two levels
+// of two characters each (see `CacheConfig`):
 //
 // sha512-BaSE64Hex= ->
 // ~/.my-cache/content-v2/sha512/ba/da/55deadbeefc0ffee
 //
-pub fn content_path(cache: &Path, sri: &Integrity) -> PathBuf {
-    let mut path = PathBuf::new();
+// If the cache is sharded across `content_roots`, `~/.my-cache` is replaced by
+// the root selected by the first byte of the hash.
+pub fn content_path(cache: &Path, sri: &Integrity) -> Result<PathBuf> {
+    content_path_checked(&config::load(cache)?, cache, sri)
+}
+
+/// Like `content_path_with`, but fails for integrities that can't name any
+/// content, like those of metadata-only entries.
+pub fn content_path_checked(
+    config: &CacheConfig,
+    cache: &Path,
+    sri: &Integrity,
+) -> Result<PathBuf> {
+    if index::is_no_content(sri) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "metadata-only entries have no content",
+        ))
+        .with_context(|| format!("No content for {:?} in {:?}", sri.to_string(), cache))?;
+    }
+    Ok(content_path_with(config, cache, sri))
+}
+
+pub fn content_path_with(config: &CacheConfig, cache: &Path, sri: &Integrity) -> PathBuf {
     let (algo, hex) = sri.to_hex();
-    path.push(cache);
-    path.push(format!("content-v{}", CONTENT_VERSION));
-    path.push(algo.to_string());
-    path.push(&hex[0..2]);
-    path.push(&hex[2..4]);
-    path.push(&hex[4..]);
+    hex_path(config, cache, &algo.to_string(), &hex)
+}
+
+/// Like `content_path_with`, but for an algorithm name and hex digest, such as
+/// those recovered by `parse_content_path`.
+pub fn hex_path(config: &CacheConfig, cache: &Path, algo: &str, hex: &str) -> PathBuf {
+    let mut path = content_dir(&hex_root(config, cache, hex));
+    path.push(algo);
+    let mut rest = hex;
+    for _ in 0..config.content_levels {
+        let (dir, tail) = rest.split_at(config.content_width.min(rest.len()));
+        path.push(dir);
+        rest = tail;
+    }
+    path.push(rest);
     path
 }
 
+/// Directory that the content object for `sri` is stored under.
+pub fn content_root(config: &CacheConfig, cache: &Path, sri: &Integrity) -> PathBuf {
+    hex_root(config, cache, &sri.to_hex().1)
+}
+
+fn hex_root(config: &CacheConfig, cache: &Path, hex: &str) -> PathBuf {
+    if config.content_roots.is_empty() {
+        return cache.to_path_buf();
+    }
+    let byte = hex
+        .get(0..2)
+        .and_then(|prefix| u8::from_str_radix(prefix, 16).ok())
+        .unwrap_or(0);
+    cache.join(&config.content_roots[byte as usize % config.content_roots.len()])
+}
+
+pub fn content_dir(root: &Path) -> PathBuf {
+    root.join(format!("content-v{}", CONTENT_VERSION))
+}
+
+/// All the content directories of a cache, one per content root.
+pub fn content_dirs(config: &CacheConfig, cache: &Path) -> Vec<PathBuf> {
+    if config.content_roots.is_empty() {
+        vec![content_dir(cache)]
+    } else {
+        config
+            .content_roots
+            .iter()
+            .map(|root| content_dir(&cache.join(root)))
+            .collect()
+    }
+}
+
+/// Recovers the algorithm name and hex digest from the path of a content file
+/// inside `content_dir`. This is independent of the fan-out layout.
+pub fn parse_content_path(content_dir: &Path, file: &Path) -> Option<(String, String)> {
+    let mut components = file.strip_prefix(content_dir).ok()?.iter();
+    let algo = components.next()?.to_str()?.to_owned();
+    let mut hex = String::new();
+    for component in components {
+        hex.push_str(component.to_str()?);
+    }
+    if hex.is_empty() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((algo, hex))
+}
+
+/// Whether an error walking a content directory is down to something in it
+/// being removed mid-walk, like a directory pruned by a concurrent removal,
+/// which is as good as it never having been there.
+pub fn vanished(err: &walkdir::Error) -> bool {
+    matches!(err.io_error(), Some(err) if err.kind() == std::io::ErrorKind::NotFound)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use ssri::Integrity;
     use std::path::Path;
 
+    #[test]
+    fn vanished_dirs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let err = walkdir::WalkDir::new(tmp.path().join("missing"))
+            .into_iter()
+            .find_map(|entry| entry.err())
+            .unwrap();
+        assert!(vanished(&err));
+    }
+
     #[test]
     fn basic_test() {
         let sri = Integrity::from(b"hello world");
-        let cpath = content_path(Path::new("~/.my-cache"), &sri);
+        let cpath = content_path(Path::new("~/.my-cache"), &sri).unwrap();
         let mut wanted = PathBuf::new();
         wanted.push("~/.my-cache");
         wanted.push(format!("content-v{}", CONTENT_VERSION));
@@ -39,4 +140,40 @@ mod tests {
         wanted.push("27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
         assert_eq!(cpath.to_str().unwrap(), wanted.to_str().unwrap());
     }
+
+    #[test]
+    fn custom_fanout() {
+        let sri = Integrity::from(b"hello world");
+        let config = CacheConfig::new().content_levels(1).content_width(3);
+        let cpath = content_path_with(&config, Path::new("~/.my-cache"), &sri);
+        let mut wanted = PathBuf::new();
+        wanted.push("~/.my-cache");
+        wanted.push(format!("content-v{}", CONTENT_VERSION));
+        wanted.push("sha256");
+        wanted.push("b94");
+        wanted.push("d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+        assert_eq!(cpath.to_str().unwrap(), wanted.to_str().unwrap());
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let sri = Integrity::from(b"hello world");
+        let config = CacheConfig::new().content_levels(3).content_width(1);
+        let cache = Path::new("/my-cache");
+        let cpath = content_path_with(&config, cache, &sri);
+        let (algo, hex) = parse_content_path(&content_dir(cache), &cpath).unwrap();
+        assert_eq!((algo, hex), (String::from("sha256"), sri.to_hex().1));
+        assert_eq!(parse_content_path(&content_dir(cache), cache), None);
+    }
+
+    #[test]
+    fn sharded_roots() {
+        // 0xb9 % 2 == 1
+        let sri = Integrity::from(b"hello world");
+        let config = CacheConfig::new()
+            .content_root("/disk-a")
+            .content_root("disk-b");
+        let cpath = content_path_with(&config, Path::new("/my-cache"), &sri);
+        assert!(cpath.starts_with("/my-cache/disk-b/content-v2/sha256/b9/4d"));
+    }
 }
@@ -0,0 +1,15 @@
+use std::fs::Permissions;
+
+/// The permissions committed content files get when the cache is configured
+/// with `read_only_content`.
+#[cfg(unix)]
+pub fn read_only(_current: Permissions) -> Permissions {
+    use std::os::unix::fs::PermissionsExt;
+    Permissions::from_mode(0o444)
+}
+
+#[cfg(not(unix))]
+pub fn read_only(mut current: Permissions) -> Permissions {
+    current.set_readonly(true);
+    current
+}
@@ -1,55 +1,248 @@
 use std::fs::{self, File};
-use std::path::Path;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
 
-use ssri::{Algorithm, Integrity, IntegrityChecker};
+use ssri::{Integrity, IntegrityChecker, IntegrityOpts};
 
-use crate::content::path;
-use crate::errors::{Internal, Result};
+use crate::config;
+use crate::content::{path, sparse};
+use crate::errors::{Error, Internal, Result};
+use crate::get::VerifyLevel;
+use crate::index::Metadata;
 
 pub struct Reader {
     fd: File,
-    checker: IntegrityChecker,
+    sri: Integrity,
+    // Only hashed when the data is being checked.
+    builder: Option<IntegrityOpts>,
+    expected_size: Option<u64>,
+    read: u64,
 }
 
 impl std::io::Read for Reader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let amt = self.fd.read(buf)?;
-        self.checker.input(&buf[..amt]);
+        if let Some(builder) = &mut self.builder {
+            builder.input(&buf[..amt]);
+        }
+        self.read += amt as u64;
         Ok(amt)
     }
 }
 
 impl Reader {
-    pub fn check(self) -> Result<Algorithm> {
-        Ok(self.checker.result()?)
+    /// Checks what was read, returning its integrity as computed from the
+    /// data. Readers that weren't opened to check the data fully never hash
+    /// it, so they return `None`.
+    pub fn check(self) -> Result<Option<Integrity>> {
+        if let Some(expected) = self.expected_size {
+            if self.read != expected {
+                return Err(Error::SizeError(expected as usize, self.read as usize));
+            }
+        }
+        let computed = match self.builder {
+            Some(builder) => builder.result(),
+            None => return Ok(None),
+        };
+        match self.sri.matches(&computed) {
+            Some(_) => Ok(Some(computed)),
+            None => Err(ssri::Error::IntegrityCheckError(self.sri, computed).into()),
+        }
     }
 }
 
 pub fn open(cache: &Path, sri: Integrity) -> Result<Reader> {
-    let cpath = path::content_path(cache, &sri);
+    open_file(&content_file(cache, &sri)?, sri)
+}
+
+pub fn open_file(cpath: &Path, sri: Integrity) -> Result<Reader> {
+    open_file_with(cpath, sri, VerifyLevel::Full, None)
+}
+
+/// Opens `cpath` to be checked at `verify` as it's read. `size` is what the
+/// data's length is checked against at [`VerifyLevel::Size`], if known.
+pub fn open_file_with(
+    cpath: &Path,
+    sri: Integrity,
+    verify: VerifyLevel,
+    size: Option<u64>,
+) -> Result<Reader> {
     Ok(Reader {
         fd: File::open(cpath).to_internal()?,
-        checker: IntegrityChecker::new(sri),
+        builder: match verify {
+            VerifyLevel::Full => Some(IntegrityOpts::new().algorithm(sri.pick_algorithm())),
+            _ => None,
+        },
+        sri,
+        expected_size: match verify {
+            VerifyLevel::Size => size,
+            _ => None,
+        },
+        read: 0,
     })
 }
 
+/// Opens the content for `sri` after checking all of it against `sri`, for
+/// callers that keep the handle around to read from it repeatedly.
+pub fn open_verified(cache: &Path, sri: &Integrity) -> Result<File> {
+    open_file_verified(&content_file(cache, sri)?, sri)
+}
+
+/// Like `open_verified`, for a file that's already been located, such as
+/// the external file of an entry.
+pub fn open_file_verified(cpath: &Path, sri: &Integrity) -> Result<File> {
+    let mut fd = File::open(cpath).to_internal()?;
+    let mut checker = IntegrityChecker::new(sri.clone());
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let amt = fd.read(&mut buf).to_internal()?;
+        if amt == 0 {
+            break;
+        }
+        checker.input(&buf[..amt]);
+    }
+    checker.result()?;
+    Ok(fd)
+}
+
 pub fn read(cache: &Path, sri: &Integrity) -> Result<Vec<u8>> {
-    let cpath = path::content_path(cache, sri);
+    read_file(&content_file(cache, sri)?, sri)
+}
+
+pub fn read_file(cpath: &Path, sri: &Integrity) -> Result<Vec<u8>> {
+    read_file_with(cpath, sri, VerifyLevel::Full, None)
+}
+
+/// Like `read_file`, checking the data at `verify` instead, like
+/// `open_file_with`.
+pub fn read_file_with(
+    cpath: &Path,
+    sri: &Integrity,
+    verify: VerifyLevel,
+    size: Option<u64>,
+) -> Result<Vec<u8>> {
     let ret = fs::read(cpath).to_internal()?;
+    match (verify, size) {
+        (VerifyLevel::Full, _) => {
+            sri.check(&ret)?;
+        }
+        (VerifyLevel::Size, Some(size)) if ret.len() as u64 != size => {
+            return Err(Error::SizeError(size as usize, ret.len()));
+        }
+        _ => {}
+    }
+    Ok(ret)
+}
+
+/// Reads each of `files` whole and checks it against its integrity, like
+/// `read_file`, returning the results in the same order. With the
+/// `io-uring` feature on Linux, the reads are all handed to the kernel at
+/// once, unless it turns out not to support that.
+pub fn read_files(files: &[(PathBuf, Integrity)]) -> Vec<Result<Vec<u8>>> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if files.len() > 1 {
+        if let Some(results) = super::uring::read_files(files) {
+            return results;
+        }
+    }
+    files
+        .iter()
+        .map(|(cpath, sri)| read_file(cpath, sri))
+        .collect()
+}
+
+/// Like `read_file`, but fails with `Error::LimitExceeded` instead of reading
+/// more than `limit` bytes.
+pub fn read_file_limited(cpath: &Path, sri: &Integrity, limit: u64) -> Result<Vec<u8>> {
+    let fd = File::open(cpath).to_internal()?;
+    let len = fd.metadata().to_internal()?.len();
+    if len > limit {
+        return Err(Error::LimitExceeded(limit, len));
+    }
+    // The file could still grow after being checked, so never read past the
+    // limit either way.
+    let mut ret = Vec::with_capacity(len as usize);
+    fd.take(limit + 1).read_to_end(&mut ret).to_internal()?;
+    if ret.len() as u64 > limit {
+        return Err(Error::LimitExceeded(limit, ret.len() as u64));
+    }
     sri.check(&ret)?;
     Ok(ret)
 }
 
+pub fn read_limited(cache: &Path, sri: &Integrity, limit: u64) -> Result<Vec<u8>> {
+    read_file_limited(&content_file(cache, sri)?, sri, limit)
+}
+
 pub fn copy(cache: &Path, sri: &Integrity, to: &Path) -> Result<u64> {
-    let cpath = path::content_path(cache, sri);
-    let ret = fs::copy(&cpath, to).to_internal()?;
+    copy_file(&content_file(cache, sri)?, sri, to)
+}
+
+pub fn copy_file(cpath: &Path, sri: &Integrity, to: &Path) -> Result<u64> {
+    let ret = fs::copy(cpath, to).to_internal()?;
     let data = fs::read(cpath).to_internal()?;
     sri.check(data)?;
     Ok(ret)
 }
 
+pub fn copy_to_writer<W: Write + ?Sized>(cache: &Path, sri: &Integrity, to: &mut W) -> Result<u64> {
+    let mut reader = open(cache, sri.clone())?;
+    let ret = io::copy(&mut reader, to).to_internal()?;
+    reader.check()?;
+    Ok(ret)
+}
+
+pub fn copy_sparse(cache: &Path, sri: &Integrity, to: &Path) -> Result<u64> {
+    copy_sparse_file(&content_file(cache, sri)?, sri, to)
+}
+
+pub fn copy_sparse_file(cpath: &Path, sri: &Integrity, to: &Path) -> Result<u64> {
+    let mut src = File::open(cpath).to_internal()?;
+    let mut dest = File::create(to).to_internal()?;
+    let mut checker = IntegrityChecker::new(sri.clone());
+    let ret = sparse::copy(&mut src, &mut dest, |block| checker.input(block)).to_internal()?;
+    checker.result()?;
+    Ok(ret)
+}
+
+/// Locates the data for an index entry: either the external file it points
+/// to, or its content file in the cache. Metadata-only entries have neither.
+pub fn entry_file(cache: &Path, entry: &Metadata) -> Result<PathBuf> {
+    if entry.metadata_only {
+        return Err(Error::NoContent(cache.to_path_buf(), entry.key.clone()));
+    }
+    match &entry.external {
+        Some(location) => Ok(location.clone()),
+        None => content_file(cache, &entry.integrity),
+    }
+}
+
+/// Locates the content file for `sri`. If the cache keeps content read-only,
+/// a writable file means something has been tampering with it, so it's
+/// refused rather than trusted.
+pub fn content_file(cache: &Path, sri: &Integrity) -> Result<PathBuf> {
+    let config = config::load(cache)?;
+    let cpath = path::content_path_checked(&config, cache, sri)?;
+    if config.read_only_content {
+        // A missing file is reported by whoever opens it.
+        if let Ok(meta) = fs::metadata(&cpath) {
+            if !meta.permissions().readonly() {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!(
+                        "Content file {:?} should be read-only, but is writable",
+                        cpath
+                    ),
+                ))
+                .to_internal()?;
+            }
+        }
+    }
+    Ok(cpath)
+}
+
 pub fn has_content(cache: &Path, sri: &Integrity) -> Option<Integrity> {
-    if path::content_path(cache, sri).exists() {
+    if path::content_path(cache, sri).ok()?.exists() {
         Some(sri.clone())
     } else {
         None
@@ -3,10 +3,65 @@ use std::path::Path;
 
 use ssri::Integrity;
 
+use crate::config::{self, CacheConfig};
 use crate::content::path;
 use crate::errors::{Internal, Result};
+use crate::retry;
+use crate::stats::{self, StatsDelta};
 
 pub fn rm(cache: &Path, sri: &Integrity) -> Result<()> {
-    fs::remove_file(path::content_path(cache, sri)).to_internal()?;
-    Ok(())
+    rm_file(cache, &path::content_path(cache, sri)?)
+}
+
+/// Removes the content file at `cpath`, for callers that found it by walking
+/// the content directories rather than by its integrity hash.
+pub fn rm_file(cache: &Path, cpath: &Path) -> Result<()> {
+    let config = config::load(cache)?;
+    let size = if config.track_stats {
+        fs::metadata(cpath).to_internal()?.len()
+    } else {
+        0
+    };
+    #[cfg(windows)]
+    make_writable(cpath);
+    retry::with_retries(cache, || fs::remove_file(cpath)).to_internal()?;
+    prune_dirs(&config, cache, cpath);
+    stats::record(
+        cache,
+        StatsDelta {
+            content_objects: -1,
+            content_bytes: -(size as i64),
+            ..Default::default()
+        },
+    )
+}
+
+/// Removes the directories between `cpath` and its content directory that
+/// are left empty by its removal. Writers recreate them as needed.
+pub(crate) fn prune_dirs(config: &CacheConfig, cache: &Path, cpath: &Path) {
+    let content_dir = match path::content_dirs(config, cache)
+        .into_iter()
+        .find(|dir| cpath.starts_with(dir))
+    {
+        Some(dir) => dir,
+        None => return,
+    };
+    for dir in cpath.ancestors().skip(1) {
+        // Stops at the first directory that still has something in it.
+        if dir == content_dir || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// Windows refuses to delete read-only files, which content files are if the
+/// cache is configured with `read_only_content`.
+#[cfg(windows)]
+#[allow(clippy::permissions_set_readonly_false)]
+pub(crate) fn make_writable(cpath: &Path) {
+    if let Ok(meta) = fs::metadata(cpath) {
+        let mut perms = meta.permissions();
+        perms.set_readonly(false);
+        let _ = fs::set_permissions(cpath, perms);
+    }
 }
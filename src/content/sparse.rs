@@ -0,0 +1,165 @@
+use std::fs::File;
+use std::io::{self, prelude::*, SeekFrom};
+
+/// Granularity at which runs of zeroes are turned into holes. Matches the
+/// block size of most filesystems.
+pub const BLOCK_SIZE: usize = 4096;
+
+/// Writes `buf` to `dest`, whose cursor is at offset `pos`, seeking over any
+/// block-aligned runs of zeroes instead of writing them so the filesystem can
+/// leave holes. The file length must be fixed up with `set_len` once writing
+/// is done, since trailing holes don't extend the file.
+pub fn write(dest: &mut File, pos: u64, mut buf: &[u8]) -> io::Result<()> {
+    let mut pos = pos;
+    while !buf.is_empty() {
+        let room = BLOCK_SIZE - (pos % BLOCK_SIZE as u64) as usize;
+        let (block, rest) = buf.split_at(room.min(buf.len()));
+        if block.iter().all(|b| *b == 0) {
+            dest.seek(SeekFrom::Current(block.len() as i64))?;
+        } else {
+            dest.write_all(block)?;
+        }
+        pos += block.len() as u64;
+        buf = rest;
+    }
+    Ok(())
+}
+
+/// Copies all of `src` into `dest`, preserving its holes. Where the
+/// filesystem can report where `src`'s holes are, they're skipped without
+/// being read. Runs of zeroes in the data that's left are turned into holes
+/// too, which is all that's done where holes can't be found.
+/// `inspect` is called with every block of data, holes included, in order.
+pub fn copy<F: FnMut(&[u8])>(src: &mut File, dest: &mut File, mut inspect: F) -> io::Result<u64> {
+    let len = src.metadata()?.len();
+    let zeroes = vec![0; BLOCK_SIZE];
+    let mut buf = vec![0; BLOCK_SIZE];
+    let mut total = 0;
+    while total < len {
+        let (start, end) = data_range(src, total, len);
+        while total < start {
+            let amt = (start - total).min(BLOCK_SIZE as u64) as usize;
+            inspect(&zeroes[..amt]);
+            total += amt as u64;
+        }
+        src.seek(SeekFrom::Start(total))?;
+        dest.seek(SeekFrom::Start(total))?;
+        while total < end {
+            let want = (end - total).min(BLOCK_SIZE as u64) as usize;
+            let amt = read_block(src, &mut buf[..want])?;
+            if amt == 0 {
+                // Shorter than it said it was.
+                dest.set_len(total)?;
+                return Ok(total);
+            }
+            inspect(&buf[..amt]);
+            write(dest, total, &buf[..amt])?;
+            total += amt as u64;
+        }
+    }
+    dest.set_len(total)?;
+    Ok(total)
+}
+
+/// The next range of `src`, which is `len` bytes long, at or after `pos`
+/// that the filesystem says holds data, as found with `SEEK_DATA` and
+/// `SEEK_HOLE`. Filesystems that don't track holes report the whole file as
+/// data.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos"
+))]
+fn data_range(src: &File, pos: u64, len: u64) -> (u64, u64) {
+    use std::os::unix::io::AsRawFd;
+
+    let seek = |pos: u64, whence| -> Option<u64> {
+        let pos = libc::off_t::try_from(pos).ok()?;
+        let found = unsafe { libc::lseek(src.as_raw_fd(), pos, whence) };
+        u64::try_from(found).ok()
+    };
+    let start = match seek(pos, libc::SEEK_DATA) {
+        Some(start) => start.min(len),
+        // Nothing but a hole is left.
+        None if io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) => {
+            return (len, len)
+        }
+        None => return (pos, len),
+    };
+    let end = seek(start, libc::SEEK_HOLE).map_or(len, |end| end.min(len));
+    (start, end.max(start))
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos"
+)))]
+fn data_range(_src: &File, pos: u64, len: u64) -> (u64, u64) {
+    (pos, len)
+}
+
+fn read_block(src: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match src.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(amt) => filled += amt,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_preserves_data() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut data = vec![0; BLOCK_SIZE * 8];
+        data[BLOCK_SIZE * 3 + 7] = 1;
+        data.extend_from_slice(b"trailing data");
+        data.extend_from_slice(&[0; BLOCK_SIZE * 2]);
+        std::fs::write(tmp.path().join("src"), &data).unwrap();
+
+        let mut src = File::open(tmp.path().join("src")).unwrap();
+        let mut dest = File::create(tmp.path().join("dest")).unwrap();
+        let mut seen = 0;
+        let copied = copy(&mut src, &mut dest, |block| seen += block.len()).unwrap();
+        assert_eq!(copied, data.len() as u64);
+        assert_eq!(seen, data.len());
+        assert_eq!(std::fs::read(tmp.path().join("dest")).unwrap(), data);
+    }
+
+    #[test]
+    fn copy_skips_holes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut src = File::create(tmp.path().join("src")).unwrap();
+        src.seek(SeekFrom::Start(BLOCK_SIZE as u64 * 256)).unwrap();
+        src.write_all(b"data").unwrap();
+        src.set_len(BLOCK_SIZE as u64 * 512).unwrap();
+        drop(src);
+        let mut data = vec![0; BLOCK_SIZE * 512];
+        data[BLOCK_SIZE * 256..][..4].copy_from_slice(b"data");
+
+        let mut src = File::open(tmp.path().join("src")).unwrap();
+        let mut dest = File::create(tmp.path().join("dest")).unwrap();
+        let mut seen = Vec::new();
+        let copied = copy(&mut src, &mut dest, |block| seen.extend_from_slice(block)).unwrap();
+        assert_eq!(copied, data.len() as u64);
+        assert!(seen == data);
+        assert!(std::fs::read(tmp.path().join("dest")).unwrap() == data);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let src = std::fs::metadata(tmp.path().join("src")).unwrap();
+            let dest = std::fs::metadata(tmp.path().join("dest")).unwrap();
+            assert!(dest.blocks() <= src.blocks());
+        }
+    }
+}
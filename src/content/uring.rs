@@ -0,0 +1,177 @@
+//! Reads of many content files at once through `io_uring`, so that fast
+//! devices see a deep queue of requests rather than one at a time.
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use io_uring::{opcode, types, IoUring, Probe};
+use ssri::Integrity;
+
+use crate::errors::{Internal, Result};
+
+/// How many reads are kept in flight at once.
+const QUEUE_DEPTH: u32 = 64;
+
+/// A file being read into `buf`, which is sized to what the file was when it
+/// was opened.
+struct Pending {
+    fd: File,
+    buf: Vec<u8>,
+    filled: usize,
+}
+
+/// Reads each of `files` whole and checks it against its integrity, like
+/// `read::read_file`. Returns `None` if this kernel can't do it, such as one
+/// without `io_uring`, or one where it's been disabled, so the caller can
+/// read the files the usual way instead.
+///
+/// Files are only opened as there's room for them in the queue, so no more
+/// than `QUEUE_DEPTH` of them are open, with buffers waiting to be filled,
+/// at any one time.
+pub fn read_files(files: &[(PathBuf, Integrity)]) -> Option<Vec<Result<Vec<u8>>>> {
+    let mut ring = IoUring::new(QUEUE_DEPTH).ok()?;
+    let mut probe = Probe::new();
+    ring.submitter().register_probe(&mut probe).ok()?;
+    if !probe.is_supported(opcode::Read::CODE) {
+        return None;
+    }
+
+    let mut results = Vec::with_capacity(files.len());
+    results.resize_with(files.len(), || None);
+    let mut pending = HashMap::new();
+    let mut queue = VecDeque::new();
+    let mut next = 0;
+    let mut in_flight = 0;
+    while !pending.is_empty() || next < files.len() {
+        while pending.len() < QUEUE_DEPTH as usize && next < files.len() {
+            let (cpath, sri) = &files[next];
+            let file = File::open(cpath).and_then(|fd| {
+                let len = fd.metadata()?.len() as usize;
+                Ok(Pending {
+                    fd,
+                    buf: vec![0; len],
+                    filled: 0,
+                })
+            });
+            match file {
+                Ok(file) if !file.buf.is_empty() => {
+                    pending.insert(next, file);
+                    queue.push_back(next);
+                }
+                file => results[next] = Some(finish(sri, file.map(|file| file.buf))),
+            }
+            next += 1;
+        }
+        while let Some(idx) = queue.pop_front() {
+            // Safe unwrap. Only pending files are queued.
+            let file = pending.get_mut(&idx).unwrap();
+            let rest = &mut file.buf[file.filled..];
+            let len = rest.len().min(u32::MAX as usize) as u32;
+            let read = opcode::Read::new(types::Fd(file.fd.as_raw_fd()), rest.as_mut_ptr(), len)
+                .offset(file.filled as u64)
+                .build()
+                .user_data(idx as u64);
+            // The buffer and file stay where they are, untouched, until the
+            // read completes.
+            unsafe { ring.submission().push(&read) }
+                .expect("submission queue is never fuller than the files pending");
+            in_flight += 1;
+        }
+        if in_flight == 0 {
+            continue;
+        }
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => {
+                // Reads still in flight could write to their buffers at any
+                // point, so they can't be freed.
+                for (_, file) in pending {
+                    std::mem::forget(file.buf);
+                }
+                return Some(
+                    results
+                        .into_iter()
+                        .zip(files)
+                        .map(|(result, (_, sri))| {
+                            result.unwrap_or_else(|| {
+                                finish(sri, Err(io::Error::new(err.kind(), err.to_string())))
+                            })
+                        })
+                        .collect(),
+                );
+            }
+        }
+        for cqe in ring.completion() {
+            in_flight -= 1;
+            let idx = cqe.user_data() as usize;
+            // Safe unwrap. Files stay pending until their last read completes.
+            let mut file = pending.remove(&idx).unwrap();
+            let read = match cqe.result() {
+                res if res < 0 => Err(io::Error::from_raw_os_error(-res)),
+                // The file shrank since it was opened. Whatever was read is
+                // checked like anything else, and fails.
+                0 => {
+                    file.buf.truncate(file.filled);
+                    Ok(file.buf)
+                }
+                res => {
+                    file.filled += res as usize;
+                    if file.filled < file.buf.len() {
+                        pending.insert(idx, file);
+                        queue.push_back(idx);
+                        continue;
+                    }
+                    Ok(file.buf)
+                }
+            };
+            results[idx] = Some(finish(&files[idx].1, read));
+        }
+    }
+
+    // Safe unwrap. Every file has been opened and finished with.
+    Some(results.into_iter().map(Option::unwrap).collect())
+}
+
+/// Checks what was read from a file against `sri`.
+fn finish(sri: &Integrity, read: io::Result<Vec<u8>>) -> Result<Vec<u8>> {
+    let data = read.to_internal()?;
+    sri.check(&data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Error;
+
+    #[test]
+    fn reads_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut files = Vec::new();
+        for i in 0..200 {
+            let data = vec![i as u8; i * 1000];
+            let cpath = dir.join(i.to_string());
+            std::fs::write(&cpath, &data).unwrap();
+            files.push((cpath, Integrity::from(&data)));
+        }
+        std::fs::write(dir.join("3"), b"corrupted").unwrap();
+        files[5].0 = dir.join("missing");
+
+        let results = match read_files(&files) {
+            Some(results) => results,
+            // Nothing to test where io_uring isn't allowed.
+            None => return,
+        };
+        for (i, result) in results.into_iter().enumerate() {
+            match i {
+                3 => assert!(matches!(result, Err(Error::IntegrityError { .. }))),
+                5 => assert!(result.is_err()),
+                _ => assert_eq!(result.unwrap(), vec![i as u8; i * 1000]),
+            }
+        }
+    }
+}
@@ -1,13 +1,19 @@
-use std::fs::DirBuilder;
+use std::fs::{DirBuilder, File};
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
-use memmap2::MmapMut;
 use ssri::{Algorithm, Integrity, IntegrityOpts};
-use tempfile::NamedTempFile;
+use tempfile::{NamedTempFile, PersistError};
 
-use crate::content::path;
-use crate::errors::{Internal, Result};
+use crate::config;
+use crate::content::direct::DirectWriter;
+use crate::content::{filter, path, perms, read, sparse};
+use crate::errors::{Error, Internal, Result};
+use crate::mmap::MmapMut;
+use crate::put::WriteOpts;
+use crate::quarantine;
+use crate::retry::Backoff;
+use crate::stats::{self, StatsDelta};
 
 pub const MAX_MMAP_SIZE: usize = 1024 * 1024;
 
@@ -15,11 +21,20 @@ pub struct Writer {
     cache: PathBuf,
     builder: IntegrityOpts,
     mmap: Option<MmapMut>,
-    tmpfile: NamedTempFile,
+    tmpfile: Option<NamedTempFile>,
+    direct: Option<DirectWriter>,
+    sparse: bool,
+    verify_existing: bool,
+    fsync: bool,
+    written: u64,
+    algorithm: Algorithm,
+    size: Option<u64>,
+    random_access: bool,
 }
 
 impl Writer {
-    pub fn new(cache: &Path, algo: Algorithm, size: Option<usize>) -> Result<Writer> {
+    pub fn new(cache: &Path, opts: &WriteOpts) -> Result<Writer> {
+        let algo = opts.algorithm.unwrap_or(Algorithm::Sha256);
         let cache_path = cache.to_path_buf();
         let mut tmp_path = cache_path.clone();
         tmp_path.push("tmp");
@@ -28,8 +43,10 @@ impl Writer {
             .create(&tmp_path)
             .to_internal()?;
         let mut tmpfile = NamedTempFile::new_in(tmp_path).to_internal()?;
-        let mmap = if let Some(size) = size {
-            if size <= MAX_MMAP_SIZE {
+        let mmap = if let Some(size) = opts.size {
+            // Writing zeroes through a mapping allocates them, so sparse
+            // writes always go through the file.
+            if size <= MAX_MMAP_SIZE && !opts.sparse {
                 tmpfile.as_file_mut().set_len(size as u64).to_internal()?;
                 unsafe { MmapMut::map_mut(tmpfile.as_file()).ok() }
             } else {
@@ -38,24 +55,196 @@ impl Writer {
         } else {
             None
         };
+        // Mapped writes are small enough not to bother with, and sparse ones
+        // have to seek over their holes.
+        let direct = if opts.direct_io && mmap.is_none() && !opts.sparse {
+            DirectWriter::open(tmpfile.path())
+        } else {
+            None
+        };
         Ok(Writer {
             cache: cache_path,
             builder: IntegrityOpts::new().algorithm(algo),
-            tmpfile,
+            tmpfile: Some(tmpfile),
             mmap,
+            direct,
+            sparse: opts.sparse,
+            verify_existing: opts.verify_existing,
+            fsync: opts.fsync,
+            written: 0,
+            algorithm: algo,
+            size: opts.size.map(|size| size as u64),
+            random_access: false,
         })
     }
 
-    pub fn close(self) -> Result<Integrity> {
-        let sri = self.builder.result();
-        let cpath = path::content_path(&self.cache, &sri);
-        DirBuilder::new()
-            .recursive(true)
+    pub fn integrity_so_far(&self) -> Integrity {
+        self.builder.clone().result()
+    }
+
+    /// Writes `buf` at `offset`, independently of any other writes. Once
+    /// this is used, the integrity is computed from the completed file when
+    /// the writer is closed, and sequential writes are no longer accepted.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        let size = self.size.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "writes at offsets need a declared size",
+            )
+        })?;
+        let end = match u64::try_from(buf.len())
+            .ok()
+            .and_then(|len| offset.checked_add(len))
+        {
+            Some(end) if end <= size => end,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "write would exceed the declared size",
+                ))
+            }
+        };
+        if let Some(mmap) = &mut self.mmap {
+            mmap[offset as usize..end as usize].copy_from_slice(buf);
+        } else {
+            // Safe unwrap. The tmpfile is only ever taken when the writer is
+            // being consumed.
+            let file = self.tmpfile.as_mut().unwrap().as_file_mut();
+            if let Some(direct) = self.direct.take() {
+                direct.finish(file)?;
+            }
+            file.seek(std::io::SeekFrom::Start(offset))?;
+            file.write_all(buf)?;
+        }
+        self.random_access = true;
+        Ok(())
+    }
+
+    /// Hashes the whole tmpfile, for writers filled in at arbitrary offsets.
+    fn hash_file(&mut self) -> Result<Integrity> {
+        let mut builder = IntegrityOpts::new().algorithm(self.algorithm);
+        if let Some(mmap) = &self.mmap {
+            builder.input(&mmap[..]);
+        } else {
+            // Safe unwrap, as above. Ranges that were never written read back
+            // as zeroes.
+            let file = self.tmpfile.as_mut().unwrap().as_file_mut();
+            file.set_len(self.size.unwrap_or(0)).to_internal()?;
+            file.seek(std::io::SeekFrom::Start(0)).to_internal()?;
+            let mut buf = vec![0; sparse::BLOCK_SIZE * 16];
+            loop {
+                let amt = file.read(&mut buf).to_internal()?;
+                if amt == 0 {
+                    break;
+                }
+                builder.input(&buf[..amt]);
+            }
+        }
+        Ok(builder.result())
+    }
+
+    /// The integrity of everything written so far.
+    pub fn integrity(&mut self) -> Result<Integrity> {
+        if self.random_access {
+            self.hash_file()
+        } else {
+            Ok(self.builder.clone().result())
+        }
+    }
+
+    /// Moves the written data into place as `sri`, which must come from
+    /// [`Writer::integrity`] with nothing written since.
+    pub fn close(mut self, sri: Integrity) -> Result<Integrity> {
+        let config = config::load(&self.cache)?;
+        let cpath = path::content_path_with(&config, &self.cache, &sri);
+        // Safe unwrap. cpath always has multiple segments
+        create_content_dir(cpath.parent().unwrap())?;
+        // Safe unwrap. The tmpfile is only taken by `close` and `abort`, which
+        // both consume the writer.
+        let mut tmpfile = self.tmpfile.take().unwrap();
+        if let Some(direct) = self.direct.take() {
+            direct.finish(tmpfile.as_file()).to_internal()?;
+        }
+        if self.sparse && !self.random_access {
+            // Trailing holes were only seeked over, so pin down the length.
+            tmpfile.as_file().set_len(self.written).to_internal()?;
+        }
+        // Unmapped before anything else touches the file. Mapped writes are
+        // already visible through the file itself, but only reach the disk
+        // once flushed.
+        if let Some(mmap) = self.mmap.take() {
+            if self.fsync {
+                mmap.flush().to_internal()?;
+            }
+        }
+        let root = path::content_root(&config, &self.cache, &sri);
+        if root != self.cache {
+            // Content roots may live on other volumes, which a rename can't
+            // cross, so stage a copy in the root's own tmp dir first.
+            tmpfile = copy_to_tmp(tmpfile, &root, self.sparse)?;
+        }
+        tmpfile.as_file_mut().flush().to_internal()?;
+        if self.fsync {
+            tmpfile.as_file().sync_all().to_internal()?;
+        }
+        // A short file would be committed under a hash it doesn't match, and
+        // every read of it would fail, so make sure it all made it out.
+        let len = tmpfile.as_file().metadata().to_internal()?.len();
+        let expected = if self.random_access {
+            self.size.unwrap_or(0)
+        } else {
+            self.written
+        };
+        if len != expected {
+            return Err(Error::SizeError(expected as usize, len as usize));
+        }
+        // Renames replace existing files on most platforms, so check up front
+        // whether this is actually new content.
+        let mut existed = cpath.exists();
+        if self.verify_existing && existed && !has_valid_content(&cpath, &sri) {
+            // The existing file is corrupt, so it's set aside for this one,
+            // which is known to be good, rather than just being renamed over.
+            quarantine::quarantine_file(
+                &self.cache,
+                &cpath,
+                &sri,
+                "Conflicted with a write of the same content, but didn't match its hash",
+            )?;
+            // Which may have pruned its directory.
             // Safe unwrap. cpath always has multiple segments
-            .create(cpath.parent().unwrap())
-            .to_internal()?;
-        let res = self.tmpfile.persist(&cpath).to_internal();
-        if res.is_err() {
+            create_content_dir(cpath.parent().unwrap())?;
+            existed = false;
+        }
+        let res = persist(&self.cache, tmpfile, &cpath)?.to_internal();
+        #[cfg(unix)]
+        if self.fsync {
+            // The rename itself only lasts once the directory is synced too.
+            // Safe unwrap. cpath always has multiple segments
+            File::open(cpath.parent().unwrap())
+                .and_then(|dir| dir.sync_all())
+                .to_internal()?;
+        }
+        if res.is_ok() && config.content_filter {
+            // Content that already existed might predate the filter.
+            filter::record(&self.cache, &sri)?;
+        }
+        // Even when the content already existed, the file that was just moved
+        // into place may have replaced it.
+        if let (Ok(file), true) = (&res, config.read_only_content) {
+            let perms = perms::read_only(file.metadata().to_internal()?.permissions());
+            file.set_permissions(perms)
+                .with_context(|| format!("Failed to make {:?} read-only", cpath))?;
+        }
+        if let (Ok(file), false) = (&res, existed) {
+            stats::record(
+                &self.cache,
+                StatsDelta {
+                    content_objects: 1,
+                    content_bytes: file.metadata().to_internal()?.len() as i64,
+                    ..Default::default()
+                },
+            )?;
+        } else {
             // We might run into conflicts sometimes when persisting files.
             // This is ok. We can deal. Let's just make sure the destination
             // file actually exists, and we can move on.
@@ -63,21 +252,169 @@ impl Writer {
         }
         Ok(sri)
     }
+
+    pub fn abort(mut self) -> Result<()> {
+        self.mmap = None;
+        if let Some(tmpfile) = self.tmpfile.take() {
+            tmpfile.close().to_internal()?;
+        }
+        Ok(())
+    }
+}
+
+/// How many times a content directory pruned out from under a writer is
+/// made again before giving up.
+const MAX_RECREATES: u32 = 16;
+
+/// Creates the content directory `dir`, along with its parents. Removals
+/// running alongside can prune them part way through, which fails creating
+/// them with `NotFound`, or even `AlreadyExists`, so that's retried.
+fn create_content_dir(dir: &Path) -> Result<()> {
+    let mut attempts = 0;
+    loop {
+        match DirBuilder::new().recursive(true).create(dir) {
+            Ok(()) => return Ok(()),
+            Err(err)
+                if attempts < MAX_RECREATES
+                    && matches!(
+                        err.kind(),
+                        std::io::ErrorKind::NotFound | std::io::ErrorKind::AlreadyExists
+                    ) =>
+            {
+                attempts += 1;
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to create content directory {:?}", dir))?
+            }
+        }
+    }
+}
+
+/// Moves `tmpfile` to `cpath`, retrying transient failures. Other errors,
+/// including any conflict with a file already at `cpath`, are handed back
+/// along with the tmpfile for the caller to deal with.
+fn persist(
+    cache: &Path,
+    tmpfile: NamedTempFile,
+    cpath: &Path,
+) -> Result<std::result::Result<File, PersistError>> {
+    let mut backoff = Backoff::for_persist(cache);
+    let mut recreated = 0;
+    let mut res = tmpfile.persist(cpath);
+    loop {
+        res = match res {
+            // Removals prune directories they leave empty, which may include
+            // the one the writer created, and can keep doing so for as long
+            // as other writes to the same directory keep emptying it.
+            Err(err)
+                if recreated < MAX_RECREATES
+                    && err.error.kind() == std::io::ErrorKind::NotFound =>
+            {
+                // Safe unwrap. cpath always has multiple segments
+                create_content_dir(cpath.parent().unwrap())?;
+                recreated += 1;
+                err.file.persist(cpath)
+            }
+            Err(err) if !cpath.exists() && backoff.retry(&err.error) => err.file.persist(cpath),
+            Err(err) if backoff.exhausted() => {
+                return Err(Error::PersistRetriesExhausted(
+                    cpath.to_path_buf(),
+                    backoff.attempts(),
+                    err.error,
+                ))
+            }
+            res => return Ok(res),
+        };
+    }
+}
+
+fn has_valid_content(cpath: &Path, sri: &Integrity) -> bool {
+    let mut reader = match read::open_file(cpath, sri.clone()) {
+        Ok(reader) => reader,
+        Err(_) => return false,
+    };
+    std::io::copy(&mut reader, &mut std::io::sink()).is_ok() && reader.check().is_ok()
+}
+
+fn copy_to_tmp(mut tmpfile: NamedTempFile, root: &Path, sparse: bool) -> Result<NamedTempFile> {
+    let tmp_path = root.join("tmp");
+    DirBuilder::new()
+        .recursive(true)
+        .create(&tmp_path)
+        .to_internal()?;
+    let mut copy = NamedTempFile::new_in(tmp_path).to_internal()?;
+    tmpfile
+        .as_file_mut()
+        .seek(std::io::SeekFrom::Start(0))
+        .to_internal()?;
+    if sparse {
+        sparse::copy(tmpfile.as_file_mut(), copy.as_file_mut(), |_| {}).to_internal()?;
+    } else {
+        std::io::copy(tmpfile.as_file_mut(), copy.as_file_mut()).to_internal()?;
+    }
+    Ok(copy)
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // Unmap first: some platforms refuse to remove a file that is still
+        // mapped into memory.
+        self.mmap = None;
+        if let Some(tmpfile) = self.tmpfile.take() {
+            #[cfg(feature = "leak-warnings")]
+            eprintln!(
+                "cacache-sync: Writer for cache at {:?} was dropped without calling commit() or abort(). Its data has been discarded.",
+                self.cache
+            );
+            let _ = tmpfile.close();
+        }
+    }
 }
 
 impl Write for Writer {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.builder.input(buf);
-        if let Some(mmap) = &mut self.mmap {
-            mmap.copy_from_slice(buf);
-            Ok(buf.len())
-        } else {
-            self.tmpfile.write(buf)
+        if self.random_access {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "sequential writes can't follow writes at offsets",
+            ));
         }
+        let amt = if let Some(mmap) = &mut self.mmap {
+            let start = self.written as usize;
+            let dest = match mmap.get_mut(start..start + buf.len()) {
+                Some(dest) => dest,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "write would exceed the declared size",
+                    ))
+                }
+            };
+            dest.copy_from_slice(buf);
+            buf.len()
+        } else if self.sparse {
+            // Safe unwrap. The tmpfile is only ever taken when the writer is
+            // being consumed.
+            sparse::write(
+                self.tmpfile.as_mut().unwrap().as_file_mut(),
+                self.written,
+                buf,
+            )?;
+            buf.len()
+        } else if let Some(direct) = &mut self.direct {
+            direct.write(self.tmpfile.as_ref().unwrap().as_file(), buf)?;
+            buf.len()
+        } else {
+            self.tmpfile.as_mut().unwrap().write(buf)?
+        };
+        self.builder.input(&buf[..amt]);
+        self.written += amt as u64;
+        Ok(amt)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        self.tmpfile.flush()
+        self.tmpfile.as_mut().unwrap().flush()
     }
 }
 
@@ -89,13 +426,171 @@ mod tests {
     fn basic_write() {
         let tmp = tempfile::tempdir().unwrap();
         let dir = tmp.path().to_owned();
-        let mut writer = Writer::new(&dir, Algorithm::Sha256, None).unwrap();
+        let mut writer = Writer::new(&dir, &WriteOpts::new()).unwrap();
         writer.write_all(b"hello world").unwrap();
-        let sri = writer.close().unwrap();
+        let sri = writer.integrity().unwrap();
+        let sri = writer.close(sri).unwrap();
         assert_eq!(sri.to_string(), Integrity::from(b"hello world").to_string());
         assert_eq!(
-            std::fs::read(path::content_path(&dir, &sri)).unwrap(),
+            std::fs::read(path::content_path(&dir, &sri).unwrap()).unwrap(),
             b"hello world"
         );
     }
+
+    #[test]
+    fn drop_removes_tmpfile() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let mut writer = Writer::new(&dir, &WriteOpts::new()).unwrap();
+        writer.write_all(b"hello world").unwrap();
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 1);
+        drop(writer);
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn abort_removes_tmpfile() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let mut writer = Writer::new(&dir, &WriteOpts::new().size(11)).unwrap();
+        writer.write_all(b"hello world").unwrap();
+        writer.abort().unwrap();
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn fsync_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        for opts in [WriteOpts::new(), WriteOpts::new().size(11)] {
+            let mut writer = Writer::new(&dir, &opts.fsync(true)).unwrap();
+            writer.write_all(b"hello world").unwrap();
+            let sri = writer.integrity().unwrap();
+            let sri = writer.close(sri).unwrap();
+            assert_eq!(
+                std::fs::read(path::content_path(&dir, &sri).unwrap()).unwrap(),
+                b"hello world"
+            );
+        }
+    }
+
+    #[test]
+    fn direct_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let data = (0..3_500_000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut writer = Writer::new(&dir, &WriteOpts::new().direct_io(true)).unwrap();
+        for chunk in data.chunks(300_001) {
+            writer.write_all(chunk).unwrap();
+        }
+        let sri = writer.integrity().unwrap();
+        let sri = writer.close(sri).unwrap();
+        assert_eq!(sri, Integrity::from(&data));
+        assert_eq!(
+            std::fs::read(path::content_path(&dir, &sri).unwrap()).unwrap(),
+            data
+        );
+
+        // Writes at offsets pick up where the buffered data left off.
+        let size = data.len() + 10;
+        let opts = WriteOpts::new().direct_io(true).size(size);
+        let mut writer = Writer::new(&dir, &opts).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.write_at(data.len() as u64, &[1; 10]).unwrap();
+        let sri = writer.integrity().unwrap();
+        let sri = writer.close(sri).unwrap();
+        let written = std::fs::read(path::content_path(&dir, &sri).unwrap()).unwrap();
+        assert_eq!(written[..data.len()], data[..]);
+        assert_eq!(written[data.len()..], [1; 10]);
+    }
+
+    #[test]
+    fn short_file_not_persisted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let mut writer = Writer::new(&dir, &WriteOpts::new()).unwrap();
+        writer.write_all(b"hello world").unwrap();
+        // As if the end of the write never made it to the file.
+        writer
+            .tmpfile
+            .as_ref()
+            .unwrap()
+            .as_file()
+            .set_len(5)
+            .unwrap();
+        let sri = writer.integrity().unwrap();
+        assert!(matches!(
+            writer.close(sri.clone()),
+            Err(Error::SizeError(11, 5))
+        ));
+        assert!(!path::content_path(&dir, &sri).unwrap().exists());
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn verify_existing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = Integrity::from(b"hello world");
+        let cpath = path::content_path(&dir, &sri).unwrap();
+        assert!(!has_valid_content(&cpath, &sri));
+        std::fs::create_dir_all(cpath.parent().unwrap()).unwrap();
+        std::fs::write(&cpath, b"corrupted!!").unwrap();
+        assert!(!has_valid_content(&cpath, &sri));
+
+        let opts = WriteOpts::new().verify_existing(true);
+        let mut writer = Writer::new(&dir, &opts).unwrap();
+        writer.write_all(b"hello world").unwrap();
+        assert_eq!(writer.integrity().unwrap(), sri);
+        assert_eq!(writer.close(sri.clone()).unwrap(), sri);
+        assert!(has_valid_content(&cpath, &sri));
+        assert_eq!(std::fs::read(&cpath).unwrap(), b"hello world");
+        let quarantined = crate::list_quarantine(&dir).unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].integrity, sri.to_string());
+        assert_eq!(quarantined[0].size, b"corrupted!!".len() as u64);
+    }
+
+    #[test]
+    fn sparse_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let mut data = vec![0; 1024 * 1024];
+        data[100] = 1;
+        let mut writer = Writer::new(&dir, &WriteOpts::new().sparse(true)).unwrap();
+        // Odd-sized chunks, so writes straddle block boundaries.
+        for chunk in data.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        let sri = writer.integrity().unwrap();
+        let sri = writer.close(sri).unwrap();
+        assert_eq!(sri, Integrity::from(&data));
+        let cpath = path::content_path(&dir, &sri).unwrap();
+        assert_eq!(std::fs::read(&cpath).unwrap(), data);
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let allocated = std::fs::metadata(&cpath).unwrap().blocks() * 512;
+            assert!(allocated < data.len() as u64);
+        }
+    }
+
+    #[test]
+    fn writes_alongside_pruning() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        // Without fan-out, every object shares one directory, which each
+        // removal prunes whenever it leaves it empty.
+        crate::configure(dir, crate::CacheConfig::new().content_levels(0)).unwrap();
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                scope.spawn(move || {
+                    for i in 0..200 {
+                        let sri = crate::write_hash(dir, format!("{}-{}", t, i)).unwrap();
+                        crate::remove_hash(dir, &sri).unwrap();
+                    }
+                });
+            }
+        });
+    }
 }
@@ -0,0 +1,292 @@
+//! Functions for deduplicating content across caches, and for measuring how
+//! much is already shared within one.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+use ssri::Integrity;
+use walkdir::WalkDir;
+
+use crate::config;
+use crate::content::path;
+use crate::errors::{Internal, Result};
+use crate::index;
+
+/// How many of the most shared objects [`dedupe_report`] lists.
+const TOP_SHARED: usize = 10;
+
+/// Summary of what was deduplicated by [`dedupe_against`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DedupeReport {
+    /// Number of content files that were replaced with hard links.
+    pub linked: usize,
+    /// Total size in bytes of the content files that were replaced.
+    pub bytes_saved: u64,
+}
+
+/// Replaces content files in `cache` that are also present in `other` with
+/// hard links to the copies in `other`, returning how much space was saved.
+/// Both caches must live on the same filesystem. Files are compared byte for
+/// byte before being linked, so a corrupted object in either cache is never
+/// propagated to the other.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let report = cacache_sync::dedupe_against("./project-a/cache", "./project-b/cache")?;
+///     println!("saved {} bytes", report.bytes_saved);
+///     Ok(())
+/// }
+/// ```
+pub fn dedupe_against<P, Q>(cache: P, other: Q) -> Result<DedupeReport>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let cache = cache.as_ref();
+    let other = other.as_ref();
+    let config = config::load(cache)?;
+    let other_config = config::load(other)?;
+    let mut report = DedupeReport::default();
+    for dir in path::content_dirs(&config, cache) {
+        for file in WalkDir::new(&dir) {
+            let file = match file {
+                Ok(file) if file.file_type().is_file() => file,
+                Ok(_) => continue,
+                // No content yet.
+                Err(err) if err.depth() == 0 => break,
+                Err(err) if path::vanished(&err) => continue,
+                Err(err) => return Err(err).to_internal()?,
+            };
+            let (algo, hex) = match path::parse_content_path(&dir, file.path()) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            let theirs = path::hex_path(&other_config, other, &algo, &hex);
+            if !theirs.is_file() || same_file(file.path(), &theirs)? {
+                continue;
+            }
+            if !same_contents(file.path(), &theirs)? {
+                continue;
+            }
+            let size = file.metadata().to_internal()?.len();
+            // Link next to the original and rename over it, so the content is
+            // never missing from `cache`.
+            let staging = file.path().with_extension("dedupe");
+            fs::hard_link(&theirs, &staging)
+                .with_context(|| format!("Failed to hard link {:?} to {:?}", theirs, staging))?;
+            fs::rename(&staging, file.path())
+                .with_context(|| format!("Failed to replace {:?} with a link", file.path()))?;
+            report.linked += 1;
+            report.bytes_saved += size;
+        }
+    }
+    Ok(report)
+}
+
+/// How much content addressing is saving in a cache, as reported by
+/// [`dedupe_report`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DedupeAnalysis {
+    /// Number of live index entries.
+    pub entries: usize,
+    /// Number of distinct content objects those entries point to.
+    pub objects: usize,
+    /// Number of content objects pointed to by more than one entry.
+    pub shared_objects: usize,
+    /// Total size of the data behind every entry, as if each had its own copy.
+    pub logical_bytes: u64,
+    /// Total size of the distinct content objects actually stored.
+    pub physical_bytes: u64,
+    /// The objects that save the most space by being shared, most first.
+    pub top_shared: Vec<SharedObject>,
+}
+
+/// A content object pointed to by more than one index entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SharedObject {
+    /// Integrity hash of the object.
+    pub integrity: Integrity,
+    /// Size of the object in bytes.
+    pub size: u64,
+    /// Keys of the entries that point to the object.
+    pub keys: Vec<String>,
+}
+
+/// Groups the index entries of `cache` by the content they point to, to show
+/// how much space is saved by keys sharing content. Entries whose content is
+/// missing are left out.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let report = cacache_sync::dedupe_report("./my-cache")?;
+///     println!(
+///         "{} bytes of entries stored in {} bytes",
+///         report.logical_bytes, report.physical_bytes
+///     );
+///     Ok(())
+/// }
+/// ```
+pub fn dedupe_report<P: AsRef<Path>>(cache: P) -> Result<DedupeAnalysis> {
+    let cache = cache.as_ref();
+    let config = config::load(cache)?;
+    let mut objects: HashMap<String, SharedObject> = HashMap::new();
+    for entry in index::ls(cache) {
+        let entry = entry?;
+        if entry.metadata_only {
+            continue;
+        }
+        let key = entry.integrity.to_string();
+        if let Some(object) = objects.get_mut(&key) {
+            object.keys.push(entry.key);
+            continue;
+        }
+        let cpath = path::content_path_with(&config, cache, &entry.integrity);
+        let size = match fs::metadata(&cpath) {
+            Ok(meta) => meta.len(),
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err).to_internal()?,
+        };
+        objects.insert(
+            key,
+            SharedObject {
+                integrity: entry.integrity,
+                size,
+                keys: vec![entry.key],
+            },
+        );
+    }
+    let mut report = DedupeAnalysis::default();
+    let mut shared = Vec::new();
+    for (_, mut object) in objects {
+        report.entries += object.keys.len();
+        report.objects += 1;
+        report.logical_bytes += object.size * object.keys.len() as u64;
+        report.physical_bytes += object.size;
+        if object.keys.len() > 1 {
+            object.keys.sort();
+            shared.push(object);
+        }
+    }
+    report.shared_objects = shared.len();
+    shared.sort_by(|a, b| {
+        let saved = |o: &SharedObject| o.size * (o.keys.len() as u64 - 1);
+        saved(b).cmp(&saved(a)).then_with(|| a.keys.cmp(&b.keys))
+    });
+    shared.truncate(TOP_SHARED);
+    report.top_shared = shared;
+    Ok(report)
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let a = fs::metadata(a).to_internal()?;
+    let b = fs::metadata(b).to_internal()?;
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    if fs::metadata(a).to_internal()?.len() != fs::metadata(b).to_internal()?.len() {
+        return Ok(false);
+    }
+    let mut a = File::open(a).to_internal()?;
+    let mut b = File::open(b).to_internal()?;
+    let mut buf_a = vec![0; 64 * 1024];
+    let mut buf_b = vec![0; 64 * 1024];
+    loop {
+        let amt = a.read(&mut buf_a).to_internal()?;
+        if amt == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..amt]).to_internal()?;
+        if buf_a[..amt] != buf_b[..amt] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedupe_basic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let a = tmp.path().join("a");
+        let b = tmp.path().join("b");
+        crate::write(&a, "shared", b"shared data").unwrap();
+        crate::write(&a, "only-a", b"only in a").unwrap();
+        crate::write(&b, "shared-too", b"shared data").unwrap();
+
+        let report = dedupe_against(&a, &b).unwrap();
+        assert_eq!(
+            report,
+            DedupeReport {
+                linked: 1,
+                bytes_saved: 11
+            }
+        );
+        assert_eq!(crate::read(&a, "shared").unwrap(), b"shared data");
+        assert_eq!(crate::read(&a, "only-a").unwrap(), b"only in a");
+
+        // Already linked files are skipped the second time around.
+        #[cfg(unix)]
+        assert_eq!(dedupe_against(&a, &b).unwrap(), DedupeReport::default());
+    }
+
+    #[test]
+    fn dedupe_different_layouts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let a = tmp.path().join("a");
+        let b = tmp.path().join("b");
+        crate::configure(&b, crate::CacheConfig::new().content_levels(1)).unwrap();
+        crate::write(&a, "shared", b"shared data").unwrap();
+        crate::write(&b, "shared", b"shared data").unwrap();
+
+        let report = dedupe_against(&a, &b).unwrap();
+        assert_eq!(report.linked, 1);
+        assert_eq!(crate::read(&a, "shared").unwrap(), b"shared data");
+    }
+
+    #[test]
+    fn dedupe_empty() {
+        let tmp = tempfile::tempdir().unwrap();
+        let report = dedupe_against(tmp.path().join("a"), tmp.path().join("b")).unwrap();
+        assert_eq!(report, DedupeReport::default());
+    }
+
+    #[test]
+    fn report_basic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let shared = crate::write(&dir, "a", b"shared data").unwrap();
+        crate::write(&dir, "b", b"shared data").unwrap();
+        crate::write(&dir, "c", b"shared data").unwrap();
+        crate::write(&dir, "d", b"unique").unwrap();
+        let missing = crate::write(&dir, "e", b"gone").unwrap();
+        crate::remove_hash(&dir, &missing).unwrap();
+
+        let report = dedupe_report(&dir).unwrap();
+        assert_eq!(report.entries, 4);
+        assert_eq!(report.objects, 2);
+        assert_eq!(report.shared_objects, 1);
+        assert_eq!(report.logical_bytes, 39);
+        assert_eq!(report.physical_bytes, 17);
+        assert_eq!(
+            report.top_shared,
+            vec![SharedObject {
+                integrity: shared,
+                size: 11,
+                keys: vec!["a".into(), "b".into(), "c".into()],
+            }]
+        );
+    }
+}
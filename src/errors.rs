@@ -1,5 +1,6 @@
 use std::path::PathBuf;
 
+use ssri::Integrity;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -38,10 +39,55 @@ pub enum Error {
     #[error("Entry not found for key {1:?} in cache {0:?}")]
     EntryNotFound(PathBuf, String),
 
+    /// Returned when an index entry is missing a signature, or its signature
+    /// doesn't match the expected signer.
+    #[error("Missing or invalid signature for key {1:?} in cache {0:?}")]
+    SignatureError(PathBuf, String),
+
     /// Returned when a size check has failed.
     #[error("Size check failed.\n\tWanted: {0}\n\tActual: {1}")]
     SizeError(usize, usize),
 
+    /// Returned when reading the data of an entry that only has metadata,
+    /// written by [`crate::write_metadata_only`].
+    #[error("Entry for key {1:?} in cache {0:?} only has metadata, and no data")]
+    NoContent(PathBuf, String),
+
+    /// Returned when a [`crate::Writer`] for a key is committed with data
+    /// that doesn't match the size or integrity it was opened with, wrapping
+    /// the [`Error::SizeError`] or [`Error::IntegrityError`] that writers
+    /// without a key return. None of the data is kept.
+    #[error("Data written for key {0:?} doesn't match what was expected: {1}")]
+    CommitMismatch(String, #[source] Box<Error>),
+
+    /// Returned when data is larger than the most a read was allowed to
+    /// return.
+    #[error("Data is {1} bytes, over the read limit of {0} bytes")]
+    LimitExceeded(u64, u64),
+
+    /// Returned when a key is written with data that differs from its
+    /// existing entry, in a cache configured with
+    /// [`crate::KeyConflict::ErrorOnDifferent`].
+    #[error("Key {1:?} in cache {0:?} already has an entry with different data")]
+    KeyConflict(PathBuf, String),
+
+    /// Returned when content can't be removed because index entries still
+    /// reference it.
+    #[error("Content {0} is still referenced by {1} index entries")]
+    ContentInUse(Integrity, usize),
+
+    /// Returned when a cache configuration is invalid, or can't be applied to
+    /// the cache in its current state.
+    #[error("Invalid configuration for cache {0:?}: {1}")]
+    InvalidConfig(PathBuf, String),
+
+    /// Returned when content couldn't be moved into place at the given path
+    /// because the rename kept failing transiently, even after the given
+    /// number of retries. On Windows, this usually means a virus scanner or
+    /// indexer is holding on to the file.
+    #[error("Failed to move content into place at {0:?} after {1} retries: {2}")]
+    PersistRetriesExhausted(PathBuf, u32, std::io::Error),
+
     /// Returned when an integrity check has failed.
     #[error(transparent)]
     IntegrityError {
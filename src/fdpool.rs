@@ -0,0 +1,142 @@
+//! A small pool of open, already verified content files, for
+//! [`crate::Cache`] handles that read the same large objects repeatedly.
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use ssri::Integrity;
+
+use crate::content::read;
+use crate::errors::{Internal, Result};
+
+pub(crate) struct FdPool {
+    capacity: usize,
+    // Most recently used first.
+    files: Mutex<VecDeque<(String, Arc<File>)>>,
+    // Bumped by every removal, under the `files` lock, so files opened while
+    // one was going on aren't pooled.
+    generation: AtomicU64,
+}
+
+impl FdPool {
+    pub(crate) fn new(capacity: usize) -> FdPool {
+        FdPool {
+            capacity,
+            files: Mutex::new(VecDeque::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns an open handle to the content for `sri`. Content is verified
+    /// in full when it's first opened. The handle keeps pointing at the
+    /// verified file even if the content is later replaced or removed on
+    /// disk, so it never has to be checked again.
+    pub(crate) fn open(&self, cache: &Path, sri: &Integrity) -> Result<Arc<File>> {
+        let key = sri.to_string();
+        {
+            let mut files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(pos) = files.iter().position(|(k, _)| *k == key) {
+                // Safe unwrap. `pos` was just found.
+                let entry = files.remove(pos).unwrap();
+                let file = entry.1.clone();
+                files.push_front(entry);
+                return Ok(file);
+            }
+        }
+        // Verify outside the lock; it reads the whole file.
+        let generation = self.generation.load(Ordering::SeqCst);
+        let file = Arc::new(read::open_verified(cache, sri)?);
+        let mut files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.capacity > 0 && self.generation.load(Ordering::SeqCst) == generation {
+            files.retain(|(k, _)| *k != key);
+            files.truncate(self.capacity - 1);
+            files.push_front((key, file.clone()));
+        }
+        Ok(file)
+    }
+
+    pub(crate) fn remove(&self, sri: &Integrity) {
+        let key = sri.to_string();
+        let mut files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+        files.retain(|(k, _)| *k != key);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+        files.clear();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Reads up to `len` bytes of `file` starting at `offset`, without moving
+/// any shared cursor, so concurrent readers can share the handle. Returns
+/// fewer bytes if the file ends first.
+pub(crate) fn read_range(file: &File, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let size = file.metadata().to_internal()?.len();
+    let len = len.min(size.saturating_sub(offset) as usize);
+    let mut buf = vec![0; len];
+    let mut filled = 0;
+    while filled < len {
+        match read_at(file, &mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(amt) => filled += amt,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err).to_internal()?,
+        }
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::io::{Read, Seek, SeekFrom};
+    // No positional reads here, so serialize seek-and-read pairs instead.
+    static SEEK_LOCK: Mutex<()> = Mutex::new(());
+    let _guard = SEEK_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset))?;
+    file.read(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pooled_range_reads() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let a = crate::write(&dir, "a", b"hello world").unwrap();
+        let b = crate::write(&dir, "b", b"goodbye").unwrap();
+        let pool = FdPool::new(1);
+
+        let file = pool.open(&dir, &a).unwrap();
+        assert_eq!(read_range(&file, 6, 100).unwrap(), b"world");
+        assert_eq!(read_range(&file, 100, 5).unwrap(), b"");
+        // The pooled handle survives the content being removed.
+        crate::remove_hash(&dir, &a).unwrap();
+        let file = pool.open(&dir, &a).unwrap();
+        assert_eq!(read_range(&file, 0, 5).unwrap(), b"hello");
+
+        // Opening another object evicts the first from a pool of one.
+        pool.open(&dir, &b).unwrap();
+        assert!(pool.open(&dir, &a).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! Functions for fetching data over HTTP straight into the cache.
+use std::io;
+use std::path::Path;
+
+use serde_json::{json, Map, Value};
+use ssri::Integrity;
+
+use crate::errors::{Internal, Result};
+use crate::put::WriteOpts;
+
+/// Fetches `url` with a GET request and streams the response body into the
+/// cache under `key`, returning its integrity hash. The final URL, status
+/// code and response headers are stored as the entry's metadata:
+///
+/// ```json
+/// { "url": "https://...", "status": 200, "headers": { "content-type": "..." } }
+/// ```
+///
+/// The `Content-Type` header, if any, is also stored as the entry's
+/// `content_type`. Responses with an error status (4xx or 5xx) fail without
+/// writing anything.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::fetch_into(
+///         "./my-cache",
+///         "crates-index",
+///         "https://index.crates.io/config.json",
+///     )?;
+///     let entry = cacache_sync::metadata("./my-cache", "crates-index")?.unwrap();
+///     println!("{} {}", sri, entry.metadata["headers"]["content-type"]);
+///     Ok(())
+/// }
+/// ```
+pub fn fetch_into<P, K>(cache: P, key: K, url: &str) -> Result<Integrity>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    let response = ureq::get(url)
+        .call()
+        .map_err(Box::new)
+        .with_context(|| format!("Failed to fetch {}", url))?;
+    let mut headers = Map::new();
+    for name in response.headers_names() {
+        if let Some(value) = response.header(&name) {
+            headers.insert(name.to_lowercase(), Value::from(value));
+        }
+    }
+    let metadata = json!({
+        "url": response.get_url(),
+        "status": response.status(),
+        "headers": headers,
+    });
+    // Content-Length isn't used as the expected size: bodies are decoded as
+    // they're read, so it may not match what's written.
+    let mut opts = WriteOpts::new();
+    if let Some(content_type) = metadata["headers"]["content-type"].as_str() {
+        opts = opts.content_type(content_type);
+    }
+    let mut writer = opts.metadata(metadata).open(cache, key)?;
+    io::copy(&mut response.into_reader(), &mut writer)
+        .with_context(|| format!("Failed to read response body from {}", url))?;
+    writer.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Serves a single request with `response`, returning the URL to hit.
+    fn serve_once(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        format!("http://{}/thing", addr)
+    }
+
+    #[test]
+    fn fetch_basic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let url = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+        );
+        let sri = fetch_into(&dir, "key", &url).unwrap();
+        assert_eq!(sri, Integrity::from(b"hello"));
+        assert_eq!(crate::read(&dir, "key").unwrap(), b"hello");
+        let entry = crate::metadata(&dir, "key").unwrap().unwrap();
+        assert_eq!(entry.metadata["url"], url.as_str());
+        assert_eq!(entry.metadata["status"], 200);
+        assert_eq!(entry.metadata["headers"]["content-type"], "text/plain");
+        assert_eq!(entry.content_type.as_deref(), Some("text/plain"));
+    }
+
+    #[test]
+    fn fetch_error_status() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let url =
+            serve_once("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        assert!(fetch_into(&dir, "key", &url).is_err());
+        assert!(crate::metadata(&dir, "key").unwrap().is_none());
+    }
+}
@@ -0,0 +1,168 @@
+//! Coalescing of concurrent writes of the same data to the same key, for
+//! [`crate::Cache`] handles shared between threads.
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+use ssri::Integrity;
+
+use crate::errors::Result;
+
+#[derive(Default)]
+pub(crate) struct Flights {
+    in_flight: Mutex<HashMap<String, Arc<Flight>>>,
+}
+
+struct Flight {
+    // A copy of the data being written, for joining threads to compare
+    // theirs against.
+    data: Vec<u8>,
+    // `None` while the write is still going, then the integrity it wrote, or
+    // `None` again if it failed.
+    outcome: Mutex<Option<Option<Integrity>>>,
+    landed: Condvar,
+}
+
+impl Flights {
+    /// Writes `data` under `key` with `write`, unless another thread is
+    /// already doing so. In that case this waits for it to finish and hands
+    /// back the integrity it wrote, as long as it was writing the same data,
+    /// which is compared byte for byte rather than hashed again. Also returns
+    /// whether this call did the writing, so the write is only reported once.
+    pub(crate) fn write<F>(&self, key: &str, data: &[u8], write: F) -> Result<(Integrity, bool)>
+    where
+        F: FnOnce() -> Result<Integrity>,
+    {
+        let leading = {
+            let mut in_flight = self
+                .in_flight
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            match in_flight.get(key) {
+                Some(flight) => Err(flight.clone()),
+                None => {
+                    let flight = Arc::new(Flight {
+                        data: data.to_vec(),
+                        outcome: Mutex::new(None),
+                        landed: Condvar::new(),
+                    });
+                    in_flight.insert(key.to_owned(), flight.clone());
+                    Ok(flight)
+                }
+            }
+        };
+        match leading {
+            Ok(flight) => {
+                let landing = Landing {
+                    flights: self,
+                    key,
+                    flight,
+                    outcome: None,
+                };
+                landing.finish(write()).map(|sri| (sri, true))
+            }
+            Err(flight) if flight.data == data => match flight.wait() {
+                Some(sri) => Ok((sri, false)),
+                // A write that failed, so go it alone.
+                None => write().map(|sri| (sri, true)),
+            },
+            // Different data can't share a result.
+            Err(_) => write().map(|sri| (sri, true)),
+        }
+    }
+}
+
+impl Flight {
+    fn wait(&self) -> Option<Integrity> {
+        let mut outcome = self.outcome.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(outcome) = &*outcome {
+                return outcome.clone();
+            }
+            outcome = self
+                .landed
+                .wait(outcome)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Ends a flight when dropped, so waiting threads are released even if the
+/// write panics.
+struct Landing<'a> {
+    flights: &'a Flights,
+    key: &'a str,
+    flight: Arc<Flight>,
+    outcome: Option<Integrity>,
+}
+
+impl Landing<'_> {
+    fn finish(mut self, result: Result<Integrity>) -> Result<Integrity> {
+        self.outcome = result.as_ref().ok().cloned();
+        result
+    }
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        self.flights
+            .in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(self.key);
+        *self
+            .flight
+            .outcome
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(self.outcome.take());
+        self.flight.landed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    #[test]
+    fn coalesces_identical_writes() {
+        let flights = Flights::default();
+        let writes = AtomicUsize::new(0);
+        let barrier = Barrier::new(8);
+        let write = |data: &'static [u8]| {
+            barrier.wait();
+            flights.write("key", data, || {
+                writes.fetch_add(1, Ordering::SeqCst);
+                // Long enough for every other thread to join the flight.
+                std::thread::sleep(Duration::from_millis(200));
+                Ok(Integrity::from(data))
+            })
+        };
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8).map(|_| scope.spawn(|| write(b"hello"))).collect();
+            let mut wrote = 0;
+            for thread in threads {
+                let (sri, leader) = thread.join().unwrap().unwrap();
+                assert_eq!(sri, Integrity::from(b"hello"));
+                wrote += leader as usize;
+            }
+            // Only the thread that wrote reports it.
+            assert_eq!(wrote, 1);
+        });
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+        // Writes of different data to the same key can't share a result.
+        writes.store(0, Ordering::SeqCst);
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8u8)
+                .map(|i| scope.spawn(move || write(if i == 0 { b"hello" } else { b"world" })))
+                .collect();
+            for thread in threads {
+                thread.join().unwrap().unwrap();
+            }
+        });
+        assert!(writes.load(Ordering::SeqCst) >= 2);
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,509 @@
+//! Functions for mounting a cache as a read-only filesystem.
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, Request,
+};
+use ssri::Integrity;
+
+use crate::content::read;
+use crate::errors::{Error, Internal, Result};
+use crate::fdpool;
+use crate::index::{self, Metadata};
+
+/// How long the kernel may keep attributes and lookups before asking again.
+/// Short, since the cache can change underneath the mount at any time.
+const TTL: Duration = Duration::from_secs(1);
+
+const ROOT: u64 = 1;
+const BY_KEY: u64 = 2;
+const BY_HASH: u64 = 3;
+
+/// A cache mounted with [`spawn_mount`]. The cache is unmounted when this is
+/// dropped.
+pub struct Mount {
+    _session: fuser::BackgroundSession,
+}
+
+impl std::fmt::Debug for Mount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mount").finish()
+    }
+}
+
+/// Mounts `cache` as a read-only filesystem at `mountpoint`, blocking until
+/// it's unmounted.
+///
+/// Each entry's data is at `by-key/<key>`, and each piece of content an
+/// entry points to is at `by-hash/<sri>`. Since names can't hold them, `/`,
+/// `%` and NUL in keys and hashes are written as `%2F`, `%25` and `%00`.
+/// Entries without content aren't listed. Content is checked against its
+/// integrity when it's opened, and opening it fails if it doesn't match.
+///
+/// Mounting needs FUSE to be available, and the `fusermount` helper to be
+/// installed.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     cacache_sync::mount("./my-cache", "/mnt/my-cache")?;
+///     Ok(())
+/// }
+/// ```
+pub fn mount<P, Q>(cache: P, mountpoint: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mountpoint = mountpoint.as_ref();
+    fuser::mount2(CacheFs::new(cache.as_ref()), mountpoint, &options())
+        .with_context(|| format!("Failed to mount cache at {:?}", mountpoint))?;
+    Ok(())
+}
+
+/// Like [`mount`], serving the filesystem from a background thread instead
+/// of blocking, until the returned [`Mount`] is dropped.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let _mount = cacache_sync::spawn_mount("./my-cache", "/mnt/my-cache")?;
+///     let data = std::fs::read("/mnt/my-cache/by-key/my-key").unwrap();
+///     Ok(())
+/// }
+/// ```
+pub fn spawn_mount<P, Q>(cache: P, mountpoint: Q) -> Result<Mount>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mountpoint = mountpoint.as_ref();
+    let session = fuser::spawn_mount2(CacheFs::new(cache.as_ref()), mountpoint, &options())
+        .with_context(|| format!("Failed to mount cache at {:?}", mountpoint))?;
+    Ok(Mount { _session: session })
+}
+
+fn options() -> Vec<MountOption> {
+    vec![MountOption::RO, MountOption::FSName("cacache".into())]
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Node {
+    Key(String),
+    Hash(String),
+}
+
+struct CacheFs {
+    cache: PathBuf,
+    /// Inodes are handed out as files are looked up, and kept for as long as
+    /// the filesystem is mounted, so a file keeps its inode.
+    nodes: HashMap<u64, Node>,
+    inodes: HashMap<Node, u64>,
+    files: HashMap<u64, File>,
+    /// What each open directory lists, as it was when it was opened, so
+    /// listings read in several calls stay consistent.
+    listings: HashMap<u64, Vec<(u64, FileType, String)>>,
+    next_fh: u64,
+    uid: u32,
+    gid: u32,
+}
+
+impl CacheFs {
+    fn new(cache: &Path) -> Self {
+        CacheFs {
+            cache: cache.to_path_buf(),
+            nodes: HashMap::new(),
+            inodes: HashMap::new(),
+            files: HashMap::new(),
+            listings: HashMap::new(),
+            next_fh: 1,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    fn inode(&mut self, node: Node) -> u64 {
+        if let Some(ino) = self.inodes.get(&node) {
+            return *ino;
+        }
+        let ino = BY_HASH + 1 + self.nodes.len() as u64;
+        self.nodes.insert(ino, node.clone());
+        self.inodes.insert(node, ino);
+        ino
+    }
+
+    /// The file holding the data for `node`, with the integrity to check it
+    /// against.
+    fn locate(&self, node: &Node) -> Result<(PathBuf, Integrity, Option<SystemTime>)> {
+        match node {
+            Node::Key(key) => {
+                let entry = crate::metadata(&self.cache, key)?
+                    .ok_or_else(|| Error::EntryNotFound(self.cache.clone(), key.clone()))?;
+                let path = read::entry_file(&self.cache, &entry)?;
+                let time = UNIX_EPOCH.checked_add(Duration::from_millis(entry.time as u64));
+                Ok((path, entry.integrity, time))
+            }
+            Node::Hash(sri) => {
+                let sri = sri.parse::<Integrity>()?;
+                Ok((read::content_file(&self.cache, &sri)?, sri, None))
+            }
+        }
+    }
+
+    fn file_attr(&self, ino: u64) -> std::result::Result<FileAttr, i32> {
+        let node = match ino {
+            ROOT | BY_KEY | BY_HASH => return Ok(self.dir_attr(ino)),
+            _ => self.nodes.get(&ino).ok_or(libc::ENOENT)?,
+        };
+        let mut attr = self.node_attr(node)?;
+        attr.ino = ino;
+        Ok(attr)
+    }
+
+    /// The attributes of `node`, less its inode.
+    fn node_attr(&self, node: &Node) -> std::result::Result<FileAttr, i32> {
+        let (path, _, time) = self.locate(node).map_err(|err| errno(&err))?;
+        let meta = fs::metadata(path).map_err(|err| err.raw_os_error().unwrap_or(libc::EIO))?;
+        let time = time.or_else(|| meta.modified().ok()).unwrap_or(UNIX_EPOCH);
+        Ok(FileAttr {
+            ino: 0,
+            size: meta.len(),
+            blocks: meta.blocks(),
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+
+    fn dir_attr(&self, ino: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Directory,
+            perm: 0o555,
+            nlink: 2,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    /// Entries with content, as the index lists them.
+    fn entries(&self) -> impl Iterator<Item = Metadata> {
+        index::ls(&self.cache)
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.metadata_only)
+    }
+
+    /// Everything in the directory `ino`, sorted by name.
+    fn listing(&mut self, ino: u64) -> std::result::Result<Vec<(u64, FileType, String)>, i32> {
+        let mut children = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (ROOT, FileType::Directory, "..".to_string()),
+        ];
+        let nodes = match ino {
+            ROOT => {
+                children.push((BY_KEY, FileType::Directory, "by-key".into()));
+                children.push((BY_HASH, FileType::Directory, "by-hash".into()));
+                return Ok(children);
+            }
+            BY_KEY => self
+                .entries()
+                .map(|entry| Node::Key(entry.key))
+                .collect::<Vec<_>>(),
+            // Several entries can share content, so it's listed once.
+            BY_HASH => self
+                .entries()
+                .map(|entry| entry.integrity.to_string())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .map(Node::Hash)
+                .collect(),
+            _ => return Err(libc::ENOTDIR),
+        };
+        let mut files = nodes
+            .into_iter()
+            .map(|node| {
+                let name = match &node {
+                    Node::Key(name) | Node::Hash(name) => escape(name),
+                };
+                (self.inode(node), FileType::RegularFile, name)
+            })
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.2.cmp(&b.2));
+        children.extend(files);
+        Ok(children)
+    }
+}
+
+impl Filesystem for CacheFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return reply.error(libc::ENOENT),
+        };
+        let node = match (parent, name) {
+            (ROOT, "by-key") => return reply.entry(&TTL, &self.dir_attr(BY_KEY), 0),
+            (ROOT, "by-hash") => return reply.entry(&TTL, &self.dir_attr(BY_HASH), 0),
+            (BY_KEY, name) => unescape(name).map(Node::Key),
+            (BY_HASH, name) => unescape(name).map(Node::Hash),
+            _ => None,
+        };
+        let node = match node {
+            Some(node) => node,
+            None => return reply.error(libc::ENOENT),
+        };
+        // Only files that are there get an inode, so looking up missing names
+        // doesn't grow the table.
+        match self.node_attr(&node) {
+            Ok(mut attr) => {
+                attr.ino = self.inode(node);
+                reply.entry(&TTL, &attr, 0);
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.file_attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.listing(ino) {
+            Ok(listing) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.listings.insert(fh, listing);
+                reply.opened(fh, 0);
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let listing = match self.listings.get(&fh) {
+            Some(listing) => listing,
+            None => return reply.error(libc::EBADF),
+        };
+        for (i, (ino, kind, name)) in listing.iter().enumerate().skip(offset as usize) {
+            if reply.add(*ino, (i + 1) as i64, *kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.listings.remove(&fh);
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return reply.error(libc::EROFS);
+        }
+        let node = match self.nodes.get(&ino) {
+            Some(node) => node,
+            None => return reply.error(libc::EISDIR),
+        };
+        match self
+            .locate(node)
+            .and_then(|(path, sri, _)| read::open_file_verified(&path, &sri))
+        {
+            Ok(file) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.files.insert(fh, file);
+                reply.opened(fh, 0);
+            }
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let file = match self.files.get(&fh) {
+            Some(file) => file,
+            None => return reply.error(libc::EBADF),
+        };
+        match fdpool::read_range(file, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(_) => reply.error(libc::EIO),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.files.remove(&fh);
+        reply.ok();
+    }
+}
+
+/// The errno to report `err` as.
+fn errno(err: &Error) -> i32 {
+    match err {
+        Error::EntryNotFound(..) | Error::NoContent(..) => libc::ENOENT,
+        _ => libc::EIO,
+    }
+}
+
+/// Makes `name` usable as a file name.
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '%' => escaped.push_str("%25"),
+            '/' => escaped.push_str("%2F"),
+            '\0' => escaped.push_str("%00"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undoes `escape`, or returns `None` if `name` isn't something it made.
+fn unescape(name: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(at) = rest.find('%') {
+        unescaped.push_str(&rest[..at]);
+        let c = match rest.get(at + 1..at + 3)? {
+            "25" => '%',
+            "2F" => '/',
+            "00" => '\0',
+            _ => return None,
+        };
+        unescaped.push(c);
+        rest = &rest[at + 3..];
+    }
+    unescaped.push_str(rest);
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_names() {
+        for name in ["plain", "a/b", "100%", "%2F", "nul\0", ""] {
+            let escaped = escape(name);
+            assert!(!escaped.contains('/') && !escaped.contains('\0'));
+            assert_eq!(unescape(&escaped).as_deref(), Some(name));
+        }
+        assert_eq!(unescape("bad%zz"), None);
+        assert_eq!(unescape("short%2"), None);
+    }
+
+    #[test]
+    fn sorted_listings() {
+        let tmp = tempfile::tempdir().unwrap();
+        for key in ["b", "c", "a"] {
+            crate::write(tmp.path(), key, key).unwrap();
+        }
+        let mut fs = CacheFs::new(tmp.path());
+        let names = |listing: Vec<(u64, FileType, String)>| {
+            listing
+                .into_iter()
+                .map(|(_, _, name)| name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(fs.listing(BY_KEY).unwrap()),
+            [".", "..", "a", "b", "c"]
+        );
+        let hashes = names(fs.listing(BY_HASH).unwrap());
+        assert!(hashes[2..].windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(fs.listing(BY_KEY), fs.listing(BY_KEY));
+        assert_eq!(fs.listing(99), Err(libc::ENOTDIR));
+    }
+
+    #[test]
+    fn mounted_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = tmp.path().join("cache");
+        let mountpoint = tmp.path().join("mnt");
+        fs::create_dir(&mountpoint).unwrap();
+        let sri = crate::write(&cache, "a/key", b"hello").unwrap();
+        crate::write(&cache, "other", b"hello").unwrap();
+
+        // FUSE isn't always available, such as in containers.
+        let _mount = match spawn_mount(&cache, &mountpoint) {
+            Ok(mount) => mount,
+            Err(_) => return,
+        };
+        assert_eq!(
+            fs::read(mountpoint.join("by-key").join("a%2Fkey")).unwrap(),
+            b"hello"
+        );
+        assert_eq!(
+            fs::read(mountpoint.join("by-hash").join(escape(&sri.to_string()))).unwrap(),
+            b"hello"
+        );
+        let mut keys = fs::read_dir(mountpoint.join("by-key"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["a%2Fkey", "other"]);
+        assert_eq!(fs::read_dir(mountpoint.join("by-hash")).unwrap().count(), 1);
+        assert!(fs::write(mountpoint.join("by-key").join("other"), b"nope").is_err());
+        assert!(fs::metadata(mountpoint.join("by-key").join("missing")).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Mark-and-sweep garbage collection of content that nothing needs anymore.
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use ssri::Integrity;
+use walkdir::WalkDir;
+
+use crate::config;
+use crate::content::{path, rm};
+use crate::errors::{Internal, Result};
+use crate::index;
+
+/// Builder for options for garbage collecting a cache with [`GcOpts::run`].
+///
+/// Collection marks the content of every live index entry, plus any extra
+/// roots given here, and removes every other content object. By default index
+/// entries are kept, so only content that's unreachable by key is removed.
+///
+/// It should not run while other processes are writing to the cache: content
+/// written after marking has started, but before its index entry is, can be
+/// removed out from under the writer.
+#[derive(Clone, Debug, Default)]
+pub struct GcOpts {
+    keep_hashes: Vec<Integrity>,
+    keep_keys: HashSet<String>,
+    sweep_index: bool,
+}
+
+/// Summary of what was removed by [`GcOpts::run`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Keys of the index entries that were removed.
+    pub removed_keys: Vec<String>,
+    /// Number of content objects that were removed.
+    pub content_objects: usize,
+    /// Total size in bytes of the content objects that were removed.
+    pub bytes: u64,
+}
+
+impl GcOpts {
+    /// Creates a blank set of garbage collection options.
+    pub fn new() -> GcOpts {
+        Default::default()
+    }
+
+    /// Keeps the content for `sri`, even if no index entry references it.
+    /// Useful for artifacts referenced by external manifests by hash.
+    pub fn keep_hash(mut self, sri: Integrity) -> Self {
+        self.keep_hashes.push(sri);
+        self
+    }
+
+    /// Keeps the entry for `key` and its content, even when sweeping the
+    /// index with [`GcOpts::sweep_index`].
+    pub fn keep_key<K: Into<String>>(mut self, key: K) -> Self {
+        self.keep_keys.insert(key.into());
+        self
+    }
+
+    /// Removes every index entry that wasn't kept with [`GcOpts::keep_key`]
+    /// too, instead of treating them all as roots. With this, the cache ends
+    /// up holding only what the given roots need.
+    pub fn sweep_index(mut self, sweep: bool) -> Self {
+        self.sweep_index = sweep;
+        self
+    }
+
+    /// Garbage collects `cache` synchronously.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use cacache_sync::GcOpts;
+    ///
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     let sri = cacache_sync::write_hash("./my-cache", b"from a manifest")?;
+    ///     let report = GcOpts::new().keep_hash(sri).run("./my-cache")?;
+    ///     println!("freed {} bytes", report.bytes);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn run<P: AsRef<Path>>(self, cache: P) -> Result<GcReport> {
+        let cache = cache.as_ref();
+        let config = config::load(cache)?;
+        let mut report = GcReport::default();
+        let mut marked = self
+            .keep_hashes
+            .iter()
+            .map(|sri| path::content_path_with(&config, cache, sri))
+            .collect::<HashSet<PathBuf>>();
+        let has_index = index::index_dir(cache).exists();
+        for entry in index::ls(cache).filter(|_| has_index) {
+            let entry = entry?;
+            if self.sweep_index && !self.keep_keys.contains(&entry.key) {
+                index::delete(cache, &entry.key)?;
+                report.removed_keys.push(entry.key);
+            } else if entry.external.is_none() && !entry.metadata_only {
+                marked.insert(path::content_path_with(&config, cache, &entry.integrity));
+            }
+        }
+        for dir in path::content_dirs(&config, cache) {
+            for file in WalkDir::new(&dir) {
+                let file = match file {
+                    Ok(file) if file.file_type().is_file() => file,
+                    Ok(_) => continue,
+                    // No content yet.
+                    Err(err) if err.depth() == 0 => break,
+                    Err(err) if path::vanished(&err) => continue,
+                    Err(err) => return Err(err).to_internal()?,
+                };
+                // Leave anything that isn't a content object alone.
+                if path::parse_content_path(&dir, file.path()).is_none()
+                    || marked.contains(file.path())
+                {
+                    continue;
+                }
+                let size = match file.metadata() {
+                    Ok(meta) => meta.len(),
+                    Err(err) if path::vanished(&err) => continue,
+                    Err(err) => return Err(err).to_internal()?,
+                };
+                match rm::rm_file(cache, file.path()) {
+                    Ok(()) => {}
+                    // Removed by someone else since it was found.
+                    Err(_) if !file.path().exists() => continue,
+                    Err(err) => return Err(err),
+                }
+                report.content_objects += 1;
+                report.bytes += size;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_roots() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let live = crate::write(dir, "live", b"live").unwrap();
+        let orphan = crate::write(dir, "orphan", b"orphan").unwrap();
+        crate::remove(dir, "orphan").unwrap();
+        let manifest = crate::write_hash(dir, b"manifest").unwrap();
+        let loose = crate::write_hash(dir, b"loose").unwrap();
+
+        let report = GcOpts::new().keep_hash(manifest.clone()).run(dir).unwrap();
+        assert_eq!(report.content_objects, 2);
+        assert_eq!(report.bytes, 11);
+        assert!(report.removed_keys.is_empty());
+        assert!(crate::exists(dir, &live));
+        assert!(crate::exists(dir, &manifest));
+        assert!(!crate::exists(dir, &orphan));
+        assert!(!crate::exists(dir, &loose));
+    }
+
+    #[test]
+    fn sweeps_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let kept = crate::write(dir, "kept", b"kept").unwrap();
+        let dropped = crate::write(dir, "dropped", b"dropped").unwrap();
+
+        let report = GcOpts::new()
+            .keep_key("kept")
+            .sweep_index(true)
+            .run(dir)
+            .unwrap();
+        assert_eq!(report.removed_keys, vec![String::from("dropped")]);
+        assert_eq!(report.content_objects, 1);
+        assert!(crate::exists(dir, &kept));
+        assert!(!crate::exists(dir, &dropped));
+        assert!(crate::metadata(dir, "dropped").unwrap().is_none());
+    }
+
+    #[test]
+    fn empty_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(GcOpts::new().run(tmp.path()).unwrap(), GcReport::default());
+    }
+}
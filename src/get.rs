@@ -1,11 +1,15 @@
 //! Functions for reading from cache.
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-use ssri::{Algorithm, Integrity};
+use serde::de::DeserializeOwned;
+use ssri::Integrity;
 
-use crate::content::read;
-use crate::errors::{Error, Result};
+use crate::content::{filter, path, read};
+use crate::errors::{Error, Internal, Result};
 use crate::index::{self, Metadata};
+use crate::put::Writer;
+use crate::stats::{self, VerifySummary};
 
 // ---------------
 // Synchronous API
@@ -28,8 +32,12 @@ impl std::io::Read for Reader {
 
 impl Reader {
     /// Checks that data read from disk passes integrity checks. Returns the
-    /// algorithm that was used verified the data. Should be called only after
-    /// all data has been read from disk.
+    /// integrity computed from the data, so it can be logged or passed along
+    /// without hashing the data again. Readers that don't hash the data, like
+    /// those from [`Reader::open_unchecked`], or ones opened at a
+    /// [`VerifyLevel`] short of [`VerifyLevel::Full`], return `None`, since
+    /// nothing was verified. Should be called only after all data has been
+    /// read from disk.
     ///
     /// ## Example
     /// ```no_run
@@ -40,11 +48,13 @@ impl Reader {
     ///     let mut str = String::new();
     ///     fd.read_to_string(&mut str).expect("Failed to read to string");
     ///     // Remember to check that the data you got was correct!
-    ///     fd.check()?;
+    ///     if let Some(sri) = fd.check()? {
+    ///         println!("read {}", sri);
+    ///     }
     ///     Ok(())
     /// }
     /// ```
-    pub fn check(self) -> Result<Algorithm> {
+    pub fn check(self) -> Result<Option<Integrity>> {
         self.reader.check()
     }
 
@@ -65,17 +75,45 @@ impl Reader {
     /// }
     /// ```
     pub fn open<P, K>(cache: P, key: K) -> Result<Reader>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        Reader::open_with_metadata(cache, key).map(|(reader, _)| reader)
+    }
+
+    /// Opens a new synchronous file handle into the cache like
+    /// [`Reader::open`], also returning the index entry it was found through,
+    /// so its size and metadata can be used without looking it up again.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use std::io::Read;
+    ///
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     let (mut fd, entry) = cacache_sync::Reader::open_with_metadata("./my-cache", "my-key")?;
+    ///     let mut data = Vec::with_capacity(entry.size);
+    ///     fd.read_to_end(&mut data).expect("Failed to read data");
+    ///     fd.check()?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn open_with_metadata<P, K>(cache: P, key: K) -> Result<(Reader, Metadata)>
     where
         P: AsRef<Path>,
         K: AsRef<str>,
     {
         if let Some(entry) = index::find(cache.as_ref(), key.as_ref())? {
-            Reader::open_hash(cache, entry.integrity)
+            let cpath = read::entry_file(cache.as_ref(), &entry)?;
+            let reader = Reader {
+                reader: read::open_file(&cpath, entry.integrity.clone())?,
+            };
+            Ok((reader, entry))
         } else {
-            return Err(Error::EntryNotFound(
+            Err(Error::EntryNotFound(
                 cache.as_ref().to_path_buf(),
                 key.as_ref().into(),
-            ));
+            ))
         }
     }
 
@@ -103,6 +141,199 @@ impl Reader {
             reader: read::open(cache.as_ref(), sri)?,
         })
     }
+
+    /// Opens a handle to the data for `key` like [`Reader::open`], without
+    /// hashing the data as it's read. Its `check()` always passes. Only for
+    /// data that's already trusted, see [`crate::read_unchecked`].
+    pub fn open_unchecked<P, K>(cache: P, key: K) -> Result<Reader>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        ReadOpts::new().verify(VerifyLevel::None).open(cache, key)
+    }
+
+    /// Opens a handle to the data for `sri` like [`Reader::open_hash`],
+    /// without hashing the data as it's read, like [`Reader::open_unchecked`].
+    pub fn open_hash_unchecked<P>(cache: P, sri: Integrity) -> Result<Reader>
+    where
+        P: AsRef<Path>,
+    {
+        ReadOpts::new()
+            .verify(VerifyLevel::None)
+            .open_hash(cache, sri)
+    }
+
+    /// Turns the handle into an iterator over chunks of up to `chunk_size`
+    /// bytes of its data. The data is hashed as it goes by, and once it runs
+    /// out the iterator yields one last error instead of ending if the data
+    /// failed integrity verification, so there's no need to call `check()`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     let fd = cacache_sync::Reader::open("./my-cache", "my-key")?;
+    ///     for chunk in fd.chunks(64 * 1024) {
+    ///         let chunk = chunk?;
+    ///         println!("got {} bytes", chunk.len());
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn chunks(self, chunk_size: usize) -> Chunks {
+        assert!(chunk_size != 0, "chunk size must be non-zero");
+        Chunks {
+            reader: Some(self.reader),
+            chunk_size,
+        }
+    }
+}
+
+/// Iterator over verified chunks of a cache entry, created by
+/// [`Reader::chunks`].
+pub struct Chunks {
+    // Taken once the data runs out or fails to read.
+    reader: Option<read::Reader>,
+    chunk_size: usize,
+}
+
+impl Iterator for Chunks {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let reader = self.reader.as_mut()?;
+        use std::io::Read;
+        let mut chunk = vec![0; self.chunk_size];
+        let mut filled = 0;
+        while filled < chunk.len() {
+            match reader.read(&mut chunk[filled..]) {
+                Ok(0) => break,
+                Ok(amt) => filled += amt,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.reader = None;
+                    return Some(Err::<Vec<u8>, _>(e).to_internal().map_err(Error::from));
+                }
+            }
+        }
+        if filled == 0 {
+            return match self.reader.take()?.check() {
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            };
+        }
+        chunk.truncate(filled);
+        Some(Ok(chunk))
+    }
+}
+
+/// How thoroughly data is checked as it's read, see [`ReadOpts::verify`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VerifyLevel {
+    /// Hashes all of the data and checks it against its integrity.
+    #[default]
+    Full,
+    /// Only checks the length of the data against the size recorded in its
+    /// index entry. This catches truncated data, but not corrupted data.
+    /// Entries written without a declared [`crate::WriteOpts::size`] and
+    /// reads by hash have no size to check against, so aren't checked at all.
+    Size,
+    /// Trusts the data as it is.
+    None,
+}
+
+/// Builder for options for reading data from the cache, for reads that need
+/// something other than the defaults used by [`read`] and friends.
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::{ReadOpts, VerifyLevel};
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let data = ReadOpts::new()
+///         .verify(VerifyLevel::Size)
+///         .read("./my-cache", "my-key")?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadOpts {
+    verify: VerifyLevel,
+}
+
+impl ReadOpts {
+    /// Creates a blank set of cache reading options.
+    pub fn new() -> ReadOpts {
+        Default::default()
+    }
+
+    /// Sets how thoroughly data is checked as it's read. Defaults to
+    /// [`VerifyLevel::Full`]. The cheaper levels are meant for caches on
+    /// trusted local disks, where hashing everything on every read costs
+    /// more than it's worth.
+    pub fn verify(mut self, verify: VerifyLevel) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Reads the data for `key`, like [`read`].
+    pub fn read<P, K>(self, cache: P, key: K) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        let entry = self.find(cache.as_ref(), key.as_ref())?;
+        read::read_file_with(
+            &read::entry_file(cache.as_ref(), &entry)?,
+            &entry.integrity,
+            self.verify,
+            entry_size(&entry),
+        )
+    }
+
+    /// Reads the data for `sri`, like [`read_hash`].
+    pub fn read_hash<P>(self, cache: P, sri: &Integrity) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+    {
+        let cpath = read::content_file(cache.as_ref(), sri)?;
+        read::read_file_with(&cpath, sri, self.verify, None)
+    }
+
+    /// Opens a handle to the data for `key`, like [`Reader::open`]. Its
+    /// `check()` only checks as much as these options ask for.
+    pub fn open<P, K>(self, cache: P, key: K) -> Result<Reader>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        let entry = self.find(cache.as_ref(), key.as_ref())?;
+        let cpath = read::entry_file(cache.as_ref(), &entry)?;
+        let size = entry_size(&entry);
+        Ok(Reader {
+            reader: read::open_file_with(&cpath, entry.integrity, self.verify, size)?,
+        })
+    }
+
+    /// Opens a handle to the data for `sri`, like [`Reader::open_hash`].
+    pub fn open_hash<P>(self, cache: P, sri: Integrity) -> Result<Reader>
+    where
+        P: AsRef<Path>,
+    {
+        let cpath = read::content_file(cache.as_ref(), &sri)?;
+        Ok(Reader {
+            reader: read::open_file_with(&cpath, sri, self.verify, None)?,
+        })
+    }
+
+    fn find(&self, cache: &Path, key: &str) -> Result<Metadata> {
+        index::find(cache, key)?
+            .ok_or_else(|| Error::EntryNotFound(cache.to_path_buf(), key.into()))
+    }
 }
 
 /// Reads the entire contents of a cache file synchronously into a bytes
@@ -118,70 +349,185 @@ impl Reader {
 /// }
 /// ```
 pub fn read<P, K>(cache: P, key: K) -> Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    read_with_metadata(cache, key).map(|(data, _)| data)
+}
+
+/// Reads the entire contents of a cache file synchronously like [`read`],
+/// returning it along with its index entry from the same lookup.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let (data, entry) = cacache_sync::read_with_metadata("./my-cache", "my-key")?;
+///     println!("{} bytes, stored with {}", data.len(), entry.metadata);
+///     Ok(())
+/// }
+/// ```
+pub fn read_with_metadata<P, K>(cache: P, key: K) -> Result<(Vec<u8>, Metadata)>
 where
     P: AsRef<Path>,
     K: AsRef<str>,
 {
     if let Some(entry) = index::find(cache.as_ref(), key.as_ref())? {
-        read_hash(cache, &entry.integrity)
+        let data = read::read_file(&read::entry_file(cache.as_ref(), &entry)?, &entry.integrity)?;
+        Ok((data, entry))
     } else {
-        return Err(Error::EntryNotFound(
+        Err(Error::EntryNotFound(
             cache.as_ref().to_path_buf(),
             key.as_ref().into(),
-        ));
+        ))
     }
 }
 
-/// Reads the entire contents of a cache file synchronously into a bytes
-/// vector, looking the data up by its content address.
+/// Reads the data for `key` like [`read`], or if there's none, computes it
+/// with `f`, writes it under `key`, and returns it. Data that's missing or
+/// fails its integrity check counts as none, so it gets computed and written
+/// afresh, and so do entries that have expired, see
+/// [`crate::WriteOpts::ttl`], and entries that are only metadata. Errors from `f` are returned as they are, and nothing is written.
 ///
 /// ## Example
 /// ```no_run
-/// use std::io::Read;
-///
 /// fn main() -> cacache_sync::Result<()> {
-///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
-///     let data = cacache_sync::read_hash("./my-cache", &sri)?;
+///     let data = cacache_sync::get_or_insert_with("./my-cache", "my-key", || {
+///         Ok::<_, cacache_sync::Error>(b"expensive to compute".to_vec())
+///     })?;
 ///     Ok(())
 /// }
 /// ```
-pub fn read_hash<P>(cache: P, sri: &Integrity) -> Result<Vec<u8>>
+pub fn get_or_insert_with<P, K, F, E>(cache: P, key: K, f: F) -> std::result::Result<Vec<u8>, E>
 where
     P: AsRef<Path>,
+    K: AsRef<str>,
+    F: FnOnce() -> std::result::Result<Vec<u8>, E>,
+    E: From<Error>,
 {
-    read::read(cache.as_ref(), sri)
+    let (cache, key) = (cache.as_ref(), key.as_ref());
+    if let Some(entry) = index::find(cache, key)?.filter(is_fresh) {
+        let cpath = read::entry_file(cache, &entry)?;
+        match read::read_file(&cpath, &entry.integrity) {
+            Ok(data) => return Ok(data),
+            Err(Error::IntegrityError { .. }) => {}
+            Err(_) if !cpath.exists() => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let data = f()?;
+    crate::write(cache, key, &data)?;
+    Ok(data)
 }
 
-/// Copies a cache entry by key to a specified location. Returns the number of
-/// bytes copied.
+/// Opens the data for `key` like [`Reader::open`], or if there's none, has `f`
+/// stream it into a [`Writer`] for `key`, commits it, and opens what was
+/// written. Large computed data can then be cached without ever holding all
+/// of it in memory. Like with [`get_or_insert_with`], expired entries and
+/// entries that are only metadata count as none. If `f` fails, its writer is aborted, nothing is written,
+/// and its error is returned.
+///
+/// Like any [`Reader`], the one returned still needs [`Reader::check`]
+/// called on it once it's been read.
 ///
 /// ## Example
 /// ```no_run
-/// use std::io::Read;
+/// use std::io::{Read, Write};
 ///
 /// fn main() -> cacache_sync::Result<()> {
-///     cacache_sync::copy("./my-cache", "my-key", "./my-hello.txt")?;
+///     let mut fd = cacache_sync::get_or_insert_streaming("./my-cache", "my-key", |writer| {
+///         for chunk in 0..100u8 {
+///             writer.write_all(&[chunk; 1024]).expect("Failed to write to cache");
+///         }
+///         Ok::<_, cacache_sync::Error>(())
+///     })?;
+///     let mut data = Vec::new();
+///     fd.read_to_end(&mut data).expect("Failed to read data");
+///     fd.check()?;
 ///     Ok(())
 /// }
 /// ```
-pub fn copy<P, K, Q>(cache: P, key: K, to: Q) -> Result<u64>
+pub fn get_or_insert_streaming<P, K, F, E>(cache: P, key: K, f: F) -> std::result::Result<Reader, E>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    F: FnOnce(&mut Writer) -> std::result::Result<(), E>,
+    E: From<Error>,
+{
+    let (cache, key) = (cache.as_ref(), key.as_ref());
+    if let Some(entry) = index::find(cache, key)?.filter(is_fresh) {
+        let cpath = read::entry_file(cache, &entry)?;
+        match read::open_file(&cpath, entry.integrity) {
+            Ok(reader) => return Ok(Reader { reader }),
+            Err(_) if !cpath.exists() => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let mut writer = Writer::create(cache, key)?;
+    if let Err(err) = f(&mut writer) {
+        // The loader's error says more than any failure to clean up after it.
+        let _ = writer.abort();
+        return Err(err);
+    }
+    let sri = writer.commit()?;
+    Ok(Reader::open_hash(cache, sri)?)
+}
+
+/// Whether `entry` has data that [`get_or_insert_with`] and
+/// [`get_or_insert_streaming`] can hand back instead of computing it again.
+fn is_fresh(entry: &Metadata) -> bool {
+    !entry.metadata_only && !entry.is_expired()
+}
+
+/// Reads the entire contents of a cache file synchronously like [`read`], but
+/// fails with [`Error::LimitExceeded`] rather than return more than `limit`
+/// bytes. The size is checked before any data is read, so oversized entries
+/// cost nothing to reject.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let data = cacache_sync::read_with_limit("./my-cache", "untrusted-key", 1024 * 1024)?;
+///     Ok(())
+/// }
+/// ```
+pub fn read_with_limit<P, K>(cache: P, key: K, limit: u64) -> Result<Vec<u8>>
 where
     P: AsRef<Path>,
     K: AsRef<str>,
-    Q: AsRef<Path>,
 {
     if let Some(entry) = index::find(cache.as_ref(), key.as_ref())? {
-        copy_hash(cache, &entry.integrity, to)
+        let cpath = read::entry_file(cache.as_ref(), &entry)?;
+        read::read_file_limited(&cpath, &entry.integrity, limit)
     } else {
-        return Err(Error::EntryNotFound(
+        Err(Error::EntryNotFound(
             cache.as_ref().to_path_buf(),
             key.as_ref().into(),
-        ));
+        ))
     }
 }
 
-/// Copies a cache entry by integrity address to a specified location. Returns
-/// the number of bytes copied.
+/// Reads the entire contents of a cache file synchronously like
+/// [`read_hash`], but fails with [`Error::LimitExceeded`] rather than return
+/// more than `limit` bytes.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     let data = cacache_sync::read_hash_with_limit("./my-cache", &sri, 1024)?;
+///     Ok(())
+/// }
+/// ```
+pub fn read_hash_with_limit<P>(cache: P, sri: &Integrity, limit: u64) -> Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+{
+    read::read_limited(cache.as_ref(), sri, limit)
+}
+
+/// Reads the entire contents of a cache file synchronously into a bytes
+/// vector, looking the data up by its content address.
 ///
 /// ## Example
 /// ```no_run
@@ -189,78 +535,1317 @@ where
 ///
 /// fn main() -> cacache_sync::Result<()> {
 ///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
-///     cacache_sync::copy_hash("./my-cache", &sri, "./my-hello.txt")?;
+///     let data = cacache_sync::read_hash("./my-cache", &sri)?;
 ///     Ok(())
 /// }
 /// ```
-pub fn copy_hash<P, Q>(cache: P, sri: &Integrity, to: Q) -> Result<u64>
+pub fn read_hash<P>(cache: P, sri: &Integrity) -> Result<Vec<u8>>
 where
     P: AsRef<Path>,
-    Q: AsRef<Path>,
 {
-    read::copy(cache.as_ref(), sri, to.as_ref())
+    read::read(cache.as_ref(), sri)
 }
 
-/// Gets metadata for a certain key.
+/// Reads the data for each of `keys` like [`read`], returning a result for
+/// each, in the same order. Meant for bulk extraction: with the `io-uring`
+/// feature on Linux, the reads are submitted to the kernel in batches, so
+/// fast drives aren't held back by waiting on one read at a time.
 ///
-/// Note that the existence of a metadata entry is not a guarantee that the
-/// underlying data exists, since they are stored and managed independently.
-/// To verify that the underlying associated data exists, use `exists()`.
-pub fn metadata<P, K>(cache: P, key: K) -> Result<Option<Metadata>>
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     for data in cacache_sync::read_many("./my-cache", ["index.html", "app.js"]) {
+///         println!("{} bytes", data?.len());
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn read_many<P, I, K>(cache: P, keys: I) -> Vec<Result<Vec<u8>>>
 where
     P: AsRef<Path>,
+    I: IntoIterator<Item = K>,
     K: AsRef<str>,
 {
-    index::find(cache.as_ref(), key.as_ref())
+    let cache = cache.as_ref();
+    read_files(keys.into_iter().map(|key| find_file(cache, key.as_ref())))
 }
 
-/// Returns true if the given hash exists in the cache.
-pub fn exists<P: AsRef<Path>>(cache: P, sri: &Integrity) -> bool {
-    read::has_content(cache.as_ref(), sri).is_some()
+/// Reads the data for each of `sris` like [`read_hash`], in bulk like
+/// [`read_many`].
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     let data = cacache_sync::read_hash_many("./my-cache", [&sri]);
+///     Ok(())
+/// }
+/// ```
+pub fn read_hash_many<'a, P, I>(cache: P, sris: I) -> Vec<Result<Vec<u8>>>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = &'a Integrity>,
+{
+    let cache = cache.as_ref();
+    read_files(
+        sris.into_iter()
+            .map(|sri| Ok((read::content_file(cache, sri)?, sri.clone()))),
+    )
 }
 
-#[cfg(test)]
-mod tests {
-    use std::fs;
-
-    #[test]
-    fn test_open() {
-        use std::io::prelude::*;
-        let tmp = tempfile::tempdir().unwrap();
-        let dir = tmp.path().to_owned();
-        crate::write(&dir, "my-key", b"hello world").unwrap();
-
-        let mut handle = crate::Reader::open(&dir, "my-key").unwrap();
-        let mut str = String::new();
-        handle.read_to_string(&mut str).unwrap();
-        handle.check().unwrap();
-        assert_eq!(str, String::from("hello world"));
+/// Reads the data for each of `keys` like [`read_many`], but only ever holds
+/// up to `budget` bytes of it at once, for batch jobs that can't afford to
+/// have everything in memory together. Keys are read in batches whose data
+/// fits within `budget`, and `f` is called with each key and its result, in
+/// order, before the next batch is read. Data that's bigger than the whole
+/// budget fails with [`Error::LimitExceeded`] instead of being read, so a few
+/// huge entries can't blow it. They can still be streamed with [`Reader`].
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let keys = ["index.html", "app.js", "huge.tar"];
+///     cacache_sync::read_many_budgeted("./my-cache", keys, 64 * 1024 * 1024, |key, data| {
+///         match data {
+///             Ok(data) => println!("{}: {} bytes", key, data.len()),
+///             Err(err) => println!("{}: {}", key, err),
+///         }
+///     });
+///     Ok(())
+/// }
+/// ```
+pub fn read_many_budgeted<P, I, K, F>(cache: P, keys: I, budget: u64, mut f: F)
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = K>,
+    K: AsRef<str>,
+    F: FnMut(K, Result<Vec<u8>>),
+{
+    let cache = cache.as_ref();
+    let mut batch = Vec::new();
+    let mut batch_size = 0;
+    let mut flush = |batch: &mut Vec<(K, Result<(PathBuf, Integrity)>)>| {
+        let (keys, lookups): (Vec<_>, Vec<_>) = batch.drain(..).unzip();
+        for (key, data) in keys.into_iter().zip(read_files(lookups.into_iter())) {
+            f(key, data);
+        }
+    };
+    for key in keys {
+        let lookup = find_file(cache, key.as_ref()).and_then(|(cpath, sri)| {
+            // Sizes in the index can be missing, so go by the file itself.
+            let size = std::fs::metadata(&cpath).to_internal()?.len();
+            if size > budget {
+                return Err(Error::LimitExceeded(budget, size));
+            }
+            Ok((cpath, sri, size))
+        });
+        let size = lookup.as_ref().map_or(0, |(_, _, size)| *size);
+        if batch_size + size > budget {
+            flush(&mut batch);
+            batch_size = 0;
+        }
+        batch_size += size;
+        batch.push((key, lookup.map(|(cpath, sri, _)| (cpath, sri))));
     }
+    flush(&mut batch);
+}
 
-    #[test]
-    fn test_open_hash() {
-        use std::io::prelude::*;
-        let tmp = tempfile::tempdir().unwrap();
-        let dir = tmp.path().to_owned();
-        let sri = crate::write(&dir, "my-key", b"hello world").unwrap();
+/// Looks up the file holding the data for `key`, along with its integrity.
+fn find_file(cache: &Path, key: &str) -> Result<(PathBuf, Integrity)> {
+    let entry = index::find(cache, key)?
+        .ok_or_else(|| Error::EntryNotFound(cache.to_path_buf(), key.into()))?;
+    Ok((read::entry_file(cache, &entry)?, entry.integrity))
+}
 
-        let mut handle = crate::Reader::open_hash(&dir, sri).unwrap();
-        let mut str = String::new();
-        handle.read_to_string(&mut str).unwrap();
-        handle.check().unwrap();
-        assert_eq!(str, String::from("hello world"));
-    }
+/// Reads every file that was found in one batch, keeping the errors for
+/// those that weren't in their place.
+fn read_files<I>(lookups: I) -> Vec<Result<Vec<u8>>>
+where
+    I: Iterator<Item = Result<(PathBuf, Integrity)>>,
+{
+    let mut files = Vec::new();
+    let results = lookups
+        .map(|lookup| match lookup {
+            Ok(file) => {
+                files.push(file);
+                None
+            }
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<Vec<_>>();
+    let mut data = read::read_files(&files).into_iter();
+    results
+        .into_iter()
+        .map(|result| result.unwrap_or_else(|| data.next().unwrap()))
+        .collect()
+}
 
-    #[test]
-    fn test_read() {
-        let tmp = tempfile::tempdir().unwrap();
-        let dir = tmp.path().to_owned();
-        crate::write(&dir, "my-key", b"hello world").unwrap();
+/// Reads the data for `key` like [`read`], without checking it against its
+/// integrity at all. Only for data that's already trusted, such as data that
+/// was just written, or that's checked again further along anyway.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let data = cacache_sync::read_unchecked("./my-cache", "my-key")?;
+///     Ok(())
+/// }
+/// ```
+pub fn read_unchecked<P, K>(cache: P, key: K) -> Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    ReadOpts::new().verify(VerifyLevel::None).read(cache, key)
+}
+
+/// Reads the data for `sri` like [`read_hash`], without checking it, like
+/// [`read_unchecked`].
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     let data = cacache_sync::read_hash_unchecked("./my-cache", &sri)?;
+///     Ok(())
+/// }
+/// ```
+pub fn read_hash_unchecked<P>(cache: P, sri: &Integrity) -> Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+{
+    ReadOpts::new()
+        .verify(VerifyLevel::None)
+        .read_hash(cache, sri)
+}
+
+/// Reads the entire contents of a cache file synchronously into a string,
+/// looking the data up by key. The data is checked for integrity before being
+/// validated as UTF-8.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let data = cacache_sync::read_to_string("./my-cache", "my-key")?;
+///     Ok(())
+/// }
+/// ```
+pub fn read_to_string<P, K>(cache: P, key: K) -> Result<String>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    let data = read(cache.as_ref(), key.as_ref())?;
+    Ok(String::from_utf8(data).with_context(|| {
+        format!(
+            "Data for key {} in cache at {:?} is not valid UTF-8",
+            key.as_ref(),
+            cache.as_ref()
+        )
+    })?)
+}
+
+/// Reads the entire contents of a cache file synchronously into a string,
+/// looking the data up by its content address. The data is checked for
+/// integrity before being validated as UTF-8.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     let data = cacache_sync::read_hash_to_string("./my-cache", &sri)?;
+///     Ok(())
+/// }
+/// ```
+pub fn read_hash_to_string<P>(cache: P, sri: &Integrity) -> Result<String>
+where
+    P: AsRef<Path>,
+{
+    let data = read_hash(cache.as_ref(), sri)?;
+    Ok(String::from_utf8(data).with_context(|| {
+        format!(
+            "Data for {} in cache at {:?} is not valid UTF-8",
+            sri,
+            cache.as_ref()
+        )
+    })?)
+}
+
+/// Reads a cache entry synchronously by key and deserializes it from JSON.
+/// The data is checked for integrity before being deserialized.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let data: Vec<u32> = cacache_sync::read_json("./my-cache", "my-key")?;
+///     Ok(())
+/// }
+/// ```
+pub fn read_json<P, K, T>(cache: P, key: K) -> Result<T>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    T: DeserializeOwned,
+{
+    let data = read(cache.as_ref(), key.as_ref())?;
+    Ok(serde_json::from_slice(&data).with_context(|| {
+        format!(
+            "Failed to deserialize JSON data for key {} in cache at {:?}",
+            key.as_ref(),
+            cache.as_ref()
+        )
+    })?)
+}
+
+/// Copies a cache entry by key to a specified location. Returns the number of
+/// bytes copied.
+///
+/// ## Example
+/// ```no_run
+/// use std::io::Read;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     cacache_sync::copy("./my-cache", "my-key", "./my-hello.txt")?;
+///     Ok(())
+/// }
+/// ```
+pub fn copy<P, K, Q>(cache: P, key: K, to: Q) -> Result<u64>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    Q: AsRef<Path>,
+{
+    if let Some(entry) = index::find(cache.as_ref(), key.as_ref())? {
+        let cpath = read::entry_file(cache.as_ref(), &entry)?;
+        read::copy_file(&cpath, &entry.integrity, to.as_ref())
+    } else {
+        Err(Error::EntryNotFound(
+            cache.as_ref().to_path_buf(),
+            key.as_ref().into(),
+        ))
+    }
+}
+
+/// Copies a cache entry by integrity address to a specified location. Returns
+/// the number of bytes copied.
+///
+/// ## Example
+/// ```no_run
+/// use std::io::Read;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     cacache_sync::copy_hash("./my-cache", &sri, "./my-hello.txt")?;
+///     Ok(())
+/// }
+/// ```
+pub fn copy_hash<P, Q>(cache: P, sri: &Integrity, to: Q) -> Result<u64>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    read::copy(cache.as_ref(), sri, to.as_ref())
+}
+
+/// Streams a cache entry by integrity address into `to`, hashing it along the
+/// way. Returns the number of bytes written.
+///
+/// Verification can only finish once all of the data has been written, so on
+/// an integrity error `to` will already have received the bad data. Callers
+/// that can't take that back, like HTTP handlers, should treat the error as a
+/// reason to abort what they're sending.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     let mut out = Vec::new();
+///     cacache_sync::copy_hash_to_writer("./my-cache", &sri, &mut out)?;
+///     assert_eq!(out, b"hello");
+///     Ok(())
+/// }
+/// ```
+pub fn copy_hash_to_writer<P, W>(cache: P, sri: &Integrity, to: &mut W) -> Result<u64>
+where
+    P: AsRef<Path>,
+    W: Write + ?Sized,
+{
+    read::copy_to_writer(cache.as_ref(), sri, to)
+}
+
+/// Copies a cache entry by key to a specified location, like [`copy`], but
+/// leaves block-sized runs of zeroes in the data as holes in the destination
+/// file, on filesystems that support sparse files. Returns the number of bytes
+/// copied.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     cacache_sync::copy_sparse("./my-cache", "my-disk-image", "./disk.img")?;
+///     Ok(())
+/// }
+/// ```
+pub fn copy_sparse<P, K, Q>(cache: P, key: K, to: Q) -> Result<u64>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    Q: AsRef<Path>,
+{
+    if let Some(entry) = index::find(cache.as_ref(), key.as_ref())? {
+        let cpath = read::entry_file(cache.as_ref(), &entry)?;
+        read::copy_sparse_file(&cpath, &entry.integrity, to.as_ref())
+    } else {
+        Err(Error::EntryNotFound(
+            cache.as_ref().to_path_buf(),
+            key.as_ref().into(),
+        ))
+    }
+}
+
+/// Copies a cache entry by integrity address to a specified location, like
+/// [`copy_hash`], but preserving runs of zeroes as holes. Returns the number of
+/// bytes copied.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", vec![0; 1024 * 1024])?;
+///     cacache_sync::copy_hash_sparse("./my-cache", &sri, "./zeroes.bin")?;
+///     Ok(())
+/// }
+/// ```
+pub fn copy_hash_sparse<P, Q>(cache: P, sri: &Integrity, to: Q) -> Result<u64>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    read::copy_sparse(cache.as_ref(), sri, to.as_ref())
+}
+
+/// Gets metadata for a certain key.
+///
+/// Note that the existence of a metadata entry is not a guarantee that the
+/// underlying data exists, since they are stored and managed independently.
+/// To verify that the underlying associated data exists, use `exists()`.
+pub fn metadata<P, K>(cache: P, key: K) -> Result<Option<Metadata>>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    index::find(cache.as_ref(), key.as_ref())
+}
+
+/// Returns true if the given hash exists in the cache. Caches configured
+/// with [`crate::CacheConfig::content_filter`] can rule out most missing
+/// content without touching its file.
+pub fn exists<P: AsRef<Path>>(cache: P, sri: &Integrity) -> bool {
+    let cache = cache.as_ref();
+    // The filter can only rule content out. Anything it might hold still
+    // needs finding.
+    if filter::contains(cache, sri) == Some(false) {
+        return false;
+    }
+    read::has_content(cache, sri).is_some()
+}
+
+/// What the cache knows about a key, from [`exists_key`] and [`read_fresh`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Lookup<T = ()> {
+    /// The key has data that hasn't expired.
+    Found(T),
+    /// The key was recorded as missing with [`crate::write_negative`], or
+    /// some other metadata-only entry, and that hasn't expired yet. There's
+    /// no point asking upstream for it again.
+    KnownMissing,
+    /// The cache has nothing current for the key: no entry at all, or one
+    /// whose [`WriteOpts::ttl`](crate::WriteOpts::ttl) has run out.
+    Unknown,
+}
+
+impl<T> Lookup<T> {
+    /// Returns the found value, if there is one.
+    pub fn found(self) -> Option<T> {
+        match self {
+            Lookup::Found(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+fn lookup_fresh(cache: &Path, key: &str) -> Result<Lookup<Metadata>> {
+    Ok(match index::find(cache, key)? {
+        Some(entry) if entry.is_expired() => Lookup::Unknown,
+        Some(entry) if entry.metadata_only => Lookup::KnownMissing,
+        Some(entry) => Lookup::Found(entry),
+        None => Lookup::Unknown,
+    })
+}
+
+/// Checks what the index knows about `key`, without touching its data:
+/// whether it has a current entry, is known to be missing, or needs to be
+/// looked up elsewhere. Expired entries of either kind count as
+/// [`Lookup::Unknown`].
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::Lookup;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     match cacache_sync::exists_key("./my-cache", "left-pad@9.9.9")? {
+///         Lookup::Found(()) => println!("cached"),
+///         Lookup::KnownMissing => println!("upstream said 404 recently"),
+///         Lookup::Unknown => println!("ask upstream"),
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn exists_key<P, K>(cache: P, key: K) -> Result<Lookup>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    Ok(match lookup_fresh(cache.as_ref(), key.as_ref())? {
+        Lookup::Found(_) => Lookup::Found(()),
+        Lookup::KnownMissing => Lookup::KnownMissing,
+        Lookup::Unknown => Lookup::Unknown,
+    })
+}
+
+/// Reads the data for `key` like [`read`], as long as its entry hasn't
+/// expired. Keys recorded as missing with [`crate::write_negative`] come back
+/// as [`Lookup::KnownMissing`] until their time to live runs out, rather
+/// than as an error.
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::Lookup;
+/// use std::time::Duration;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let data = match cacache_sync::read_fresh("./my-cache", "left-pad@9.9.9")? {
+///         Lookup::Found(data) => Some(data),
+///         Lookup::KnownMissing => None,
+///         Lookup::Unknown => {
+///             // Not found upstream either.
+///             cacache_sync::write_negative(
+///                 "./my-cache",
+///                 "left-pad@9.9.9",
+///                 Duration::from_secs(300),
+///             )?;
+///             None
+///         }
+///     };
+///     Ok(())
+/// }
+/// ```
+pub fn read_fresh<P, K>(cache: P, key: K) -> Result<Lookup<Vec<u8>>>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    let cache = cache.as_ref();
+    Ok(match lookup_fresh(cache, key.as_ref())? {
+        Lookup::Found(entry) => Lookup::Found(read::read_file(
+            &read::entry_file(cache, &entry)?,
+            &entry.integrity,
+        )?),
+        Lookup::KnownMissing => Lookup::KnownMissing,
+        Lookup::Unknown => Lookup::Unknown,
+    })
+}
+
+/// Returns the path where the content for `sri` is, or would be, stored in
+/// the cache, taking the cache's layout configuration into account. Useful
+/// for mapping, hard-linking or serving content files directly.
+///
+/// Content files must never be modified in place: anything changing them
+/// will make reads of the content fail integrity verification.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     let path = cacache_sync::content_path("./my-cache", &sri)?;
+///     println!("hello is stored at {:?}", path);
+///     Ok(())
+/// }
+/// ```
+pub fn content_path<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<PathBuf> {
+    path::content_path(cache.as_ref(), sri)
+}
+
+/// Returns the path of the content for `sri` like [`content_path`], or `None`
+/// if the cache doesn't have that content.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     if let Some(path) = cacache_sync::existing_content_path("./my-cache", &sri)? {
+///         std::fs::hard_link(path, "./hello.txt").expect("Failed to link content");
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn existing_content_path<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<Option<PathBuf>> {
+    let cpath = content_path(cache, sri)?;
+    Ok(if cpath.exists() { Some(cpath) } else { None })
+}
+
+/// Returns the size in bytes of the content for `sri`, from the content
+/// file's metadata rather than by reading it. The data isn't verified, so this
+/// is meant for things like `Content-Length` headers ahead of a verified read.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     assert_eq!(cacache_sync::size_hash("./my-cache", &sri)?, 5);
+///     Ok(())
+/// }
+/// ```
+pub fn size_hash<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<u64> {
+    let cpath = content_path(cache, sri)?;
+    let meta = std::fs::metadata(&cpath)
+        .with_context(|| format!("Failed to stat content file at {:?}", cpath))?;
+    Ok(meta.len())
+}
+
+/// Counts the live index entries that reference the content for `sri`. It's
+/// computed on demand by walking the whole index, so it's not meant for hot
+/// paths. Content with a count of zero is only reachable by its hash.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     cacache_sync::write("./my-cache", "other-key", b"hello")?;
+///     assert_eq!(cacache_sync::ref_count("./my-cache", &sri)?, 2);
+///     Ok(())
+/// }
+/// ```
+pub fn ref_count<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<usize> {
+    index::references(cache.as_ref(), sri)
+}
+
+/// Outcome of a quick check of cached data, from [`validate`] and
+/// [`validate_hash`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Validity {
+    /// The data is there and looks intact. It hasn't been hashed, so this
+    /// isn't a guarantee that a verified read will succeed.
+    Present,
+    /// The cache doesn't have the data.
+    Absent,
+    /// Something is there, but it's clearly not the expected data.
+    Suspect,
+}
+
+/// Quickly checks the content for `sri` without reading it: it's
+/// [`Validity::Suspect`] if the content path holds something other than a
+/// regular file.
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::Validity;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     if cacache_sync::validate_hash("./my-cache", &sri)? == Validity::Present {
+///         let data = cacache_sync::read_hash("./my-cache", &sri)?;
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn validate_hash<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<Validity> {
+    let cpath = content_path(cache, sri)?;
+    Ok(file_validity(&cpath, None))
+}
+
+/// Quickly checks the data for `key` without reading it, like
+/// [`validate_hash`]. If the entry recorded a size, data of any other length
+/// is also [`Validity::Suspect`].
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let validity = cacache_sync::validate("./my-cache", "my-key")?;
+///     println!("my-key is {:?}", validity);
+///     Ok(())
+/// }
+/// ```
+pub fn validate<P, K>(cache: P, key: K) -> Result<Validity>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    match index::find(cache.as_ref(), key.as_ref())? {
+        Some(entry) if entry.metadata_only => Ok(Validity::Absent),
+        Some(entry) => {
+            let cpath = read::entry_file(cache.as_ref(), &entry)?;
+            Ok(file_validity(&cpath, entry_size(&entry)))
+        }
+        None => Ok(Validity::Absent),
+    }
+}
+
+/// Outcome of checking one part of an entry, as part of an
+/// [`EntryVerification`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// It's there and passed its check.
+    Valid,
+    /// It isn't there.
+    Missing,
+    /// It's there, but failed its check.
+    Invalid,
+}
+
+/// Outcome of [`verify_entry`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryVerification {
+    /// Whether the newest index line for the key is intact. It's
+    /// [`Verification::Invalid`] if the line fails its checksum, in which
+    /// case lookups fall back to an older entry, if there is one.
+    pub index: Verification,
+    /// Whether the data of the entry that lookups find hashes to its
+    /// integrity. It's [`Verification::Missing`] if there's no such entry.
+    pub content: Verification,
+}
+
+impl EntryVerification {
+    /// Returns true if both the index entry and its data are intact.
+    pub fn is_valid(&self) -> bool {
+        self.index == Verification::Valid && self.content == Verification::Valid
+    }
+}
+
+/// Fully checks the entry for `key`: the checksum of its line in the index,
+/// and the hash of the data it refers to. Unlike [`validate`], this reads all
+/// of the data.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let verification = cacache_sync::verify_entry("./my-cache", "my-key")?;
+///     if !verification.is_valid() {
+///         println!("my-key is damaged: {:?}", verification);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn verify_entry<P, K>(cache: P, key: K) -> Result<EntryVerification>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    let (cache, key) = (cache.as_ref(), key.as_ref());
+    let entry = index::find(cache, key)?;
+    let index = if index::tampered(cache, key)? {
+        Verification::Invalid
+    } else if entry.is_some() {
+        Verification::Valid
+    } else {
+        Verification::Missing
+    };
+    let content = match entry {
+        Some(entry) if entry.metadata_only => Verification::Missing,
+        Some(entry) => {
+            let cpath = read::entry_file(cache, &entry)?;
+            match read::open_file(&cpath, entry.integrity) {
+                Ok(mut reader) => {
+                    std::io::copy(&mut reader, &mut std::io::sink()).to_internal()?;
+                    match reader.check() {
+                        Ok(_) => Verification::Valid,
+                        Err(Error::IntegrityError { .. }) => Verification::Invalid,
+                        Err(err) => return Err(err),
+                    }
+                }
+                Err(_) if !cpath.exists() => Verification::Missing,
+                Err(err) => return Err(err),
+            }
+        }
+        None => Verification::Missing,
+    };
+    Ok(EntryVerification { index, content })
+}
+
+/// Verifies every live entry in the cache like [`verify_entry`], and records
+/// a summary of the outcome, which [`crate::stats_json`] reports as
+/// `last_verify`. Returns the summary. Entries that are only metadata don't
+/// count as damaged for having no data.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let summary = cacache_sync::verify_cache("./my-cache")?;
+///     for key in &summary.damaged {
+///         println!("{} is damaged", key);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn verify_cache<P: AsRef<Path>>(cache: P) -> Result<VerifySummary> {
+    let cache = cache.as_ref();
+    let mut summary = VerifySummary::default();
+    for entry in index::ls(cache) {
+        let entry = entry?;
+        let verification = verify_entry(cache, &entry.key)?;
+        let damaged = verification.index == Verification::Invalid
+            || verification.content == Verification::Invalid
+            || (verification.content == Verification::Missing && !entry.metadata_only);
+        summary.entries += 1;
+        if damaged {
+            summary.damaged.push(entry.key);
+        }
+    }
+    summary.time = index::now();
+    stats::record_verify(cache, &summary)?;
+    Ok(summary)
+}
+
+/// The size recorded in `entry`, if any. Entries written without one have a
+/// size of 0, so that counts as unknown too.
+fn entry_size(entry: &Metadata) -> Option<u64> {
+    Some(entry.size as u64).filter(|size| *size != 0)
+}
+
+fn file_validity(path: &Path, size: Option<u64>) -> Validity {
+    match std::fs::metadata(path) {
+        Ok(meta) if !meta.is_file() => Validity::Suspect,
+        Ok(meta) if matches!(size, Some(size) if size != meta.len()) => Validity::Suspect,
+        Ok(_) => Validity::Present,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Validity::Absent,
+        Err(_) => Validity::Suspect,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_read_opts_verify() {
+        use crate::{ReadOpts, VerifyLevel};
+        use std::io::Read;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::WriteOpts::new()
+            .size(11)
+            .open(dir, "my-key")
+            .and_then(|mut fd| {
+                std::io::Write::write_all(&mut fd, b"hello world").unwrap();
+                fd.commit()
+            })
+            .unwrap();
+        let cpath = crate::content_path(dir, &sri).unwrap();
+        let read_at = |verify| ReadOpts::new().verify(verify).read(dir, "my-key");
+        let stream_at = |verify| {
+            let mut reader = ReadOpts::new().verify(verify).open(dir, "my-key")?;
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            reader.check()?;
+            Ok::<_, crate::Error>(data)
+        };
+
+        // Same length, different data.
+        fs::write(&cpath, b"hello there").unwrap();
+        assert!(read_at(VerifyLevel::Full).is_err());
+        assert!(stream_at(VerifyLevel::Full).is_err());
+        assert_eq!(read_at(VerifyLevel::Size).unwrap(), b"hello there");
+        assert_eq!(stream_at(VerifyLevel::Size).unwrap(), b"hello there");
+        assert!(ReadOpts::new().read_hash(dir, &sri).is_err());
+
+        fs::write(&cpath, b"hello").unwrap();
+        assert!(matches!(
+            read_at(VerifyLevel::Size),
+            Err(crate::Error::SizeError(11, 5))
+        ));
+        assert!(matches!(
+            stream_at(VerifyLevel::Size),
+            Err(crate::Error::SizeError(11, 5))
+        ));
+        assert_eq!(read_at(VerifyLevel::None).unwrap(), b"hello");
+        assert_eq!(stream_at(VerifyLevel::None).unwrap(), b"hello");
+        assert_eq!(
+            ReadOpts::new()
+                .verify(VerifyLevel::None)
+                .read_hash(dir, &sri)
+                .unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn test_read_unchecked() {
+        use std::io::Read;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write(dir, "my-key", b"hello world").unwrap();
+        fs::write(crate::content_path(dir, &sri).unwrap(), b"corrupted").unwrap();
+        assert!(crate::read(dir, "my-key").is_err());
+
+        assert_eq!(crate::read_unchecked(dir, "my-key").unwrap(), b"corrupted");
+        assert_eq!(crate::read_hash_unchecked(dir, &sri).unwrap(), b"corrupted");
+        for mut reader in [
+            crate::Reader::open_unchecked(dir, "my-key").unwrap(),
+            crate::Reader::open_hash_unchecked(dir, sri.clone()).unwrap(),
+        ] {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            assert_eq!(data, b"corrupted");
+            assert_eq!(reader.check().unwrap(), None);
+        }
+        assert!(matches!(
+            crate::read_unchecked(dir, "missing"),
+            Err(crate::Error::EntryNotFound(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_negative_lookups() {
+        use crate::Lookup;
+        use std::time::Duration;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        crate::write(dir, "cached", b"hello").unwrap();
+        crate::write_negative(dir, "missing", Duration::from_secs(3600)).unwrap();
+        crate::write_negative(dir, "stale", Duration::ZERO).unwrap();
+
+        assert_eq!(crate::exists_key(dir, "cached").unwrap(), Lookup::Found(()));
+        assert_eq!(
+            crate::read_fresh(dir, "cached").unwrap(),
+            Lookup::Found(b"hello".to_vec())
+        );
+        assert_eq!(
+            crate::exists_key(dir, "missing").unwrap(),
+            Lookup::KnownMissing
+        );
+        assert_eq!(
+            crate::read_fresh(dir, "missing").unwrap(),
+            Lookup::KnownMissing
+        );
+        assert_eq!(crate::exists_key(dir, "stale").unwrap(), Lookup::Unknown);
+        assert_eq!(crate::read_fresh(dir, "nothing").unwrap(), Lookup::Unknown);
+
+        crate::write(dir, "missing", b"found after all").unwrap();
+        assert_eq!(
+            crate::read_fresh(dir, "missing").unwrap().found().unwrap(),
+            b"found after all"
+        );
+    }
+
+    #[test]
+    fn test_content_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write(dir, "my-key", b"hello world").unwrap();
+
+        let cpath = crate::content_path(dir, &sri).unwrap();
+        assert_eq!(fs::read(&cpath).unwrap(), b"hello world");
+        assert_eq!(
+            crate::existing_content_path(dir, &sri).unwrap(),
+            Some(cpath)
+        );
+
+        let missing = ssri::Integrity::from(b"goodbye world");
+        assert!(crate::content_path(dir, &missing).is_ok());
+        assert_eq!(crate::existing_content_path(dir, &missing).unwrap(), None);
+    }
+
+    #[test]
+    fn test_size_hash() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write(dir, "my-key", b"hello world").unwrap();
+
+        assert_eq!(crate::size_hash(dir, &sri).unwrap(), 11);
+        let missing = ssri::Integrity::from(b"goodbye world");
+        assert!(crate::size_hash(dir, &missing).is_err());
+    }
+
+    #[test]
+    fn test_ref_count() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = ssri::Integrity::from(b"hello world");
+        assert_eq!(crate::ref_count(dir, &sri).unwrap(), 0);
+
+        crate::write(dir, "a", b"hello world").unwrap();
+        crate::write(dir, "b", b"hello world").unwrap();
+        crate::write(dir, "c", b"goodbye world").unwrap();
+        assert_eq!(crate::ref_count(dir, &sri).unwrap(), 2);
+
+        crate::remove(dir, "a").unwrap();
+        assert_eq!(crate::ref_count(dir, &sri).unwrap(), 1);
+        crate::write(dir, "b", b"goodbye world").unwrap();
+        assert_eq!(crate::ref_count(dir, &sri).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_verify_entry() {
+        use crate::{EntryVerification, Verification};
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write(dir, "my-key", b"hello world").unwrap();
+        assert!(crate::verify_entry(dir, "my-key").unwrap().is_valid());
+        assert_eq!(
+            crate::verify_entry(dir, "missing").unwrap(),
+            EntryVerification {
+                index: Verification::Missing,
+                content: Verification::Missing,
+            }
+        );
+
+        // A forged line that doesn't match its checksum.
+        let bucket = walkdir::WalkDir::new(dir.join("index-v5"))
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.file_type().is_file())
+            .unwrap()
+            .into_path();
+        let line = fs::read_to_string(&bucket).unwrap();
+        let (hash, json) = line.trim_start().split_once('\t').unwrap();
+        let forged = json.replace(&sri.to_string(), "sha1-deadbeef");
+        fs::write(&bucket, format!("{}\n{}\t{}", line, hash, forged)).unwrap();
+        let verification = crate::verify_entry(dir, "my-key").unwrap();
+        assert_eq!(verification.index, Verification::Invalid);
+        assert_eq!(verification.content, Verification::Valid);
+
+        let cpath = crate::content_path(dir, &sri).unwrap();
+        fs::write(&cpath, b"hello w0rld").unwrap();
+        assert_eq!(
+            crate::verify_entry(dir, "my-key").unwrap().content,
+            Verification::Invalid
+        );
+        fs::remove_file(&cpath).unwrap();
+        assert_eq!(
+            crate::verify_entry(dir, "my-key").unwrap().content,
+            Verification::Missing
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        use crate::Validity;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write(dir, "my-key", b"hello world").unwrap();
+        crate::WriteOpts::new()
+            .size(11)
+            .open(dir, "sized")
+            .and_then(|mut fd| {
+                std::io::Write::write_all(&mut fd, b"hello world").unwrap();
+                fd.commit()
+            })
+            .unwrap();
+
+        assert_eq!(crate::validate_hash(dir, &sri).unwrap(), Validity::Present);
+        assert_eq!(crate::validate(dir, "sized").unwrap(), Validity::Present);
+        assert_eq!(crate::validate(dir, "missing").unwrap(), Validity::Absent);
+
+        let cpath = crate::content_path(dir, &sri).unwrap();
+        fs::write(&cpath, b"hello").unwrap();
+        assert_eq!(crate::validate(dir, "sized").unwrap(), Validity::Suspect);
+        // Without a recorded size, a truncated file can't be told apart.
+        assert_eq!(crate::validate(dir, "my-key").unwrap(), Validity::Present);
+
+        fs::remove_file(&cpath).unwrap();
+        assert_eq!(crate::validate_hash(dir, &sri).unwrap(), Validity::Absent);
+        fs::create_dir(&cpath).unwrap();
+        assert_eq!(crate::validate_hash(dir, &sri).unwrap(), Validity::Suspect);
+    }
+
+    #[test]
+    fn test_open() {
+        use std::io::prelude::*;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "my-key", b"hello world").unwrap();
+
+        let mut handle = crate::Reader::open(&dir, "my-key").unwrap();
+        let mut str = String::new();
+        handle.read_to_string(&mut str).unwrap();
+        assert_eq!(
+            handle.check().unwrap(),
+            Some(ssri::Integrity::from(b"hello world"))
+        );
+        assert_eq!(str, String::from("hello world"));
+    }
+
+    #[test]
+    fn test_open_with_metadata() {
+        use std::io::prelude::*;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::WriteOpts::new()
+            .metadata(serde_json::json!({ "content-type": "text/plain" }))
+            .size(11)
+            .open(&dir, "my-key")
+            .and_then(|mut fd| {
+                fd.write_all(b"hello world").unwrap();
+                fd.commit()
+            })
+            .unwrap();
+
+        let (mut handle, entry) = crate::Reader::open_with_metadata(&dir, "my-key").unwrap();
+        let mut str = String::new();
+        handle.read_to_string(&mut str).unwrap();
+        handle.check().unwrap();
+        assert_eq!(str, String::from("hello world"));
+        assert_eq!(entry.integrity, sri);
+        assert_eq!(entry.size, 11);
+        assert_eq!(entry.metadata["content-type"], "text/plain");
+        assert!(matches!(
+            crate::Reader::open_with_metadata(&dir, "missing"),
+            Err(crate::Error::EntryNotFound(..))
+        ));
+    }
+
+    #[test]
+    fn test_chunks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "my-key", b"hello world").unwrap();
+
+        let handle = crate::Reader::open(&dir, "my-key").unwrap();
+        let chunks = handle.chunks(4).collect::<crate::Result<Vec<_>>>().unwrap();
+        assert_eq!(chunks, vec![&b"hell"[..], b"o wo", b"rld"]);
+
+        let cpath = crate::content::path::content_path(&dir, &sri).unwrap();
+        fs::write(cpath, b"goodbye world").unwrap();
+        let handle = crate::Reader::open(&dir, "my-key").unwrap();
+        let mut chunks = handle.chunks(64);
+        assert!(chunks.next().unwrap().is_ok());
+        assert!(matches!(
+            chunks.next(),
+            Some(Err(crate::Error::IntegrityError { .. }))
+        ));
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn test_open_hash() {
+        use std::io::prelude::*;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "my-key", b"hello world").unwrap();
+
+        let mut handle = crate::Reader::open_hash(&dir, sri).unwrap();
+        let mut str = String::new();
+        handle.read_to_string(&mut str).unwrap();
+        handle.check().unwrap();
+        assert_eq!(str, String::from("hello world"));
+    }
+
+    #[test]
+    fn test_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "my-key", b"hello world").unwrap();
 
         let data = crate::read(&dir, "my-key").unwrap();
         assert_eq!(data, b"hello world");
     }
 
+    #[test]
+    fn test_read_with_metadata() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "my-key", b"hello world").unwrap();
+
+        let (data, entry) = crate::read_with_metadata(&dir, "my-key").unwrap();
+        assert_eq!(data, b"hello world");
+        assert_eq!(entry.key, "my-key");
+        assert_eq!(entry.integrity, sri);
+        assert!(matches!(
+            crate::read_with_metadata(&dir, "missing"),
+            Err(crate::Error::EntryNotFound(..))
+        ));
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let calls = std::cell::Cell::new(0);
+        let compute = || {
+            calls.set(calls.get() + 1);
+            Ok::<_, crate::Error>(b"hello world".to_vec())
+        };
+        let data = crate::get_or_insert_with(&dir, "my-key", compute).unwrap();
+        assert_eq!(data, b"hello world");
+        let data = crate::get_or_insert_with(&dir, "my-key", compute).unwrap();
+        assert_eq!(data, b"hello world");
+        assert_eq!(calls.get(), 1);
+
+        // Lost data is computed again.
+        let sri = crate::metadata(&dir, "my-key").unwrap().unwrap().integrity;
+        crate::remove_hash(&dir, &sri).unwrap();
+        let data = crate::get_or_insert_with(&dir, "my-key", compute).unwrap();
+        assert_eq!(data, b"hello world");
+        assert_eq!(calls.get(), 2);
+        assert_eq!(crate::read(&dir, "my-key").unwrap(), b"hello world");
+
+        // So are expired entries, and entries with no data to begin with.
+        crate::WriteOpts::new()
+            .ttl(std::time::Duration::ZERO)
+            .open(&dir, "my-key")
+            .unwrap()
+            .commit()
+            .unwrap();
+        let data = crate::get_or_insert_with(&dir, "my-key", compute).unwrap();
+        assert_eq!(data, b"hello world");
+        assert_eq!(calls.get(), 3);
+        crate::write_metadata_only(&dir, "my-key", serde_json::Value::Null).unwrap();
+        let data = crate::get_or_insert_with(&dir, "my-key", compute).unwrap();
+        assert_eq!(data, b"hello world");
+        assert_eq!(calls.get(), 4);
+        assert!(
+            !crate::metadata(&dir, "my-key")
+                .unwrap()
+                .unwrap()
+                .metadata_only
+        );
+
+        let res = crate::get_or_insert_with(&dir, "other", || {
+            Err(crate::Error::EntryNotFound(dir.clone(), "other".into()))
+        });
+        assert!(res.is_err());
+        assert!(crate::metadata(&dir, "other").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_get_or_insert_streaming() {
+        use std::io::{Read, Write};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let calls = std::cell::Cell::new(0);
+        let load = |writer: &mut crate::Writer| {
+            calls.set(calls.get() + 1);
+            for _ in 0..4 {
+                writer.write_all(b"hello ").unwrap();
+            }
+            Ok::<_, crate::Error>(())
+        };
+        for _ in 0..2 {
+            let mut fd = crate::get_or_insert_streaming(&dir, "my-key", load).unwrap();
+            let mut data = Vec::new();
+            fd.read_to_end(&mut data).unwrap();
+            fd.check().unwrap();
+            assert_eq!(data, b"hello hello hello hello ");
+        }
+        assert_eq!(calls.get(), 1);
+
+        crate::write_metadata_only(&dir, "my-key", serde_json::Value::Null).unwrap();
+        let mut fd = crate::get_or_insert_streaming(&dir, "my-key", load).unwrap();
+        let mut data = Vec::new();
+        fd.read_to_end(&mut data).unwrap();
+        fd.check().unwrap();
+        assert_eq!(data, b"hello hello hello hello ");
+        assert_eq!(calls.get(), 2);
+
+        let res = crate::get_or_insert_streaming(&dir, "other", |writer| {
+            writer.write_all(b"partial").unwrap();
+            Err(crate::Error::EntryNotFound(dir.clone(), "other".into()))
+        });
+        assert!(res.is_err());
+        assert!(crate::metadata(&dir, "other").unwrap().is_none());
+        assert!(!crate::exists(&dir, &ssri::Integrity::from(b"partial")));
+    }
+
+    #[test]
+    fn test_read_with_limit() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "my-key", b"hello world").unwrap();
+
+        assert_eq!(
+            crate::read_with_limit(&dir, "my-key", 11).unwrap(),
+            b"hello world"
+        );
+        assert!(matches!(
+            crate::read_with_limit(&dir, "my-key", 10),
+            Err(crate::Error::LimitExceeded(10, 11))
+        ));
+        assert_eq!(
+            crate::read_hash_with_limit(&dir, &sri, 100).unwrap(),
+            b"hello world"
+        );
+        assert!(matches!(
+            crate::read_hash_with_limit(&dir, &sri, 0),
+            Err(crate::Error::LimitExceeded(0, 11))
+        ));
+        assert!(matches!(
+            crate::read_with_limit(&dir, "missing", 100),
+            Err(crate::Error::EntryNotFound(..))
+        ));
+    }
+
+    #[test]
+    fn test_read_many() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let one = crate::write(&dir, "one", b"hello").unwrap();
+        let two = crate::write(&dir, "two", b"world").unwrap();
+        crate::write(&dir, "empty", b"").unwrap();
+        crate::write_metadata_only(&dir, "no-data", "meta").unwrap();
+
+        let results = crate::read_many(&dir, ["one", "missing", "two", "empty", "no-data"]);
+        assert_eq!(results.len(), 5);
+        let mut results = results.into_iter();
+        assert_eq!(results.next().unwrap().unwrap(), b"hello");
+        assert!(matches!(
+            results.next().unwrap(),
+            Err(crate::Error::EntryNotFound(..))
+        ));
+        assert_eq!(results.next().unwrap().unwrap(), b"world");
+        assert!(results.next().unwrap().unwrap().is_empty());
+        assert!(matches!(
+            results.next().unwrap(),
+            Err(crate::Error::NoContent(..))
+        ));
+
+        std::fs::write(crate::content_path(&dir, &one).unwrap(), b"jello").unwrap();
+        let results = crate::read_hash_many(&dir, [&two, &one]);
+        assert_eq!(results[0].as_ref().unwrap(), b"world");
+        assert!(matches!(
+            results[1],
+            Err(crate::Error::IntegrityError { .. })
+        ));
+        assert!(crate::read_many(&dir, Vec::<String>::new()).is_empty());
+    }
+
+    #[test]
+    fn test_read_many_budgeted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        for (key, size) in [("a", 10), ("b", 20), ("c", 30), ("huge", 100), ("d", 5)] {
+            crate::write(&dir, key, vec![size as u8; size]).unwrap();
+        }
+
+        let mut seen = Vec::new();
+        crate::read_many_budgeted(
+            &dir,
+            ["a", "b", "missing", "c", "huge", "d"],
+            35,
+            |key, data| seen.push((key, data)),
+        );
+        let keys = seen.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+        assert_eq!(keys, ["a", "b", "missing", "c", "huge", "d"]);
+        for (key, data) in seen {
+            match key {
+                "missing" => assert!(matches!(data, Err(crate::Error::EntryNotFound(..)))),
+                "huge" => assert!(matches!(data, Err(crate::Error::LimitExceeded(35, 100)))),
+                _ => assert_eq!(data.unwrap(), crate::read(&dir, key).unwrap()),
+            }
+        }
+    }
+
     #[test]
     fn test_read_hash() {
         let tmp = tempfile::tempdir().unwrap();
@@ -271,6 +1856,40 @@ mod tests {
         assert_eq!(data, b"hello world");
     }
 
+    #[test]
+    fn test_read_to_string() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "my-key", b"hello world").unwrap();
+        crate::write(&dir, "bad-key", [0xff, 0xfe]).unwrap();
+
+        let data = crate::read_to_string(&dir, "my-key").unwrap();
+        assert_eq!(data, "hello world");
+        assert!(crate::read_to_string(&dir, "bad-key").is_err());
+    }
+
+    #[test]
+    fn test_read_hash_to_string() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "my-key", b"hello world").unwrap();
+
+        let data = crate::read_hash_to_string(&dir, &sri).unwrap();
+        assert_eq!(data, "hello world");
+    }
+
+    #[test]
+    fn test_read_json() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "my-key", b"[1, 2, 3]").unwrap();
+        crate::write(&dir, "bad-key", b"not json").unwrap();
+
+        let data: Vec<u32> = crate::read_json(&dir, "my-key").unwrap();
+        assert_eq!(data, vec![1, 2, 3]);
+        assert!(crate::read_json::<_, _, Vec<u32>>(&dir, "bad-key").is_err());
+    }
+
     #[test]
     fn test_copy() {
         let tmp = tempfile::tempdir().unwrap();
@@ -294,4 +1913,37 @@ mod tests {
         let data = fs::read(&dest).unwrap();
         assert_eq!(data, b"hello world");
     }
+
+    #[test]
+    fn test_copy_hash_to_writer() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write(dir, "my-key", b"hello world").unwrap();
+
+        let mut out = Vec::new();
+        let copied = crate::copy_hash_to_writer(dir, &sri, &mut out).unwrap();
+        assert_eq!(copied, 11);
+        assert_eq!(out, b"hello world");
+
+        let cpath = crate::content::path::content_path(dir, &sri).unwrap();
+        fs::write(cpath, b"goodbye world").unwrap();
+        assert!(matches!(
+            crate::copy_hash_to_writer(dir, &sri, &mut Vec::new()),
+            Err(crate::Error::IntegrityError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_copy_sparse() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let dest = dir.join("data");
+        let mut original = vec![0; 64 * 1024];
+        original.extend_from_slice(b"hello world");
+        crate::write(dir, "my-key", &original).unwrap();
+
+        let copied = crate::copy_sparse(dir, "my-key", &dest).unwrap();
+        assert_eq!(copied, original.len() as u64);
+        assert_eq!(fs::read(&dest).unwrap(), original);
+    }
 }
@@ -0,0 +1,142 @@
+//! A storage backend for the `http-cache` family of HTTP caching middlewares.
+use std::path::{Path, PathBuf};
+
+use http_cache::{CacheManager, HttpResponse, Result};
+use http_cache_semantics::CachePolicy;
+use serde::{Deserialize, Serialize};
+
+use crate::put::WriteOpts;
+
+/// Implements `http-cache`'s [`CacheManager`] on top of this crate, so HTTP
+/// clients using `http-cache` middleware (such as `http-cache-reqwest`) can
+/// cache responses in a cacache directory.
+///
+/// Response bodies are stored as content, so identical bodies served under
+/// different URLs are only stored once. Everything else about the response,
+/// along with its cache policy, is stored as the entry's metadata.
+///
+/// All operations are synchronous, so they block the executor thread they're
+/// called on for the duration of the file I/O.
+#[derive(Clone, Debug)]
+pub struct HttpCacheManager {
+    /// Directory where the cache is stored.
+    pub path: PathBuf,
+}
+
+impl HttpCacheManager {
+    /// Creates a manager storing responses in the cache at `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> HttpCacheManager {
+        HttpCacheManager {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Store {
+    // With an empty body; the body is the entry's content.
+    response: HttpResponse,
+    policy: CachePolicy,
+}
+
+#[async_trait::async_trait]
+impl CacheManager for HttpCacheManager {
+    async fn get(&self, cache_key: &str) -> Result<Option<(HttpResponse, CachePolicy)>> {
+        let entry = match crate::metadata(&self.path, cache_key)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let body = match crate::read_hash(&self.path, &entry.integrity) {
+            Ok(body) => body,
+            // The content is gone, so there's no response to return.
+            Err(_) if !crate::exists(&self.path, &entry.integrity) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut store: Store = serde_json::from_value(entry.metadata)?;
+        store.response.body = body;
+        Ok(Some((store.response, store.policy)))
+    }
+
+    async fn put(
+        &self,
+        cache_key: String,
+        response: HttpResponse,
+        policy: CachePolicy,
+    ) -> Result<HttpResponse> {
+        let store = Store {
+            response: HttpResponse {
+                body: Vec::new(),
+                ..response.clone()
+            },
+            policy,
+        };
+        let mut writer = WriteOpts::new()
+            .metadata(&store)
+            .open(&self.path, cache_key)?;
+        std::io::Write::write_all(&mut writer, &response.body)?;
+        writer.commit()?;
+        Ok(response)
+    }
+
+    async fn delete(&self, cache_key: &str) -> Result<()> {
+        Ok(crate::remove(&self.path, cache_key)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let manager = HttpCacheManager::new(tmp.path());
+        let response: HttpResponse = serde_json::from_value(serde_json::json!({
+            "body": b"hello".to_vec(),
+            "headers": { "cache-control": "max-age=60" },
+            "status": 200,
+            "url": "https://example.com/hello",
+            "version": "HTTP/1.1",
+        }))
+        .unwrap();
+        let request = http::Request::get("https://example.com/hello")
+            .body(())
+            .unwrap();
+        let parts = http::Response::builder()
+            .status(200)
+            .header("cache-control", "max-age=60")
+            .body(())
+            .unwrap();
+        let policy = CachePolicy::new(&request, &parts);
+
+        block_on(manager.put("GET:https://example.com/hello".into(), response, policy)).unwrap();
+        let (cached, policy) = block_on(manager.get("GET:https://example.com/hello"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached.body, b"hello");
+        assert_eq!(cached.status, 200);
+        assert_eq!(cached.headers["cache-control"], "max-age=60");
+        assert!(policy.is_storable());
+
+        // Corrupt content is an error, not a miss.
+        let sri = crate::metadata(tmp.path(), "GET:https://example.com/hello")
+            .unwrap()
+            .unwrap()
+            .integrity;
+        let cpath = crate::content::path::content_path(tmp.path(), &sri).unwrap();
+        std::fs::write(&cpath, b"corrupted").unwrap();
+        assert!(block_on(manager.get("GET:https://example.com/hello")).is_err());
+
+        // Missing content is a miss.
+        std::fs::remove_file(&cpath).unwrap();
+        assert!(block_on(manager.get("GET:https://example.com/hello"))
+            .unwrap()
+            .is_none());
+
+        block_on(manager.delete("GET:https://example.com/hello")).unwrap();
+        assert!(block_on(manager.get("GET:https://example.com/hello"))
+            .unwrap()
+            .is_none());
+    }
+}
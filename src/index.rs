@@ -1,9 +1,10 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, OpenOptions};
 use std::hash::{Hash, Hasher};
 use std::io::{ErrorKind, Write};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use digest::Digest;
 use either::{Left, Right};
@@ -14,13 +15,30 @@ use sha2::Sha256;
 use ssri::Integrity;
 use walkdir::WalkDir;
 
-use crate::errors::{Internal, InternalResult, Result};
+use crate::config::{self, IndexFormat, KeyConflict};
+use crate::content::path;
+use crate::errors::{Error, Internal, InternalResult, Result};
+use crate::lock::Lock;
+use crate::mmap::Mmap;
 use crate::put::WriteOpts;
+use crate::retry;
+use crate::secondary;
+use crate::stats::{self, StatsDelta};
+
+mod binary;
+mod fixed;
+mod journal;
+mod keydir;
 
 const INDEX_VERSION: &str = "5";
+/// Where the locks taken on buckets are kept, outside the index itself so
+/// they're never mistaken for buckets.
+const BUCKET_LOCKS: &str = "index-locks-v5";
 
-/// Represents a cache index entry, which points to content.
-#[derive(PartialEq, Debug)]
+/// Represents a cache index entry, which points to content. More fields may
+/// be added, so it can't be built outside this crate.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+#[non_exhaustive]
 pub struct Metadata {
     /// Key this entry is stored under.
     pub key: String,
@@ -32,15 +50,124 @@ pub struct Metadata {
     pub size: usize,
     /// Arbitrary JSON  associated with this entry.
     pub metadata: Value,
+    /// Hex-encoded ed25519 signature over this entry's key, integrity, time
+    /// and metadata, if it was written with a signing key.
+    #[cfg(feature = "signing")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Location of the data, for entries that point to a file kept outside
+    /// the cache instead of to cached content. See [`crate::write_pointer`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external: Option<PathBuf>,
+    /// MIME type of the data, if one was given when it was written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Tags attached to this entry when it was written, for group operations
+    /// like [`crate::list_by_tag`] and [`crate::remove_by_tag`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Timestamp in unix milliseconds after which this entry is stale, if it
+    /// was written with [`crate::WriteOpts::ttl`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u128>,
+    /// Whether this entry only records metadata, and has no data at all.
+    /// Its `integrity` is then a null one, with an empty digest, which no
+    /// content can match: reading it fails with [`crate::Error::NoContent`],
+    /// and looking it up as content finds nothing. See
+    /// [`crate::write_metadata_only`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub metadata_only: bool,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+impl Metadata {
+    /// The time this entry was written, as a [`SystemTime`].
+    pub fn inserted_at(&self) -> SystemTime {
+        // Times too far out to represent can only come from a bogus `time`.
+        u64::try_from(self.time)
+            .ok()
+            .and_then(|millis| UNIX_EPOCH.checked_add(Duration::from_millis(millis)))
+            .unwrap_or(UNIX_EPOCH)
+    }
+
+    /// How long ago this entry was written. Entries with a time in the future
+    /// have an age of zero.
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.inserted_at())
+            .unwrap_or_default()
+    }
+
+    /// Returns true if this entry was written with a time to live that has
+    /// since run out.
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
 struct SerializableMetadata {
     key: String,
     integrity: Option<String>,
     time: u128,
     size: usize,
     metadata: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    external: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u128>,
+    // Stored with no integrity, which older versions read as a removal
+    // rather than as an entry for data that isn't there.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    metadata_only: bool,
+}
+
+impl SerializableMetadata {
+    /// The live entry this stores, `None` if it's a removal, or an error if
+    /// its integrity doesn't parse.
+    fn into_metadata(self) -> std::result::Result<Option<Metadata>, ssri::Error> {
+        let integrity = match (self.integrity, self.metadata_only) {
+            (_, true) => no_content(),
+            (Some(integrity), false) => integrity.parse()?,
+            (None, false) => return Ok(None),
+        };
+        Ok(Some(Metadata {
+            key: self.key,
+            integrity,
+            time: self.time,
+            size: self.size,
+            metadata: self.metadata,
+            #[cfg(feature = "signing")]
+            signature: self.signature,
+            external: self.external,
+            content_type: self.content_type,
+            tags: self.tags,
+            expires: self.expires,
+            metadata_only: self.metadata_only,
+        }))
+    }
+}
+
+/// The integrity reported for metadata-only entries. Its digest is empty, so
+/// unlike that of empty data, it can't name any content.
+pub(crate) fn no_content() -> Integrity {
+    Integrity {
+        hashes: vec![ssri::Hash {
+            algorithm: ssri::Algorithm::Sha256,
+            digest: String::new(),
+        }],
+    }
+}
+
+/// Whether `sri` names no content at all, like the one given to
+/// metadata-only entries.
+pub(crate) fn is_no_content(sri: &Integrity) -> bool {
+    sri.hashes.iter().all(|hash| hash.digest.is_empty())
 }
 
 impl PartialEq for SerializableMetadata {
@@ -58,115 +185,832 @@ impl Hash for SerializableMetadata {
 }
 
 pub fn insert(cache: &Path, key: &str, opts: WriteOpts) -> Result<Integrity> {
-    let bucket = bucket_path(cache, key);
-    fs::create_dir_all(bucket.parent().unwrap()).with_context(|| {
+    Ok(insert_batch(cache, vec![(key.to_owned(), opts)])?.remove(0))
+}
+
+/// Inserts entries for many keys, just like calling `insert` for each in
+/// turn, but appends everything bound for the same bucket in a single write.
+/// Entries are checked against the key conflict policy before any of them
+/// are written, so on a conflict none are. Unless the policy is
+/// [`KeyConflict::LastWins`], every bucket the batch touches is locked from
+/// before the checks until after the appends, so writers of the same key at
+/// once can't both find it missing. Buckets are written in the order the
+/// entries first touch them, and an I/O error part way through leaves the
+/// ones written so far as they are. If the cache keeps an
+/// [`index_journal`](crate::CacheConfig::index_journal), the whole batch is
+/// journaled first, and the rest of it is written once it's recovered.
+pub fn insert_batch(cache: &Path, entries: Vec<(String, WriteOpts)>) -> Result<Vec<Integrity>> {
+    let config = config::load(cache)?;
+    let format = config.index_format;
+    let check_current = config.track_stats || config.key_conflict != KeyConflict::LastWins;
+    // What's current for keys written earlier in the batch, which lookups
+    // can't see yet.
+    let mut batched = HashMap::new();
+    // Written in the order the batch first touches them, so a batch that
+    // adds one entry before removing another never loses both to a crash.
+    let mut buckets = Vec::<(PathBuf, (Vec<u8>, bool))>::new();
+    let mut bucket_order = HashMap::new();
+    let mut entries_delta = 0;
+    // Keys and metadata for the secondary indexes, if the cache keeps any.
+    let mut indexed = Vec::new();
+    // Keys and whether they're live, for the key directory, if there is one.
+    let mut changes = Vec::new();
+    let mut results = Vec::with_capacity(entries.len());
+    let _locks = if config.key_conflict == KeyConflict::LastWins {
+        Vec::new()
+    } else {
+        // Taken in a fixed order, so batches sharing buckets can't deadlock.
+        let bases = entries
+            .iter()
+            .map(|(key, _)| bucket_path(cache, key))
+            .collect::<std::collections::BTreeSet<_>>();
+        bases
+            .iter()
+            .map(|base| lock_bucket(cache, base))
+            .collect::<Result<Vec<_>>>()?
+    };
+    for (key, opts) in entries {
+        let integrity = opts.sri.as_ref().map(|x| x.to_string());
+        let time = opts.time.unwrap_or_else(now);
+        let out = match format {
+            IndexFormat::Json => json_entry(new_entry(&key, integrity, time, &opts))?,
+            IndexFormat::Binary => binary::encode(new_entry(&key, integrity, time, &opts))
+                .with_context(|| format!("Failed to serialize entry with key `{}`", key))?,
+            IndexFormat::Fixed => fixed_entry(cache, &key, integrity, time, &opts)?.to_vec(),
+        };
+        let written = if opts.metadata_only {
+            Some(no_content())
+        } else {
+            opts.sri.clone()
+        };
+        let current = if check_current {
+            Some(match batched.get(&key) {
+                Some(current) => Option::clone(current),
+                None => find(cache, &key)?.map(|entry| entry.integrity),
+            })
+        } else {
+            None
+        };
+        if let (Some(Some(current)), Some(sri)) = (&current, &written) {
+            match config.key_conflict {
+                KeyConflict::LastWins => {}
+                KeyConflict::FirstWins => {
+                    results.push(current.clone());
+                    continue;
+                }
+                KeyConflict::ErrorOnDifferent => {
+                    if current.matches(sri).is_none() {
+                        return Err(Error::KeyConflict(cache.to_path_buf(), key));
+                    }
+                }
+            }
+        }
+        // Worked out from lookups made without holding anything, so writers
+        // of the same key at once can both count it. Stats are documented as
+        // approximate in that case.
+        match (current.map(|current| current.is_some()), &written) {
+            (Some(false), Some(_)) => entries_delta += 1,
+            (Some(true), None) => entries_delta -= 1,
+            _ => {}
+        }
+        let base = bucket_path(cache, &key);
+        let idx = *bucket_order.entry(base.clone()).or_insert_with(|| {
+            buckets.push((base, Default::default()));
+            buckets.len() - 1
+        });
+        let bucket = &mut buckets[idx].1;
+        bucket.0.extend_from_slice(&out);
+        bucket.1 |= opts.fsync;
+        if let Some(metadata) = opts.metadata.filter(|_| !config.indexed_fields.is_empty()) {
+            indexed.push((key.clone(), metadata));
+        }
+        if config.prefix_index {
+            changes.push((key.clone(), written.is_some()));
+        }
+        if check_current {
+            batched.insert(key, written.clone());
+        }
+        results.push(
+            written
+                .or_else(|| "sha1-deadbeef".parse::<Integrity>().ok())
+                .unwrap(),
+        );
+    }
+    let journal = if config.index_journal && !buckets.is_empty() {
+        // Batches older writers never finished go in before this one.
+        journal::recover(cache, format, true)?;
+        let appends = buckets
+            .iter()
+            .map(|(base, (out, _))| (base.as_path(), out.as_slice()))
+            .collect::<Vec<_>>();
+        Some(journal::begin(cache, &appends)?)
+    } else {
+        None
+    };
+    for (base, (out, sync)) in &buckets {
+        // The journal can only go once everything it covers is on disk.
+        append(cache, base, format, out, *sync || journal.is_some())?;
+    }
+    if let Some(journal) = journal {
+        journal::finish(journal)?;
+    }
+    for (key, metadata) in indexed {
+        secondary::record(cache, &config.indexed_fields, &key, &metadata)?;
+    }
+    if !changes.is_empty() {
+        keydir::record(cache, &changes)?;
+    }
+    if entries_delta != 0 {
+        stats::record(
+            cache,
+            StatsDelta {
+                entries: entries_delta,
+                ..Default::default()
+            },
+        )?;
+    }
+    Ok(results)
+}
+
+/// Appends `out` to the newest generation of the bucket at `base`, starting
+/// a new generation if that one is full, and syncs it to disk if `sync`.
+fn append(cache: &Path, base: &Path, format: IndexFormat, out: &[u8], sync: bool) -> Result<()> {
+    fs::create_dir_all(base.parent().unwrap()).with_context(|| {
         format!(
             "Failed to create index bucket directory: {:?}",
-            bucket.parent().unwrap()
+            base.parent().unwrap()
         )
     })?;
-    let stringified = serde_json::to_string(&SerializableMetadata {
-        key: key.to_owned(),
-        integrity: opts.sri.clone().map(|x| x.to_string()),
-        time: opts.time.unwrap_or_else(now),
-        size: opts.size.unwrap_or(0),
-        metadata: opts.metadata.unwrap_or(serde_json::Value::Null),
+    let bucket = match bucket_generations(base)?.pop() {
+        Some((generation, bucket)) => {
+            let len = fs::metadata(&bucket)
+                .with_context(|| format!("Failed to read index bucket at {:?}", bucket))?
+                .len();
+            if len >= GENERATION_SIZE {
+                generation_path(base, generation + 1)
+            } else {
+                bucket
+            }
+        }
+        None => base.to_path_buf(),
+    };
+    let mut buck = retry::with_retries(cache, || {
+        OpenOptions::new().create(true).append(true).open(&bucket)
     })
-    .with_context(|| format!("Failed to serialize entry with key `{}`", key))?;
-
-    let mut buck = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&bucket)
-        .with_context(|| format!("Failed to create or open index bucket at {:?}", bucket))?;
+    .with_context(|| format!("Failed to create or open index bucket at {:?}", bucket))?;
 
-    let out = format!("\n{}\t{}", hash_entry(&stringified), stringified);
-    buck.write_all(out.as_bytes())
-        .with_context(|| format!("Failed to write to index bucket at {:?}", bucket))?;
+    // Partial entries fail their checks when read, and readers find the
+    // start of the next entry after one, so a failed append is safe to retry.
+    retry::with_retries(cache, || match format {
+        IndexFormat::Json | IndexFormat::Binary => buck.write_all(out),
+        IndexFormat::Fixed => {
+            let mut records = vec![0; fixed::padding(buck.metadata()?.len())];
+            records.extend_from_slice(out);
+            buck.write_all(&records)
+        }
+    })
+    .with_context(|| format!("Failed to write to index bucket at {:?}", bucket))?;
     buck.flush()
         .with_context(|| format!("Failed to flush bucket at {:?}", bucket))?;
-    Ok(opts
-        .sri
-        .or_else(|| "sha1-deadbeef".parse::<Integrity>().ok())
-        .unwrap())
+    if sync {
+        buck.sync_data()
+            .with_context(|| format!("Failed to sync bucket at {:?}", bucket))?;
+    }
+    Ok(())
+}
+
+/// Builds the entry to store for a write.
+fn new_entry(
+    key: &str,
+    integrity: Option<String>,
+    time: u128,
+    opts: &WriteOpts,
+) -> SerializableMetadata {
+    let metadata = opts.metadata.clone().unwrap_or(serde_json::Value::Null);
+    #[cfg(feature = "signing")]
+    let signature = match (&opts.signing_key, &integrity) {
+        (Some(signing_key), Some(integrity)) => Some(crate::signing::sign(
+            signing_key,
+            key,
+            integrity,
+            time,
+            &metadata,
+        )),
+        _ => None,
+    };
+    #[cfg(not(feature = "signing"))]
+    let signature = None;
+    SerializableMetadata {
+        key: key.to_owned(),
+        integrity,
+        time,
+        size: opts.size.unwrap_or(0),
+        metadata,
+        signature,
+        external: opts.external.clone(),
+        content_type: opts.content_type.clone(),
+        tags: opts.tags.clone(),
+        expires: opts.ttl.map(|ttl| time.saturating_add(ttl.as_millis())),
+        metadata_only: opts.metadata_only,
+    }
+}
+
+/// Serializes an entry as a bucket line.
+fn json_entry(entry: SerializableMetadata) -> Result<Vec<u8>> {
+    let stringified = serde_json::to_string(&entry)
+        .with_context(|| format!("Failed to serialize entry with key `{}`", entry.key))?;
+    Ok(format!("\n{}\t{}", hash_entry(&stringified), stringified).into_bytes())
+}
+
+/// Encodes an entry as a fixed-size record, refusing anything a record has
+/// no room for.
+fn fixed_entry(
+    cache: &Path,
+    key: &str,
+    integrity: Option<String>,
+    time: u128,
+    opts: &WriteOpts,
+) -> Result<[u8; fixed::RECORD_SIZE]> {
+    #[cfg(feature = "signing")]
+    let signed = opts.signing_key.is_some();
+    #[cfg(not(feature = "signing"))]
+    let signed = false;
+    if opts.metadata.is_some()
+        || signed
+        || opts.external.is_some()
+        || opts.content_type.is_some()
+        || !opts.tags.is_empty()
+        || opts.ttl.is_some()
+        || opts.metadata_only
+    {
+        return Err(Error::InvalidConfig(
+            cache.to_path_buf(),
+            format!(
+                "entry with key `{}` has fields that a fixed-format index can't store",
+                key
+            ),
+        ));
+    }
+    fixed::encode(key, integrity.as_deref(), time, opts.size.unwrap_or(0)).ok_or_else(|| {
+        Error::InvalidConfig(
+            cache.to_path_buf(),
+            format!(
+                "integrity of entry with key `{}` is too long for a fixed-format index",
+                key
+            ),
+        )
+    })
 }
 
 pub fn find(cache: &Path, key: &str) -> Result<Option<Metadata>> {
-    let bucket = bucket_path(cache, key);
-    Ok(bucket_entries(&bucket)
-        .with_context(|| format!("Failed to read index bucket entries from {:?}", bucket))?
-        .into_iter()
-        .fold(None, |acc, entry| {
-            if entry.key == key {
-                if let Some(integrity) = entry.integrity {
-                    let integrity: Integrity = match integrity.parse() {
-                        Ok(sri) => sri,
-                        _ => return acc,
-                    };
-                    Some(Metadata {
-                        key: entry.key,
-                        integrity,
-                        size: entry.size,
-                        time: entry.time,
-                        metadata: entry.metadata,
-                    })
-                } else {
-                    None
-                }
-            } else {
-                acc
-            }
-        }))
+    Ok(find_latest(cache, key)?.flatten())
+}
+
+/// Like `find`, but tells a key whose latest entry removed it (`Some(None)`)
+/// apart from one that was never written at all (`None`).
+pub fn find_latest(cache: &Path, key: &str) -> Result<Option<Option<Metadata>>> {
+    let format = config::load(cache)?.index_format;
+    let stored_key = match format {
+        IndexFormat::Json | IndexFormat::Binary => key.to_owned(),
+        IndexFormat::Fixed => hex::encode(fixed::key_id(key)),
+    };
+    // Entries are append-only, so the most recent one for a key is the last
+    // valid line in the newest generation of its bucket that has one. Walk
+    // backwards and stop at the first match.
+    let generations = bucket_generations(&bucket_path(cache, key))?;
+    for (_, bucket) in generations.iter().rev() {
+        if let Some(entry) = find_in_bucket(bucket, format, &stored_key)? {
+            return Ok(Some(entry.map(|entry| Metadata {
+                key: key.to_owned(),
+                ..entry
+            })));
+        }
+    }
+    Ok(None)
+}
+
+/// Finds the newest entry stored under `stored_key` in a single bucket file.
+fn find_in_bucket(
+    bucket: &Path,
+    format: IndexFormat,
+    stored_key: &str,
+) -> Result<Option<Option<Metadata>>> {
+    let entries = bucket_entries_rev(bucket, format)
+        .with_context(|| format!("Failed to read index bucket entries from {:?}", bucket))?;
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("Failed to read index bucket entries from {:?}", bucket))?;
+        if entry.key != stored_key {
+            continue;
+        }
+        match entry.into_metadata() {
+            Ok(entry) => return Ok(Some(entry)),
+            Err(_) => continue,
+        }
+    }
+    Ok(None)
 }
 
 pub fn delete(cache: &Path, key: &str) -> Result<()> {
-    insert(
-        cache,
-        key,
-        WriteOpts {
-            algorithm: None,
-            size: None,
-            sri: None,
-            time: None,
-            metadata: None,
-        },
-    )
-    .map(|_| ())
+    insert(cache, key, removal()).map(|_| ())
+}
+
+/// Options that write an entry removing whatever's under its key.
+pub(crate) fn removal() -> WriteOpts {
+    WriteOpts {
+        algorithm: None,
+        size: None,
+        sri: None,
+        time: None,
+        metadata: None,
+        metadata_error: None,
+        sparse: false,
+        verify_existing: false,
+        fsync: false,
+        direct_io: false,
+        metadata_only: false,
+        #[cfg(feature = "signing")]
+        signing_key: None,
+        external: None,
+        content_type: None,
+        tags: Vec::new(),
+        ttl: None,
+    }
+}
+
+/// Finishes every batch of index writes that was interrupted part way
+/// through, in a cache configured with [`crate::CacheConfig::index_journal`],
+/// so each one ends up either entirely in the index or not at all. Returns
+/// how many were finished.
+///
+/// Writes already finish batches whose writers have exited, before making
+/// their own, and so do [`crate::Cache`] handles when they're first used.
+/// Call this on startup, before this process writes to the cache,
+/// to do it straight away: any batch this process still has in progress is
+/// taken to have been interrupted. Batches other processes still have in
+/// progress are left alone, wherever it can be told that they're running.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let finished = cacache_sync::recover_index("./my-cache")?;
+///     println!("finished {} interrupted writes", finished);
+///     Ok(())
+/// }
+/// ```
+pub fn recover_index<P: AsRef<Path>>(cache: P) -> Result<usize> {
+    let cache = cache.as_ref();
+    journal::recover(cache, config::load(cache)?.index_format, false)
 }
 
+/// Finishes the batches of index writes left behind by processes that have
+/// exited, like writes do before making their own. For [`crate::Cache`]
+/// handles to run when they're first used.
+pub(crate) fn recover_stale(cache: &Path) -> Result<usize> {
+    journal::recover(cache, config::load(cache)?.index_format, true)
+}
+
+/// Drops the batches of index writes left pending, for when the index is
+/// about to be replaced wholesale.
+pub(crate) fn discard_journals(cache: &Path) -> Result<usize> {
+    journal::discard(cache)
+}
+
+/// Lists the latest entry for every key. Entries in a fixed-format index have
+/// the hex-encoded hash of their key in place of the key.
 pub fn ls(cache: &Path) -> impl Iterator<Item = Result<Metadata>> {
-    WalkDir::new(cache.join(format!("index-v{}", INDEX_VERSION)))
+    ls_where(cache, |_| true)
+}
+
+/// Like [`ls`], but only lists entries whose metadata passes `keep`. The
+/// check runs on entries as they're parsed out of each bucket, so the rest
+/// are never turned into [`Metadata`] at all.
+pub fn ls_where<F>(cache: &Path, keep: F) -> impl Iterator<Item = Result<Metadata>>
+where
+    F: Fn(&Value) -> bool,
+{
+    let (format, config_err) = match config::load(cache) {
+        Ok(config) => (config.index_format, None),
+        Err(err) => (IndexFormat::default(), Some(err)),
+    };
+    let entries = WalkDir::new(cache.join(format!("index-v{}", INDEX_VERSION)))
         .into_iter()
-        .map(|bucket| {
+        .map(move |bucket| {
             let bucket = bucket.to_internal()?;
 
-            if bucket.file_type().is_dir() {
+            // Later generations are read along with the bucket they belong to.
+            if bucket.file_type().is_dir() || generation_of(bucket.path()).is_some() {
                 return Ok(Vec::new());
             }
 
-            Ok(bucket_entries(bucket.path())?
+            let mut entries = Vec::new();
+            for (_, generation) in bucket_generations(bucket.path())? {
+                entries.extend(bucket_entries(&generation, format)?);
+            }
+            // Walk in reverse so the set keeps the most recent entry per key.
+            Ok(entries
                 .into_iter()
+                .rev()
                 .collect::<HashSet<SerializableMetadata>>()
                 .into_iter()
-                .filter_map(|se| {
-                    if let Some(i) = se.integrity {
-                        Some(Metadata {
-                            key: se.key,
-                            integrity: i.parse().unwrap(),
-                            time: se.time,
-                            size: se.size,
-                            metadata: se.metadata,
-                        })
-                    } else {
-                        None
-                    }
-                })
+                .filter(|se| keep(&se.metadata))
+                .filter_map(|se| se.into_metadata().ok().flatten())
                 .collect())
         })
         .flat_map(|res| match res {
             Ok(it) => Left(it.into_iter().map(Ok)),
             Err(err) => Right(std::iter::once(Err(err))),
-        })
+        });
+    config_err.map(Err).into_iter().chain(entries)
+}
+
+/// Lists every key that starts with `prefix`, in order. Uses the key
+/// directory if the cache keeps one, and scans the whole index otherwise.
+pub fn keys_with_prefix(cache: &Path, prefix: &str) -> Result<Vec<String>> {
+    if config::load(cache)?.prefix_index {
+        if let Some(keys) = keydir::keys_with_prefix(cache, prefix)? {
+            return Ok(keys);
+        }
+    }
+    let mut keys = Vec::new();
+    if index_dir(cache).exists() {
+        for entry in ls(cache) {
+            let entry = entry?;
+            if entry.key.starts_with(prefix) {
+                keys.push(entry.key);
+            }
+        }
+    }
+    keys.sort();
+    Ok(keys)
+}
+
+/// Builds the key directory from scratch if the cache keeps one, or removes
+/// any that's left over if it doesn't.
+pub(crate) fn rebuild_key_dir(cache: &Path) -> Result<()> {
+    if !config::load(cache)?.prefix_index {
+        return keydir::remove(cache);
+    }
+    // Listing a cache with no index at all fails, rather than finding nothing.
+    let keys = if index_dir(cache).exists() {
+        Left(ls(cache).map(|entry| entry.map(|entry| entry.key)))
+    } else {
+        Right(std::iter::empty())
+    };
+    keydir::rebuild(cache, keys)
+}
+
+/// Rebuilds everything kept alongside the index from it: the key directory
+/// and the secondary indexes. For after the index has been replaced or
+/// rewritten wholesale.
+pub(crate) fn rebuild_sidecars(cache: &Path) -> Result<()> {
+    rebuild_key_dir(cache)?;
+    secondary::rebuild(cache)
+}
+
+/// Rewrites every bucket in the index to hold only the latest entry for each
+/// key that still has one, merging generations back into a single file and
+/// dropping removals and damaged entries. Returns how many bytes the index
+/// took up before and after. Entries written to a bucket while it's being
+/// rewritten can be lost, so nothing else should be writing to the cache.
+pub fn compact(cache: &Path) -> Result<(u64, u64)> {
+    let format = config::load(cache)?.index_format;
+    // Interrupted batches have to be finished against the buckets they
+    // started from.
+    journal::recover(cache, format, true)?;
+    let index = index_dir(cache);
+    let (mut before, mut after) = (0, 0);
+    for bucket in WalkDir::new(&index) {
+        let bucket = match bucket {
+            Ok(bucket) => bucket,
+            // No index yet.
+            Err(err) if err.depth() == 0 => break,
+            Err(err) => return Err(err).to_internal()?,
+        };
+        if bucket.file_type().is_dir() || generation_of(bucket.path()).is_some() {
+            continue;
+        }
+        let generations = bucket_generations(bucket.path())?;
+        // Each entry's key, whether it's live, and how it's written out,
+        // oldest first.
+        let mut entries = Vec::new();
+        let mut original = Vec::new();
+        for (_, generation) in &generations {
+            let data = read_bucket(generation)?;
+            before += data.len() as u64;
+            if format == IndexFormat::Fixed {
+                for record in fixed::records(&data) {
+                    // Safe unwrap. The integrity already fit in a record.
+                    let out = fixed::encode_id(
+                        record.key_id,
+                        record.integrity.as_deref(),
+                        record.time,
+                        record.size,
+                    )
+                    .unwrap();
+                    entries.push((
+                        hex::encode(record.key_id),
+                        record.integrity.is_some(),
+                        out.to_vec(),
+                    ));
+                }
+            } else {
+                for entry in parse_bucket(&data, format) {
+                    let live = entry.integrity.is_some() || entry.metadata_only;
+                    let key = entry.key.clone();
+                    entries.push((key, live, encode_entry(entry, format)?));
+                }
+            }
+            if generations.len() == 1 {
+                original.extend_from_slice(&data);
+            }
+        }
+        let mut seen = HashSet::new();
+        let mut latest = entries
+            .into_iter()
+            .rev()
+            .filter(|(key, _, _)| seen.insert(key.clone()))
+            .filter(|(_, live, _)| *live)
+            .map(|(_, _, out)| out)
+            .collect::<Vec<_>>();
+        latest.reverse();
+        let out = latest.concat();
+        after += out.len() as u64;
+        if generations.len() == 1 && out == original {
+            continue;
+        }
+        replace_bucket(cache, bucket.path(), &generations, &out)?;
+    }
+    Ok((before, after))
+}
+
+/// Moves the latest entry for every key that `f` maps to a new key over to
+/// that key, in a single pass over the index like [`compact`], which the
+/// buckets the moved entries came from are also given. Returns how many
+/// entries were moved. An entry moved onto a key that already has one
+/// replaces it, and of several moved onto the same key, the newest wins.
+/// Signatures cover the key, so moved entries lose theirs. Fixed-format
+/// indexes only keep hashes of keys, so they can't be remapped.
+pub fn remap(cache: &Path, f: &mut dyn FnMut(&str) -> Option<String>) -> Result<usize> {
+    let format = config::load(cache)?.index_format;
+    if format == IndexFormat::Fixed {
+        return Err(Error::InvalidConfig(
+            cache.to_path_buf(),
+            "keys in a fixed-format index can't be remapped".into(),
+        ));
+    }
+    journal::recover(cache, format, true)?;
+    let index = index_dir(cache);
+    // The entries kept in each bucket that loses some.
+    let mut sources = HashMap::new();
+    let mut moved = Vec::new();
+    // Removals from, and additions to, the key directory.
+    let mut changes = Vec::new();
+    let (mut before, mut after) = (0i64, HashSet::new());
+    for bucket in WalkDir::new(&index) {
+        let bucket = match bucket {
+            Ok(bucket) => bucket,
+            // No index yet.
+            Err(err) if err.depth() == 0 => break,
+            Err(err) => return Err(err).to_internal()?,
+        };
+        if bucket.file_type().is_dir() || generation_of(bucket.path()).is_some() {
+            continue;
+        }
+        let generations = bucket_generations(bucket.path())?;
+        let mut entries = Vec::new();
+        for (_, generation) in &generations {
+            entries.extend(parse_bucket(&read_bucket(generation)?, format));
+        }
+        let mut seen = HashSet::new();
+        let mut kept = Vec::new();
+        let mut moves = false;
+        for mut entry in entries.into_iter().rev() {
+            if !seen.insert(entry.key.clone())
+                || (entry.integrity.is_none() && !entry.metadata_only)
+            {
+                continue;
+            }
+            before += 1;
+            match f(&entry.key).filter(|key| *key != entry.key) {
+                Some(key) => {
+                    moves = true;
+                    changes.push((entry.key.clone(), false));
+                    changes.push((key.clone(), true));
+                    after.insert(key.clone());
+                    entry.key = key;
+                    entry.signature = None;
+                    moved.push(entry);
+                }
+                None => {
+                    after.insert(entry.key.clone());
+                    kept.push(entry);
+                }
+            }
+        }
+        if moves {
+            kept.reverse();
+            sources.insert(bucket.path().to_path_buf(), kept);
+        }
+    }
+    let count = moved.len();
+    let config = config::load(cache)?;
+    let fields = config.indexed_fields;
+    let indexed = if fields.is_empty() {
+        Vec::new()
+    } else {
+        moved
+            .iter()
+            .map(|entry| (entry.key.clone(), entry.metadata.clone()))
+            .collect()
+    };
+    // Oldest first, so the newest of several entries moved onto one key is
+    // the one lookups find.
+    moved.sort_by_key(|entry| entry.time);
+    let mut targets = HashMap::<_, Vec<_>>::new();
+    for entry in moved {
+        targets
+            .entry(bucket_path(cache, &entry.key))
+            .or_default()
+            .push(entry);
+    }
+    // Moved entries are written out to every bucket they're moving to, and
+    // synced, before any of them are removed from where they were. If this is
+    // interrupted, they can end up under both keys, but never under neither.
+    let mut moved_in = HashMap::new();
+    for (base, entries) in targets {
+        let mut out = Vec::new();
+        for entry in entries {
+            out.extend(encode_entry(entry, format)?);
+        }
+        append(cache, &base, format, &out, true)?;
+        moved_in.insert(base, out);
+    }
+    for (base, kept) in sources {
+        let mut out = Vec::new();
+        for entry in kept {
+            out.extend(encode_entry(entry, format)?);
+        }
+        out.extend(moved_in.remove(&base).unwrap_or_default());
+        // Appending what moved in may have started a new generation.
+        let generations = bucket_generations(&base)?;
+        replace_bucket(cache, &base, &generations, &out)?;
+    }
+    for (key, metadata) in indexed {
+        secondary::record(cache, &fields, &key, &metadata)?;
+    }
+    if config.prefix_index && !changes.is_empty() {
+        keydir::record(cache, &changes)?;
+    }
+    let entries = after.len() as i64 - before;
+    if entries != 0 {
+        stats::record(
+            cache,
+            StatsDelta {
+                entries,
+                ..Default::default()
+            },
+        )?;
+    }
+    Ok(count)
+}
+
+/// Serializes an entry for a JSON or binary bucket.
+fn encode_entry(entry: SerializableMetadata, format: IndexFormat) -> Result<Vec<u8>> {
+    match format {
+        IndexFormat::Binary => {
+            let key = entry.key.clone();
+            Ok(binary::encode(entry)
+                .with_context(|| format!("Failed to serialize entry with key `{}`", key))?)
+        }
+        _ => json_entry(entry),
+    }
+}
+
+/// Replaces all `generations` of the bucket at `base` with a single one
+/// holding `out`, or removes them along with any directories this empties if
+/// `out` is empty.
+fn replace_bucket(
+    cache: &Path,
+    base: &Path,
+    generations: &[(u64, PathBuf)],
+    out: &[u8],
+) -> Result<()> {
+    if out.is_empty() {
+        // Newest first, so no gaps are left if this is interrupted.
+        for (_, generation) in generations.iter().rev() {
+            fs::remove_file(generation)
+                .with_context(|| format!("Failed to remove index bucket at {:?}", generation))?;
+        }
+        // Prune the directories this emptied. Fails harmlessly for ones
+        // that still hold other buckets.
+        let index = index_dir(cache);
+        let mut dir = base.parent();
+        while let Some(parent) = dir.filter(|dir| *dir != index) {
+            if fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+        return Ok(());
+    }
+    let tmp_path = cache.join("tmp");
+    fs::create_dir_all(&tmp_path)
+        .with_context(|| format!("Failed to create tmp directory at {:?}", tmp_path))?;
+    let mut tmp = tempfile::NamedTempFile::new_in(&tmp_path).to_internal()?;
+    tmp.write_all(out)
+        .with_context(|| format!("Failed to write compacted bucket for {:?}", base))?;
+    tmp.persist(base)
+        .with_context(|| format!("Failed to replace index bucket at {:?}", base))?;
+    // The new bucket already has everything the later generations did, so
+    // lookups stay correct while these go.
+    for (generation, path) in generations.iter().rev() {
+        if *generation != 0 {
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to remove index bucket at {:?}", path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Counts the live entries whose data is the content object for `sri`.
+/// Pointer entries keep their data outside the cache, and metadata-only
+/// entries have none, so they don't count.
+pub fn references(cache: &Path, sri: &Integrity) -> Result<usize> {
+    Ok(referencing_keys(cache, sri)?.len())
+}
+
+/// Lists the keys of the live entries counted by `references`.
+pub fn referencing_keys(cache: &Path, sri: &Integrity) -> Result<Vec<String>> {
+    if !index_dir(cache).exists() {
+        return Ok(Vec::new());
+    }
+    let config = config::load(cache)?;
+    let cpath = path::content_path_with(&config, cache, sri);
+    let mut keys = Vec::new();
+    for entry in ls(cache) {
+        let entry = entry?;
+        if entry.external.is_none()
+            && !entry.metadata_only
+            && path::content_path_with(&config, cache, &entry.integrity) == cpath
+        {
+            keys.push(entry.key);
+        }
+    }
+    Ok(keys)
+}
+
+/// Checks whether the newest line in the index for `key` fails its checksum,
+/// meaning lookups silently fall back to an older entry, or to none at all.
+pub(crate) fn tampered(cache: &Path, key: &str) -> Result<bool> {
+    let format = config::load(cache)?.index_format;
+    for (_, bucket) in bucket_generations(&bucket_path(cache, key))?.iter().rev() {
+        if let Some(tampered) = tampered_in_bucket(bucket, format, key)? {
+            return Ok(tampered);
+        }
+    }
+    Ok(false)
+}
+
+/// Like `tampered`, for a single bucket file. Returns `None` if the bucket
+/// has nothing that can be told to be for `key`.
+fn tampered_in_bucket(bucket: &Path, format: IndexFormat, key: &str) -> Result<Option<bool>> {
+    match format {
+        IndexFormat::Json => {}
+        IndexFormat::Fixed => return Ok(fixed::tampered(&read_bucket(bucket)?, key)),
+        IndexFormat::Binary => {
+            let data = read_bucket(bucket)?;
+            // Like with JSON lines, frames too mangled to tell which key
+            // they're for can't be held against this one.
+            let newest = binary::frames(&data).filter(
+                |frame| matches!(binary::decode(frame.payload), Some(entry) if entry.key == key),
+            );
+            return Ok(newest.last().map(|frame| !frame.valid));
+        }
+    }
+    let lines = match fs::File::open(bucket) {
+        Ok(fd) => RevLines::new(fd).to_internal()?,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).to_internal()?,
+    };
+    for line in lines {
+        let line =
+            line.with_context(|| format!("Failed to read index bucket entries from {:?}", bucket))?;
+        let (hash, entry_str) = match std::str::from_utf8(&line)
+            .ok()
+            .and_then(|line| line.split_once('\t'))
+        {
+            Some(parts) => parts,
+            None => continue,
+        };
+        // Lines too mangled to tell which key they're for can't be held
+        // against this one.
+        match serde_json::from_str::<SerializableMetadata>(entry_str) {
+            Ok(entry) if entry.key == key => return Ok(Some(hash_entry(entry_str) != hash)),
+            _ => continue,
+        }
+    }
+    Ok(None)
 }
 
 fn bucket_path(cache: &Path, key: &str) -> PathBuf {
@@ -178,6 +1022,76 @@ fn bucket_path(cache: &Path, key: &str) -> PathBuf {
         .join(&hashed[4..])
 }
 
+/// Locks the bucket at `base` against writers that check what's in it before
+/// appending to it.
+fn lock_bucket(cache: &Path, base: &Path) -> Result<Lock> {
+    // Safe unwrap. Buckets are always inside the index.
+    let relative = base.strip_prefix(index_dir(cache)).unwrap();
+    let name = relative
+        .iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<String>();
+    Lock::acquire(&cache.join(BUCKET_LOCKS).join(format!("{}.lock", name)))
+}
+
+/// Once the newest generation of a bucket grows past this, writes start a
+/// new one, so a key that's rewritten very often doesn't leave every lookup
+/// wading through its whole history.
+const GENERATION_SIZE: u64 = 1024 * 1024;
+
+/// The generations of a bucket that exist, oldest first. Generation 0 is the
+/// bucket itself, and later ones add a `.{generation}` suffix to its name.
+/// New generations are only ever started after the newest one, and removed
+/// newest first, so they're numbered without gaps, and this only has to
+/// look for each in turn until one is missing, rather than list the
+/// directory the bucket shares with others. A bucket only holds entries for
+/// keys with the same hash, so that's all a lookup of a key that was never
+/// written takes.
+fn bucket_generations(bucket: &Path) -> InternalResult<Vec<(u64, PathBuf)>> {
+    let mut generations = Vec::new();
+    loop {
+        let generation = generations.len() as u64;
+        let path = match generation {
+            0 => bucket.to_path_buf(),
+            _ => generation_path(bucket, generation),
+        };
+        match fs::symlink_metadata(&path) {
+            Ok(_) => generations.push((generation, path)),
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(generations),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read index bucket at {:?}", path))
+            }
+        }
+    }
+}
+
+/// Tells files apart even when one replaces another at the same path: by
+/// inode on unix, and elsewhere by when the file was created.
+pub(crate) fn file_id(meta: &fs::Metadata) -> Option<u128> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(meta.ino() as u128)
+    }
+    #[cfg(not(unix))]
+    {
+        meta.created()
+            .ok()
+            .and_then(|created| created.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|since| since.as_nanos())
+    }
+}
+
+fn generation_path(bucket: &Path, generation: u64) -> PathBuf {
+    bucket.with_extension(generation.to_string())
+}
+
+/// The generation a bucket file holds, if it's past the first.
+fn generation_of(path: &Path) -> Option<u64> {
+    path.extension()?.to_str()?.parse().ok()
+}
+
 fn hash_key(key: &str) -> String {
     let mut hasher = Sha1::new();
     hasher.update(key);
@@ -190,37 +1104,305 @@ fn hash_entry(key: &str) -> String {
     hex::encode(hasher.finalize())
 }
 
-fn now() -> u128 {
+pub(crate) fn now() -> u128 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_millis()
 }
 
-fn bucket_entries(bucket: &Path) -> InternalResult<Vec<SerializableMetadata>> {
-    use std::io::{BufRead, BufReader};
-    fs::File::open(bucket)
-        .map(|file| {
-            BufReader::new(file)
-                .lines()
-                .filter_map(std::result::Result::ok)
-                .filter_map(|entry| {
-                    let entry_str = match entry.split('\t').collect::<Vec<&str>>()[..] {
-                        [hash, entry_str] if hash_entry(entry_str) == hash => entry_str,
-                        // Something's wrong with the entry. Abort.
-                        _ => return None,
-                    };
-                    serde_json::from_str::<SerializableMetadata>(entry_str).ok()
-                })
-                .collect()
-        })
-        .or_else(|err| {
-            if err.kind() == ErrorKind::NotFound {
-                Ok(Vec::new())
-            } else {
-                Err(err).to_internal()?
+// Each bucket line is `{sha256 of json}\t{json}`. Keys and metadata are only
+// ever stored JSON-encoded, which escapes every control character, so no key
+// can produce a raw newline or tab that would break this framing.
+fn parse_entry(entry: &str) -> Option<SerializableMetadata> {
+    let entry_str = match entry.split_once('\t') {
+        Some((hash, entry_str)) if hash_entry(entry_str) == hash => entry_str,
+        // Something's wrong with the entry. Abort.
+        _ => return None,
+    };
+    serde_json::from_str::<SerializableMetadata>(entry_str).ok()
+}
+
+impl From<fixed::Record> for SerializableMetadata {
+    fn from(record: fixed::Record) -> Self {
+        SerializableMetadata {
+            key: hex::encode(record.key_id),
+            integrity: record.integrity,
+            time: record.time,
+            size: record.size,
+            ..Default::default()
+        }
+    }
+}
+
+fn bucket_entries(bucket: &Path, format: IndexFormat) -> InternalResult<Vec<SerializableMetadata>> {
+    Ok(parse_bucket(&read_bucket(bucket)?, format))
+}
+
+fn parse_bucket(data: &[u8], format: IndexFormat) -> Vec<SerializableMetadata> {
+    match format {
+        IndexFormat::Json => data
+            .split(|b| *b == b'\n')
+            .filter_map(|line| parse_entry(std::str::from_utf8(line).ok()?))
+            .collect(),
+        IndexFormat::Fixed => fixed::records(data).map(Into::into).collect(),
+        IndexFormat::Binary => binary::entries(data).collect(),
+    }
+}
+
+/// Like `bucket_entries`, but lazily yields valid entries starting from the
+/// end of the bucket, so lookups don't have to parse the whole history.
+fn bucket_entries_rev(
+    bucket: &Path,
+    format: IndexFormat,
+) -> InternalResult<impl Iterator<Item = std::io::Result<SerializableMetadata>>> {
+    if format != IndexFormat::Json {
+        // Binary entries are quick enough to decode that reading the whole
+        // bucket is cheap.
+        let entries = bucket_entries(bucket, format)?;
+        return Ok(Right(Left(entries.into_iter().rev().map(Ok))));
+    }
+    let fd = match fs::File::open(bucket) {
+        Ok(fd) => fd,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(Left(Right(std::iter::empty())))
+        }
+        Err(err) => return Err(err).to_internal(),
+    };
+    if fd.metadata().to_internal()?.len() >= MMAP_BUCKET_SIZE {
+        // Hot keys can grow their buckets large. Parse them where they're
+        // mapped rather than copying them chunk by chunk, if they can be.
+        if let Ok(data) = map_bucket(&fd) {
+            let lines = MappedRevLines {
+                end: data.len(),
+                data,
+                done: false,
+            };
+            return Ok(Right(Right(lines.map(Ok))));
+        }
+    }
+    let lines = RevLines::new(fd).to_internal()?;
+    Ok(Left(Left(lines.filter_map(|line| match line {
+        Ok(line) => parse_entry(std::str::from_utf8(&line).ok()?).map(Ok),
+        Err(err) => Some(Err(err)),
+    }))))
+}
+
+/// Buckets at least this big are memory-mapped when read, instead of being
+/// copied into memory.
+const MMAP_BUCKET_SIZE: u64 = 1024 * 1024;
+
+/// The contents of a bucket, either read into memory or mapped.
+enum BucketData {
+    Read(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Deref for BucketData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            BucketData::Read(data) => data,
+            BucketData::Mapped(map) => map,
+        }
+    }
+}
+
+/// Reads a whole bucket, which is empty if it doesn't exist yet. Large
+/// buckets are mapped rather than read, where that's possible.
+fn read_bucket(bucket: &Path) -> InternalResult<BucketData> {
+    let read = || -> std::io::Result<BucketData> {
+        let mut fd = fs::File::open(bucket)?;
+        let len = fd.metadata()?.len();
+        if len >= MMAP_BUCKET_SIZE {
+            if let Ok(map) = map_bucket(&fd) {
+                return Ok(BucketData::Mapped(map));
+            }
+        }
+        let mut data = Vec::with_capacity(len as usize);
+        std::io::Read::read_to_end(&mut fd, &mut data)?;
+        Ok(BucketData::Read(data))
+    };
+    match read() {
+        Ok(data) => Ok(data),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(BucketData::Read(Vec::new())),
+        Err(err) => Err(err)
+            .with_context(|| format!("Failed to read index bucket entries from {:?}", bucket)),
+    }
+}
+
+fn map_bucket(fd: &fs::File) -> std::io::Result<Mmap> {
+    // Buckets are only ever appended to, and replaced or removed as a whole,
+    // so the mapped part never changes underneath the map.
+    unsafe { Mmap::map(fd) }
+}
+
+/// The key of an index entry, its integrity, or `None` for a deletion, and
+/// when it was written.
+#[cfg(feature = "notify")]
+pub(crate) type KeyChange = (String, Option<Integrity>, u128);
+
+/// Reads the entries appended to the bucket open as `fd` after byte
+/// `offset`, returning a change for each, along with the offset to resume
+/// from. A trailing entry that's still being written is left for the next
+/// call.
+#[cfg(feature = "notify")]
+pub(crate) fn appended_entries(
+    mut fd: fs::File,
+    offset: u64,
+    format: IndexFormat,
+) -> std::io::Result<(Vec<KeyChange>, u64)> {
+    use std::io::{Read, Seek, SeekFrom};
+    // An offset partway into a record can only come from a record that was
+    // still being written when the bucket was first seen, so skip past it.
+    let offset = match format {
+        IndexFormat::Json | IndexFormat::Binary => offset,
+        IndexFormat::Fixed => offset + fixed::padding(offset) as u64,
+    };
+    fd.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::new();
+    fd.read_to_end(&mut buf)?;
+    if format == IndexFormat::Fixed {
+        let complete = buf.len() - buf.len() % fixed::RECORD_SIZE;
+        let entries = fixed::records(&buf[..complete])
+            .filter_map(|record| key_change(record.into()))
+            .collect();
+        return Ok((entries, offset + complete as u64));
+    }
+    if format == IndexFormat::Binary {
+        let mut entries = Vec::new();
+        let mut consumed = 0;
+        for frame in binary::frames(&buf) {
+            if frame.valid {
+                entries.extend(binary::decode(frame.payload).and_then(key_change));
+            }
+            consumed = frame.end;
+        }
+        return Ok((entries, offset + consumed as u64));
+    }
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    let mut consumed = 0;
+    for line in buf.split(|b| *b == b'\n') {
+        let end = pos + line.len();
+        match std::str::from_utf8(line).ok().and_then(parse_entry) {
+            Some(entry) => {
+                entries.extend(key_change(entry));
+                consumed = end;
+            }
+            // Entries are written in one go, each starting with a newline, so
+            // an unparsable last line may just not be finished yet.
+            None if end == buf.len() && !line.is_empty() => break,
+            None => consumed = end,
+        }
+        pos = end + 1;
+    }
+    Ok((entries, offset + consumed as u64))
+}
+
+#[cfg(feature = "notify")]
+fn key_change(entry: SerializableMetadata) -> Option<KeyChange> {
+    let (key, time) = (entry.key.clone(), entry.time);
+    let entry = entry.into_metadata().ok()?;
+    Some((key, entry.map(|entry| entry.integrity), time))
+}
+
+pub(crate) fn index_dir(cache: &Path) -> PathBuf {
+    cache.join(format!("index-v{}", INDEX_VERSION))
+}
+
+/// The valid entries of a mapped bucket, newest first, parsed in place.
+struct MappedRevLines {
+    data: Mmap,
+    /// End of the next line to parse.
+    end: usize,
+    done: bool,
+}
+
+impl Iterator for MappedRevLines {
+    type Item = SerializableMetadata;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let end = self.end;
+            let start = match self.data[..end].iter().rposition(|b| *b == b'\n') {
+                Some(newline) => {
+                    self.end = newline;
+                    newline + 1
+                }
+                None => {
+                    self.done = true;
+                    0
+                }
+            };
+            let entry = std::str::from_utf8(&self.data[start..end])
+                .ok()
+                .and_then(parse_entry);
+            if entry.is_some() {
+                return entry;
             }
+        }
+        None
+    }
+}
+
+const REV_CHUNK_SIZE: u64 = 8 * 1024;
+
+/// Iterator over the lines of a file, from last to first. The file is read in
+/// `REV_CHUNK_SIZE` chunks from the end, so only as much of it as is actually
+/// consumed ever gets read.
+struct RevLines {
+    fd: fs::File,
+    pos: u64,
+    buf: Vec<u8>,
+}
+
+impl RevLines {
+    fn new(fd: fs::File) -> std::io::Result<Self> {
+        let pos = fd.metadata()?.len();
+        Ok(RevLines {
+            fd,
+            pos,
+            buf: Vec::new(),
         })
+    }
+}
+
+impl Iterator for RevLines {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        use std::io::{Read, Seek, SeekFrom};
+        loop {
+            if let Some(idx) = self.buf.iter().rposition(|b| *b == b'\n') {
+                let line = self.buf.split_off(idx + 1);
+                self.buf.truncate(idx);
+                return Some(Ok(line));
+            }
+            if self.pos == 0 {
+                return if self.buf.is_empty() {
+                    None
+                } else {
+                    Some(Ok(std::mem::take(&mut self.buf)))
+                };
+            }
+            let amt = REV_CHUNK_SIZE.min(self.pos);
+            self.pos -= amt;
+            let mut chunk = vec![0; amt as usize];
+            if let Err(err) = self
+                .fd
+                .seek(SeekFrom::Start(self.pos))
+                .and_then(|_| self.fd.read_exact(&mut chunk))
+            {
+                self.pos = 0;
+                self.buf.clear();
+                return Some(Err(err));
+            }
+            chunk.append(&mut self.buf);
+            self.buf = chunk;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -242,6 +1424,202 @@ mod tests {
         assert_eq!(entry, MOCK_ENTRY);
     }
 
+    #[test]
+    fn key_conflicts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let first = Integrity::from(b"first");
+        let second = Integrity::from(b"second");
+        let write = |sri: &Integrity| insert(&dir, "key", WriteOpts::new().integrity(sri.clone()));
+
+        config::configure(
+            &dir,
+            crate::CacheConfig::new().key_conflict(KeyConflict::FirstWins),
+        )
+        .unwrap();
+        assert_eq!(write(&first).unwrap(), first);
+        assert_eq!(write(&second).unwrap(), first);
+        assert_eq!(find(&dir, "key").unwrap().unwrap().integrity, first);
+
+        let policy = KeyConflict::ErrorOnDifferent;
+        config::configure(&dir, crate::CacheConfig::new().key_conflict(policy)).unwrap();
+        assert_eq!(write(&first).unwrap(), first);
+        assert!(matches!(write(&second), Err(Error::KeyConflict(..))));
+
+        // Removing a key always works, after which it can be written afresh.
+        delete(&dir, "key").unwrap();
+        assert_eq!(write(&second).unwrap(), second);
+
+        config::configure(&dir, crate::CacheConfig::new()).unwrap();
+        assert_eq!(write(&first).unwrap(), first);
+        assert_eq!(find(&dir, "key").unwrap().unwrap().integrity, first);
+    }
+
+    #[test]
+    fn racing_key_conflicts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        config::configure(
+            &dir,
+            crate::CacheConfig::new().key_conflict(KeyConflict::FirstWins),
+        )
+        .unwrap();
+        for round in 0..20 {
+            let key = format!("key{}", round);
+            let handles = (0..4)
+                .map(|i| {
+                    let dir = dir.clone();
+                    let key = key.clone();
+                    std::thread::spawn(move || {
+                        let sri = Integrity::from(format!("writer {}", i));
+                        insert(&dir, &key, WriteOpts::new().integrity(sri)).unwrap()
+                    })
+                })
+                .collect::<Vec<_>>();
+            let results = handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>();
+            // Everyone is told the same entry won, and it's the only one.
+            let entry = find(&dir, &key).unwrap().unwrap();
+            assert!(results.iter().all(|sri| *sri == entry.integrity));
+            let lines = fs::read_to_string(bucket_path(&dir, &key)).unwrap();
+            assert_eq!(lines.lines().filter(|line| !line.is_empty()).count(), 1);
+        }
+        assert_eq!(fs::read_dir(dir.join(BUCKET_LOCKS)).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn compact_index() {
+        for format in [IndexFormat::Json, IndexFormat::Fixed, IndexFormat::Binary] {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path().to_owned();
+            config::configure(&dir, crate::CacheConfig::new().index_format(format)).unwrap();
+            let opts = |data: &[u8]| WriteOpts::new().integrity(Integrity::from(data)).size(5);
+            for _ in 0..10 {
+                insert(&dir, "hot", opts(b"stale")).unwrap();
+            }
+            insert(&dir, "hot", opts(b"fresh")).unwrap();
+            insert(&dir, "gone", opts(b"gone!")).unwrap();
+            delete(&dir, "gone").unwrap();
+            // A later generation, as if the bucket had grown large.
+            let bucket = bucket_path(&dir, "later");
+            insert(&dir, "later", opts(b"first")).unwrap();
+            fs::copy(&bucket, generation_path(&bucket, 1)).unwrap();
+            insert(&dir, "later", opts(b"after")).unwrap();
+
+            let (before, after) = compact(&dir).unwrap();
+            assert!(after < before, "{:?}", format);
+            let sri = |key| find(&dir, key).unwrap().map(|entry| entry.integrity);
+            assert_eq!(sri("hot"), Some(Integrity::from(b"fresh")));
+            assert_eq!(sri("later"), Some(Integrity::from(b"after")));
+            assert_eq!(sri("gone"), None);
+            assert!(!bucket_path(&dir, "gone").exists());
+            assert_eq!(bucket_generations(&bucket).unwrap().len(), 1);
+            assert_eq!(ls(&dir).count(), 2);
+            assert_eq!(compact(&dir).unwrap(), (after, after));
+        }
+    }
+
+    #[test]
+    fn fixed_format() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let config = crate::CacheConfig::new().index_format(IndexFormat::Fixed);
+        config::configure(&dir, config).unwrap();
+        let sri = Integrity::from(b"hello");
+        let opts = || WriteOpts::new().integrity(sri.clone()).size(5).time(1234);
+        insert(&dir, "key", opts()).unwrap();
+        insert(&dir, "other", opts()).unwrap();
+
+        let entry = find(&dir, "key").unwrap().unwrap();
+        assert_eq!(entry.key, "key");
+        assert_eq!(entry.integrity, sri);
+        assert_eq!((entry.time, entry.size), (1234, 5));
+        assert_eq!(entry.metadata, Value::Null);
+        assert!(find(&dir, "missing").unwrap().is_none());
+
+        // Listings only know the hashes of keys.
+        let mut keys: Vec<_> = ls(&dir).map(|entry| entry.unwrap().key).collect();
+        keys.sort();
+        let mut expected = vec![
+            hex::encode(fixed::key_id("key")),
+            hex::encode(fixed::key_id("other")),
+        ];
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        delete(&dir, "key").unwrap();
+        assert_eq!(find_latest(&dir, "key").unwrap(), Some(None));
+        assert_eq!(ls(&dir).count(), 1);
+
+        assert!(matches!(
+            insert(&dir, "key", opts().metadata(Value::Bool(true))),
+            Err(Error::InvalidConfig(..))
+        ));
+
+        // A torn append doesn't throw later records out of alignment.
+        let bucket = bucket_path(&dir, "key");
+        let mut fd = OpenOptions::new().append(true).open(&bucket).unwrap();
+        fd.write_all(&[1; 10]).unwrap();
+        insert(&dir, "key", opts()).unwrap();
+        assert_eq!(find(&dir, "key").unwrap().unwrap().integrity, sri);
+        assert!(!tampered(&dir, "key").unwrap());
+    }
+
+    #[test]
+    fn binary_format() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let config = crate::CacheConfig::new().index_format(IndexFormat::Binary);
+        config::configure(&dir, config).unwrap();
+        let sri = Integrity::from(b"hello");
+        let opts = || {
+            WriteOpts::new()
+                .integrity(sri.clone())
+                .size(5)
+                .time(1234)
+                .metadata(serde_json::json!({ "a": [1, 2] }))
+                .tag("tag")
+        };
+        insert(&dir, "key", opts()).unwrap();
+        insert(&dir, "other", opts().metadata(Value::Null)).unwrap();
+
+        let entry = find(&dir, "key").unwrap().unwrap();
+        assert_eq!(entry.key, "key");
+        assert_eq!(entry.integrity, sri);
+        assert_eq!((entry.time, entry.size), (1234, 5));
+        assert_eq!(entry.metadata, serde_json::json!({ "a": [1, 2] }));
+        assert_eq!(entry.tags, vec!["tag".to_owned()]);
+        assert_eq!(find(&dir, "other").unwrap().unwrap().metadata, Value::Null);
+
+        let mut keys: Vec<_> = ls(&dir).map(|entry| entry.unwrap().key).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["key", "other"]);
+        delete(&dir, "other").unwrap();
+        assert_eq!(find_latest(&dir, "other").unwrap(), Some(None));
+
+        // A torn append doesn't hide the entries written after it.
+        let bucket = bucket_path(&dir, "key");
+        let mut fd = OpenOptions::new().append(true).open(&bucket).unwrap();
+        let torn = binary::encode(new_entry("key", None, 1, &WriteOpts::new())).unwrap();
+        fd.write_all(&torn[..torn.len() - 1]).unwrap();
+        insert(&dir, "key", opts().time(5678)).unwrap();
+        assert_eq!(find(&dir, "key").unwrap().unwrap().time, 5678);
+        assert!(!tampered(&dir, "key").unwrap());
+
+        // So is a corrupted checksum, which comes after the magic bytes and
+        // the length.
+        let newest =
+            binary::encode(new_entry("key", Some(sri.to_string()), 5678, &opts())).unwrap();
+        let mut data = fs::read(&bucket).unwrap();
+        let checksum = data.len() - newest.len() + 8;
+        data[checksum] ^= 1;
+        fs::write(&bucket, data).unwrap();
+        assert_eq!(find(&dir, "key").unwrap().unwrap().time, 1234);
+        assert!(tampered(&dir, "key").unwrap());
+    }
+
     #[test]
     fn find_basic() {
         let tmp = tempfile::tempdir().unwrap();
@@ -259,7 +1637,14 @@ mod tests {
                 integrity: sri,
                 time,
                 size: 0,
-                metadata: json!(null)
+                metadata: json!(null),
+                #[cfg(feature = "signing")]
+                signature: None,
+                external: None,
+                content_type: None,
+                tags: Vec::new(),
+                expires: None,
+                metadata_only: false,
             }
         );
     }
@@ -271,6 +1656,101 @@ mod tests {
         assert_eq!(find(&dir, "hello").unwrap(), None);
     }
 
+    #[test]
+    fn find_latest_in_large_bucket() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri: Integrity = "sha1-deadbeef".parse().unwrap();
+        // Enough history to span many read chunks.
+        for time in 0..1_000 {
+            let opts = WriteOpts::new().integrity(sri.clone()).time(time);
+            insert(&dir, "hello", opts).unwrap();
+        }
+        let entry = find(&dir, "hello").unwrap().unwrap();
+        assert_eq!(entry.time, 999);
+    }
+
+    #[test]
+    fn hot_key_generations() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri: Integrity = "sha1-deadbeef".parse().unwrap();
+        let padding = "x".repeat(100 * 1024);
+        for time in 0..25 {
+            let opts = WriteOpts::new()
+                .integrity(sri.clone())
+                .time(time)
+                .metadata(Value::String(padding.clone()));
+            insert(&dir, "hello", opts).unwrap();
+        }
+        let bucket = bucket_path(&dir, "hello");
+        let generations = bucket_generations(&bucket).unwrap();
+        assert_eq!(
+            generations.iter().map(|(gen, _)| *gen).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert!(fs::metadata(&generations[2].1).unwrap().len() < GENERATION_SIZE);
+
+        assert_eq!(find(&dir, "hello").unwrap().unwrap().time, 24);
+        let entries: Vec<_> = ls(&dir).map(|entry| entry.unwrap()).collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].time, 24);
+        assert!(!tampered(&dir, "hello").unwrap());
+
+        delete(&dir, "hello").unwrap();
+        assert_eq!(find_latest(&dir, "hello").unwrap(), Some(None));
+        assert_eq!(ls(&dir).count(), 0);
+    }
+
+    #[test]
+    fn find_in_mapped_bucket() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri: Integrity = "sha1-deadbeef".parse().unwrap();
+        // Build a bucket past the mapping threshold, where the only entry for
+        // the key being looked up is the oldest one.
+        let bucket = bucket_path(&dir, "hello");
+        fs::create_dir_all(bucket.parent().unwrap()).unwrap();
+        let opts = WriteOpts::new();
+        let mut data = json_entry(new_entry("hello", Some(sri.to_string()), 1, &opts)).unwrap();
+        let mut time = 1;
+        while (data.len() as u64) < MMAP_BUCKET_SIZE {
+            time += 1;
+            data.extend(
+                json_entry(new_entry("other", Some(sri.to_string()), time, &opts)).unwrap(),
+            );
+        }
+        data.extend_from_slice(b"\nnot an entry");
+        fs::write(&bucket, data).unwrap();
+
+        // Without memory maps, the same lookups go through the read path.
+        assert_eq!(
+            matches!(read_bucket(&bucket).unwrap(), BucketData::Mapped(_)),
+            cfg!(all(feature = "mmap", not(target_os = "wasi")))
+        );
+        assert_eq!(find(&dir, "hello").unwrap().unwrap().time, 1);
+        let mut times: Vec<_> = ls(&dir).map(|entry| entry.unwrap().time).collect();
+        times.sort();
+        assert_eq!(times, vec![1, time]);
+    }
+
+    #[test]
+    fn find_after_delete_and_reinsert() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri: Integrity = "sha1-deadbeef".parse().unwrap();
+        insert(
+            &dir,
+            "hello",
+            WriteOpts::new().integrity(sri.clone()).time(1),
+        )
+        .unwrap();
+        delete(&dir, "hello").unwrap();
+        assert_eq!(find(&dir, "hello").unwrap(), None);
+        insert(&dir, "hello", WriteOpts::new().integrity(sri).time(2)).unwrap();
+        assert_eq!(find(&dir, "hello").unwrap().unwrap().time, 2);
+    }
+
     #[test]
     fn delete_basic() {
         let tmp = tempfile::tempdir().unwrap();
@@ -299,11 +1779,58 @@ mod tests {
                 integrity: sri,
                 time,
                 size: 0,
-                metadata: json!(null)
+                metadata: json!(null),
+                #[cfg(feature = "signing")]
+                signature: None,
+                external: None,
+                content_type: None,
+                tags: Vec::new(),
+                expires: None,
+                metadata_only: false,
             }
         );
     }
 
+    #[test]
+    fn metadata_serde_round_trip() {
+        let entry = Metadata {
+            key: String::from("hello"),
+            integrity: "sha1-deadbeef".parse().unwrap(),
+            time: 1_234_567,
+            size: 5,
+            metadata: json!({ "etag": "abc" }),
+            #[cfg(feature = "signing")]
+            signature: None,
+            external: None,
+            content_type: None,
+            tags: Vec::new(),
+            expires: None,
+            metadata_only: false,
+        };
+        let serialized = serde_json::to_string(&entry).unwrap();
+        let deserialized: Metadata = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, entry);
+    }
+
+    #[test]
+    fn inserted_at_and_age() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri: Integrity = "sha1-deadbeef".parse().unwrap();
+        let then = SystemTime::now() - Duration::from_secs(60);
+        insert(
+            &dir,
+            "hello",
+            WriteOpts::new().integrity(sri).time_sys(then),
+        )
+        .unwrap();
+        let entry = find(&dir, "hello").unwrap().unwrap();
+        // Only millisecond precision is stored.
+        let diff = then.duration_since(entry.inserted_at()).unwrap();
+        assert!(diff < Duration::from_millis(1));
+        assert!(entry.age() >= Duration::from_secs(60));
+    }
+
     #[test]
     fn ls_basic() {
         let tmp = tempfile::tempdir().unwrap();
@@ -322,4 +1849,92 @@ mod tests {
         entries.sort();
         assert_eq!(entries, vec![String::from("hello"), String::from("world")])
     }
+
+    #[test]
+    fn ls_skips_deleted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri: Integrity = "sha1-deadbeef".parse().unwrap();
+        insert(&dir, "hello", WriteOpts::new().integrity(sri.clone())).unwrap();
+        insert(&dir, "world", WriteOpts::new().integrity(sri)).unwrap();
+        delete(&dir, "hello").unwrap();
+
+        let entries = ls(&dir)
+            .map(|x| Ok(x?.key))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries, vec![String::from("world")])
+    }
+
+    const HOSTILE_KEYS: &[&str] = &[
+        "line\nbreak",
+        "tab\tseparated",
+        "\r\n",
+        "\t",
+        "\n\n\n",
+        "nul\u{0}byte",
+        "\"}\n{\"key\":\"x\"",
+        "unicode\u{2028}separator",
+    ];
+
+    #[test]
+    fn hostile_keys_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri: Integrity = "sha1-deadbeef".parse().unwrap();
+        for key in HOSTILE_KEYS {
+            insert(&dir, key, WriteOpts::new().integrity(sri.clone())).unwrap();
+            let bucket = fs::read_to_string(bucket_path(&dir, key)).unwrap();
+            // One line per entry, and every line parses.
+            assert_eq!(bucket.lines().filter(|l| !l.is_empty()).count(), 1);
+            assert!(bucket.lines().skip(1).all(|l| parse_entry(l).is_some()));
+        }
+        for key in HOSTILE_KEYS {
+            assert_eq!(find(&dir, key).unwrap().unwrap().key, *key);
+        }
+        let mut listed = ls(&dir).map(|x| x.unwrap().key).collect::<Vec<_>>();
+        let mut expected = HOSTILE_KEYS
+            .iter()
+            .map(|k| k.to_string())
+            .collect::<Vec<_>>();
+        listed.sort();
+        expected.sort();
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn hostile_key_cannot_forge_entry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri: Integrity = "sha1-deadbeef".parse().unwrap();
+        // A key that contains a complete, correctly hashed entry for another
+        // key must not be mistaken for that entry.
+        let forged = serde_json::to_string(&SerializableMetadata {
+            key: "victim".into(),
+            integrity: Some(sri.to_string()),
+            time: 0,
+            size: 0,
+            metadata: Value::Null,
+            signature: None,
+            external: None,
+            content_type: None,
+            tags: Vec::new(),
+            expires: None,
+            metadata_only: false,
+        })
+        .unwrap();
+        let key = format!("\n{}\t{}\n", hash_entry(&forged), forged);
+        insert(&dir, &key, WriteOpts::new().integrity(sri)).unwrap();
+        assert_eq!(find(&dir, &key).unwrap().unwrap().key, key);
+
+        // Keys only share a bucket when their hashes collide, which can't
+        // be arranged here, so move the line to where the victim's would go.
+        let victim = bucket_path(&dir, "victim");
+        fs::create_dir_all(victim.parent().unwrap()).unwrap();
+        fs::rename(bucket_path(&dir, &key), &victim).unwrap();
+        let entries = bucket_entries(&victim, IndexFormat::Json).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, key);
+        assert!(find(&dir, "victim").unwrap().is_none());
+    }
 }
@@ -0,0 +1,156 @@
+//! The length-prefixed entries kept by caches configured with
+//! [`IndexFormat::Binary`](crate::IndexFormat::Binary).
+//!
+//! Each entry is framed as `MAGIC`, the length of its payload as a
+//! little-endian `u32`, the start of a SHA-256 of the payload, and then the
+//! payload itself: the entry's fields encoded with `bincode`. Readers skip
+//! frames that fail their checksum and look for the next `MAGIC` after them,
+//! so torn and corrupted entries are passed over just like bad JSON lines.
+use std::path::PathBuf;
+
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::SerializableMetadata;
+
+const MAGIC: [u8; 4] = *b"\xcaIX\x01";
+const CHECKSUM_LEN: usize = 8;
+const HEADER_LEN: usize = MAGIC.len() + 4 + CHECKSUM_LEN;
+
+/// The fields of an entry, laid out for `bincode`, which can't skip fields
+/// or encode arbitrary JSON values.
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    key: String,
+    /// Empty for metadata-only entries, which JSON marks with a field of
+    /// their own.
+    integrity: Option<String>,
+    time: u128,
+    size: u64,
+    /// JSON-encoded, or empty for `null` so most entries skip JSON entirely.
+    metadata: String,
+    signature: Option<String>,
+    external: Option<PathBuf>,
+    content_type: Option<String>,
+    tags: Vec<String>,
+    expires: Option<u128>,
+}
+
+/// A frame found in a bucket.
+pub(super) struct Frame<'a> {
+    pub(super) payload: &'a [u8],
+    /// Whether the payload passed its checksum.
+    pub(super) valid: bool,
+    /// Offset just past the end of the frame.
+    #[cfg(feature = "notify")]
+    pub(super) end: usize,
+}
+
+/// Frames an entry, ready to be appended to a bucket.
+pub(super) fn encode(entry: SerializableMetadata) -> bincode::Result<Vec<u8>> {
+    let metadata = if entry.metadata.is_null() {
+        String::new()
+    } else {
+        serde_json::to_string(&entry.metadata)
+            .map_err(|err| bincode::ErrorKind::Custom(err.to_string()))?
+    };
+    let payload = bincode::serialize(&Entry {
+        key: entry.key,
+        integrity: if entry.metadata_only {
+            Some(String::new())
+        } else {
+            entry.integrity
+        },
+        time: entry.time,
+        size: entry.size as u64,
+        metadata,
+        signature: entry.signature,
+        external: entry.external,
+        content_type: entry.content_type,
+        tags: entry.tags,
+        expires: entry.expires,
+    })?;
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(&checksum(&payload));
+    out.extend_from_slice(&payload);
+    Ok(out)
+}
+
+/// Decodes the payload of a frame.
+pub(super) fn decode(payload: &[u8]) -> Option<SerializableMetadata> {
+    let entry: Entry = bincode::deserialize(payload).ok()?;
+    let metadata = if entry.metadata.is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::from_str(&entry.metadata).ok()?
+    };
+    let metadata_only = entry.integrity.as_deref() == Some("");
+    Some(SerializableMetadata {
+        key: entry.key,
+        integrity: entry.integrity.filter(|_| !metadata_only),
+        time: entry.time,
+        size: entry.size as usize,
+        metadata,
+        signature: entry.signature,
+        external: entry.external,
+        content_type: entry.content_type,
+        tags: entry.tags,
+        expires: entry.expires,
+        metadata_only,
+    })
+}
+
+/// Iterates over the frames in a bucket, oldest first. A frame running past
+/// the end of `bucket` with nothing after it may still be being written, so
+/// iteration stops there.
+pub(super) fn frames(bucket: &[u8]) -> impl Iterator<Item = Frame<'_>> {
+    let mut pos = 0;
+    std::iter::from_fn(move || loop {
+        pos += find_magic(&bucket[pos..])?;
+        let frame = bucket.get(pos..pos + HEADER_LEN).and_then(|header| {
+            let len = u32::from_le_bytes(header[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap());
+            let start = pos + HEADER_LEN;
+            Some((header, bucket.get(start..start.checked_add(len as usize)?)?))
+        });
+        let (header, payload) = match frame {
+            Some(frame) => frame,
+            None => {
+                find_magic(&bucket[pos + 1..])?;
+                pos += 1;
+                continue;
+            }
+        };
+        let valid = checksum(payload) == header[MAGIC.len() + 4..];
+        // A bad frame's length can't be trusted, so look for the next one
+        // right after its start.
+        pos = if valid {
+            pos + HEADER_LEN + payload.len()
+        } else {
+            pos + 1
+        };
+        return Some(Frame {
+            payload,
+            valid,
+            #[cfg(feature = "notify")]
+            end: pos,
+        });
+    })
+}
+
+/// Decodes every valid entry in a bucket, oldest first.
+pub(super) fn entries(bucket: &[u8]) -> impl Iterator<Item = SerializableMetadata> + '_ {
+    frames(bucket)
+        .filter(|frame| frame.valid)
+        .filter_map(|frame| decode(frame.payload))
+}
+
+fn find_magic(data: &[u8]) -> Option<usize> {
+    data.windows(MAGIC.len()).position(|window| window == MAGIC)
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    Sha256::digest(data)[..CHECKSUM_LEN].try_into().unwrap()
+}
@@ -0,0 +1,115 @@
+//! The binary records kept by caches configured with
+//! [`IndexFormat::Fixed`](crate::IndexFormat::Fixed).
+//!
+//! Each record is `RECORD_SIZE` bytes: the SHA-256 of the key, the integrity
+//! string padded out with zeroes (all zeroes for a removal), the time and the
+//! size as little-endian `u64`s, and finally the start of a SHA-256 of all of
+//! that, so torn or corrupted records are skipped just like bad JSON lines.
+use digest::Digest;
+use sha2::Sha256;
+
+const KEY_LEN: usize = 32;
+/// Fits a single SHA-512 integrity string, the longest one `ssri` produces.
+const SRI_LEN: usize = 96;
+const CHECKSUM_LEN: usize = 8;
+const CHECKSUM_START: usize = KEY_LEN + SRI_LEN + 16;
+
+pub(super) const RECORD_SIZE: usize = CHECKSUM_START + CHECKSUM_LEN;
+
+pub(super) struct Record {
+    pub(super) key_id: [u8; KEY_LEN],
+    pub(super) integrity: Option<String>,
+    pub(super) time: u128,
+    pub(super) size: usize,
+}
+
+/// The hash a record stores in place of `key`.
+pub(super) fn key_id(key: &str) -> [u8; KEY_LEN] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+/// Encodes an entry, or returns `None` if `integrity` is too long to fit.
+pub(super) fn encode(
+    key: &str,
+    integrity: Option<&str>,
+    time: u128,
+    size: usize,
+) -> Option<[u8; RECORD_SIZE]> {
+    encode_id(key_id(key), integrity, time, size)
+}
+
+/// Like `encode`, for a key that's only known by its hash.
+pub(super) fn encode_id(
+    key_id: [u8; KEY_LEN],
+    integrity: Option<&str>,
+    time: u128,
+    size: usize,
+) -> Option<[u8; RECORD_SIZE]> {
+    let integrity = integrity.unwrap_or_default().as_bytes();
+    if integrity.len() > SRI_LEN {
+        return None;
+    }
+    let mut record = [0; RECORD_SIZE];
+    record[..KEY_LEN].copy_from_slice(&key_id);
+    record[KEY_LEN..KEY_LEN + integrity.len()].copy_from_slice(integrity);
+    let time = u64::try_from(time).unwrap_or(u64::MAX);
+    let size = size as u64;
+    record[KEY_LEN + SRI_LEN..KEY_LEN + SRI_LEN + 8].copy_from_slice(&time.to_le_bytes());
+    record[KEY_LEN + SRI_LEN + 8..CHECKSUM_START].copy_from_slice(&size.to_le_bytes());
+    let checksum = checksum(&record[..CHECKSUM_START]);
+    record[CHECKSUM_START..].copy_from_slice(&checksum);
+    Some(record)
+}
+
+/// Decodes a record, or returns `None` if it fails its checksum.
+pub(super) fn decode(record: &[u8]) -> Option<Record> {
+    if record.len() != RECORD_SIZE
+        || checksum(&record[..CHECKSUM_START]) != record[CHECKSUM_START..]
+    {
+        return None;
+    }
+    let integrity = &record[KEY_LEN..KEY_LEN + SRI_LEN];
+    let integrity = &integrity[..integrity.iter().position(|b| *b == 0).unwrap_or(SRI_LEN)];
+    let integrity = if integrity.is_empty() {
+        None
+    } else {
+        Some(std::str::from_utf8(integrity).ok()?.to_owned())
+    };
+    let u64_at = |start: usize| u64::from_le_bytes(record[start..start + 8].try_into().unwrap());
+    Some(Record {
+        key_id: record[..KEY_LEN].try_into().unwrap(),
+        integrity,
+        time: u64_at(KEY_LEN + SRI_LEN).into(),
+        size: u64_at(KEY_LEN + SRI_LEN + 8) as usize,
+    })
+}
+
+/// Decodes every valid record in a bucket, oldest first.
+pub(super) fn records(bucket: &[u8]) -> impl DoubleEndedIterator<Item = Record> + '_ {
+    bucket.chunks_exact(RECORD_SIZE).filter_map(decode)
+}
+
+/// How many zeroes to write before appending to a bucket of `len` bytes, so
+/// the new record starts on a record boundary even if an earlier append was
+/// torn. The torn record and its padding then fail their checksum together.
+pub(super) fn padding(len: u64) -> usize {
+    match (len % RECORD_SIZE as u64) as usize {
+        0 => 0,
+        partial => RECORD_SIZE - partial,
+    }
+}
+
+/// Checks whether the newest record for `key` in a bucket fails its checksum,
+/// or returns `None` if the bucket has no record for `key` at all.
+pub(super) fn tampered(bucket: &[u8], key: &str) -> Option<bool> {
+    let key_id = key_id(key);
+    bucket
+        .chunks_exact(RECORD_SIZE)
+        .rev()
+        .find(|record| record[..KEY_LEN] == key_id)
+        .map(|record| decode(record).is_none())
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    Sha256::digest(data)[..CHECKSUM_LEN].try_into().unwrap()
+}
@@ -0,0 +1,446 @@
+//! The write-ahead journal kept by caches configured with
+//! [`CacheConfig::index_journal`](crate::CacheConfig::index_journal).
+//!
+//! Before a batch of entries is appended to the index, everything it's about
+//! to append, and how long each bucket was beforehand, is written to a file
+//! of its own under `{cache}/index-journal-v1` and synced. The file is only
+//! removed once the appends have been synced too. A journal left behind by a
+//! crash is replayed: whatever part of the batch didn't make it into a bucket
+//! is written there again, where it would have gone. Journals are moved into
+//! place whole, so one that can't be read never got as far as the index, and
+//! is simply dropped.
+//!
+//! Along with each bucket's length, a journal keeps the bytes just before
+//! the append, so a bucket that's been rewritten since, by compaction or the
+//! like, isn't mistaken for the one the batch started from. The batch is then
+//! only appended to it if it isn't already there. Rewrites of the whole index
+//! replay or drop pending journals first anyway.
+//!
+//! Each journal is named after a [`token`](crate::lock::token) of the writer
+//! that made it, so a journal is only taken to have been left behind once the
+//! process that wrote it has exited or given up on the batch, however slow
+//! the batch is. Where that can't be told, journals older than `STALE_AFTER`
+//! are taken to have been left behind instead.
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use super::fixed;
+use crate::config::IndexFormat;
+use crate::errors::{Internal, Result};
+use crate::lock::{self, Lock};
+
+const JOURNAL_DIR: &str = "index-journal-v1";
+const JOURNAL_LOCK: &str = "index-journal-v1.lock";
+
+/// Where there's no telling whether the writer of a journal is still running,
+/// journals older than this are assumed to belong to one that died part way
+/// through its batch.
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+/// How many of the bytes before each append a journal keeps.
+const TAIL_LEN: usize = 64;
+
+/// Data about to be appended to one bucket.
+#[derive(Deserialize, Serialize)]
+struct Record {
+    /// Path of the bucket, relative to the cache.
+    bucket: PathBuf,
+    /// Combined length of the bucket's generations before the append.
+    len: u64,
+    /// Hex-encoded data being appended.
+    data: String,
+    /// Hex-encoded last bytes of the bucket before the append, up to
+    /// `TAIL_LEN` of them.
+    #[serde(default)]
+    tail: String,
+}
+
+/// A batch that's been journaled, but not yet applied.
+pub(super) struct Journal {
+    path: PathBuf,
+    /// Marks the batch as still in progress in this process.
+    _token: lock::Token,
+}
+
+/// Journals the appends of `data` to each bucket in `appends`, and syncs it
+/// to disk, before any of them are made.
+pub(super) fn begin(cache: &Path, appends: &[(&Path, &[u8])]) -> Result<Journal> {
+    let mut records = Vec::with_capacity(appends.len());
+    for (base, data) in appends {
+        let mut bucket = Vec::new();
+        for (_, generation) in super::bucket_generations(base)? {
+            bucket.extend_from_slice(&super::read_bucket(&generation)?);
+        }
+        records.push(Record {
+            // Safe unwrap. Buckets are always inside the cache.
+            bucket: base.strip_prefix(cache).unwrap().to_path_buf(),
+            len: bucket.len() as u64,
+            data: hex::encode(data),
+            tail: hex::encode(&bucket[bucket.len().saturating_sub(TAIL_LEN)..]),
+        });
+    }
+    let dir = cache.join(JOURNAL_DIR);
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create index journal directory at {:?}", dir))?;
+    let tmp_path = cache.join("tmp");
+    fs::create_dir_all(&tmp_path)
+        .with_context(|| format!("Failed to create tmp directory at {:?}", tmp_path))?;
+    let mut tmp = tempfile::NamedTempFile::new_in(&tmp_path).to_internal()?;
+    tmp.write_all(&serde_json::to_vec(&records).to_internal()?)
+        .and_then(|_| tmp.as_file().sync_all())
+        .with_context(|| format!("Failed to write index journal for {:?}", cache))?;
+    let token = lock::token();
+    let path = dir.join(&*token);
+    tmp.persist(&path)
+        .with_context(|| format!("Failed to move index journal into {:?}", path))?;
+    #[cfg(unix)]
+    fs::File::open(&dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync index journal directory at {:?}", dir))?;
+    Ok(Journal {
+        path,
+        _token: token,
+    })
+}
+
+/// Drops the journal for a batch whose appends have all been synced.
+pub(super) fn finish(journal: Journal) -> Result<()> {
+    fs::remove_file(&journal.path)
+        .with_context(|| format!("Failed to remove index journal at {:?}", journal.path))?;
+    Ok(())
+}
+
+/// Replays the journals in `cache`, or only the ones whose writers have died
+/// if `stale_only`, and removes them. Journals written by other processes
+/// that are still running are always left alone. Returns how many were
+/// replayed.
+pub(super) fn recover(cache: &Path, format: IndexFormat, stale_only: bool) -> Result<usize> {
+    let dir = cache.join(JOURNAL_DIR);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to list index journals in {:?}", dir))?
+        }
+    };
+    let mut journals = Vec::new();
+    for entry in entries {
+        let path = entry.to_internal()?.path();
+        let owner = entry_owner(&path);
+        let replay = match owner {
+            Owner::Exited => true,
+            Owner::Other => false,
+            Owner::This => !stale_only,
+            Owner::Unknown => !stale_only || is_old(&path),
+        };
+        if replay {
+            journals.push(path);
+        }
+    }
+    if journals.is_empty() {
+        return Ok(0);
+    }
+    let _lock = Lock::acquire(&cache.join(JOURNAL_LOCK))?;
+    let mut replayed = 0;
+    for path in journals {
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            // Another process got to it first.
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read index journal at {:?}", path))?
+            }
+        };
+        if let Ok(records) = serde_json::from_slice::<Vec<Record>>(&data) {
+            for record in records {
+                replay(cache, format, record)?;
+            }
+            replayed += 1;
+        }
+        fs::remove_file(&path)
+            .with_context(|| format!("Failed to remove index journal at {:?}", path))?;
+    }
+    Ok(replayed)
+}
+
+/// Drops every pending journal, save those of batches other processes still
+/// have in progress, for when the index is being replaced wholesale and none
+/// of them apply to it anymore. Returns how many were dropped.
+pub(super) fn discard(cache: &Path) -> Result<usize> {
+    let dir = cache.join(JOURNAL_DIR);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to list index journals in {:?}", dir))?
+        }
+    };
+    let _lock = Lock::acquire(&cache.join(JOURNAL_LOCK))?;
+    let mut discarded = 0;
+    for entry in entries {
+        let path = entry.to_internal()?.path();
+        if let Owner::Other = entry_owner(&path) {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => discarded += 1,
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to remove index journal at {:?}", path))?
+            }
+        }
+    }
+    Ok(discarded)
+}
+
+/// Writes whatever part of `record` is missing from its bucket.
+fn replay(cache: &Path, format: IndexFormat, record: Record) -> Result<()> {
+    let base = cache.join(&record.bucket);
+    let data = hex::decode(&record.data).to_internal()?;
+    let generations = super::bucket_generations(&base)?;
+    let mut bucket = Vec::new();
+    for (_, generation) in &generations {
+        bucket.extend_from_slice(&super::read_bucket(generation)?);
+    }
+    let at = record.len as usize;
+    let tail = hex::decode(&record.tail).to_internal()?;
+    let intact = matches!(
+        at.checked_sub(tail.len()).and_then(|start| bucket.get(start..at)),
+        Some(before) if before == tail.as_slice()
+    );
+    if !intact {
+        // The bucket's been rewritten since the batch started, so there's no
+        // telling where it would have gone. Unless it's already there, it
+        // goes in as the newest.
+        if data.is_empty() || bucket.windows(data.len()).any(|window| window == data) {
+            return Ok(());
+        }
+        return super::append(cache, &base, format, &data, true);
+    }
+    // Only what was appended after the batch started can be the batch. An
+    // identical entry from before it doesn't count.
+    let applied = matches!(
+        bucket.get(at..),
+        Some(after) if after.windows(data.len()).any(|window| window == data)
+    );
+    if data.is_empty() || applied {
+        return Ok(());
+    }
+    if bucket.len() <= at || data.starts_with(&bucket[at..]) {
+        // Nothing was written after the batch, save maybe the start of the
+        // batch itself, which readers skip over once it's followed by more.
+        return super::append(cache, &base, format, &data, true);
+    }
+    // Other entries were appended since, which have to stay newer than the
+    // batch's, so it goes back in ahead of them.
+    let mut out = bucket[..at].to_vec();
+    if format == IndexFormat::Fixed {
+        out.resize(at + fixed::padding(at as u64), 0);
+    }
+    out.extend_from_slice(&data);
+    out.extend_from_slice(&bucket[at..]);
+    super::replace_bucket(cache, &base, &generations, &out)
+}
+
+/// Who wrote a journal, as far as can be told.
+enum Owner {
+    /// This process, which still has the batch in progress.
+    This,
+    /// Another process, that's still running.
+    Other,
+    /// A process that's exited.
+    Exited,
+    /// No telling, either because this platform can't check on processes,
+    /// or because the journal wasn't named after its writer.
+    Unknown,
+}
+
+fn entry_owner(path: &Path) -> Owner {
+    let token = match path.file_name().and_then(|name| name.to_str()) {
+        Some(token) => token,
+        None => return Owner::Unknown,
+    };
+    if lock::is_held(token) {
+        return Owner::This;
+    }
+    match lock::owner_exited(token) {
+        Some(true) => Owner::Exited,
+        Some(false) => Owner::Other,
+        None => Owner::Unknown,
+    }
+}
+
+fn is_old(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map(|age| age > STALE_AFTER)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{bucket_path, json_entry, new_entry};
+    use crate::{CacheConfig, WriteOpts};
+
+    fn entry(dir: &Path, key: &str, data: &[u8]) -> (PathBuf, Vec<u8>) {
+        let sri = crate::write_hash(dir, data).unwrap();
+        let opts = WriteOpts::new().integrity(sri).time(1);
+        let out = json_entry(new_entry(
+            key,
+            opts.sri.as_ref().map(|sri| sri.to_string()),
+            1,
+            &opts,
+        ))
+        .unwrap();
+        (bucket_path(dir, key), out)
+    }
+
+    #[test]
+    fn replays_journals() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        crate::configure(dir, CacheConfig::new().index_journal(true)).unwrap();
+        crate::write(dir, "a", b"old a").unwrap();
+
+        // A batch that crashed before any of it was applied is replayed.
+        let (a, a_out) = entry(dir, "a", b"new a");
+        let (b, b_out) = entry(dir, "b", b"new b");
+        let _journal = begin(dir, &[(&a, &a_out), (&b, &b_out)]).unwrap();
+        assert_eq!(recover(dir, IndexFormat::Json, true).unwrap(), 0);
+        assert_eq!(recover(dir, IndexFormat::Json, false).unwrap(), 1);
+        assert_eq!(crate::read(dir, "a").unwrap(), b"new a");
+        assert_eq!(crate::read(dir, "b").unwrap(), b"new b");
+        assert_eq!(fs::read_dir(dir.join(JOURNAL_DIR)).unwrap().count(), 0);
+
+        // One that partly made it out, then had other writes land after it,
+        // is finished off underneath them.
+        let (a, journaled_out) = entry(dir, "a", b"journaled a");
+        let (c, c_out) = entry(dir, "c", b"lost c");
+        let _journal = begin(dir, &[(&a, &journaled_out), (&c, &c_out)]).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&a)
+            .unwrap()
+            .write_all(&journaled_out[..10])
+            .unwrap();
+        crate::write(dir, "a", b"newest a").unwrap();
+        assert_eq!(recover(dir, IndexFormat::Json, false).unwrap(), 1);
+        assert_eq!(crate::read(dir, "a").unwrap(), b"newest a");
+        assert_eq!(crate::read(dir, "c").unwrap(), b"lost c");
+        let bucket = fs::read(&a).unwrap();
+        assert!(bucket
+            .windows(journaled_out.len())
+            .any(|window| window == journaled_out));
+
+        // A journal that was never finished being written was never acted
+        // on, so it's just dropped.
+        fs::write(dir.join(JOURNAL_DIR).join("torn"), b"[{\"bucket\":").unwrap();
+        assert_eq!(recover(dir, IndexFormat::Json, false).unwrap(), 0);
+        assert_eq!(fs::read_dir(dir.join(JOURNAL_DIR)).unwrap().count(), 0);
+
+        // One left by a process that's gone is replayed by the next write,
+        // even if it's only just been written.
+        #[cfg(unix)]
+        {
+            let mut child = std::process::Command::new("true").spawn().unwrap();
+            let pid = child.id();
+            child.wait().unwrap();
+            let (e, e_out) = entry(dir, "e", b"orphaned e");
+            let journal = begin(dir, &[(&e, &e_out)]).unwrap();
+            fs::rename(
+                &journal.path,
+                dir.join(JOURNAL_DIR).join(format!("{}-0-0", pid)),
+            )
+            .unwrap();
+            assert_eq!(recover(dir, IndexFormat::Json, true).unwrap(), 1);
+            assert_eq!(crate::read(dir, "e").unwrap(), b"orphaned e");
+        }
+
+        // An identical entry from before the batch doesn't stop it from
+        // being replayed.
+        let (f, f_out) = entry(dir, "f", b"f");
+        fs::create_dir_all(f.parent().unwrap()).unwrap();
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&f)
+            .unwrap()
+            .write_all(&f_out)
+            .unwrap();
+        let _journal = begin(dir, &[(&f, &f_out)]).unwrap();
+        assert_eq!(recover(dir, IndexFormat::Json, false).unwrap(), 1);
+        let bucket = fs::read(&f).unwrap();
+        assert_eq!(
+            bucket
+                .windows(f_out.len())
+                .filter(|window| *window == f_out)
+                .count(),
+            2
+        );
+
+        // Journaled writes clean up after themselves.
+        crate::rename_key(dir, "c", "d").unwrap();
+        assert!(crate::metadata(dir, "c").unwrap().is_none());
+        assert_eq!(crate::read(dir, "d").unwrap(), b"lost c");
+        assert_eq!(fs::read_dir(dir.join(JOURNAL_DIR)).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn replays_into_rewritten_buckets() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        crate::configure(dir, CacheConfig::new().index_journal(true)).unwrap();
+        for data in [&b"one"[..], b"two", b"three"] {
+            crate::write(dir, "a", data).unwrap();
+        }
+
+        // Compacting shrinks the bucket out from under the journal, and
+        // later writes grow it past where the batch would have gone.
+        let (a, a_out) = entry(dir, "a", b"journaled a");
+        let _journal = begin(dir, &[(&a, &a_out)]).unwrap();
+        crate::index::compact(dir).unwrap();
+        for data in [&b"four"[..], b"five", b"six", b"seven"] {
+            crate::write(dir, "a", data).unwrap();
+        }
+        assert_eq!(recover(dir, IndexFormat::Json, false).unwrap(), 1);
+        assert_eq!(crate::read(dir, "a").unwrap(), b"journaled a");
+        assert!(crate::list(dir).all(|entry| entry.is_ok()));
+        assert_eq!(crate::index::compact(dir).unwrap().1, a_out.len() as u64);
+
+        // Clearing the index drops the batches that were meant for it.
+        let (b, b_out) = entry(dir, "b", b"cleared b");
+        let _journal = begin(dir, &[(&b, &b_out)]).unwrap();
+        crate::clear_index(dir).unwrap();
+        assert_eq!(recover(dir, IndexFormat::Json, false).unwrap(), 0);
+        assert!(crate::metadata(dir, "b").unwrap().is_none());
+
+        // Handles finish batches left by exited processes when first used.
+        #[cfg(unix)]
+        {
+            let mut child = std::process::Command::new("true").spawn().unwrap();
+            let pid = child.id();
+            child.wait().unwrap();
+            let (c, c_out) = entry(dir, "c", b"orphaned c");
+            let journal = begin(dir, &[(&c, &c_out)]).unwrap();
+            fs::rename(
+                &journal.path,
+                dir.join(JOURNAL_DIR).join(format!("{}-0-0", pid)),
+            )
+            .unwrap();
+            let cache = crate::Cache::new(dir);
+            assert_eq!(cache.read("c").unwrap(), b"orphaned c");
+            assert_eq!(fs::read_dir(dir.join(JOURNAL_DIR)).unwrap().count(), 0);
+        }
+    }
+}
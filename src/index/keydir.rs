@@ -0,0 +1,226 @@
+//! The sorted key directory kept by caches configured with
+//! [`CacheConfig::prefix_index`](crate::CacheConfig::prefix_index).
+//!
+//! It's made of two files under `{cache}/keys-v1`. `sorted` holds every key
+//! as a JSON string per line, in order, and is only ever replaced whole, when
+//! the directory is rebuilt. `journal` is appended to on every write and
+//! removal since then, with a `+` or `-` before each key. Queries binary
+//! search `sorted` for the start of their range, then replay the journal
+//! over what they find there, so they take time proportional to the number
+//! of results and the size of the journal, rather than the whole index.
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+use crate::errors::{Internal, Result};
+
+const KEYS_DIR: &str = "keys-v1";
+const SORTED: &str = "sorted";
+const JOURNAL: &str = "journal";
+
+/// Records that `changes` were written, each being a key and whether it now
+/// has an entry.
+pub(super) fn record(cache: &Path, changes: &[(String, bool)]) -> Result<()> {
+    let dir = cache.join(KEYS_DIR);
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create key directory at {:?}", dir))?;
+    let mut out = Vec::new();
+    for (key, live) in changes {
+        out.push(if *live { b'+' } else { b'-' });
+        out.extend(serde_json::to_vec(key).to_internal()?);
+        out.push(b'\n');
+    }
+    let journal = dir.join(JOURNAL);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&journal)
+        .and_then(|mut fd| fd.write_all(&out))
+        .with_context(|| format!("Failed to write to key journal at {:?}", journal))?;
+    Ok(())
+}
+
+/// Replaces the directory with one listing `keys`, which needn't be sorted.
+/// The journal is removed before `keys` is collected, so `keys` must be an
+/// iterator that only reads the index once it's polled, and anything written
+/// while it runs is journaled again.
+pub(super) fn rebuild<I>(cache: &Path, keys: I) -> Result<()>
+where
+    I: IntoIterator<Item = Result<String>>,
+{
+    let dir = cache.join(KEYS_DIR);
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create key directory at {:?}", dir))?;
+    let journal = dir.join(JOURNAL);
+    match fs::remove_file(&journal) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to remove key journal at {:?}", journal))?
+        }
+    }
+    let mut keys = keys.into_iter().collect::<Result<Vec<_>>>()?;
+    keys.sort();
+    keys.dedup();
+    let mut out = Vec::new();
+    for key in keys {
+        out.extend(serde_json::to_vec(&key).to_internal()?);
+        out.push(b'\n');
+    }
+    let tmp_path = cache.join("tmp");
+    fs::create_dir_all(&tmp_path)
+        .with_context(|| format!("Failed to create tmp directory at {:?}", tmp_path))?;
+    let mut tmp = tempfile::NamedTempFile::new_in(&tmp_path).to_internal()?;
+    tmp.write_all(&out)
+        .with_context(|| format!("Failed to write key directory for {:?}", cache))?;
+    let sorted = dir.join(SORTED);
+    tmp.persist(&sorted)
+        .with_context(|| format!("Failed to replace key directory at {:?}", sorted))?;
+    Ok(())
+}
+
+/// Removes the directory entirely.
+pub(super) fn remove(cache: &Path) -> Result<()> {
+    let dir = cache.join(KEYS_DIR);
+    match fs::remove_dir_all(&dir) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => {
+            Err(err).with_context(|| format!("Failed to remove key directory at {:?}", dir))?
+        }
+    }
+}
+
+/// Lists every key starting with `prefix`, in order, or `None` if the
+/// directory hasn't been built.
+pub(super) fn keys_with_prefix(cache: &Path, prefix: &str) -> Result<Option<Vec<String>>> {
+    let dir = cache.join(KEYS_DIR);
+    let sorted = dir.join(SORTED);
+    if !sorted.exists() {
+        return Ok(None);
+    }
+    // Mapped if it's large, like a bucket. It's only ever replaced whole, so
+    // the map never changes underneath us.
+    let data = super::read_bucket(&sorted)?;
+    let mut keys = BTreeMap::new();
+    let mut pos = lower_bound(&data, prefix);
+    while pos < data.len() {
+        let (key, end) = line_at(&data, pos);
+        match key {
+            Some(key) if key.starts_with(prefix) => keys.insert(key, true),
+            Some(_) => break,
+            // Can't happen, short of tampering, since the file is only ever
+            // replaced whole.
+            None => None,
+        };
+        pos = end;
+    }
+    let journal = dir.join(JOURNAL);
+    let journal = match fs::read(&journal) {
+        Ok(journal) => journal,
+        Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read key journal at {:?}", journal))?
+        }
+    };
+    // Torn lines fail to parse, and are skipped like bad index lines.
+    for line in journal.split(|b| *b == b'\n') {
+        let live = match line.first() {
+            Some(b'+') => true,
+            Some(b'-') => false,
+            _ => continue,
+        };
+        if let Ok(key) = serde_json::from_slice::<String>(&line[1..]) {
+            if key.starts_with(prefix) {
+                keys.insert(key, live);
+            }
+        }
+    }
+    Ok(Some(
+        keys.into_iter()
+            .filter(|(_, live)| *live)
+            .map(|(key, _)| key)
+            .collect(),
+    ))
+}
+
+/// The offset of the first line in `data` whose key isn't less than `key`.
+fn lower_bound(data: &[u8], key: &str) -> usize {
+    // Everything before `lo` is less than `key`, and nothing from `hi` on
+    // is. Both are always at the start of a line.
+    let (mut lo, mut hi) = (0, data.len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let start = data[lo..mid]
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(lo, |i| lo + i + 1);
+        let (line, end) = line_at(data, start);
+        if line.as_deref().unwrap_or_default() < key {
+            lo = end;
+        } else {
+            hi = start;
+        }
+    }
+    lo
+}
+
+/// Decodes the key on the line starting at `start`, returning it along with
+/// the start of the next line.
+fn line_at(data: &[u8], start: usize) -> (Option<String>, usize) {
+    let end = data[start..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .map_or(data.len(), |i| start + i);
+    (
+        serde_json::from_slice(&data[start..end]).ok(),
+        (end + 1).min(data.len()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_prefixes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        assert_eq!(keys_with_prefix(dir, "").unwrap(), None);
+
+        let keys = ["a", "ab", "abc", "b", "ba", "c\nd", "ca"];
+        rebuild(dir, keys.iter().rev().map(|key| Ok(key.to_string()))).unwrap();
+        let found = |prefix| keys_with_prefix(dir, prefix).unwrap().unwrap();
+        assert_eq!(found(""), keys);
+        assert_eq!(found("a"), ["a", "ab", "abc"]);
+        assert_eq!(found("ab"), ["ab", "abc"]);
+        assert_eq!(found("b"), ["b", "ba"]);
+        assert_eq!(found("c"), ["c\nd", "ca"]);
+        assert!(found("aa").is_empty());
+        assert!(found("d").is_empty());
+
+        record(
+            dir,
+            &[
+                ("ab".into(), false),
+                ("aa".into(), true),
+                ("d".into(), true),
+                ("d".into(), false),
+                ("b".into(), false),
+                ("b".into(), true),
+            ],
+        )
+        .unwrap();
+        assert_eq!(found("a"), ["a", "aa", "abc"]);
+        assert_eq!(found("b"), ["b", "ba"]);
+        assert!(found("d").is_empty());
+
+        rebuild(dir, std::iter::empty()).unwrap();
+        assert!(found("").is_empty());
+        remove(dir).unwrap();
+        assert_eq!(keys_with_prefix(dir, "").unwrap(), None);
+    }
+}
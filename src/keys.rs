@@ -0,0 +1,346 @@
+//! Functions for moving index entries between keys without touching content.
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::Value;
+use ssri::Integrity;
+
+use crate::errors::{Error, Result};
+use crate::index::{self, Metadata};
+use crate::put::WriteOpts;
+
+/// Moves the entry for `old` over to `new`, keeping its data, time,
+/// metadata and everything else, so keys can be migrated to a new scheme
+/// without reading or hashing content that's already there. The entry is
+/// inserted under `new` before `old` is removed, so a crash in between
+/// leaves both keys rather than neither, and a cache with an
+/// [`index_journal`](crate::CacheConfig::index_journal) ends up with just
+/// one of them. Signatures cover the key, so a
+/// signed entry loses its signature and needs to be signed again.
+///
+/// Fails with [`Error::EntryNotFound`] if `old` has no entry.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     cacache_sync::write("./my-cache", "v1:my-key", b"hello")?;
+///     cacache_sync::rename_key("./my-cache", "v1:my-key", "v2:my-key")?;
+///     assert_eq!(cacache_sync::read("./my-cache", "v2:my-key")?, b"hello");
+///     Ok(())
+/// }
+/// ```
+pub fn rename_key<P, O, N>(cache: P, old: O, new: N) -> Result<Integrity>
+where
+    P: AsRef<Path>,
+    O: AsRef<str>,
+    N: AsRef<str>,
+{
+    let (cache, old, new) = (cache.as_ref(), old.as_ref(), new.as_ref());
+    let entry = index::find(cache, old)?
+        .ok_or_else(|| Error::EntryNotFound(cache.to_path_buf(), old.to_owned()))?;
+    if old == new {
+        return Ok(entry.integrity);
+    }
+    let batch = vec![
+        (new.to_owned(), entry_opts(entry)),
+        (old.to_owned(), index::removal()),
+    ];
+    Ok(index::insert_batch(cache, batch)?.remove(0))
+}
+
+/// Duplicates the entry for `src` under `dst` without any content I/O, such
+/// as to promote an artifact from `staging:` to `release:`. The copy refers
+/// to the same data and keeps the metadata, tags and time to live, but gets a
+/// fresh time. Like [`rename_key`], signatures aren't carried over.
+///
+/// Fails with [`Error::EntryNotFound`] if `src` has no entry.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     cacache_sync::write("./my-cache", "staging:app.tar", b"...")?;
+///     cacache_sync::copy_entry("./my-cache", "staging:app.tar", "release:app.tar")?;
+///     Ok(())
+/// }
+/// ```
+pub fn copy_entry<P, S, D>(cache: P, src: S, dst: D) -> Result<Integrity>
+where
+    P: AsRef<Path>,
+    S: AsRef<str>,
+    D: AsRef<str>,
+{
+    copy_entry_with(cache, src, dst, |metadata| metadata)
+}
+
+/// Like [`copy_entry`], but passes the metadata through `f` on the way, so
+/// the copy can record where it came from or drop fields that only made
+/// sense under `src`. A [`crate::IndexFormat::Fixed`] index can't store
+/// metadata, so there `f` has to return [`Value::Null`].
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     cacache_sync::copy_entry_with("./my-cache", "staging:app.tar", "release:app.tar", |_| {
+///         serde_json::json!({ "promoted_from": "staging:app.tar" })
+///     })?;
+///     Ok(())
+/// }
+/// ```
+pub fn copy_entry_with<P, S, D, F>(cache: P, src: S, dst: D, f: F) -> Result<Integrity>
+where
+    P: AsRef<Path>,
+    S: AsRef<str>,
+    D: AsRef<str>,
+    F: FnOnce(Value) -> Value,
+{
+    let (cache, src) = (cache.as_ref(), src.as_ref());
+    let mut entry = index::find(cache, src)?
+        .ok_or_else(|| Error::EntryNotFound(cache.to_path_buf(), src.to_owned()))?;
+    entry.metadata = f(entry.metadata);
+    let opts = WriteOpts {
+        time: None,
+        ..entry_opts(entry)
+    };
+    index::insert(cache, dst.as_ref(), opts)
+}
+
+/// Rewrites keys across the whole index in one pass: the entry for every
+/// key that `f` maps to `Some` new key is moved over to it, like
+/// [`rename_key`] but without a lookup per key. Keys that `f` maps to `None`
+/// are left alone. Returns how many entries were moved.
+///
+/// An entry moved onto a key that already has one replaces it, and of
+/// several moved onto the same key, the newest wins. Like
+/// [`crate::repack`], this shouldn't run while anything else is writing to
+/// the cache. Caches with a [`crate::IndexFormat::Fixed`] index only keep
+/// hashes of keys, so they fail with [`Error::InvalidConfig`].
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let moved = cacache_sync::remap_keys("./my-cache", |key| {
+///         key.strip_prefix("v1:").map(|rest| format!("v2:{}", rest))
+///     })?;
+///     println!("migrated {} keys", moved);
+///     Ok(())
+/// }
+/// ```
+pub fn remap_keys<P, F>(cache: P, mut f: F) -> Result<usize>
+where
+    P: AsRef<Path>,
+    F: FnMut(&str) -> Option<String>,
+{
+    index::remap(cache.as_ref(), &mut f)
+}
+
+/// Options that write `entry` out again as it is, under whichever key.
+fn entry_opts(entry: Metadata) -> WriteOpts {
+    let ttl = entry
+        .expires
+        .map(|expires| Duration::from_millis(expires.saturating_sub(entry.time) as u64));
+    WriteOpts {
+        sri: Some(entry.integrity).filter(|_| !entry.metadata_only),
+        size: Some(entry.size),
+        time: Some(entry.time),
+        // Entries without metadata have to stay that way, or they couldn't
+        // be written to a fixed-format index.
+        metadata: Some(entry.metadata).filter(|metadata| !metadata.is_null()),
+        metadata_only: entry.metadata_only,
+        external: entry.external,
+        content_type: entry.content_type,
+        tags: entry.tags,
+        ttl,
+        ..WriteOpts::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn renames_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut writer = WriteOpts::new()
+            .metadata("meta")
+            .tag("tagged")
+            .time(1234)
+            .ttl(Duration::from_secs(10))
+            .open(dir, "old")
+            .unwrap();
+        writer.write_all(b"hello").unwrap();
+        let sri = writer.commit().unwrap();
+        let before = crate::metadata(dir, "old").unwrap().unwrap();
+
+        assert_eq!(rename_key(dir, "old", "new").unwrap(), sri);
+        assert!(crate::metadata(dir, "old").unwrap().is_none());
+        let after = crate::metadata(dir, "new").unwrap().unwrap();
+        assert_eq!(after.key, "new");
+        assert_eq!(
+            Metadata {
+                key: "old".into(),
+                ..after
+            },
+            before
+        );
+        assert_eq!(crate::read(dir, "new").unwrap(), b"hello");
+
+        crate::write_metadata_only(dir, "empty", "no data").unwrap();
+        rename_key(dir, "empty", "still-empty").unwrap();
+        assert!(
+            crate::metadata(dir, "still-empty")
+                .unwrap()
+                .unwrap()
+                .metadata_only
+        );
+
+        assert_eq!(rename_key(dir, "new", "new").unwrap(), sri);
+        assert!(matches!(
+            rename_key(dir, "old", "newer"),
+            Err(Error::EntryNotFound(_, key)) if key == "old"
+        ));
+    }
+
+    #[test]
+    fn renames_fixed_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let config = crate::CacheConfig::new().index_format(crate::IndexFormat::Fixed);
+        crate::configure(dir, config).unwrap();
+        let sri = crate::write(dir, "old", b"hello").unwrap();
+
+        assert_eq!(rename_key(dir, "old", "new").unwrap(), sri);
+        assert!(crate::metadata(dir, "old").unwrap().is_none());
+        assert_eq!(crate::read(dir, "new").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn copies_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut writer = WriteOpts::new()
+            .metadata("meta")
+            .tag("tagged")
+            .time(1234)
+            .open(dir, "staging:app")
+            .unwrap();
+        writer.write_all(b"hello").unwrap();
+        let sri = writer.commit().unwrap();
+        let modified = std::fs::metadata(crate::content_path(dir, &sri).unwrap())
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        assert_eq!(copy_entry(dir, "staging:app", "release:app").unwrap(), sri);
+        let copy = crate::metadata(dir, "release:app").unwrap().unwrap();
+        assert_eq!(copy.integrity, sri);
+        assert_eq!(copy.metadata, "meta");
+        assert_eq!(copy.tags, ["tagged"]);
+        assert!(copy.time > 1234);
+        assert_eq!(
+            crate::metadata(dir, "staging:app").unwrap().unwrap().time,
+            1234
+        );
+        assert_eq!(crate::read(dir, "release:app").unwrap(), b"hello");
+        assert_eq!(
+            std::fs::metadata(crate::content_path(dir, &sri).unwrap())
+                .unwrap()
+                .modified()
+                .unwrap(),
+            modified
+        );
+
+        copy_entry_with(
+            dir,
+            "staging:app",
+            "archive:app",
+            |metadata| serde_json::json!({ "from": "staging:app", "was": metadata }),
+        )
+        .unwrap();
+        assert_eq!(
+            crate::metadata(dir, "archive:app")
+                .unwrap()
+                .unwrap()
+                .metadata,
+            serde_json::json!({ "from": "staging:app", "was": "meta" })
+        );
+        assert!(matches!(
+            copy_entry(dir, "missing", "elsewhere"),
+            Err(Error::EntryNotFound(_, key)) if key == "missing"
+        ));
+    }
+
+    #[test]
+    fn copies_fixed_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let config = crate::CacheConfig::new().index_format(crate::IndexFormat::Fixed);
+        crate::configure(dir, config).unwrap();
+        let sri = crate::write(dir, "staging:app", b"hello").unwrap();
+
+        assert_eq!(copy_entry(dir, "staging:app", "release:app").unwrap(), sri);
+        assert_eq!(crate::read(dir, "release:app").unwrap(), b"hello");
+        assert_eq!(crate::read(dir, "staging:app").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn remaps_keys() {
+        for format in [crate::IndexFormat::Json, crate::IndexFormat::Binary] {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path();
+            crate::configure(dir, crate::CacheConfig::new().index_format(format)).unwrap();
+            for i in 0..20 {
+                crate::write(dir, format!("v1:{}", i), format!("data {}", i)).unwrap();
+            }
+            crate::write(dir, "v1:0", b"newer").unwrap();
+            crate::write(dir, "v2:5", b"replaced").unwrap();
+            crate::write(dir, "other", b"untouched").unwrap();
+            crate::remove(dir, "v1:19").unwrap();
+            crate::write_metadata_only(dir, "v1:empty", "no data").unwrap();
+
+            let moved = remap_keys(dir, |key| {
+                key.strip_prefix("v1:").map(|rest| format!("v2:{}", rest))
+            })
+            .unwrap();
+            assert_eq!(moved, 20);
+
+            let mut keys = crate::list(dir)
+                .map(|entry| entry.unwrap().key)
+                .collect::<Vec<_>>();
+            keys.sort();
+            let mut expected = (0..19)
+                .map(|i| format!("v2:{}", i))
+                .chain(["v2:empty".to_owned(), "other".to_owned()])
+                .collect::<Vec<_>>();
+            expected.sort();
+            assert_eq!(keys, expected);
+            assert_eq!(crate::read(dir, "v2:0").unwrap(), b"newer");
+            assert_eq!(crate::read(dir, "v2:5").unwrap(), b"data 5");
+            assert_eq!(crate::read(dir, "v2:7").unwrap(), b"data 7");
+            assert_eq!(crate::read(dir, "other").unwrap(), b"untouched");
+            assert!(
+                crate::metadata(dir, "v2:empty")
+                    .unwrap()
+                    .unwrap()
+                    .metadata_only
+            );
+            assert!(crate::metadata(dir, "v1:3").unwrap().is_none());
+
+            assert_eq!(remap_keys(dir, |_| None).unwrap(), 0);
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        crate::configure(
+            dir,
+            crate::CacheConfig::new().index_format(crate::IndexFormat::Fixed),
+        )
+        .unwrap();
+        assert!(matches!(
+            remap_keys(dir, |key| Some(key.to_uppercase())),
+            Err(Error::InvalidConfig(..))
+        ));
+    }
+}
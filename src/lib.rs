@@ -20,6 +20,10 @@
 //!   index and its metadata. These functions use an `Integrity` to look up
 //!   data, instead of a string key.
 //!
+//! Unlike upstream `cacache`, there is no `_sync` suffix: every function is
+//! synchronous, so e.g. `cacache::remove_sync` is simply
+//! `cacache_sync::remove`, and `cacache::clear_sync` is `cacache_sync::clear`.
+//!
 //! ## Examples
 //!
 //! ```no_run
@@ -86,22 +90,81 @@
 //! }
 //! ```
 
+#[cfg(feature = "signing")]
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
 pub use serde_json::Value;
 pub use ssri::Algorithm;
 
+mod cache;
+mod config;
 mod content;
+mod dedupe;
 mod errors;
+mod fdpool;
+#[cfg(feature = "http-client")]
+mod fetch;
+mod flight;
+#[cfg(all(feature = "fuse", unix))]
+mod fuse;
+mod gc;
 mod index;
+mod keys;
+mod lock;
+mod memo;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod mmap;
+mod overlay;
 
 mod get;
+#[cfg(feature = "http-cache")]
+mod http_manager;
 mod ls;
 mod put;
+mod quarantine;
+mod repack;
+mod retry;
 mod rm;
+mod secondary;
+#[cfg(feature = "signing")]
+mod signing;
+mod snapshot;
+mod stats;
+mod warm;
+#[cfg(feature = "notify")]
+mod watch;
+
+pub mod prelude;
 
 pub use errors::{Error, Result};
-pub use index::Metadata;
+pub use index::{recover_index, Metadata};
 
+pub use cache::*;
+pub use config::*;
+pub use dedupe::*;
+#[cfg(feature = "http-client")]
+pub use fetch::*;
+#[cfg(all(feature = "fuse", unix))]
+pub use fuse::*;
+pub use gc::*;
 pub use get::*;
+#[cfg(feature = "http-cache")]
+pub use http_manager::*;
+pub use keys::*;
 pub use ls::*;
+#[cfg(feature = "metrics")]
+pub use metrics::*;
+pub use overlay::*;
 pub use put::*;
+pub use quarantine::*;
+pub use repack::*;
+pub use retry::*;
 pub use rm::*;
+pub use secondary::*;
+#[cfg(feature = "signing")]
+pub use signing::*;
+pub use snapshot::*;
+pub use stats::*;
+pub use warm::*;
+#[cfg(feature = "notify")]
+pub use watch::*;
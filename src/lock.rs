@@ -0,0 +1,363 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::errors::{Internal, Result};
+
+/// How long to wait for a lock before giving up.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where there's no telling whether the process that holds a lock is still
+/// running, locks older than this are assumed to belong to one that died
+/// while holding them, and are broken. Lock files that haven't had their
+/// token written yet are judged the same way everywhere.
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+/// A cross-process advisory lock, held for as long as the lock file exists.
+/// Lock files are created atomically with `create_new`, so this works across
+/// threads as well as processes, on every platform. The lock is released when
+/// dropped.
+///
+/// Each lock file holds a [`token`] naming its holder. On unix, a lock is
+/// only ever broken once the process named in it has exited, so it can be
+/// held for as long as needed. A lock naming this process, but that it
+/// doesn't hold, was left by an earlier process with the same id, like
+/// the first process of a container that's since been restarted, and is
+/// broken too. Elsewhere, locks older than `STALE_AFTER` are
+/// broken, so they shouldn't be held that long. Either way, a lock is only
+/// removed by its holder, or by breaking it, which moves it aside first and
+/// puts it back if it turns out not to be the stale lock it was taken for.
+pub struct Lock {
+    path: PathBuf,
+    token: Token,
+}
+
+impl Lock {
+    pub fn acquire(path: &Path) -> Result<Lock> {
+        let start = Instant::now();
+        let token = token();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(mut file) => {
+                    // The lock is held from here on, so it's dropped if the
+                    // token can't be written.
+                    let lock = Lock {
+                        path: path.to_path_buf(),
+                        token,
+                    };
+                    file.write_all(lock.token.as_bytes())
+                        .with_context(|| format!("Failed to write lock at {:?}", path))?;
+                    return Ok(lock);
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    if break_stale(path, &token) {
+                        continue;
+                    }
+                    if start.elapsed() > LOCK_TIMEOUT {
+                        return Err(std::io::Error::new(
+                            ErrorKind::TimedOut,
+                            "timed out waiting for lock",
+                        ))
+                        .with_context(|| format!("Failed to acquire lock at {:?}", path))?;
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    let parent = path.parent().unwrap_or(path);
+                    fs::create_dir_all(parent).with_context(|| {
+                        format!("Failed to create lock directory at {:?}", parent)
+                    })?;
+                }
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Failed to acquire lock at {:?}", path))?
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // If this lock was broken, the file might be someone else's by now.
+        if matches!(fs::read_to_string(&self.path), Ok(held) if held == *self.token) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Tokens handed out by [`token`] that haven't been dropped yet.
+static HELD: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// A string unique to this call, made up of the id of this process (0 where
+/// processes have none), when it started where that can be told, and a
+/// nonce, for telling apart the owners of things that have to be cleaned up
+/// after processes that die, like locks. See [`owner_exited`]. The token
+/// counts as held by this process until it's dropped.
+pub(crate) fn token() -> Token {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or(0);
+    let pid = pid();
+    let token = format!(
+        "{}-{}-{:x}-{:x}",
+        pid.unwrap_or(0),
+        pid.and_then(start_time)
+            .map_or_else(String::new, |start| format!("{:x}", start)),
+        nanos,
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    );
+    HELD.lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get_or_insert_with(HashSet::new)
+        .insert(token.clone());
+    Token(token)
+}
+
+/// A token from [`token`], held until it's dropped.
+pub(crate) struct Token(String);
+
+impl Deref for Token {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        if let Some(held) = HELD.lock().unwrap_or_else(PoisonError::into_inner).as_mut() {
+            held.remove(&self.0);
+        }
+    }
+}
+
+/// Whether `token` was made by this process, and hasn't been dropped yet.
+pub(crate) fn is_held(token: &str) -> bool {
+    matches!(
+        HELD.lock().unwrap_or_else(PoisonError::into_inner).as_ref(),
+        Some(held) if held.contains(token)
+    )
+}
+
+/// Whether the process that made `token` has exited, or `None` if that
+/// can't be told, either because it's not a token, or because this platform
+/// has no way to check. A token naming this process that it doesn't hold is
+/// taken to be from an earlier one that had the same id, and so to have
+/// exited. So is one naming a process that's running, but started at a
+/// different time than the one that made it.
+pub(crate) fn owner_exited(token: &str) -> Option<bool> {
+    let parts = token.split('-').collect::<Vec<_>>();
+    let pid = parts.first()?.parse::<u32>().ok()?;
+    let start = match parts[..] {
+        [_, start, _, _] => u64::from_str_radix(start, 16).ok(),
+        // Tokens from older versions don't have the start time.
+        _ => None,
+    };
+    if is_held(token) {
+        return Some(false);
+    }
+    if Some(pid) == self::pid() {
+        return Some(true);
+    }
+    match process_exited(pid)? {
+        false => match (start, start_time(pid)) {
+            (Some(start), Some(running)) => Some(start != running),
+            _ => Some(false),
+        },
+        true => Some(true),
+    }
+}
+
+/// The id of this process, if this platform gives processes ids. WASI
+/// doesn't, and `std::process::id` panics there.
+#[cfg(not(target_os = "wasi"))]
+fn pid() -> Option<u32> {
+    Some(std::process::id())
+}
+
+#[cfg(target_os = "wasi")]
+fn pid() -> Option<u32> {
+    None
+}
+
+/// When the process with `pid` started, in clock ticks since boot, if it's
+/// running and this platform can tell.
+#[cfg(target_os = "linux")]
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name before this can hold spaces and parentheses of its
+    // own. The start time is the 22nd field, and the state the 3rd.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+fn process_exited(pid: u32) -> Option<bool> {
+    let pid = libc::pid_t::try_from(pid).ok().filter(|pid| *pid > 0)?;
+    // Signal 0 only checks whether the process could be signalled.
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return Some(false);
+    }
+    match std::io::Error::last_os_error().raw_os_error() {
+        Some(libc::ESRCH) => Some(true),
+        // It's there, it just belongs to someone else.
+        Some(libc::EPERM) => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn process_exited(_pid: u32) -> Option<bool> {
+    None
+}
+
+/// Whether `path` was last modified more than `STALE_AFTER` ago.
+fn is_old(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map(|age| age > STALE_AFTER)
+        .unwrap_or(false)
+}
+
+/// Breaks the lock at `path` if it's stale, on behalf of the waiter with
+/// `token`. Returns whether it's worth trying to take the lock again right
+/// away.
+fn break_stale(path: &Path, token: &str) -> bool {
+    let held = match fs::read_to_string(path) {
+        Ok(held) => held,
+        // Released since.
+        Err(err) if err.kind() == ErrorKind::NotFound => return true,
+        Err(_) => return false,
+    };
+    let stale = match owner_exited(&held) {
+        Some(exited) => exited,
+        None => is_old(path),
+    };
+    if !stale {
+        return false;
+    }
+    // Moved aside rather than removed, so it can be checked that it's still
+    // the same lock, rather than one taken since by another waiter that
+    // broke it first.
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(format!(".{}.stale", token));
+    let aside = PathBuf::from(aside);
+    if fs::rename(path, &aside).is_err() {
+        return true;
+    }
+    if !matches!(fs::read_to_string(&aside), Ok(moved) if moved == held) {
+        // Another waiter broke the stale lock first and took the lock
+        // itself, so put that back. This only fails if yet another waiter
+        // took the lock in the moment it was gone, which takes three of them
+        // racing over the same stale lock.
+        let _ = fs::hard_link(&aside, path);
+    }
+    let _ = fs::remove_file(&aside);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    #[test]
+    fn lock_excludes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("test.lock");
+        let counter = Arc::new(AtomicUsize::new(0));
+        let handles = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                let counter = counter.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        let _lock = Lock::acquire(&path).unwrap();
+                        assert_eq!(counter.fetch_add(1, Ordering::SeqCst), 0);
+                        counter.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn breaks_only_stale_locks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("test.lock");
+
+        // Held by a live process, however old, it's left alone.
+        let lock = Lock::acquire(&path).unwrap();
+        assert_eq!(owner_exited(&lock.token), Some(false));
+        assert!(!break_stale(&path, &token()));
+        assert!(path.exists());
+
+        // A lock that isn't ours anymore isn't ours to remove.
+        fs::write(&path, &*token()).unwrap();
+        drop(lock);
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+
+        // Held by a process that's gone, it's broken.
+        #[cfg(unix)]
+        {
+            let mut child = std::process::Command::new("true").spawn().unwrap();
+            let pid = child.id();
+            child.wait().unwrap();
+            fs::write(&path, format!("{}-0-0", pid)).unwrap();
+            assert_eq!(owner_exited(&format!("{}-0-0", pid)), Some(true));
+            let lock = Lock::acquire(&path).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), *lock.token);
+        }
+    }
+
+    #[test]
+    fn breaks_locks_left_under_reused_pids() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("test.lock");
+
+        // Left by an earlier process with this one's id.
+        let left = token().to_string();
+        fs::write(&path, &left).unwrap();
+        assert_eq!(owner_exited(&left), Some(true));
+        let lock = Lock::acquire(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), *lock.token);
+
+        // Held by another thread of this process, it's left alone.
+        assert_eq!(owner_exited(&lock.token), Some(false));
+        assert!(!break_stale(&path, &token()));
+        drop(lock);
+
+        // Naming a running process that started after it was taken.
+        #[cfg(target_os = "linux")]
+        {
+            let parent = std::os::unix::process::parent_id();
+            let start = start_time(parent).unwrap();
+            let held = format!("{}-{:x}-0-0", parent, start);
+            assert_eq!(owner_exited(&held), Some(false));
+            let left = format!("{}-{:x}-0-0", parent, start + 1);
+            assert_eq!(owner_exited(&left), Some(true));
+        }
+    }
+}
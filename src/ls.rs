@@ -1,7 +1,11 @@
 //! Functions for iterating over the cache.
 use std::path::Path;
 
-use crate::errors::Result;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use ssri::Integrity;
+
+use crate::errors::{Internal, Result};
 use crate::index;
 
 /// Returns a synchronous iterator that lists all cache index entries.
@@ -9,6 +13,202 @@ pub fn list<P: AsRef<Path>>(cache: P) -> impl Iterator<Item = Result<index::Meta
     index::ls(cache.as_ref())
 }
 
+/// Lists the index entries whose keys start with `prefix`, ordered by key.
+/// Caches configured with [`crate::CacheConfig::prefix_index`] find them in
+/// time proportional to how many there are. Others have to scan the whole
+/// index.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     for entry in cacache_sync::list_prefix("./my-cache", "https://example.com/")? {
+///         println!("{}", entry.key);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn list_prefix<P, S>(cache: P, prefix: S) -> Result<Vec<index::Metadata>>
+where
+    P: AsRef<Path>,
+    S: AsRef<str>,
+{
+    let cache = cache.as_ref();
+    let mut entries = Vec::new();
+    for key in index::keys_with_prefix(cache, prefix.as_ref())? {
+        // A write can land between reading the keys and their entries.
+        if let Some(entry) = index::find(cache, &key)? {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Lists every key in the cache, in order, without reading their entries.
+/// Like [`list_prefix`], this is much faster for caches configured with
+/// [`crate::CacheConfig::prefix_index`]. Keys in a fixed-format index are
+/// hex-encoded hashes, like in [`list`].
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let keys = cacache_sync::keys("./my-cache")?;
+///     println!("{} keys", keys.len());
+///     Ok(())
+/// }
+/// ```
+pub fn keys<P: AsRef<Path>>(cache: P) -> Result<Vec<String>> {
+    index::keys_with_prefix(cache.as_ref(), "")
+}
+
+/// Returns a synchronous iterator that lists the cache index entries that were
+/// written with `tag`.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     for entry in cacache_sync::list_by_tag("./my-cache", "build-1234") {
+///         println!("{}", entry?.key);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn list_by_tag<P, T>(cache: P, tag: T) -> impl Iterator<Item = Result<index::Metadata>>
+where
+    P: AsRef<Path>,
+    T: AsRef<str>,
+{
+    index::ls(cache.as_ref()).filter(move |entry| match entry {
+        Ok(entry) => entry.tags.iter().any(|t| t == tag.as_ref()),
+        Err(_) => true,
+    })
+}
+
+/// Returns a synchronous iterator that lists the cache index entries whose
+/// metadata has `expected` at the JSON pointer `pointer`, such as `/etag`
+/// for the `etag` field of an object. See [`serde_json::Value::pointer`] for
+/// the syntax. Entries are checked as their buckets are parsed, so the ones
+/// that don't match never make it out of the index.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     for entry in cacache_sync::list_where("./my-cache", "/etag", "\"abc123\"") {
+///         println!("{}", entry?.key);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn list_where<P, V>(
+    cache: P,
+    pointer: &str,
+    expected: V,
+) -> impl Iterator<Item = Result<index::Metadata>>
+where
+    P: AsRef<Path>,
+    V: Into<Value>,
+{
+    let pointer = pointer.to_owned();
+    let expected = expected.into();
+    index::ls_where(cache.as_ref(), move |metadata| {
+        metadata.pointer(&pointer) == Some(&expected)
+    })
+}
+
+/// Returns a synchronous iterator that lists the cache index entries whose
+/// metadata passes `filter`, checked as their buckets are parsed like
+/// [`list_where`], for queries that aren't a single equality.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let large = cacache_sync::list_filtered("./my-cache", |metadata| {
+///         matches!(metadata["content-length"].as_u64(), Some(len) if len > 1_000_000)
+///     });
+///     for entry in large {
+///         println!("{}", entry?.key);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn list_filtered<P, F>(cache: P, filter: F) -> impl Iterator<Item = Result<index::Metadata>>
+where
+    P: AsRef<Path>,
+    F: Fn(&Value) -> bool,
+{
+    index::ls_where(cache.as_ref(), filter)
+}
+
+/// Returns a synchronous iterator that lists all cache index entries, like
+/// [`list`], with each entry's metadata deserialized into a `T`. Entries whose
+/// metadata doesn't fit `T` are reported as errors.
+///
+/// ## Example
+/// ```no_run
+/// #[derive(serde::Deserialize)]
+/// struct Headers {
+///     etag: String,
+/// }
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     for entry in cacache_sync::list_as::<_, Headers>("./my-cache") {
+///         let (key, _sri, headers) = entry?;
+///         println!("{}: {}", key, headers.etag);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn list_as<P, T>(cache: P) -> impl Iterator<Item = Result<(String, Integrity, T)>>
+where
+    P: AsRef<Path>,
+    T: DeserializeOwned,
+{
+    index::ls(cache.as_ref()).map(|entry| {
+        let entry = entry?;
+        let metadata = serde_json::from_value(entry.metadata)
+            .with_context(|| format!("Failed to deserialize metadata for key {}", entry.key))?;
+        Ok((entry.key, entry.integrity, metadata))
+    })
+}
+
+/// Returns a `futures::Stream` that lists all cache index entries, like
+/// [`list`]. The index is walked on a background thread, a bounded number of
+/// entries ahead of the consumer, so awaiting the stream never blocks an async
+/// executor thread on filesystem access.
+///
+/// ## Example
+/// ```no_run
+/// use futures::StreamExt;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     futures::executor::block_on(async {
+///         let mut entries = cacache_sync::list_stream("./my-cache");
+///         while let Some(entry) = entries.next().await {
+///             println!("{}", entry?.key);
+///         }
+///         Ok(())
+///     })
+/// }
+/// ```
+#[cfg(feature = "stream")]
+pub fn list_stream<P: AsRef<Path>>(
+    cache: P,
+) -> impl futures::Stream<Item = Result<index::Metadata>> + Send + Unpin {
+    use futures::SinkExt;
+
+    const BUFFER: usize = 64;
+    let cache = cache.as_ref().to_path_buf();
+    let (mut tx, rx) = futures::channel::mpsc::channel(BUFFER);
+    std::thread::spawn(move || {
+        for entry in index::ls(&cache) {
+            // The receiver is gone, so nobody wants the rest.
+            if futures::executor::block_on(tx.send(entry)).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -25,4 +225,157 @@ mod tests {
             .collect::<Result<Vec<_>>>()
             .is_err())
     }
+
+    #[test]
+    fn test_list_by_tag() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        for (key, tag) in [("a", "one"), ("b", "two"), ("c", "one")] {
+            let mut fd = crate::WriteOpts::new()
+                .tag(tag)
+                .tag("all")
+                .open(&dir, key)
+                .unwrap();
+            std::io::Write::write_all(&mut fd, b"hello").unwrap();
+            fd.commit().unwrap();
+        }
+        crate::write(&dir, "untagged", b"hello").unwrap();
+
+        let keys = |tag| {
+            let mut keys = list_by_tag(&dir, tag)
+                .map(|entry| Ok(entry?.key))
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys("one"), vec!["a", "c"]);
+        assert_eq!(keys("all"), vec!["a", "b", "c"]);
+        assert!(keys("none").is_empty());
+    }
+
+    #[test]
+    fn test_list_prefix() {
+        for prefix_index in [false, true] {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path().to_owned();
+            assert!(keys(&dir).unwrap().is_empty());
+            crate::write(&dir, "early/1", b"hello").unwrap();
+            crate::configure(&dir, crate::CacheConfig::new().prefix_index(prefix_index)).unwrap();
+            for key in ["b/2", "a/1", "b/1", "a/2", "c"] {
+                crate::write(&dir, key, b"hello").unwrap();
+            }
+            crate::remove(&dir, "a/2").unwrap();
+            assert_eq!(dir.join("keys-v1").exists(), prefix_index);
+
+            let found = |prefix| {
+                list_prefix(&dir, prefix)
+                    .unwrap()
+                    .into_iter()
+                    .map(|entry| entry.key)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(found("a/"), ["a/1"]);
+            assert_eq!(found("b/"), ["b/1", "b/2"]);
+            assert_eq!(found("early"), ["early/1"]);
+            assert!(found("d").is_empty());
+            assert_eq!(keys(&dir).unwrap(), ["a/1", "b/1", "b/2", "c", "early/1"]);
+
+            crate::repack(&dir).unwrap();
+            crate::rename_key(&dir, "c", "a/3").unwrap();
+            assert_eq!(found("a/"), ["a/1", "a/3"]);
+            crate::clear_index(&dir).unwrap();
+            assert!(keys(&dir).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_list_where() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        for (key, etag, len) in [("a", "x", 10), ("b", "y", 20), ("c", "x", 30)] {
+            let mut fd = crate::WriteOpts::new()
+                .metadata(serde_json::json!({ "etag": etag, "len": len }))
+                .open(&dir, key)
+                .unwrap();
+            std::io::Write::write_all(&mut fd, b"hello").unwrap();
+            fd.commit().unwrap();
+        }
+        crate::write(&dir, "plain", b"hello").unwrap();
+        // Only the latest entry for a key counts.
+        let mut fd = crate::WriteOpts::new()
+            .metadata(serde_json::json!({ "etag": "y" }))
+            .open(&dir, "c")
+            .unwrap();
+        std::io::Write::write_all(&mut fd, b"hello").unwrap();
+        fd.commit().unwrap();
+
+        let keys = |entries: &mut dyn Iterator<Item = Result<index::Metadata>>| {
+            let mut keys = entries
+                .map(|entry| Ok(entry?.key))
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys(&mut list_where(&dir, "/etag", "x")), vec!["a"]);
+        assert_eq!(keys(&mut list_where(&dir, "/etag", "y")), vec!["b", "c"]);
+        assert_eq!(keys(&mut list_where(&dir, "/len", 20)), vec!["b"]);
+        assert!(keys(&mut list_where(&dir, "/missing", "x")).is_empty());
+        assert_eq!(
+            keys(&mut list_filtered(&dir, |metadata| {
+                matches!(metadata["len"].as_u64(), Some(len) if len < 25)
+            })),
+            vec!["a", "b"]
+        );
+    }
+
+    #[test]
+    fn test_list_as() {
+        #[derive(serde::Deserialize)]
+        struct Headers {
+            etag: String,
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::WriteOpts::new()
+            .metadata(serde_json::json!({ "etag": "abc" }))
+            .open(&dir, "tagged")
+            .and_then(|mut fd| {
+                std::io::Write::write_all(&mut fd, b"hello").unwrap();
+                fd.commit()
+            })
+            .unwrap();
+
+        let entries = list_as::<_, Headers>(&dir)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, "tagged");
+        assert_eq!(entries[0].1, sri);
+        assert_eq!(entries[0].2.etag, "abc");
+
+        crate::write(&dir, "untagged", b"hello").unwrap();
+        assert!(list_as::<_, Headers>(&dir).any(|entry| entry.is_err()));
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn test_list_stream() {
+        use futures::StreamExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        for i in 0..100 {
+            crate::write(&dir, format!("key-{}", i), b"hello").unwrap();
+        }
+        let mut keys = futures::executor::block_on(list_stream(&dir).collect::<Vec<_>>())
+            .into_iter()
+            .map(|entry| entry.unwrap().key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        let mut expected = (0..100).map(|i| format!("key-{}", i)).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
 }
@@ -0,0 +1,139 @@
+//! A bounded, in-process LRU of small content objects, for [`crate::Cache`]
+//! handles that serve the same data over and over.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, PoisonError};
+
+use ssri::Integrity;
+
+pub(crate) struct MemoryCache {
+    capacity: usize,
+    max_entry_size: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // sri -> (data, last use)
+    entries: HashMap<String, (Vec<u8>, u64)>,
+    // last use -> sri, oldest first
+    order: BTreeMap<u64, String>,
+    size: usize,
+    tick: u64,
+    // Bumped by every removal, so reads that raced one don't put back what
+    // it removed.
+    generation: u64,
+}
+
+impl MemoryCache {
+    pub(crate) fn new(capacity: usize, max_entry_size: usize) -> MemoryCache {
+        MemoryCache {
+            capacity,
+            max_entry_size,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub(crate) fn get(&self, sri: &Integrity) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        state.tick += 1;
+        let (data, last_use) = state.entries.get_mut(&sri.to_string())?;
+        let key = state.order.remove(last_use)?;
+        *last_use = state.tick;
+        state.order.insert(state.tick, key);
+        Some(data.clone())
+    }
+
+    /// The current generation, to pass to [`MemoryCache::insert`] for data
+    /// that's read after this is called.
+    pub(crate) fn generation(&self) -> u64 {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .generation
+    }
+
+    /// Remembers `data` for `sri`, if it's small enough, evicting the least
+    /// recently used entries to make room. Data read before `generation`
+    /// ended might have been removed since, so it's dropped instead.
+    pub(crate) fn insert(&self, sri: &Integrity, data: &[u8], generation: u64) {
+        if data.len() > self.max_entry_size || data.len() > self.capacity {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.generation != generation {
+            return;
+        }
+        let key = sri.to_string();
+        state.remove(&key);
+        while state.size + data.len() > self.capacity {
+            let oldest = match state.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            state.remove(&oldest);
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.size += data.len();
+        state.order.insert(tick, key.clone());
+        state.entries.insert(key, (data.to_vec(), tick));
+    }
+
+    pub(crate) fn remove(&self, sri: &Integrity) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.remove(&sri.to_string());
+        state.generation += 1;
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        *state = State {
+            generation: state.generation + 1,
+            ..State::default()
+        };
+    }
+}
+
+impl State {
+    fn remove(&mut self, key: &str) {
+        if let Some((data, last_use)) = self.entries.remove(key) {
+            self.order.remove(&last_use);
+            self.size -= data.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let memo = MemoryCache::new(10, 5);
+        let a = Integrity::from(b"aaaa");
+        let b = Integrity::from(b"bbbb");
+        let c = Integrity::from(b"cccc");
+        memo.insert(&a, b"aaaa", memo.generation());
+        memo.insert(&b, b"bbbb", memo.generation());
+        assert_eq!(memo.get(&a).unwrap(), b"aaaa");
+        memo.insert(&c, b"cccc", memo.generation());
+        assert!(memo.get(&b).is_none());
+        assert!(memo.get(&a).is_some());
+        assert!(memo.get(&c).is_some());
+
+        // Too large to be worth remembering.
+        let big = Integrity::from(b"123456");
+        memo.insert(&big, b"123456", memo.generation());
+        assert!(memo.get(&big).is_none());
+
+        // Data read before a removal isn't remembered after it.
+        let generation = memo.generation();
+        memo.remove(&a);
+        assert!(memo.get(&a).is_none());
+        memo.insert(&a, b"aaaa", generation);
+        assert!(memo.get(&a).is_none());
+        memo.clear();
+        assert!(memo.get(&c).is_none());
+    }
+}
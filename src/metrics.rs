@@ -0,0 +1,152 @@
+//! Counters for operations made through a [`crate::Cache`], exportable in the
+//! Prometheus text format.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::errors::{Error, Result};
+
+/// A point-in-time copy of the metrics collected by a [`crate::Cache`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Number of reads that found their data.
+    pub hits: u64,
+    /// Number of reads that found no entry or content.
+    pub misses: u64,
+    /// Total bytes returned by successful reads.
+    pub bytes_read: u64,
+    /// Total bytes written.
+    pub bytes_written: u64,
+    /// Number of entries evicted by the cache.
+    pub evictions: u64,
+    /// Number of reads whose data passed integrity verification.
+    pub verify_ok: u64,
+    /// Number of reads whose data failed integrity verification.
+    pub verify_failed: u64,
+}
+
+impl MetricsSnapshot {
+    /// Renders the metrics in the Prometheus text exposition format, ready to
+    /// be served from a `/metrics` endpoint.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     let cache = cacache_sync::Cache::new("./my-cache");
+    ///     cache.write("my-key", b"hello")?;
+    ///     print!("{}", cache.metrics_snapshot().to_prometheus());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("hits", "Reads that found their data.", self.hits),
+            ("misses", "Reads that found no data.", self.misses),
+            ("read_bytes", "Bytes returned by reads.", self.bytes_read),
+            ("written_bytes", "Bytes written.", self.bytes_written),
+            ("evictions", "Entries evicted.", self.evictions),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP cacache_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE cacache_{}_total counter", name);
+            let _ = writeln!(out, "cacache_{}_total {}", name, value);
+        }
+        let _ = writeln!(
+            out,
+            "# HELP cacache_verify_total Integrity verifications by result."
+        );
+        let _ = writeln!(out, "# TYPE cacache_verify_total counter");
+        let _ = writeln!(
+            out,
+            "cacache_verify_total{{result=\"ok\"}} {}",
+            self.verify_ok
+        );
+        let _ = writeln!(
+            out,
+            "cacache_verify_total{{result=\"failed\"}} {}",
+            self.verify_failed
+        );
+        out
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    evictions: AtomicU64,
+    verify_ok: AtomicU64,
+    verify_failed: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts the outcome of a read.
+    pub(crate) fn read<T: AsRef<[u8]>>(&self, result: &Result<T>) {
+        match result {
+            Ok(data) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                self.verify_ok.fetch_add(1, Ordering::Relaxed);
+                self.bytes_read
+                    .fetch_add(data.as_ref().len() as u64, Ordering::Relaxed);
+            }
+            Err(Error::EntryNotFound(..)) => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(Error::IntegrityError { .. }) => {
+                self.verify_failed.fetch_add(1, Ordering::Relaxed);
+            }
+            // Missing content surfaces as an I/O error.
+            Err(_) => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(crate) fn written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn evicted(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            verify_ok: self.verify_ok.load(Ordering::Relaxed),
+            verify_failed: self.verify_failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+
+    #[test]
+    fn counts_operations() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(tmp.path());
+        let sri = cache.write("key", b"hello").unwrap();
+        cache.read("key").unwrap();
+        cache.read_hash(&sri).unwrap();
+        assert!(cache.read("missing").is_err());
+
+        let snapshot = cache.metrics_snapshot();
+        assert_eq!(snapshot.hits, 2);
+        assert_eq!(snapshot.misses, 1);
+        assert_eq!(snapshot.bytes_read, 10);
+        assert_eq!(snapshot.bytes_written, 5);
+        assert_eq!(snapshot.verify_ok, 2);
+
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("cacache_hits_total 2\n"));
+        assert!(text.contains("cacache_verify_total{result=\"ok\"} 2\n"));
+    }
+}
@@ -0,0 +1,66 @@
+//! Memory maps, on targets that have them and in builds with the `mmap`
+//! feature. Otherwise, such as on WASI, which has no `mmap`, every attempt
+//! to map a file fails with [`ErrorKind::Unsupported`], and callers fall back
+//! to reading and writing it like they do for any map that can't be made.
+//!
+//! [`ErrorKind::Unsupported`]: std::io::ErrorKind::Unsupported
+#[cfg(all(feature = "mmap", not(target_os = "wasi")))]
+pub(crate) use memmap2::{Mmap, MmapMut};
+
+#[cfg(not(all(feature = "mmap", not(target_os = "wasi"))))]
+pub(crate) use self::unsupported::{Mmap, MmapMut};
+
+#[cfg(not(all(feature = "mmap", not(target_os = "wasi"))))]
+mod unsupported {
+    use std::fs::File;
+    use std::io::{self, ErrorKind};
+    use std::ops::{Deref, DerefMut};
+
+    fn unsupported() -> io::Error {
+        io::Error::new(ErrorKind::Unsupported, "memory maps aren't supported")
+    }
+
+    /// Never created, since maps can't be made.
+    pub(crate) enum Mmap {}
+
+    impl Mmap {
+        pub(crate) unsafe fn map(_file: &File) -> io::Result<Mmap> {
+            Err(unsupported())
+        }
+    }
+
+    impl Deref for Mmap {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            match *self {}
+        }
+    }
+
+    /// Never created, since maps can't be made.
+    pub(crate) enum MmapMut {}
+
+    impl MmapMut {
+        pub(crate) unsafe fn map_mut(_file: &File) -> io::Result<MmapMut> {
+            Err(unsupported())
+        }
+
+        pub(crate) fn flush(&self) -> io::Result<()> {
+            match *self {}
+        }
+    }
+
+    impl Deref for MmapMut {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            match *self {}
+        }
+    }
+
+    impl DerefMut for MmapMut {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! A cache made of a shared, read-only base with a private writable layer on
+//! top.
+use std::path::{Path, PathBuf};
+
+use ssri::Integrity;
+
+use crate::content::read;
+use crate::errors::{Error, Result};
+use crate::index::{self, Metadata};
+
+/// Two caches layered into one: a `base` that's only ever read from, and an
+/// `upper` cache that receives every write. Lets many jobs share one warm
+/// base cache without contending for it, each with its own upper layer.
+///
+/// Entries in the upper layer shadow entries for the same key in the base,
+/// and removing a key only hides it from this overlay, by writing a removal
+/// to the upper layer. Content is looked up in both layers.
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::Overlay;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let cache = Overlay::new("/shared/warm-cache", "./job-cache");
+///     cache.write("my-key", b"hello")?;
+///     let data = cache.read("some-key-from-the-base")?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Overlay {
+    base: PathBuf,
+    upper: PathBuf,
+}
+
+impl Overlay {
+    /// Layers the cache at `upper` over the cache at `base`.
+    pub fn new<P, Q>(base: P, upper: Q) -> Overlay
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        Overlay {
+            base: base.as_ref().to_path_buf(),
+            upper: upper.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Path of the read-only base cache.
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    /// Path of the writable upper cache. Streaming writes can go through
+    /// [`crate::WriteOpts`] with this path.
+    pub fn upper(&self) -> &Path {
+        &self.upper
+    }
+
+    /// Gets the index entry for `key`, from the upper layer if it has one.
+    pub fn metadata<K: AsRef<str>>(&self, key: K) -> Result<Option<Metadata>> {
+        Ok(self.find(key.as_ref())?.map(|(_, entry)| entry))
+    }
+
+    /// Reads the data for `key`, from whichever layer its entry is in.
+    pub fn read<K: AsRef<str>>(&self, key: K) -> Result<Vec<u8>> {
+        match self.find(key.as_ref())? {
+            Some((layer, entry)) => {
+                read::read_file(&read::entry_file(layer, &entry)?, &entry.integrity)
+            }
+            None => Err(Error::EntryNotFound(
+                self.upper.clone(),
+                key.as_ref().into(),
+            )),
+        }
+    }
+
+    /// Reads the content for `sri`, from whichever layer has it.
+    pub fn read_hash(&self, sri: &Integrity) -> Result<Vec<u8>> {
+        if crate::exists(&self.upper, sri) {
+            crate::read_hash(&self.upper, sri)
+        } else {
+            crate::read_hash(&self.base, sri)
+        }
+    }
+
+    /// Returns true if either layer has the content for `sri`.
+    pub fn exists(&self, sri: &Integrity) -> bool {
+        crate::exists(&self.upper, sri) || crate::exists(&self.base, sri)
+    }
+
+    /// Writes `data` to the upper layer, indexing it under `key`.
+    pub fn write<K, D>(&self, key: K, data: D) -> Result<Integrity>
+    where
+        K: AsRef<str>,
+        D: AsRef<[u8]>,
+    {
+        crate::write(&self.upper, key, data)
+    }
+
+    /// Writes `data` to the upper layer, without a key.
+    pub fn write_hash<D: AsRef<[u8]>>(&self, data: D) -> Result<Integrity> {
+        crate::write_hash(&self.upper, data)
+    }
+
+    /// Removes `key` from the overlay. The base is left untouched, but its
+    /// entry for `key`, if any, is hidden from then on.
+    pub fn remove<K: AsRef<str>>(&self, key: K) -> Result<()> {
+        crate::remove(&self.upper, key)
+    }
+
+    /// Lists the entries visible through the overlay: those in the upper
+    /// layer, and those in the base that aren't shadowed or removed by it.
+    pub fn list(&self) -> impl Iterator<Item = Result<Metadata>> {
+        let upper = self.upper.clone();
+        let has_upper = index::index_dir(&upper).exists();
+        let has_base = index::index_dir(&self.base).exists();
+        let base_entries =
+            index::ls(&self.base)
+                .filter(move |_| has_base)
+                .filter_map(move |entry| match entry {
+                    Ok(entry) => match index::find_latest(&upper, &entry.key) {
+                        Ok(None) => Some(Ok(entry)),
+                        Ok(Some(_)) => None,
+                        Err(err) => Some(Err(err)),
+                    },
+                    Err(err) => Some(Err(err)),
+                });
+        index::ls(&self.upper)
+            .filter(move |_| has_upper)
+            .chain(base_entries)
+    }
+
+    /// Finds the entry for `key`, along with the layer it's in.
+    fn find(&self, key: &str) -> Result<Option<(&Path, Metadata)>> {
+        match index::find_latest(&self.upper, key)? {
+            Some(entry) => Ok(entry.map(|entry| (self.upper.as_path(), entry))),
+            None => Ok(index::find(&self.base, key)?.map(|entry| (self.base.as_path(), entry))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path().join("base");
+        let upper = tmp.path().join("upper");
+        crate::write(&base, "shared", b"from base").unwrap();
+        crate::write(&base, "shadowed", b"from base").unwrap();
+        crate::write(&base, "removed", b"from base").unwrap();
+        let base_sri = crate::write_hash(&base, b"loose").unwrap();
+
+        let overlay = Overlay::new(&base, &upper);
+        overlay.write("shadowed", b"from upper").unwrap();
+        overlay.write("new", b"from upper").unwrap();
+        overlay.remove("removed").unwrap();
+
+        assert_eq!(overlay.read("shared").unwrap(), b"from base");
+        assert_eq!(overlay.read("shadowed").unwrap(), b"from upper");
+        assert_eq!(overlay.read("new").unwrap(), b"from upper");
+        assert!(matches!(
+            overlay.read("removed"),
+            Err(Error::EntryNotFound(..))
+        ));
+        assert!(overlay.metadata("removed").unwrap().is_none());
+        assert_eq!(overlay.read_hash(&base_sri).unwrap(), b"loose");
+        assert!(overlay.exists(&base_sri));
+
+        // The base is never written to.
+        assert_eq!(crate::read(&base, "shadowed").unwrap(), b"from base");
+        assert_eq!(crate::read(&base, "removed").unwrap(), b"from base");
+        assert!(crate::metadata(&base, "new").unwrap().is_none());
+
+        let mut keys = overlay
+            .list()
+            .map(|entry| Ok(entry?.key))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["new", "shadowed", "shared"]);
+    }
+
+    #[test]
+    fn empty_layers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let overlay = Overlay::new(tmp.path().join("base"), tmp.path().join("upper"));
+        assert!(overlay.list().next().is_none());
+        assert!(overlay.metadata("key").unwrap().is_none());
+    }
+}
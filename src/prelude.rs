@@ -0,0 +1,20 @@
+//! Convenience re-exports of the most commonly used types and functions.
+//!
+//! ```no_run
+//! use cacache_sync::prelude::*;
+//!
+//! fn main() -> cacache_sync::Result<()> {
+//!     let sri: Integrity = write("./my-cache", "key", b"hello")?;
+//!     let data = read_hash("./my-cache", &sri)?;
+//!     assert_eq!(data, b"hello");
+//!     Ok(())
+//! }
+//! ```
+pub use ssri::{Algorithm, Integrity};
+
+pub use crate::cache::Cache;
+pub use crate::get::{copy, copy_hash, exists, metadata, read, read_hash, Reader};
+pub use crate::index::Metadata;
+pub use crate::ls::list;
+pub use crate::put::{write, write_hash, WriteOpts, Writer};
+pub use crate::rm::{clear, remove, remove_hash};
@@ -1,11 +1,13 @@
 //! Functions for writing to cache.
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use serde::Serialize;
 use serde_json::Value;
 use ssri::{Algorithm, Integrity};
 
-use crate::content::write;
+use crate::content::{path, read, write};
 use crate::errors::{Error, Internal, Result};
 use crate::index;
 
@@ -38,6 +40,60 @@ where
     writer.commit()
 }
 
+/// Writes `data` to the `cache` synchronously like [`write()`], returning a
+/// description of the new entry instead of just its integrity hash.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let entry = cacache_sync::write_entry("./my-cache", "my-key", b"hello")?;
+///     println!("{} bytes at {:?}", entry.size, entry.path);
+///     Ok(())
+/// }
+/// ```
+pub fn write_entry<P, D, K>(cache: P, key: K, data: D) -> Result<EntryDescriptor>
+where
+    P: AsRef<Path>,
+    D: AsRef<[u8]>,
+    K: AsRef<str>,
+{
+    let mut writer = Writer::create(cache.as_ref(), key.as_ref())?;
+    writer.write_all(data.as_ref()).with_context(|| {
+        format!(
+            "Failed to write to cache data for key {} for cache at {:?}",
+            key.as_ref(),
+            cache.as_ref()
+        )
+    })?;
+    writer.commit_entry()
+}
+
+/// Serializes `value` as JSON and writes it to the `cache` synchronously,
+/// indexing it under `key`.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write_json("./my-cache", "my-key", &vec![1, 2, 3])?;
+///     Ok(())
+/// }
+/// ```
+pub fn write_json<P, K, T>(cache: P, key: K, value: &T) -> Result<Integrity>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    T: Serialize + ?Sized,
+{
+    let data = serde_json::to_vec(value).with_context(|| {
+        format!(
+            "Failed to serialize JSON data for key {} for cache at {:?}",
+            key.as_ref(),
+            cache.as_ref()
+        )
+    })?;
+    write(cache, key, data)
+}
+
 /// Writes `data` to the `cache` synchronously, skipping associating a key with it.
 ///
 /// ## Example
@@ -68,6 +124,233 @@ where
     writer.commit()
 }
 
+/// Writes many entries to the `cache` at once, returning their integrity
+/// hashes in order. This is much faster than calling [`write()`] for each
+/// when there are lots of small entries: all the content is written first,
+/// and then the index entries are grouped by bucket, so each bucket is only
+/// appended to once. If an entry is rejected by the
+/// [`crate::KeyConflict`] policy, none of them are written, although the
+/// content stays behind for [`crate::GcOpts`] to clean up. An I/O error part
+/// way through writing the index, though, can leave the entries for some
+/// buckets written and not others, unless the cache keeps an
+/// [`index_journal`](crate::CacheConfig::index_journal), in which case the
+/// rest are written once the batch is recovered.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let entries = (0..1000).map(|i| (format!("key-{}", i), format!("data-{}", i)));
+///     let sris = cacache_sync::ingest_batch("./my-cache", entries)?;
+///     Ok(())
+/// }
+/// ```
+pub fn ingest_batch<P, I, K, D>(cache: P, entries: I) -> Result<Vec<Integrity>>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = (K, D)>,
+    K: AsRef<str>,
+    D: AsRef<[u8]>,
+{
+    let cache = cache.as_ref();
+    let entries = entries
+        .into_iter()
+        .map(|(key, data)| {
+            let sri = write_hash(cache, data.as_ref())?;
+            let opts = WriteOpts::new().integrity(sri).size(data.as_ref().len());
+            Ok((key.as_ref().to_owned(), opts))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    index::insert_batch(cache, entries)
+}
+
+/// Inserts an entry for `key` that records `metadata`, but has no data at
+/// all. Useful for recording facts about keys with the same machinery as
+/// everything else, such as that something doesn't exist upstream. The entry
+/// shows up in lookups and listings with [`crate::Metadata::metadata_only`]
+/// set, and reading its data fails with [`Error::NoContent`]. Caches with a
+/// [`crate::IndexFormat::Fixed`] index can't store these.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     cacache_sync::write_metadata_only("./my-cache", "left-pad@9.9.9", "no such version")?;
+///     let entry = cacache_sync::metadata("./my-cache", "left-pad@9.9.9")?.unwrap();
+///     assert!(entry.metadata_only);
+///     Ok(())
+/// }
+/// ```
+pub fn write_metadata_only<P, K, T>(cache: P, key: K, metadata: T) -> Result<()>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    T: Serialize,
+{
+    WriteOpts::new()
+        .metadata(metadata)
+        .insert_metadata_only(cache, key)
+}
+
+/// Records that `key` is known not to exist, such as after an upstream
+/// answered with a 404, for `ttl` from now. Until then, [`crate::exists_key`]
+/// and [`crate::read_fresh`] report it as [`crate::Lookup::KnownMissing`],
+/// so clients can skip asking again. Writing data for `key` replaces the
+/// negative entry as usual.
+///
+/// ## Example
+/// ```no_run
+/// use std::time::Duration;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     cacache_sync::write_negative("./my-cache", "left-pad@9.9.9", Duration::from_secs(300))?;
+///     assert_eq!(
+///         cacache_sync::exists_key("./my-cache", "left-pad@9.9.9")?,
+///         cacache_sync::Lookup::KnownMissing
+///     );
+///     Ok(())
+/// }
+/// ```
+pub fn write_negative<P, K>(cache: P, key: K, ttl: Duration) -> Result<()>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    WriteOpts::new().ttl(ttl).insert_metadata_only(cache, key)
+}
+
+/// Indexes `key` as a pointer to data kept outside the cache at `location`,
+/// such as a very large artifact on a network share, without copying it in.
+/// Key-based reads like [`crate::read`] follow the pointer transparently and
+/// verify what they find against `sri`, so a changed or corrupted external
+/// file is never returned. Removing the entry never touches the external file.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".parse().unwrap();
+///     cacache_sync::write_pointer("./my-cache", "big-model", "/mnt/share/model.bin", sri, 0)?;
+///     let data = cacache_sync::read("./my-cache", "big-model")?;
+///     Ok(())
+/// }
+/// ```
+pub fn write_pointer<P, K, L>(
+    cache: P,
+    key: K,
+    location: L,
+    sri: Integrity,
+    size: usize,
+) -> Result<Integrity>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    L: AsRef<Path>,
+{
+    let opts = WriteOpts {
+        external: Some(location.as_ref().to_path_buf()),
+        ..WriteOpts::new().integrity(sri).size(size)
+    };
+    index::insert(cache.as_ref(), key.as_ref(), opts)
+}
+
+/// What an [`alias`] points its new key at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AliasTarget {
+    /// Content already in the cache, by its integrity.
+    Hash(Integrity),
+    /// Whatever content an existing key's entry refers to.
+    Key(String),
+}
+
+impl From<Integrity> for AliasTarget {
+    fn from(sri: Integrity) -> Self {
+        AliasTarget::Hash(sri)
+    }
+}
+
+impl From<&Integrity> for AliasTarget {
+    fn from(sri: &Integrity) -> Self {
+        AliasTarget::Hash(sri.clone())
+    }
+}
+
+impl From<&str> for AliasTarget {
+    fn from(key: &str) -> Self {
+        AliasTarget::Key(key.to_owned())
+    }
+}
+
+impl From<String> for AliasTarget {
+    fn from(key: String) -> Self {
+        AliasTarget::Key(key)
+    }
+}
+
+/// Indexes `new_key` to content that's already stored, without reading or
+/// copying it, so several lookup keys such as URL variants or version aliases
+/// can share one object for the cost of an index entry. `target` is either
+/// an integrity or an existing key. Aliasing a key copies its integrity,
+/// size, content type and external location, but not its metadata or tags,
+/// and later changes to either key don't affect the other.
+///
+/// Fails with [`Error::EntryNotFound`] if `target` is a key with no entry,
+/// [`Error::NoContent`] if it's a metadata-only one, and with an I/O error if
+/// there's no content for `target`.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "left-pad@1.3.0", b"...")?;
+///     cacache_sync::alias("./my-cache", "left-pad@latest", "left-pad@1.3.0")?;
+///     cacache_sync::alias("./my-cache", "left-pad@^1", &sri)?;
+///     Ok(())
+/// }
+/// ```
+pub fn alias<P, K, T>(cache: P, new_key: K, target: T) -> Result<Integrity>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    T: Into<AliasTarget>,
+{
+    let cache = cache.as_ref();
+    let opts = match target.into() {
+        AliasTarget::Hash(sri) => {
+            let cpath = read::content_file(cache, &sri)?;
+            let size = std::fs::metadata(&cpath)
+                .with_context(|| format!("No content to alias at {:?}", cpath))?
+                .len();
+            WriteOpts::new().integrity(sri).size(size as usize)
+        }
+        AliasTarget::Key(key) => {
+            let entry = index::find(cache, &key)?
+                .ok_or_else(|| Error::EntryNotFound(cache.to_path_buf(), key))?;
+            let cpath = read::entry_file(cache, &entry)?;
+            if !cpath.exists() {
+                return Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+                    .with_context(|| format!("No content to alias at {:?}", cpath))?;
+            }
+            WriteOpts {
+                external: entry.external,
+                content_type: entry.content_type,
+                ..WriteOpts::new().integrity(entry.integrity).size(entry.size)
+            }
+        }
+    };
+    index::insert(cache, new_key.as_ref(), opts)
+}
+
+/// Describes an entry that was just written, as returned by [`write_entry`]
+/// and [`Writer::commit_entry`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryDescriptor {
+    /// Integrity hash of the written data.
+    pub integrity: Integrity,
+    /// Number of bytes written.
+    pub size: usize,
+    /// Timestamp recorded in the index, in unix milliseconds.
+    pub time: u128,
+    /// Path of the content file holding the data.
+    pub path: PathBuf,
+}
+
 /// Builder for options and flags for opening a new cache file to write data into.
 #[derive(Clone, Default)]
 pub struct WriteOpts {
@@ -76,6 +359,19 @@ pub struct WriteOpts {
     pub(crate) size: Option<usize>,
     pub(crate) time: Option<u128>,
     pub(crate) metadata: Option<Value>,
+    // Deferred until the writer is opened, so `metadata()` can stay chainable.
+    pub(crate) metadata_error: Option<String>,
+    pub(crate) sparse: bool,
+    pub(crate) verify_existing: bool,
+    pub(crate) fsync: bool,
+    pub(crate) direct_io: bool,
+    pub(crate) metadata_only: bool,
+    #[cfg(feature = "signing")]
+    pub(crate) signing_key: Option<ed25519_dalek::SigningKey>,
+    pub(crate) external: Option<PathBuf>,
+    pub(crate) content_type: Option<String>,
+    pub(crate) tags: Vec<String>,
+    pub(crate) ttl: Option<Duration>,
 }
 
 impl WriteOpts {
@@ -90,15 +386,12 @@ impl WriteOpts {
         P: AsRef<Path>,
         K: AsRef<str>,
     {
+        self.check_metadata()?;
         Ok(Writer {
             cache: cache.as_ref().to_path_buf(),
             key: Some(String::from(key.as_ref())),
             written: 0,
-            writer: write::Writer::new(
-                cache.as_ref(),
-                *self.algorithm.as_ref().unwrap_or(&Algorithm::Sha256),
-                self.size,
-            )?,
+            writer: write::Writer::new(cache.as_ref(), &self)?,
             opts: self,
         })
     }
@@ -108,19 +401,56 @@ impl WriteOpts {
     where
         P: AsRef<Path>,
     {
+        self.check_metadata()?;
         Ok(Writer {
             cache: cache.as_ref().to_path_buf(),
             key: None,
             written: 0,
-            writer: write::Writer::new(
-                cache.as_ref(),
-                *self.algorithm.as_ref().unwrap_or(&Algorithm::Sha256),
-                self.size,
-            )?,
+            writer: write::Writer::new(cache.as_ref(), &self)?,
             opts: self,
         })
     }
 
+    /// Inserts an entry for `key` that has no data at all, just the metadata,
+    /// tags, time to live and so on set in these options, like
+    /// [`write_metadata_only`]. Any size or integrity set is ignored.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     cacache_sync::WriteOpts::new()
+    ///         .metadata("not found upstream")
+    ///         .ttl(Duration::from_secs(300))
+    ///         .insert_metadata_only("./my-cache", "my-package")?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn insert_metadata_only<P, K>(mut self, cache: P, key: K) -> Result<()>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        self.check_metadata()?;
+        self.metadata_only = true;
+        self.sri = None;
+        self.size = None;
+        self.external = None;
+        index::insert(cache.as_ref(), key.as_ref(), self).map(|_| ())
+    }
+
+    fn check_metadata(&self) -> Result<()> {
+        match &self.metadata_error {
+            Some(e) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Failed to serialize entry metadata: {}", e),
+            ))
+            .to_internal()?,
+            None => Ok(()),
+        }
+    }
+
     /// Configures the algorithm to write data under.
     pub fn algorithm(mut self, algo: Algorithm) -> Self {
         self.algorithm = Some(algo);
@@ -135,8 +465,60 @@ impl WriteOpts {
     }
 
     /// Sets arbitrary additional metadata to associate with the index entry.
-    pub fn metadata(mut self, metadata: Value) -> Self {
-        self.metadata = Some(metadata);
+    /// Any serializable value is accepted and stored as JSON. If it fails to
+    /// serialize, opening the writer will return an error.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use std::io::Write;
+    ///
+    /// #[derive(serde::Serialize)]
+    /// struct Headers {
+    ///     etag: String,
+    /// }
+    ///
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     let headers = Headers { etag: "abc".into() };
+    ///     let mut fd = cacache_sync::WriteOpts::new()
+    ///         .metadata(&headers)
+    ///         .open("./my-cache", "my-key")?;
+    ///     fd.write_all(b"hello").expect("Failed to write to cache");
+    ///     fd.commit()?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn metadata<T: Serialize>(mut self, metadata: T) -> Self {
+        match serde_json::to_value(metadata) {
+            Ok(metadata) => {
+                self.metadata = Some(metadata);
+                self.metadata_error = None;
+            }
+            Err(e) => self.metadata_error = Some(e.to_string()),
+        }
+        self
+    }
+
+    /// Sets the MIME type of the data, such as `text/html`, to be served
+    /// along with it. Stored in the index entry's `content_type`.
+    pub fn content_type<T: Into<String>>(mut self, content_type: T) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Attaches a tag to the index entry. Can be called more than once to
+    /// attach several. Tagged entries can be listed and removed as a group
+    /// with [`crate::list_by_tag`] and [`crate::remove_by_tag`].
+    pub fn tag<T: Into<String>>(mut self, tag: T) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    /// Marks the entry as stale once `ttl` has passed since it was written.
+    /// Stale entries can still be read, but [`crate::Metadata::is_expired`]
+    /// reports them, and [`crate::CacheOpts::treat_expired_as_missing`] hides
+    /// them from a [`crate::Cache`] handle's lookups by key.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
         self
     }
 
@@ -148,6 +530,68 @@ impl WriteOpts {
         self
     }
 
+    /// Sets the specific time to associate with this entry, like
+    /// [`WriteOpts::time`], but from a [`SystemTime`]. Times before the unix
+    /// epoch are stored as the epoch itself.
+    pub fn time_sys(mut self, time: SystemTime) -> Self {
+        self.time = Some(
+            time.duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis())
+                .unwrap_or(0),
+        );
+        self
+    }
+
+    /// Sets the specific time to associate with this entry, like
+    /// [`WriteOpts::time`], but from a `chrono` date-time. Times before the
+    /// unix epoch are stored as the epoch itself.
+    #[cfg(feature = "chrono")]
+    pub fn time_chrono<Tz: chrono::TimeZone>(mut self, time: chrono::DateTime<Tz>) -> Self {
+        self.time = Some(time.timestamp_millis().max(0) as u128);
+        self
+    }
+
+    /// Stores block-sized runs of zeroes as holes instead of writing them out,
+    /// on filesystems that support sparse files. Useful when ingesting VM
+    /// images, preallocated database files and the like.
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// When the content being written already exists in the cache, checks
+    /// that the existing file really hashes to the written data's integrity
+    /// before moving the new one into place, and moves it into quarantine if
+    /// it doesn't, see [`crate::quarantine_hash`]. Otherwise a previously
+    /// corrupted object would be replaced without a trace, or where it can't
+    /// be replaced, silently stand in for a good write. Defaults to false,
+    /// which only checks that the file is there.
+    pub fn verify_existing(mut self, verify: bool) -> Self {
+        self.verify_existing = verify;
+        self
+    }
+
+    /// Syncs the written data to disk before moving it into place, and syncs
+    /// the directory it lands in afterwards, so a committed entry survives a
+    /// crash or power loss. This makes commits noticeably slower. Defaults to
+    /// false, which leaves flushing to the OS.
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Writes the data with direct I/O, bypassing the OS page cache, so that
+    /// ingesting multi-gigabyte artifacts that won't be read again soon
+    /// doesn't evict everything else from it. Buffering to the alignment
+    /// direct I/O needs is taken care of. Only takes effect on Linux, on
+    /// filesystems that support `O_DIRECT`, and for writes that aren't
+    /// [`sparse`](WriteOpts::sparse) or small enough to be memory-mapped.
+    /// Elsewhere, data is written as usual. Defaults to false.
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
     /// Sets the expected integrity hash of the written data. If there's a
     /// mismatch between this Integrity and the one calculated by the write,
     /// `put.commit()` will error.
@@ -155,6 +599,14 @@ impl WriteOpts {
         self.sri = Some(sri);
         self
     }
+
+    /// Signs the index entry with `key`, so readers can check where it came
+    /// from with [`crate::metadata_verified`] or [`crate::read_verified`].
+    #[cfg(feature = "signing")]
+    pub fn sign_with(mut self, key: &ed25519_dalek::SigningKey) -> Self {
+        self.signing_key = Some(key.clone());
+        self
+    }
 }
 
 /// A reference to an open file writing to the cache.
@@ -168,15 +620,40 @@ pub struct Writer {
 
 impl Write for Writer {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(size) = self.opts.size {
+            if self.written + buf.len() > size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    Error::SizeError(size, self.written + buf.len()),
+                ));
+            }
+        }
         let written = self.writer.write(buf)?;
         self.written += written;
         Ok(written)
     }
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+        let total = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        if total > CHUNK_BUFFER_SIZE {
+            // Big enough to write as they are, one buffer at a time.
+            let buf = bufs.iter().find(|buf| !buf.is_empty());
+            return self.write(buf.map_or(&[][..], |buf| &buf[..]));
+        }
+        let mut joined = Vec::with_capacity(total);
+        for buf in bufs {
+            joined.extend_from_slice(buf);
+        }
+        self.write(&joined)
+    }
     fn flush(&mut self) -> std::io::Result<()> {
         self.writer.flush()
     }
 }
 
+/// How much [`Writer::write_chunks`] and vectored writes gather up from small
+/// chunks before writing them out together.
+const CHUNK_BUFFER_SIZE: usize = 64 * 1024;
+
 impl Writer {
     /// Creates a new writable file handle into the cache.
     ///
@@ -202,31 +679,242 @@ impl Writer {
             .open(cache.as_ref(), key.as_ref())
     }
 
+    /// Creates a new writable file handle into the cache, without associating
+    /// a key with the data. `commit()` returns its integrity hash.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use std::io::prelude::*;
+    ///
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     let mut fd = cacache_sync::Writer::create_hash("./my-cache")?;
+    ///     fd.write_all(b"hello world").expect("Failed to write to cache");
+    ///     let sri = fd.commit()?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn create_hash<P>(cache: P) -> Result<Writer>
+    where
+        P: AsRef<Path>,
+    {
+        WriteOpts::new()
+            .algorithm(Algorithm::Sha256)
+            .open_hash(cache.as_ref())
+    }
+
+    /// Writes every chunk from `chunks`, in order, like calling `write_all`
+    /// on each. Small chunks are gathered up and written together, so
+    /// producers that emit data in many tiny pieces, like serializers and
+    /// compressors, don't pay for a write per piece.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     let mut fd = cacache_sync::Writer::create("./my-cache", "my-key")?;
+    ///     let lines = (0..1000).map(|i| format!("line {}\n", i)).collect::<Vec<_>>();
+    ///     fd.write_chunks(lines.iter().map(|line| line.as_bytes()))
+    ///         .expect("Failed to write to cache");
+    ///     fd.commit()?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn write_chunks<'a, I>(&mut self, chunks: I) -> std::io::Result<()>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut pending = Vec::new();
+        for chunk in chunks {
+            if pending.len() + chunk.len() > CHUNK_BUFFER_SIZE {
+                self.write_all(&pending)?;
+                pending.clear();
+            }
+            if chunk.len() >= CHUNK_BUFFER_SIZE {
+                self.write_all(chunk)?;
+            } else {
+                pending.extend_from_slice(chunk);
+            }
+        }
+        self.write_all(&pending)
+    }
+
+    /// Writes `buf` at `offset` in the content, for producers that fill the
+    /// data in out of order, like parallel ranged downloads. Requires
+    /// [`WriteOpts::size`], and the integrity is computed from the completed
+    /// data at commit time. Ranges that are never written are committed as
+    /// zeroes, without an error, so set [`WriteOpts::integrity`] as well to
+    /// catch gaps. Once
+    /// this is used, sequential writes through [`Write`] are refused.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use cacache_sync::WriteOpts;
+    ///
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     let mut fd = WriteOpts::new().size(11).open("./my-cache", "my-key")?;
+    ///     fd.write_at(6, b"world").expect("Failed to write to cache");
+    ///     fd.write_at(0, b"hello ").expect("Failed to write to cache");
+    ///     fd.commit()?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        if let Some(size) = self.opts.size {
+            let end = u64::try_from(buf.len())
+                .ok()
+                .and_then(|len| offset.checked_add(len));
+            if !matches!(end, Some(end) if end <= size as u64) {
+                let end = end
+                    .and_then(|end| usize::try_from(end).ok())
+                    .unwrap_or(usize::MAX);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    Error::SizeError(size, end),
+                ));
+            }
+            self.writer.write_at(offset, buf)?;
+            // The content always spans the declared size once it's being
+            // filled in at offsets.
+            self.written = size;
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "writes at offsets need a declared size",
+            ))
+        }
+    }
+
+    /// Returns the integrity hash of all the data written so far, without
+    /// consuming the writer. Useful for checkpointing partial digests while
+    /// streaming data in.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use std::io::prelude::*;
+    ///
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     let mut fd = cacache_sync::Writer::create("./my-cache", "my-key")?;
+    ///     fd.write_all(b"hello").expect("Failed to write to cache");
+    ///     println!("partial integrity: {}", fd.integrity_so_far());
+    ///     fd.write_all(b" world").expect("Failed to write to cache");
+    ///     fd.commit()?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn integrity_so_far(&self) -> Integrity {
+        self.writer.integrity_so_far()
+    }
+
+    /// Checks everything written so far against the `size` and `integrity`
+    /// options, if provided, just like [`Writer::commit`] will, and returns
+    /// its integrity, without consuming the writer. The counterpart of
+    /// [`Reader::check`](crate::Reader::check), for finding out whether a
+    /// commit would go through, and what it would commit, before making it.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use std::io::prelude::*;
+    ///
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     let sri = ssri::Integrity::from(b"hello world");
+    ///     let mut fd = cacache_sync::WriteOpts::new()
+    ///         .integrity(sri)
+    ///         .open("./my-cache", "my-key")?;
+    ///     fd.write_all(b"hello world").expect("Failed to write to cache");
+    ///     println!("writing {}", fd.check()?);
+    ///     fd.commit()?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn check(&mut self) -> Result<Integrity> {
+        let sri = self.writer.integrity()?;
+        match self.mismatch(&sri) {
+            Some(err) => Err(err),
+            None => Ok(sri),
+        }
+    }
+
+    /// The error for data that fails the `size` or `integrity` checks, if it
+    /// does.
+    fn mismatch(&self, sri: &Integrity) -> Option<Error> {
+        let err = match (&self.opts.sri, self.opts.size) {
+            (Some(expected), _) if expected.matches(sri).is_none() => {
+                ssri::Error::IntegrityCheckError(expected.clone(), sri.clone()).into()
+            }
+            (_, Some(size)) if size != self.written => Error::SizeError(size, self.written),
+            _ => return None,
+        };
+        Some(match &self.key {
+            Some(key) => Error::CommitMismatch(key.clone(), Box::new(err)),
+            None => err,
+        })
+    }
+
+    /// Discards the Writer handle and everything written to it so far,
+    /// removing its temporary file. Dropping a Writer without calling
+    /// `commit()` has the same effect, but `abort()` makes the intent explicit
+    /// and reports any errors while cleaning up. With the `leak-warnings`
+    /// feature enabled, dropping without either call prints a warning.
+    pub fn abort(self) -> Result<()> {
+        self.writer.abort()
+    }
+
     /// Closes the Writer handle and writes content and index entries. Also
     /// verifies data against `size` and `integrity` options, if provided.
     /// Must be called manually in order to complete the writing process,
     /// otherwise everything will be thrown out.
+    ///
+    /// If the data fails either check, it's thrown out before anything is
+    /// written to the cache, and the [`Error::SizeError`] or
+    /// [`Error::IntegrityError`] says what was expected and what was actually
+    /// written, wrapped in [`Error::CommitMismatch`] along with the key, if
+    /// there is one.
     pub fn commit(mut self) -> Result<Integrity> {
-        let cache = self.cache;
-        let writer_sri = self.writer.close()?;
-        if let Some(sri) = &self.opts.sri {
-            if sri.matches(&writer_sri).is_none() {
-                return Err(ssri::Error::IntegrityCheckError(sri.clone(), writer_sri).into());
-            }
-        } else {
-            self.opts.sri = Some(writer_sri.clone());
-        }
-        if let Some(size) = self.opts.size {
-            if size != self.written {
-                return Err(Error::SizeError(size, self.written));
-            }
+        let writer_sri = self.writer.integrity()?;
+        if let Some(err) = self.mismatch(&writer_sri) {
+            // Checked before anything is moved into place, so dropping the
+            // tmpfile is all the cleanup needed.
+            self.writer.abort()?;
+            return Err(err);
         }
+        let cache = self.cache;
+        let writer_sri = self.writer.close(writer_sri)?;
+        self.opts.sri.get_or_insert_with(|| writer_sri.clone());
         if let Some(key) = self.key {
             index::insert(&cache, &key, self.opts)
         } else {
             Ok(writer_sri)
         }
     }
+
+    /// Closes the Writer handle and writes content and index entries, like
+    /// [`Writer::commit`], returning a description of the new entry.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use std::io::prelude::*;
+    ///
+    /// fn main() -> cacache_sync::Result<()> {
+    ///     let mut fd = cacache_sync::Writer::create("./my-cache", "my-key")?;
+    ///     fd.write_all(b"hello world").expect("Failed to write to cache");
+    ///     let entry = fd.commit_entry()?;
+    ///     println!("Wrote {} at {}", entry.integrity, entry.time);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn commit_entry(mut self) -> Result<EntryDescriptor> {
+        let time = *self.opts.time.get_or_insert_with(index::now);
+        let size = self.written;
+        let cache = self.cache.clone();
+        let integrity = self.commit()?;
+        let path = path::content_path(&cache, &integrity)?;
+        Ok(EntryDescriptor {
+            integrity,
+            size,
+            time,
+            path,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -241,6 +929,309 @@ mod tests {
         assert_eq!(data, b"hello");
     }
 
+    #[test]
+    fn json_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let value = serde_json::json!({ "hello": ["world", 1] });
+        crate::write_json(&dir, "hello", &value).unwrap();
+        let data: serde_json::Value = crate::read_json(&dir, "hello").unwrap();
+        assert_eq!(data, value);
+    }
+
+    #[test]
+    fn write_at() {
+        use std::io::Write;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let data = (0..2 * 1024 * 1024)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        // Small enough to be mapped, and too large to be.
+        for data in [&data[..1000], &data[..]] {
+            let mut writer = crate::WriteOpts::new()
+                .size(data.len())
+                .open(&dir, "hello")
+                .unwrap();
+            let mut chunks = data.chunks(300).enumerate().collect::<Vec<_>>();
+            chunks.reverse();
+            for (i, chunk) in chunks {
+                writer.write_at(i as u64 * 300, chunk).unwrap();
+            }
+            assert!(writer.write_at(data.len() as u64, b"x").is_err());
+            assert!(writer.write_all(b"x").is_err());
+            let sri = writer.commit().unwrap();
+            assert_eq!(sri, ssri::Integrity::from(data));
+            assert_eq!(crate::read(&dir, "hello").unwrap(), data);
+        }
+
+        let mut writer = crate::WriteOpts::new()
+            .size(10)
+            .open(&dir, "hello")
+            .unwrap();
+        assert!(writer.write_at(u64::MAX, b"x").is_err());
+        assert!(writer.write_at(6, b"world").is_err());
+
+        let mut writer = crate::Writer::create(&dir, "hello").unwrap();
+        assert!(writer.write_at(0, b"hello").is_err());
+    }
+
+    #[test]
+    fn write_at_gaps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let mut writer = crate::WriteOpts::new()
+            .size(10)
+            .open(&dir, "hello")
+            .unwrap();
+        writer.write_at(2, b"hi").unwrap();
+        writer.write_at(8, b"!").unwrap();
+        writer.commit().unwrap();
+        assert_eq!(crate::read(&dir, "hello").unwrap(), b"\0\0hi\0\0\0\0!\0");
+    }
+
+    #[test]
+    fn integrity_so_far() {
+        use std::io::Write;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let mut writer = crate::Writer::create(&dir, "hello").unwrap();
+        writer.write_all(b"hello").unwrap();
+        assert_eq!(writer.integrity_so_far(), ssri::Integrity::from(b"hello"));
+        writer.write_all(b" world").unwrap();
+        assert_eq!(
+            writer.integrity_so_far(),
+            ssri::Integrity::from(b"hello world")
+        );
+        let sri = writer.commit().unwrap();
+        assert_eq!(sri, ssri::Integrity::from(b"hello world"));
+    }
+
+    #[test]
+    fn serializable_metadata() {
+        use std::collections::HashMap;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let headers = HashMap::from([("etag", "abc")]);
+        let mut writer = crate::WriteOpts::new()
+            .metadata(&headers)
+            .open(&dir, "hello")
+            .unwrap();
+        std::io::Write::write_all(&mut writer, b"hello").unwrap();
+        writer.commit().unwrap();
+        let entry = crate::metadata(&dir, "hello").unwrap().unwrap();
+        assert_eq!(entry.metadata, serde_json::json!({ "etag": "abc" }));
+
+        // JSON objects need string keys.
+        let bad = HashMap::from([((1, 2), "abc")]);
+        assert!(crate::WriteOpts::new()
+            .metadata(&bad)
+            .open(&dir, "bad")
+            .is_err());
+    }
+
+    #[test]
+    fn content_type() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let mut writer = crate::WriteOpts::new()
+            .content_type("text/plain")
+            .open(&dir, "hello")
+            .unwrap();
+        std::io::Write::write_all(&mut writer, b"hello").unwrap();
+        writer.commit().unwrap();
+        crate::write(&dir, "untyped", b"hello").unwrap();
+
+        let entry = crate::metadata(&dir, "hello").unwrap().unwrap();
+        assert_eq!(entry.content_type.as_deref(), Some("text/plain"));
+        let entry = crate::metadata(&dir, "untyped").unwrap().unwrap();
+        assert_eq!(entry.content_type, None);
+    }
+
+    #[test]
+    fn create_hash() {
+        use std::io::Write;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let mut writer = crate::Writer::create_hash(&dir).unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.write_all(b" world").unwrap();
+        let sri = writer.commit().unwrap();
+        assert_eq!(sri, crate::write_hash(&dir, b"hello world").unwrap());
+        assert_eq!(crate::read_hash(&dir, &sri).unwrap(), b"hello world");
+        assert!(!dir.join("index-v5").exists());
+    }
+
+    #[test]
+    fn write_chunks() {
+        use std::io::{IoSlice, Write};
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let big = vec![b'x'; super::CHUNK_BUFFER_SIZE + 1];
+        let chunks = [&b"hello"[..], b" ", &big, b"world"];
+        let mut writer = crate::Writer::create(&dir, "chunks").unwrap();
+        writer.write_chunks(chunks.iter().copied()).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(crate::read(&dir, "chunks").unwrap(), chunks.concat());
+
+        let mut writer = crate::Writer::create(&dir, "vectored").unwrap();
+        let bufs = [IoSlice::new(b"hello"), IoSlice::new(b" world")];
+        assert_eq!(writer.write_vectored(&bufs).unwrap(), 11);
+        writer.commit().unwrap();
+        assert_eq!(crate::read(&dir, "vectored").unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn entry_descriptor() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let entry = crate::write_entry(&dir, "hello", b"hello").unwrap();
+        assert_eq!(entry.size, 5);
+        assert_eq!(std::fs::read(&entry.path).unwrap(), b"hello");
+
+        let indexed = crate::metadata(&dir, "hello").unwrap().unwrap();
+        assert_eq!(indexed.integrity, entry.integrity);
+        assert_eq!(indexed.time, entry.time);
+    }
+
+    #[test]
+    fn system_time() {
+        use std::time::{Duration, UNIX_EPOCH};
+        let then = UNIX_EPOCH + Duration::from_millis(1_234);
+        assert_eq!(crate::WriteOpts::new().time_sys(then).time, Some(1_234));
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(crate::WriteOpts::new().time_sys(before_epoch).time, Some(0));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn chrono_time() {
+        use chrono::TimeZone;
+        let then = chrono::Utc.timestamp_millis_opt(1_234).unwrap();
+        assert_eq!(crate::WriteOpts::new().time_chrono(then).time, Some(1_234));
+        let before_epoch = chrono::Utc.timestamp_millis_opt(-1_000).unwrap();
+        assert_eq!(
+            crate::WriteOpts::new().time_chrono(before_epoch).time,
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn write_past_declared_size() {
+        use std::io::Write;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let mut writer = crate::WriteOpts::new()
+            .size(10)
+            .open(&dir, "hello")
+            .unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.write_all(b"world").unwrap();
+        let err = writer.write_all(b"!").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        writer.commit().unwrap();
+        assert_eq!(crate::read(&dir, "hello").unwrap(), b"helloworld");
+
+        // Large declared sizes don't use a mapping, and still fail fast.
+        let size = super::write::MAX_MMAP_SIZE + 1;
+        let mut writer = crate::WriteOpts::new()
+            .size(size)
+            .open(&dir, "big")
+            .unwrap();
+        assert!(writer.write_all(&vec![0; size + 1]).is_err());
+    }
+
+    #[test]
+    fn commit_mismatch_cleans_up() {
+        use std::io::Write;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let mut writer = crate::WriteOpts::new()
+            .size(10)
+            .open(&dir, "hello")
+            .unwrap();
+        writer.write_all(b"hello").unwrap();
+        match writer.commit() {
+            Err(crate::Error::CommitMismatch(key, err)) => {
+                assert_eq!(key, "hello");
+                assert!(matches!(*err, crate::Error::SizeError(10, 5)));
+            }
+            res => panic!("expected a mismatch, got {:?}", res),
+        }
+        assert!(!crate::exists(&dir, &ssri::Integrity::from(b"hello")));
+
+        let wanted = ssri::Integrity::from(b"world");
+        let mut writer = crate::WriteOpts::new()
+            .integrity(wanted.clone())
+            .open_hash(&dir)
+            .unwrap();
+        writer.write_all(b"hello").unwrap();
+        match writer.commit() {
+            Err(crate::Error::IntegrityError {
+                source: ssri::Error::IntegrityCheckError(expected, actual),
+            }) => {
+                assert_eq!(expected, wanted);
+                assert_eq!(actual, ssri::Integrity::from(b"hello"));
+            }
+            res => panic!("expected a mismatch, got {:?}", res),
+        }
+
+        assert!(!crate::exists(&dir, &ssri::Integrity::from(b"hello")));
+        assert!(crate::metadata(&dir, "hello").unwrap().is_none());
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn check_before_commit() {
+        use std::io::Write;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = ssri::Integrity::from(b"hello world");
+        let mut writer = crate::WriteOpts::new()
+            .integrity(sri.clone())
+            .open(&dir, "hello")
+            .unwrap();
+        writer.write_all(b"hello").unwrap();
+        assert!(matches!(
+            writer.check(),
+            Err(crate::Error::CommitMismatch(_, _))
+        ));
+        writer.write_all(b" world").unwrap();
+        assert_eq!(writer.check().unwrap(), sri);
+        assert_eq!(writer.commit().unwrap(), sri);
+    }
+
+    #[test]
+    fn ingest_batch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::configure(&dir, crate::CacheConfig::new().track_stats(true)).unwrap();
+        crate::write(&dir, "key-0", b"old").unwrap();
+        let entries = (0..500)
+            .map(|i| (format!("key-{}", i), format!("data-{}", i)))
+            // Later entries for the same key win, as with separate writes.
+            .chain(std::iter::once(("key-1".to_owned(), "newer".to_owned())));
+        let sris = crate::ingest_batch(&dir, entries).unwrap();
+        assert_eq!(sris.len(), 501);
+        assert_eq!(sris[2], ssri::Integrity::from(b"data-2"));
+        assert_eq!(crate::read(&dir, "key-0").unwrap(), b"data-0");
+        assert_eq!(crate::read(&dir, "key-1").unwrap(), b"newer");
+        assert_eq!(crate::read(&dir, "key-499").unwrap(), b"data-499");
+        assert_eq!(crate::stats(&dir).unwrap().entries, 500);
+
+        crate::configure(
+            &dir,
+            crate::CacheConfig::new().key_conflict(crate::KeyConflict::ErrorOnDifferent),
+        )
+        .unwrap();
+        let entries = [("fresh", "a"), ("key-2", "different")];
+        assert!(matches!(
+            crate::ingest_batch(&dir, entries),
+            Err(crate::Error::KeyConflict(_, key)) if key == "key-2"
+        ));
+        assert!(crate::metadata(&dir, "fresh").unwrap().is_none());
+    }
+
     #[test]
     fn hash_write() {
         let tmp = tempfile::tempdir().unwrap();
@@ -254,4 +1245,129 @@ mod tests {
             String::from_utf8(bytes).expect("we wrote valid utf8 but did not read valid utf8 back");
         assert_eq!(result, original, "we did not read back what we wrote");
     }
+
+    #[test]
+    fn pointer_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        let external = tmp.path().join("big.bin");
+        std::fs::write(&external, b"external data").unwrap();
+        let sri = ssri::Integrity::from(b"external data");
+
+        crate::write_pointer(&dir, "big", &external, sri.clone(), 13).unwrap();
+        assert_eq!(crate::read(&dir, "big").unwrap(), b"external data");
+        assert_eq!(
+            crate::metadata(&dir, "big").unwrap().unwrap().external,
+            Some(external.clone())
+        );
+        // Nothing was copied into the cache.
+        assert!(!crate::exists(&dir, &sri));
+
+        // Changes to the external file are caught.
+        std::fs::write(&external, b"tampered data").unwrap();
+        assert!(crate::read(&dir, "big").is_err());
+
+        crate::remove_fully(&dir, "big").unwrap();
+        assert!(external.exists());
+    }
+
+    #[test]
+    fn alias_entries() {
+        use std::io::Write;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::WriteOpts::new()
+            .content_type("text/plain")
+            .metadata("original")
+            .open(&dir, "original")
+            .and_then(|mut writer| {
+                writer.write_all(b"shared").unwrap();
+                writer.commit()
+            })
+            .unwrap();
+        let content = crate::content_path(&dir, &sri).unwrap();
+        let modified = std::fs::metadata(&content).unwrap().modified().unwrap();
+
+        assert_eq!(crate::alias(&dir, "by-key", "original").unwrap(), sri);
+        assert_eq!(crate::alias(&dir, "by-hash", &sri).unwrap(), sri);
+        for key in ["by-key", "by-hash"] {
+            assert_eq!(crate::read(&dir, key).unwrap(), b"shared");
+        }
+        assert_eq!(crate::metadata(&dir, "by-hash").unwrap().unwrap().size, 6);
+        let original = crate::metadata(&dir, "original").unwrap().unwrap();
+        let entry = crate::metadata(&dir, "by-key").unwrap().unwrap();
+        assert_eq!(entry.size, original.size);
+        assert_eq!(entry.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(entry.metadata, serde_json::Value::Null);
+        assert_eq!(crate::ref_count(&dir, &sri).unwrap(), 3);
+        assert_eq!(
+            std::fs::metadata(&content).unwrap().modified().unwrap(),
+            modified
+        );
+
+        assert!(matches!(
+            crate::alias(&dir, "nope", "missing"),
+            Err(crate::Error::EntryNotFound(_, key)) if key == "missing"
+        ));
+        assert!(crate::alias(&dir, "nope", ssri::Integrity::from(b"missing")).is_err());
+        crate::write_metadata_only(&dir, "empty", "no data").unwrap();
+        assert!(matches!(
+            crate::alias(&dir, "nope", "empty"),
+            Err(crate::Error::NoContent(..))
+        ));
+        assert!(crate::metadata(&dir, "nope").unwrap().is_none());
+    }
+
+    #[test]
+    fn metadata_only_entries() {
+        for format in [crate::IndexFormat::Json, crate::IndexFormat::Binary] {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path().to_owned();
+            crate::configure(&dir, crate::CacheConfig::new().index_format(format)).unwrap();
+            crate::write_metadata_only(&dir, "missing", "not found upstream").unwrap();
+
+            let entry = crate::metadata(&dir, "missing").unwrap().unwrap();
+            assert!(entry.metadata_only);
+            assert_eq!(entry.metadata, "not found upstream");
+            assert!(matches!(
+                crate::read(&dir, "missing"),
+                Err(crate::Error::NoContent(_, key)) if key == "missing"
+            ));
+            assert_eq!(crate::list(&dir).count(), 1);
+
+            // Its integrity names no content, even once there's empty content
+            // in the cache.
+            crate::write(&dir, "empty", b"").unwrap();
+            assert!(!crate::exists(&dir, &entry.integrity));
+            assert!(crate::read_hash(&dir, &entry.integrity).is_err());
+            assert!(crate::Cache::new(&dir).read_hash(&entry.integrity).is_err());
+            crate::remove(&dir, "empty").unwrap();
+
+            // Writing content over it makes it a normal entry again.
+            crate::write(&dir, "missing", b"found").unwrap();
+            assert!(
+                !crate::metadata(&dir, "missing")
+                    .unwrap()
+                    .unwrap()
+                    .metadata_only
+            );
+            assert_eq!(crate::read(&dir, "missing").unwrap(), b"found");
+
+            crate::write_metadata_only(&dir, "missing", "gone again").unwrap();
+            crate::remove_fully(&dir, "missing").unwrap();
+            assert!(crate::metadata(&dir, "missing").unwrap().is_none());
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::configure(
+            &dir,
+            crate::CacheConfig::new().index_format(crate::IndexFormat::Fixed),
+        )
+        .unwrap();
+        assert!(matches!(
+            crate::write_metadata_only(&dir, "missing", "nope"),
+            Err(crate::Error::InvalidConfig(..))
+        ));
+    }
 }
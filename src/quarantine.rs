@@ -0,0 +1,343 @@
+//! Functions for setting corrupted content aside instead of deleting it.
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use ssri::Integrity;
+
+use crate::content::{filter, path, perms, read, rm};
+use crate::errors::{Error, Internal, Result};
+use crate::index;
+use crate::stats::{self, StatsDelta};
+
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// Describes why a content object was quarantined. Stored next to the
+/// quarantined object as `{cache}/quarantine/{algorithm}-{hex}.json`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct QuarantineReport {
+    /// Integrity hash the object was stored under.
+    pub integrity: String,
+    /// Where the object lived in the cache.
+    pub original_path: PathBuf,
+    /// What was found to be wrong with it.
+    pub reason: String,
+    /// When it was quarantined, in unix milliseconds.
+    pub time: u128,
+    /// Size of the object, in bytes.
+    pub size: u64,
+}
+
+/// Checks the content for `sri` against its hash, and if it doesn't match,
+/// moves it out of the way into `{cache}/quarantine` along with a
+/// [`QuarantineReport`], so the corruption can be investigated later.
+/// Returns where the content was moved to, or `None` if it was fine or
+/// missing.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///     if let Err(cacache_sync::Error::IntegrityError { .. }) =
+///         cacache_sync::read_hash("./my-cache", &sri)
+///     {
+///         cacache_sync::quarantine_hash("./my-cache", &sri)?;
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn quarantine_hash<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<Option<PathBuf>> {
+    let cache = cache.as_ref();
+    let cpath = path::content_path(cache, sri)?;
+    if !cpath.is_file() {
+        return Ok(None);
+    }
+    let mut reader = read::open_file(&cpath, sri.clone())?;
+    io::copy(&mut reader, &mut io::sink()).to_internal()?;
+    match reader.check() {
+        Ok(_) => Ok(None),
+        Err(Error::IntegrityError { source }) => {
+            quarantine_file(cache, &cpath, sri, &source.to_string()).map(Some)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Lists the reports of everything in quarantine, oldest first.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     for report in cacache_sync::list_quarantine("./my-cache")? {
+///         println!("{}: {}", report.integrity, report.reason);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn list_quarantine<P: AsRef<Path>>(cache: P) -> Result<Vec<QuarantineReport>> {
+    let mut reports = Vec::new();
+    for (_, report) in quarantined(cache.as_ref())? {
+        if let Some(report) = report {
+            reports.push(report);
+        }
+    }
+    reports.sort_by_key(|report| report.time);
+    Ok(reports)
+}
+
+/// Deletes everything that was quarantined more than `older_than` ago,
+/// returning how many objects were deleted. Objects whose report is missing
+/// go by the time their file was last modified instead.
+///
+/// ## Example
+/// ```no_run
+/// use std::time::Duration;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     // Keep a week of evidence around.
+///     cacache_sync::purge_quarantine("./my-cache", Duration::from_secs(7 * 24 * 60 * 60))?;
+///     Ok(())
+/// }
+/// ```
+pub fn purge_quarantine<P: AsRef<Path>>(cache: P, older_than: Duration) -> Result<usize> {
+    let cutoff = index::now().saturating_sub(older_than.as_millis());
+    let mut purged = 0;
+    for (object, report) in quarantined(cache.as_ref())? {
+        let time = match report {
+            Some(report) => report.time,
+            None => fs::metadata(&object)
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |time| time.as_millis()),
+        };
+        if time < cutoff {
+            remove(&object)?;
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+/// Moves the quarantined object for `sri` back into the cache, after checking
+/// that it does hash to `sri` after all, for example because it was set aside
+/// over a read error that has since gone away. Returns false if nothing for
+/// `sri` is in quarantine, and fails with [`Error::IntegrityError`] if it's
+/// still corrupt.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     for report in cacache_sync::list_quarantine("./my-cache")? {
+///         let sri = report.integrity.parse().unwrap();
+///         if cacache_sync::restore_quarantined("./my-cache", &sri).is_ok() {
+///             println!("{} was fine after all", sri);
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn restore_quarantined<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<bool> {
+    let cache = cache.as_ref();
+    let object = object_path(cache, sri);
+    if !object.is_file() {
+        return Ok(false);
+    }
+    let mut reader = read::open_file(&object, sri.clone())?;
+    io::copy(&mut reader, &mut io::sink()).to_internal()?;
+    reader.check()?;
+
+    let cpath = path::content_path(cache, sri)?;
+    if cpath.exists() {
+        // The data has been written again since, so this copy isn't needed.
+        return remove(&object).map(|_| true);
+    }
+    DirBuilder::new()
+        .recursive(true)
+        // Safe unwrap. cpath always has multiple segments
+        .create(cpath.parent().unwrap())
+        .to_internal()?;
+    let size = fs::metadata(&object).to_internal()?.len();
+    if fs::rename(&object, &cpath).is_ok() {
+        remove_report(&object)?;
+    } else {
+        // Content roots may live on other volumes.
+        fs::copy(&object, &cpath)
+            .with_context(|| format!("Failed to restore {:?} to {:?}", object, cpath))?;
+        remove(&object)?;
+    }
+    let config = crate::cache_config(cache)?;
+    if config.content_filter {
+        filter::record(cache, sri)?;
+    }
+    if config.read_only_content {
+        let perms = perms::read_only(fs::metadata(&cpath).to_internal()?.permissions());
+        fs::set_permissions(&cpath, perms)
+            .with_context(|| format!("Failed to make {:?} read-only", cpath))?;
+    }
+    stats::record(
+        cache,
+        StatsDelta {
+            content_objects: 1,
+            content_bytes: size as i64,
+            ..Default::default()
+        },
+    )?;
+    Ok(true)
+}
+
+/// Moves the content file at `cpath`, which should have held `sri`, into
+/// quarantine, writing a report with `reason` alongside it.
+pub(crate) fn quarantine_file(
+    cache: &Path,
+    cpath: &Path,
+    sri: &Integrity,
+    reason: &str,
+) -> Result<PathBuf> {
+    let dir = quarantine_dir(cache);
+    DirBuilder::new()
+        .recursive(true)
+        .create(&dir)
+        .with_context(|| format!("Failed to create quarantine directory at {:?}", dir))?;
+    let dest = object_path(cache, sri);
+    let size = fs::metadata(cpath).to_internal()?.len();
+    if fs::rename(cpath, &dest).is_err() {
+        // Content roots may live on other volumes.
+        fs::copy(cpath, &dest)
+            .with_context(|| format!("Failed to quarantine {:?} at {:?}", cpath, dest))?;
+        fs::remove_file(cpath).to_internal()?;
+    }
+    let report = QuarantineReport {
+        integrity: sri.to_string(),
+        original_path: cpath.to_path_buf(),
+        reason: reason.into(),
+        time: index::now(),
+        size,
+    };
+    let report_path = dest.with_extension("json");
+    let report = serde_json::to_vec_pretty(&report)
+        .with_context(|| format!("Failed to serialize quarantine report for {}", sri))?;
+    fs::write(&report_path, report)
+        .with_context(|| format!("Failed to write quarantine report at {:?}", report_path))?;
+    rm::prune_dirs(&crate::cache_config(cache)?, cache, cpath);
+    stats::record(
+        cache,
+        StatsDelta {
+            content_objects: -1,
+            content_bytes: -(size as i64),
+            ..Default::default()
+        },
+    )?;
+    Ok(dest)
+}
+
+pub(crate) fn quarantine_dir(cache: &Path) -> PathBuf {
+    cache.join(QUARANTINE_DIR)
+}
+
+fn object_path(cache: &Path, sri: &Integrity) -> PathBuf {
+    let (algo, hex) = sri.to_hex();
+    quarantine_dir(cache).join(format!("{}-{}", algo, hex))
+}
+
+/// Lists the quarantined objects, along with their reports if they can be
+/// read.
+fn quarantined(cache: &Path) -> Result<Vec<(PathBuf, Option<QuarantineReport>)>> {
+    let entries = match quarantine_dir(cache).read_dir() {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).to_internal()?,
+    };
+    let mut objects = Vec::new();
+    for entry in entries {
+        let object = entry.to_internal()?.path();
+        if object.extension().is_some() {
+            continue;
+        }
+        let report = fs::read(object.with_extension("json"))
+            .ok()
+            .and_then(|report| serde_json::from_slice(&report).ok());
+        objects.push((object, report));
+    }
+    Ok(objects)
+}
+
+/// Deletes a quarantined object and its report.
+fn remove(object: &Path) -> Result<()> {
+    #[cfg(windows)]
+    rm::make_writable(object);
+    fs::remove_file(object).to_internal()?;
+    remove_report(object)
+}
+
+fn remove_report(object: &Path) -> Result<()> {
+    match fs::remove_file(object.with_extension("json")) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err).to_internal()?,
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarantines_corrupt_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "key", b"hello world").unwrap();
+        assert_eq!(quarantine_hash(&dir, &sri).unwrap(), None);
+
+        let cpath = path::content_path(&dir, &sri).unwrap();
+        fs::write(&cpath, b"hello w0rld").unwrap();
+        let dest = quarantine_hash(&dir, &sri).unwrap().unwrap();
+        assert!(!cpath.exists());
+        assert_eq!(fs::read(&dest).unwrap(), b"hello w0rld");
+
+        let report = fs::read(dest.with_extension("json")).unwrap();
+        let report: QuarantineReport = serde_json::from_slice(&report).unwrap();
+        assert_eq!(report.integrity, sri.to_string());
+        assert_eq!(report.original_path, cpath);
+        assert_eq!(report.size, 11);
+
+        // Nothing left to quarantine.
+        assert_eq!(quarantine_hash(&dir, &sri).unwrap(), None);
+    }
+
+    #[test]
+    fn manage_quarantine() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let good = crate::write(&dir, "good", b"hello").unwrap();
+        let bad = crate::write(&dir, "bad", b"world").unwrap();
+        assert!(list_quarantine(&dir).unwrap().is_empty());
+
+        // Quarantined over a hiccup, rather than actual corruption.
+        let cpath = path::content_path(&dir, &good).unwrap();
+        quarantine_file(&dir, &cpath, &good, "read error").unwrap();
+        let cpath = path::content_path(&dir, &bad).unwrap();
+        fs::write(&cpath, b"w0rld").unwrap();
+        quarantine_hash(&dir, &bad).unwrap().unwrap();
+        let reports = list_quarantine(&dir).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().any(|report| report.reason == "read error"));
+        assert!(crate::read(&dir, "good").is_err());
+
+        assert!(restore_quarantined(&dir, &good).unwrap());
+        assert_eq!(crate::read(&dir, "good").unwrap(), b"hello");
+        assert!(!restore_quarantined(&dir, &good).unwrap());
+        assert!(matches!(
+            restore_quarantined(&dir, &bad),
+            Err(Error::IntegrityError { .. })
+        ));
+
+        assert_eq!(purge_quarantine(&dir, Duration::from_secs(60)).unwrap(), 0);
+        // Purging is by millisecond timestamps.
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(purge_quarantine(&dir, Duration::ZERO).unwrap(), 1);
+        assert!(list_quarantine(&dir).unwrap().is_empty());
+        assert_eq!(quarantine_dir(&dir).read_dir().unwrap().count(), 0);
+    }
+}
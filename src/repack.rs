@@ -0,0 +1,112 @@
+//! A single maintenance pass that shrinks a cache down to what it needs.
+use std::path::Path;
+
+use crate::content::filter;
+use crate::errors::Result;
+use crate::gc::{GcOpts, GcReport};
+use crate::index;
+use crate::rm;
+use crate::stats;
+
+/// Summary of what [`repack`] did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepackReport {
+    /// Size in bytes of the index before repacking.
+    pub index_bytes_before: u64,
+    /// Size in bytes of the index after repacking.
+    pub index_bytes_after: u64,
+    /// Total size in bytes of the content before repacking.
+    pub content_bytes_before: u64,
+    /// Total size in bytes of the content after repacking.
+    pub content_bytes_after: u64,
+    /// What garbage collection removed.
+    pub gc: GcReport,
+    /// Number of empty content directories that were removed.
+    pub removed_dirs: usize,
+}
+
+impl RepackReport {
+    /// Total size in bytes of the index and content before repacking.
+    pub fn bytes_before(&self) -> u64 {
+        self.index_bytes_before + self.content_bytes_before
+    }
+
+    /// Total size in bytes of the index and content after repacking.
+    pub fn bytes_after(&self) -> u64 {
+        self.index_bytes_after + self.content_bytes_after
+    }
+}
+
+/// Rewrites `cache` into its smallest healthy form in one go: the index is
+/// compacted down to the latest entry for each live key, content no entry
+/// refers to is garbage collected like [`GcOpts::run`] does by default,
+/// empty directories are removed like [`crate::vacuum`] does, and the stats,
+/// key directory, secondary indexes and content filter are rebuilt.
+///
+/// Like garbage collection, this should not run while other processes are
+/// writing to the cache.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let report = cacache_sync::repack("./my-cache")?;
+///     println!("{} -> {} bytes", report.bytes_before(), report.bytes_after());
+///     Ok(())
+/// }
+/// ```
+pub fn repack<P: AsRef<Path>>(cache: P) -> Result<RepackReport> {
+    let cache = cache.as_ref();
+    let content_bytes_before = stats::rebuild_stats(cache)?.content_bytes;
+    let (index_bytes_before, index_bytes_after) = index::compact(cache)?;
+    let gc = GcOpts::new().run(cache)?;
+    let removed_dirs = rm::vacuum(cache)?;
+    // The key journal and secondary indexes only grow, so start them over
+    // along with everything else.
+    index::rebuild_sidecars(cache)?;
+    filter::rebuild(cache)?;
+    let content_bytes_after = stats::rebuild_stats(cache)?.content_bytes;
+    Ok(RepackReport {
+        index_bytes_before,
+        index_bytes_after,
+        content_bytes_before,
+        content_bytes_after,
+        gc,
+        removed_dirs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shrinks_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for i in 0..10 {
+            crate::write(dir, "hot", format!("version {}", i)).unwrap();
+        }
+        crate::write(dir, "kept", b"kept").unwrap();
+        crate::write(dir, "gone", b"gone").unwrap();
+        crate::remove(dir, "gone").unwrap();
+
+        let report = repack(dir).unwrap();
+        assert!(report.index_bytes_after < report.index_bytes_before);
+        assert_eq!(report.gc.content_objects, 10);
+        assert_eq!(
+            report.content_bytes_after,
+            report.content_bytes_before - report.gc.bytes
+        );
+        assert!(report.bytes_after() < report.bytes_before());
+
+        assert_eq!(crate::read(dir, "hot").unwrap(), b"version 9");
+        assert_eq!(crate::read(dir, "kept").unwrap(), b"kept");
+        assert!(crate::metadata(dir, "gone").unwrap().is_none());
+        assert_eq!(crate::list(dir).count(), 2);
+
+        // Already as small as it gets.
+        let again = repack(dir).unwrap();
+        assert_eq!(again.index_bytes_after, again.index_bytes_before);
+        assert_eq!(again.gc, GcReport::default());
+    }
+}
@@ -0,0 +1,297 @@
+//! Retrying filesystem operations that fail for transient reasons.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// The fewest retries made when moving content into place on Windows, where
+/// virus scanners and indexers routinely hold freshly written files open for
+/// a moment.
+const WINDOWS_PERSIST_ATTEMPTS: u32 = 6;
+
+/// How a cache retries filesystem operations that fail transiently, such as
+/// an interrupted system call, a busy file, or a Windows sharing violation
+/// while a scanner holds a freshly written file open. Applies to persisting
+/// content, appending to the index, and removing content. Part of a
+/// [`crate::CacheConfig`].
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::{CacheConfig, RetryPolicy};
+/// use std::time::Duration;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let retry = RetryPolicy::new()
+///         .attempts(5)
+///         .initial_delay(Duration::from_millis(20));
+///     cacache_sync::configure("./my-cache", CacheConfig::new().retry(retry))?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// How many times to retry an operation after its first transient
+    /// failure. Defaults to 0, which never retries. Moving content into
+    /// place on Windows always retries a few times regardless, and fails
+    /// with [`crate::Error::PersistRetriesExhausted`] once out of retries.
+    pub attempts: u32,
+    /// Milliseconds to wait before the first retry. Each retry after that
+    /// waits twice as long as the one before. Defaults to 10.
+    pub initial_delay_ms: u64,
+    /// The most milliseconds to wait before any one retry. Defaults to 1000.
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 0,
+            initial_delay_ms: 10,
+            max_delay_ms: 1000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a policy that never retries.
+    pub fn new() -> RetryPolicy {
+        Default::default()
+    }
+
+    /// Sets how many times to retry. See `attempts`.
+    pub fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Sets how long to wait before the first retry.
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay_ms = delay.as_millis() as u64;
+        self
+    }
+
+    /// Sets the longest wait before any one retry.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay_ms = delay.as_millis() as u64;
+        self
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u64.checked_shl(attempt).unwrap_or(u64::MAX);
+        Duration::from_millis(
+            self.initial_delay_ms
+                .saturating_mul(factor)
+                .min(self.max_delay_ms),
+        )
+    }
+}
+
+/// Tracks the retries of a single operation against a cache. The cache's
+/// policy is only loaded once something actually fails, so operations that
+/// succeed the first time don't pay for it.
+pub(crate) struct Backoff<'a> {
+    cache: &'a Path,
+    policy: Option<RetryPolicy>,
+    attempt: u32,
+    persist: bool,
+    exhausted: bool,
+}
+
+impl<'a> Backoff<'a> {
+    pub(crate) fn new(cache: &'a Path) -> Backoff<'a> {
+        Backoff {
+            cache,
+            policy: None,
+            attempt: 0,
+            persist: false,
+            exhausted: false,
+        }
+    }
+
+    /// Creates a backoff for renaming content into place. Its delays are
+    /// jittered, so writers racing for the same file don't retry in lockstep,
+    /// and on Windows it always makes a few retries whatever the policy says.
+    pub(crate) fn for_persist(cache: &'a Path) -> Backoff<'a> {
+        Backoff {
+            persist: true,
+            ..Backoff::new(cache)
+        }
+    }
+
+    /// Returns how many retries were made.
+    pub(crate) fn attempts(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Returns whether the operation was given up on because it ran out of
+    /// retries, rather than because it failed for a non-transient reason.
+    pub(crate) fn exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    /// Returns whether the operation that failed with `err` should be tried
+    /// again, after waiting out the backoff delay if so.
+    pub(crate) fn retry(&mut self, err: &io::Error) -> bool {
+        if !is_transient(err) {
+            return false;
+        }
+        let cache = self.cache;
+        // A config that can't be read shouldn't hide the original error.
+        let policy = self
+            .policy
+            .get_or_insert_with(|| config::load(cache).map(|c| c.retry).unwrap_or_default());
+        let mut attempts = policy.attempts;
+        if self.persist && cfg!(windows) {
+            attempts = attempts.max(WINDOWS_PERSIST_ATTEMPTS);
+        }
+        if self.attempt >= attempts {
+            self.exhausted = attempts > 0;
+            return false;
+        }
+        let mut delay = policy.delay(self.attempt);
+        if self.persist {
+            delay = jitter(delay);
+        }
+        std::thread::sleep(delay);
+        self.attempt += 1;
+        true
+    }
+}
+
+/// Runs `op`, retrying it according to the cache's [`RetryPolicy`] for as
+/// long as it fails transiently.
+pub(crate) fn with_retries<T, F>(cache: &Path, mut op: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    let mut backoff = Backoff::new(cache);
+    loop {
+        match op() {
+            Err(err) if backoff.retry(&err) => continue,
+            res => return res,
+        }
+    }
+}
+
+/// Picks a random delay between half of `delay` and all of it.
+fn jitter(delay: Duration) -> Duration {
+    // Every `RandomState` is seeded differently, which is all the randomness
+    // this needs.
+    let random = RandomState::new().build_hasher().finish();
+    let half = delay / 2;
+    half + Duration::from_nanos(random % (half.as_nanos() as u64 + 1))
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    if err.kind() == io::ErrorKind::Interrupted {
+        return true;
+    }
+    // EBUSY and ETXTBSY.
+    #[cfg(unix)]
+    let codes = [16, 26];
+    // ERROR_ACCESS_DENIED, ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION,
+    // which is how files held open by scanners and indexers show up.
+    #[cfg(windows)]
+    let codes = [5, 32, 33];
+    #[cfg(not(any(unix, windows)))]
+    let codes: [i32; 0] = [];
+    matches!(err.raw_os_error(), Some(code) if codes.contains(&code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CacheConfig;
+
+    #[test]
+    fn retries_transient_errors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let interrupted = || io::Error::from(io::ErrorKind::Interrupted);
+
+        // Never retries by default.
+        let mut calls = 0;
+        let res: io::Result<()> = with_retries(&dir, || {
+            calls += 1;
+            Err(interrupted())
+        });
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+
+        let retry = RetryPolicy::new()
+            .attempts(3)
+            .initial_delay(Duration::from_millis(1));
+        config::configure(&dir, CacheConfig::new().retry(retry.clone())).unwrap();
+        assert_eq!(config::cache_config(&dir).unwrap().retry, retry);
+
+        let mut calls = 0;
+        let res = with_retries(&dir, || {
+            calls += 1;
+            if calls < 3 {
+                Err(interrupted())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+
+        let mut calls = 0;
+        let res: io::Result<()> = with_retries(&dir, || {
+            calls += 1;
+            Err(interrupted())
+        });
+        assert!(res.is_err());
+        assert_eq!(calls, 4);
+
+        // Other errors aren't worth retrying.
+        let mut calls = 0;
+        let res: io::Result<()> = with_retries(&dir, || {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn persist_backoff() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let retry = RetryPolicy::new()
+            .attempts(8)
+            .initial_delay(Duration::from_millis(1));
+        config::configure(&dir, CacheConfig::new().retry(retry)).unwrap();
+
+        let busy = io::Error::from(io::ErrorKind::Interrupted);
+        let mut backoff = Backoff::for_persist(&dir);
+        while backoff.retry(&busy) {}
+        assert_eq!(backoff.attempts(), 8);
+        assert!(backoff.exhausted());
+
+        let mut backoff = Backoff::for_persist(&dir);
+        assert!(!backoff.retry(&io::Error::from(io::ErrorKind::NotFound)));
+        assert!(!backoff.exhausted());
+
+        for _ in 0..100 {
+            let delay = jitter(Duration::from_millis(10));
+            assert!(delay >= Duration::from_millis(5) && delay <= Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn backoff_delays() {
+        let policy = RetryPolicy::new()
+            .initial_delay(Duration::from_millis(10))
+            .max_delay(Duration::from_millis(50));
+        assert_eq!(policy.delay(0), Duration::from_millis(10));
+        assert_eq!(policy.delay(2), Duration::from_millis(40));
+        assert_eq!(policy.delay(3), Duration::from_millis(50));
+        assert_eq!(policy.delay(100), Duration::from_millis(50));
+    }
+}
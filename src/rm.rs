@@ -3,10 +3,13 @@ use std::fs;
 use std::path::Path;
 
 use ssri::Integrity;
+use walkdir::WalkDir;
 
-use crate::content::rm;
-use crate::errors::{Internal, Result};
+use crate::config::{self, CONFIG_FILE};
+use crate::content::{path, read, rm};
+use crate::errors::{Error, Internal, Result};
 use crate::index;
+use crate::stats::{self, StatsDelta};
 
 /// Removes an individual index entry synchronously. The associated content
 /// will be left in the cache.
@@ -37,6 +40,69 @@ where
     index::delete(cache.as_ref(), key.as_ref())
 }
 
+/// Removes an individual index entry synchronously, along with its associated
+/// content if no other index entry still references it.
+///
+/// ## Example
+/// ```no_run
+/// use std::io::Read;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///
+///     cacache_sync::remove_fully("./my-cache", "my-key")?;
+///
+///     // These both fail, since nothing else pointed at the content:
+///     cacache_sync::read("./my-cache", "my-key")?;
+///     cacache_sync::read_hash("./my-cache", &sri)?;
+///
+///     Ok(())
+/// }
+/// ```
+pub fn remove_fully<P, K>(cache: P, key: K) -> Result<()>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    let cache = cache.as_ref();
+    let entry = match index::find(cache, key.as_ref())? {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+    index::delete(cache, key.as_ref())?;
+    if !entry.metadata_only
+        && index::references(cache, &entry.integrity)? == 0
+        && read::has_content(cache, &entry.integrity).is_some()
+    {
+        rm::rm(cache, &entry.integrity)?;
+    }
+    Ok(())
+}
+
+/// Removes every index entry that was written with `tag` synchronously,
+/// returning how many were removed. Their content is left in the cache.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let removed = cacache_sync::remove_by_tag("./my-cache", "build-1234")?;
+///     println!("dropped {} entries", removed);
+///     Ok(())
+/// }
+/// ```
+pub fn remove_by_tag<P, T>(cache: P, tag: T) -> Result<usize>
+where
+    P: AsRef<Path>,
+    T: AsRef<str>,
+{
+    let mut removed = 0;
+    for entry in crate::list_by_tag(cache.as_ref(), tag) {
+        index::delete(cache.as_ref(), &entry?.key)?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
 /// Removes an individual content entry synchronously. Any index entries
 /// pointing to this content will become invalidated.
 ///
@@ -63,8 +129,147 @@ pub fn remove_hash<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<()> {
     rm::rm(cache.as_ref(), sri)
 }
 
+/// What [`remove_hash_checked`] does about index entries that still reference
+/// the content being removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Referenced {
+    /// Leave everything in place and fail with [`crate::Error::ContentInUse`].
+    Refuse,
+    /// Remove the referencing index entries first, then the content.
+    RemoveEntries,
+}
+
+/// Removes an individual content entry synchronously, like [`remove_hash`],
+/// but without leaving index entries behind that point at missing content.
+/// Returns the number of index entries that were removed along with it.
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::Referenced;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///
+///     // This fails, since "my-key" still needs the content:
+///     cacache_sync::remove_hash_checked("./my-cache", &sri, Referenced::Refuse)?;
+///
+///     // This removes "my-key" along with it:
+///     cacache_sync::remove_hash_checked("./my-cache", &sri, Referenced::RemoveEntries)?;
+///
+///     Ok(())
+/// }
+/// ```
+pub fn remove_hash_checked<P: AsRef<Path>>(
+    cache: P,
+    sri: &Integrity,
+    referenced: Referenced,
+) -> Result<usize> {
+    let cache = cache.as_ref();
+    let keys = index::referencing_keys(cache, sri)?;
+    if !keys.is_empty() && referenced == Referenced::Refuse {
+        return Err(Error::ContentInUse(sri.clone(), keys.len()));
+    }
+    // Entries go first, so none are ever left pointing at missing content.
+    for key in &keys {
+        index::delete(cache, key)?;
+    }
+    if read::has_content(cache, sri).is_some() {
+        rm::rm(cache, sri)?;
+    }
+    Ok(keys.len())
+}
+
+/// Removes every index entry synchronously, keeping all content. Content is
+/// still readable by hash, and can be indexed again under new keys without
+/// being rewritten. Returns the number of live entries that were removed.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let sri = cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///
+///     cacache_sync::clear_index("./my-cache")?;
+///
+///     // This fails:
+///     cacache_sync::read("./my-cache", "my-key")?;
+///
+///     // But this succeeds:
+///     cacache_sync::read_hash("./my-cache", &sri)?;
+///
+///     Ok(())
+/// }
+/// ```
+pub fn clear_index<P: AsRef<Path>>(cache: P) -> Result<usize> {
+    let cache = cache.as_ref();
+    let index_dir = index::index_dir(cache);
+    if !index_dir.exists() {
+        return Ok(0);
+    }
+    // A missing or unreadable index just means there's nothing to count.
+    let entries = index::ls(cache).filter(|entry| entry.is_ok()).count();
+    // Batches left unfinished belong to the index being cleared.
+    index::discard_journals(cache)?;
+    // Move the index out of the way first, so it disappears all at once
+    // rather than bucket by bucket.
+    let tmp_path = cache.join("tmp");
+    fs::create_dir_all(&tmp_path)
+        .with_context(|| format!("Failed to create tmp directory at {:?}", tmp_path))?;
+    let trash = tempfile::Builder::new()
+        .prefix("index-")
+        .tempdir_in(&tmp_path)
+        .to_internal()?;
+    fs::rename(&index_dir, trash.path().join("index"))
+        .with_context(|| format!("Failed to move index at {:?} out of the way", index_dir))?;
+    trash.close().to_internal()?;
+    index::rebuild_sidecars(cache)?;
+    stats::record(
+        cache,
+        StatsDelta {
+            entries: -(entries as i64),
+            ..Default::default()
+        },
+    )?;
+    Ok(entries)
+}
+
+/// Removes empty directories left behind in the cache's content directories
+/// synchronously, returning how many were removed. Content removals already
+/// clean up after themselves, so this is mostly useful for caches that had
+/// content removed by older versions, or by hand.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let removed = cacache_sync::vacuum("./my-cache")?;
+///     println!("removed {} empty directories", removed);
+///     Ok(())
+/// }
+/// ```
+pub fn vacuum<P: AsRef<Path>>(cache: P) -> Result<usize> {
+    let config = config::load(cache.as_ref())?;
+    let mut removed = 0;
+    for dir in path::content_dirs(&config, cache.as_ref()) {
+        // Children come before their parents, so emptied parents go too.
+        for entry in WalkDir::new(&dir).min_depth(1).contents_first(true) {
+            let entry = match entry {
+                Ok(entry) if entry.file_type().is_dir() => entry,
+                Ok(_) => continue,
+                // No content yet.
+                Err(err) if err.depth() == 0 => break,
+                Err(err) => return Err(err).to_internal()?,
+            };
+            // Fails for directories that aren't empty, which is fine.
+            if fs::remove_dir(entry.path()).is_ok() {
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
 /// Removes entire contents of the cache synchronously, including temporary
-/// files, the entry index, and all content data.
+/// files, the entry index, and all content data. The cache configuration, if
+/// any, is kept.
 ///
 /// ## Example
 /// ```no_run
@@ -84,12 +289,105 @@ pub fn remove_hash<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<()> {
 /// }
 /// ```
 pub fn clear<P: AsRef<Path>>(cache: P) -> Result<()> {
+    let config = config::load(cache.as_ref())?;
     for entry in (cache.as_ref().read_dir().to_internal()?).flatten() {
-        fs::remove_dir_all(entry.path()).to_internal()?;
+        if entry.file_name() == CONFIG_FILE {
+            continue;
+        }
+        remove_path(&entry.path())?;
+    }
+    for dir in path::content_dirs(&config, cache.as_ref()) {
+        if dir.exists() {
+            fs::remove_dir_all(dir).to_internal()?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path).to_internal()?;
+    } else {
+        fs::remove_file(path).to_internal()?;
     }
     Ok(())
 }
 
+/// Summary of what was removed by [`clear_with_report`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClearReport {
+    /// Number of live index entries that were removed.
+    pub entries: usize,
+    /// Number of content objects that were removed.
+    pub content_objects: usize,
+    /// Number of leftover temporary files that were removed.
+    pub temp_files: usize,
+    /// Total size in bytes of all files that were removed.
+    pub bytes: u64,
+}
+
+/// Removes entire contents of the cache synchronously, like [`clear`], and
+/// returns a report of what was removed.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     cacache_sync::write("./my-cache", "my-key", b"hello")?;
+///
+///     let report = cacache_sync::clear_with_report("./my-cache")?;
+///     println!("removed {} entries ({} bytes)", report.entries, report.bytes);
+///
+///     Ok(())
+/// }
+/// ```
+pub fn clear_with_report<P: AsRef<Path>>(cache: P) -> Result<ClearReport> {
+    let cache = cache.as_ref();
+    let mut report = ClearReport {
+        // A missing or unreadable index just means there's nothing to count.
+        entries: index::ls(cache).filter(|entry| entry.is_ok()).count(),
+        ..Default::default()
+    };
+    let config = config::load(cache)?;
+    let content_dirs = path::content_dirs(&config, cache);
+    // Writes to content roots are staged in a tmp dir of their own.
+    let mut tmp_dirs = vec![cache.join("tmp")];
+    for dir in &content_dirs {
+        // Safe unwrap. Content dirs are always inside their root.
+        let tmp_dir = dir.parent().unwrap().join("tmp");
+        if !tmp_dirs.contains(&tmp_dir) {
+            tmp_dirs.push(tmp_dir);
+        }
+    }
+    let mut dirs = Vec::new();
+    for entry in (cache.read_dir().to_internal()?).flatten() {
+        if entry.file_name() != CONFIG_FILE {
+            dirs.push(entry.path());
+        }
+    }
+    // Content roots outside the cache.
+    for dir in content_dirs.iter().chain(&tmp_dirs) {
+        if dir.exists() && !dirs.iter().any(|top| dir.starts_with(top)) {
+            dirs.push(dir.clone());
+        }
+    }
+    for dir in dirs {
+        for file in WalkDir::new(&dir) {
+            let file = file.to_internal()?;
+            if !file.file_type().is_file() {
+                continue;
+            }
+            report.bytes += file.metadata().to_internal()?.len();
+            if content_dirs.iter().any(|dir| file.path().starts_with(dir)) {
+                report.content_objects += 1;
+            } else if tmp_dirs.iter().any(|dir| file.path().starts_with(dir)) {
+                report.temp_files += 1;
+            }
+        }
+        remove_path(&dir)?;
+    }
+    Ok(report)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -108,6 +406,45 @@ mod tests {
         assert!(data_exists);
     }
 
+    #[test]
+    fn test_remove_fully() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "key", b"my-data").unwrap();
+        crate::write(&dir, "other-key", b"my-data").unwrap();
+
+        crate::remove_fully(&dir, "key").unwrap();
+        assert!(crate::metadata(&dir, "key").unwrap().is_none());
+        // Still referenced by "other-key".
+        assert!(crate::exists(&dir, &sri));
+
+        crate::remove_fully(&dir, "other-key").unwrap();
+        assert!(crate::metadata(&dir, "other-key").unwrap().is_none());
+        assert!(!crate::exists(&dir, &sri));
+    }
+
+    #[test]
+    fn test_remove_by_tag() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        for key in ["a", "b"] {
+            let mut fd = crate::WriteOpts::new()
+                .tag("build-1")
+                .open(&dir, key)
+                .unwrap();
+            std::io::Write::write_all(&mut fd, b"my-data").unwrap();
+            fd.commit().unwrap();
+        }
+        let sri = crate::write(&dir, "c", b"my-data").unwrap();
+
+        assert_eq!(crate::remove_by_tag(&dir, "build-1").unwrap(), 2);
+        assert!(crate::metadata(&dir, "a").unwrap().is_none());
+        assert!(crate::metadata(&dir, "b").unwrap().is_none());
+        assert!(crate::metadata(&dir, "c").unwrap().is_some());
+        assert!(crate::exists(&dir, &sri));
+        assert_eq!(crate::remove_by_tag(&dir, "build-1").unwrap(), 0);
+    }
+
     #[test]
     fn test_remove_data() {
         let tmp = tempfile::tempdir().unwrap();
@@ -123,6 +460,80 @@ mod tests {
         assert!(!data_exists);
     }
 
+    #[test]
+    fn test_remove_hash_checked() {
+        use crate::Referenced;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "key", b"my-data").unwrap();
+        crate::write(&dir, "other-key", b"my-data").unwrap();
+        crate::write(&dir, "unrelated", b"other-data").unwrap();
+
+        assert!(matches!(
+            crate::remove_hash_checked(&dir, &sri, Referenced::Refuse),
+            Err(crate::Error::ContentInUse(_, 2))
+        ));
+        assert!(crate::exists(&dir, &sri));
+        assert!(crate::metadata(&dir, "key").unwrap().is_some());
+
+        let removed = crate::remove_hash_checked(&dir, &sri, Referenced::RemoveEntries).unwrap();
+        assert_eq!(removed, 2);
+        assert!(!crate::exists(&dir, &sri));
+        assert!(crate::metadata(&dir, "key").unwrap().is_none());
+        assert!(crate::metadata(&dir, "other-key").unwrap().is_none());
+        assert!(crate::metadata(&dir, "unrelated").unwrap().is_some());
+
+        // Unreferenced content is removed under either policy.
+        let sri = crate::write_hash(&dir, b"loose").unwrap();
+        assert_eq!(
+            crate::remove_hash_checked(&dir, &sri, Referenced::Refuse).unwrap(),
+            0
+        );
+        assert!(!crate::exists(&dir, &sri));
+    }
+
+    #[test]
+    fn test_clear_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::configure(&dir, crate::CacheConfig::new().track_stats(true)).unwrap();
+        let sri = crate::write(&dir, "key", b"my-data").unwrap();
+        crate::write(&dir, "other-key", b"other-data").unwrap();
+
+        assert_eq!(crate::clear_index(&dir).unwrap(), 2);
+        assert!(crate::metadata(&dir, "key").unwrap().is_none());
+        assert_eq!(crate::read_hash(&dir, &sri).unwrap(), b"my-data");
+        assert_eq!(crate::stats(&dir).unwrap().entries, 0);
+        assert_eq!(crate::stats(&dir).unwrap().content_objects, 2);
+        assert_eq!(crate::clear_index(&dir).unwrap(), 0);
+
+        crate::write(&dir, "new-key", b"my-data").unwrap();
+        assert_eq!(crate::read(&dir, "new-key").unwrap(), b"my-data");
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "key", b"my-data").unwrap();
+        let other = crate::write(&dir, "other-key", b"other-data").unwrap();
+        let cpath = crate::content_path(&dir, &sri).unwrap();
+
+        // Removals prune the directories they empty...
+        crate::remove_hash(&dir, &sri).unwrap();
+        assert!(!cpath.parent().unwrap().exists());
+        assert!(crate::exists(&dir, &other));
+        crate::write(&dir, "key", b"my-data").unwrap();
+        assert!(crate::exists(&dir, &sri));
+
+        // ...and vacuum catches any left behind some other way.
+        std::fs::remove_file(&cpath).unwrap();
+        assert_eq!(crate::vacuum(&dir).unwrap(), 2);
+        assert!(!cpath.parent().unwrap().parent().unwrap().exists());
+        assert!(crate::exists(&dir, &other));
+        assert_eq!(crate::vacuum(&dir).unwrap(), 0);
+    }
+
     #[test]
     fn test_clear() {
         let tmp = tempfile::tempdir().unwrap();
@@ -137,4 +548,49 @@ mod tests {
         let data_exists = crate::exists(&dir, &sri);
         assert!(!data_exists);
     }
+
+    #[test]
+    fn test_clear_with_report() {
+        use std::io::Write;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "key", b"my-data").unwrap();
+        crate::write(&dir, "other-key", b"my-data").unwrap();
+        crate::write(&dir, "third-key", b"more-data").unwrap();
+        let mut fd = crate::Writer::create(&dir, "uncommitted").unwrap();
+        fd.write_all(b"hello").unwrap();
+        std::mem::forget(fd);
+
+        let report = crate::clear_with_report(&dir).unwrap();
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.content_objects, 2);
+        assert_eq!(report.temp_files, 1);
+        assert!(report.bytes >= 16);
+        assert_eq!(crate::metadata(&dir, "key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_clear_with_report_content_roots() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        let disk = tempfile::tempdir().unwrap();
+        let config = crate::CacheConfig::new()
+            .content_root("shard")
+            .content_root(disk.path());
+        crate::configure(&dir, config).unwrap();
+        for i in 0..10 {
+            crate::write(&dir, format!("key-{}", i), format!("data-{}", i)).unwrap();
+        }
+        // Left behind by a write to the outside root that never finished.
+        std::fs::create_dir_all(disk.path().join("tmp")).unwrap();
+        std::fs::write(disk.path().join("tmp").join("leftover"), b"data").unwrap();
+
+        let report = crate::clear_with_report(&dir).unwrap();
+        assert_eq!(report.entries, 10);
+        assert_eq!(report.content_objects, 10);
+        assert_eq!(report.temp_files, 1);
+        assert!(!dir.join("shard").exists());
+        assert!(!crate::content::path::content_dir(disk.path()).exists());
+        assert!(!disk.path().join("tmp").exists());
+    }
 }
@@ -0,0 +1,213 @@
+//! Secondary indexes from metadata fields back to the keys that have them,
+//! for caches configured with [`crate::CacheConfig::indexed_fields`].
+//!
+//! Each indexed field and value gets a sidecar file under
+//! `{cache}/secondary-v1`, named after hashes of the two, listing the keys
+//! written with that value one JSON string per line. Sidecars are only ever
+//! appended to, so they can hold keys that have since moved on to another
+//! value or been removed. Lookups check every key against the index, which
+//! keeps them correct, and [`crate::repack`] rebuilds them from scratch.
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use digest::Digest;
+use serde_json::Value;
+use sha1::Sha1;
+
+use crate::config;
+use crate::errors::{Error, Internal, Result};
+use crate::index::{self, Metadata};
+
+const SECONDARY_DIR: &str = "secondary-v1";
+
+/// Finds the entries whose metadata has `value` in its top-level `field`,
+/// using the secondary index the cache keeps for it, so there's no need to
+/// scan the whole index. Each matching key is returned once.
+///
+/// Fails with [`Error::InvalidConfig`] if `field` isn't one of the cache's
+/// [`indexed_fields`](crate::CacheConfig::indexed_fields). For one-off
+/// queries on other fields, see [`crate::list_where`].
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::CacheConfig;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     cacache_sync::configure("./my-cache", CacheConfig::new().indexed_field("etag"))?;
+///     cacache_sync::WriteOpts::new()
+///         .metadata(serde_json::json!({ "etag": "abc123" }))
+///         .open("./my-cache", "https://example.com/app.js")?
+///         .commit()?;
+///     let entries = cacache_sync::find_by("./my-cache", "etag", "abc123")?;
+///     assert_eq!(entries[0].key, "https://example.com/app.js");
+///     Ok(())
+/// }
+/// ```
+pub fn find_by<P, F, V>(cache: P, field: F, value: V) -> Result<Vec<Metadata>>
+where
+    P: AsRef<Path>,
+    F: AsRef<str>,
+    V: Into<Value>,
+{
+    let (cache, field, value) = (cache.as_ref(), field.as_ref(), value.into());
+    if !config::load(cache)?
+        .indexed_fields
+        .iter()
+        .any(|indexed| indexed == field)
+    {
+        return Err(Error::InvalidConfig(
+            cache.to_path_buf(),
+            format!("metadata field `{}` isn't indexed", field),
+        ));
+    }
+    let path = sidecar_path(cache, field, &value);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read secondary index at {:?}", path))?
+        }
+    };
+    let mut seen = HashSet::new();
+    let mut found = Vec::new();
+    // Torn lines fail to parse, and are skipped like bad index lines.
+    for key in data
+        .split(|b| *b == b'\n')
+        .filter_map(|line| serde_json::from_slice::<String>(line).ok())
+    {
+        if !seen.insert(key.clone()) {
+            continue;
+        }
+        if let Some(entry) = index::find(cache, &key)? {
+            if entry.metadata.get(field) == Some(&value) {
+                found.push(entry);
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Adds `key` to the sidecars for whichever of `fields` its `metadata` has.
+pub(crate) fn record(cache: &Path, fields: &[String], key: &str, metadata: &Value) -> Result<()> {
+    for field in fields {
+        let value = match metadata.get(field) {
+            Some(value) => value,
+            None => continue,
+        };
+        let path = sidecar_path(cache, field, value);
+        fs::create_dir_all(path.parent().unwrap()).with_context(|| {
+            format!(
+                "Failed to create secondary index directory at {:?}",
+                path.parent().unwrap()
+            )
+        })?;
+        let mut line = serde_json::to_vec(key).to_internal()?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut fd| fd.write_all(&line))
+            .with_context(|| format!("Failed to write to secondary index at {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Throws away all the sidecars and builds them again from the index, for
+/// whatever fields the cache indexes now.
+pub(crate) fn rebuild(cache: &Path) -> Result<()> {
+    let dir = cache.join(SECONDARY_DIR);
+    match fs::remove_dir_all(&dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to remove secondary indexes at {:?}", dir))?
+        }
+    }
+    let fields = config::load(cache)?.indexed_fields;
+    if fields.is_empty() || !index::index_dir(cache).exists() {
+        return Ok(());
+    }
+    for entry in index::ls(cache) {
+        let entry = entry?;
+        record(cache, &fields, &entry.key, &entry.metadata)?;
+    }
+    Ok(())
+}
+
+fn sidecar_path(cache: &Path, field: &str, value: &Value) -> PathBuf {
+    let field = hex::encode(Sha1::digest(field.as_bytes()));
+    let value = hex::encode(Sha1::digest(value.to_string().as_bytes()));
+    cache
+        .join(SECONDARY_DIR)
+        .join(field)
+        .join(&value[..2])
+        .join(&value[2..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CacheConfig, WriteOpts};
+
+    fn write(cache: &Path, key: &str, metadata: Value) {
+        let mut writer = WriteOpts::new()
+            .metadata(metadata)
+            .open(cache, key)
+            .unwrap();
+        writer.write_all(key.as_bytes()).unwrap();
+        writer.commit().unwrap();
+    }
+
+    fn keys(entries: Vec<Metadata>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.key).collect()
+    }
+
+    #[test]
+    fn finds_by_field() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        write(dir, "before", serde_json::json!({ "etag": "a" }));
+        crate::configure(dir, CacheConfig::new().indexed_field("etag")).unwrap();
+        write(dir, "one", serde_json::json!({ "etag": "a", "size": 1 }));
+        write(dir, "two", serde_json::json!({ "etag": "b" }));
+        write(dir, "three", serde_json::json!({ "etag": "a" }));
+        write(dir, "none", Value::Null);
+
+        // Entries from before the field was indexed are found too.
+        assert_eq!(
+            keys(find_by(dir, "etag", "a").unwrap()),
+            ["before", "one", "three"]
+        );
+        assert_eq!(keys(find_by(dir, "etag", "b").unwrap()), ["two"]);
+        assert!(find_by(dir, "etag", "c").unwrap().is_empty());
+        assert!(matches!(
+            find_by(dir, "size", 1),
+            Err(Error::InvalidConfig(..))
+        ));
+
+        // Stale mappings are filtered out, and dropped by a rebuild.
+        write(dir, "one", serde_json::json!({ "etag": "b" }));
+        crate::remove(dir, "three").unwrap();
+        assert_eq!(keys(find_by(dir, "etag", "a").unwrap()), ["before"]);
+        assert_eq!(keys(find_by(dir, "etag", "b").unwrap()), ["two", "one"]);
+        rebuild(dir).unwrap();
+        let sidecar = fs::read(sidecar_path(dir, "etag", &Value::from("a"))).unwrap();
+        assert_eq!(sidecar, b"\"before\"\n");
+
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            crate::configure(
+                tmp.path(),
+                CacheConfig::new()
+                    .indexed_field("etag")
+                    .index_format(crate::IndexFormat::Fixed)
+            ),
+            Err(Error::InvalidConfig(..))
+        ));
+    }
+}
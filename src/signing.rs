@@ -0,0 +1,165 @@
+//! Functions for signing index entries and verifying their signatures.
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde_json::Value;
+
+use crate::errors::{Error, Result};
+use crate::index::{self, Metadata};
+
+/// Gets the index entry for `key` like [`crate::metadata`], but fails with
+/// [`Error::SignatureError`] unless the entry was signed by `signer`.
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::{SigningKey, WriteOpts};
+/// use std::io::Write;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let key = SigningKey::from_bytes(&[7; 32]);
+///     let mut fd = WriteOpts::new().sign_with(&key).open("./my-cache", "my-key")?;
+///     fd.write_all(b"hello").expect("Failed to write to cache");
+///     fd.commit()?;
+///
+///     let entry = cacache_sync::metadata_verified("./my-cache", "my-key", &key.verifying_key())?;
+///     assert!(entry.is_some());
+///     Ok(())
+/// }
+/// ```
+pub fn metadata_verified<P, K>(cache: P, key: K, signer: &VerifyingKey) -> Result<Option<Metadata>>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    let entry = match index::find(cache.as_ref(), key.as_ref())? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    if !verify(signer, &entry) {
+        return Err(Error::SignatureError(
+            cache.as_ref().to_path_buf(),
+            key.as_ref().into(),
+        ));
+    }
+    Ok(Some(entry))
+}
+
+/// Reads the data for `key` like [`crate::read`], but fails with
+/// [`Error::SignatureError`] unless its index entry was signed by `signer`.
+/// Since the signature covers the content's integrity hash, this proves the
+/// data itself came from the signer.
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::SigningKey;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let trusted = SigningKey::from_bytes(&[7; 32]).verifying_key();
+///     let data = cacache_sync::read_verified("./my-cache", "my-key", &trusted)?;
+///     Ok(())
+/// }
+/// ```
+pub fn read_verified<P, K>(cache: P, key: K, signer: &VerifyingKey) -> Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    match metadata_verified(cache.as_ref(), key.as_ref(), signer)? {
+        Some(entry) if entry.metadata_only => Err(Error::NoContent(
+            cache.as_ref().to_path_buf(),
+            key.as_ref().into(),
+        )),
+        Some(entry) => crate::read_hash(cache, &entry.integrity),
+        None => Err(Error::EntryNotFound(
+            cache.as_ref().to_path_buf(),
+            key.as_ref().into(),
+        )),
+    }
+}
+
+/// Signs the fields of an index entry, returning the hex-encoded signature.
+pub(crate) fn sign(
+    signing_key: &SigningKey,
+    key: &str,
+    integrity: &str,
+    time: u128,
+    metadata: &Value,
+) -> String {
+    let signature = signing_key.sign(&payload(key, integrity, time, metadata));
+    hex::encode(signature.to_bytes())
+}
+
+fn verify(signer: &VerifyingKey, entry: &Metadata) -> bool {
+    let signature = match entry.signature.as_deref().map(hex::decode) {
+        Some(Ok(bytes)) => bytes,
+        _ => return false,
+    };
+    let signature = match Signature::from_slice(&signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let payload = payload(
+        &entry.key,
+        &entry.integrity.to_string(),
+        entry.time,
+        &entry.metadata,
+    );
+    signer.verify(&payload, &signature).is_ok()
+}
+
+fn payload(key: &str, integrity: &str, time: u128, metadata: &Value) -> Vec<u8> {
+    // Object keys in a `Value` are kept sorted, so this is stable across a
+    // round trip through the index.
+    serde_json::to_vec(&(key, integrity, time, metadata)).expect("entry fields are serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WriteOpts;
+    use std::io::Write;
+
+    fn write_signed(dir: &Path, key: &str, signing_key: &SigningKey) {
+        let mut fd = WriteOpts::new()
+            .metadata(serde_json::json!({ "b": 1, "a": [true] }))
+            .sign_with(signing_key)
+            .open(dir, key)
+            .unwrap();
+        fd.write_all(b"hello").unwrap();
+        fd.commit().unwrap();
+    }
+
+    #[test]
+    fn signed_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        write_signed(&dir, "key", &signing_key);
+
+        let signer = signing_key.verifying_key();
+        assert!(metadata_verified(&dir, "key", &signer).unwrap().is_some());
+        assert_eq!(read_verified(&dir, "key", &signer).unwrap(), b"hello");
+        assert!(metadata_verified(&dir, "missing", &signer)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_signature() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let other = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        write_signed(&dir, "key", &signing_key);
+        crate::write(&dir, "unsigned", b"hello").unwrap();
+
+        assert!(matches!(
+            read_verified(&dir, "key", &other),
+            Err(Error::SignatureError(..))
+        ));
+        assert!(matches!(
+            read_verified(&dir, "unsigned", &signing_key.verifying_key()),
+            Err(Error::SignatureError(..))
+        ));
+    }
+}
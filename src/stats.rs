@@ -0,0 +1,514 @@
+//! Functions for reporting cache statistics.
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use walkdir::WalkDir;
+
+use crate::config;
+use crate::content::path;
+use crate::errors::{Internal, Result};
+use crate::index;
+use crate::lock::Lock;
+
+const STATS_FILE: &str = "stats.json";
+const STATS_LOCK: &str = "stats.json.lock";
+const VERIFY_FILE: &str = "verify.json";
+
+/// Summary statistics for a cache.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheStats {
+    /// Number of live index entries.
+    pub entries: u64,
+    /// Number of content objects.
+    pub content_objects: u64,
+    /// Total size in bytes of all content objects.
+    pub content_bytes: u64,
+}
+
+/// Returns summary statistics for a cache.
+///
+/// If the cache is configured with `track_stats`, this reads the
+/// incrementally maintained stats file and returns instantly. Otherwise, the
+/// whole cache is walked to compute them.
+///
+/// Tracked stats are approximate while writers race each other: each write
+/// works out what it changes, like whether it adds a new entry or replaces
+/// one, before making the change, so writers of the same key or content at
+/// the same time can each count it. [`rebuild_stats`] makes them exact again.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let stats = cacache_sync::stats("./my-cache")?;
+///     println!("{} entries, {} bytes", stats.entries, stats.content_bytes);
+///     Ok(())
+/// }
+/// ```
+pub fn stats<P: AsRef<Path>>(cache: P) -> Result<CacheStats> {
+    let cache = cache.as_ref();
+    if !config::load(cache)?.track_stats {
+        return compute(cache);
+    }
+    match read_stats(cache)? {
+        Some(stats) => Ok(stats),
+        None => {
+            let _lock = Lock::acquire(&cache.join(STATS_LOCK))?;
+            rebuild(cache)
+        }
+    }
+}
+
+/// Counts of values grouped into power-of-two buckets.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// Number of values in each bucket, keyed by the bucket's upper bound.
+    /// A value `v` falls in the bucket of the smallest power of two that's
+    /// at least `v`, and zero has a bucket of its own. Empty buckets are left
+    /// out.
+    pub buckets: BTreeMap<u64, u64>,
+}
+
+impl Histogram {
+    fn record(&mut self, value: u64) {
+        let bound = if value == 0 {
+            0
+        } else {
+            value.checked_next_power_of_two().unwrap_or(u64::MAX)
+        };
+        *self.buckets.entry(bound).or_default() += 1;
+    }
+}
+
+/// The shape of the data in a cache, as returned by [`histograms`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheHistograms {
+    /// Sizes in bytes of the data of live index entries.
+    pub entry_sizes: Histogram,
+    /// Seconds since each content object was written.
+    pub content_ages: Histogram,
+    /// Number of content objects for each hash algorithm.
+    pub algorithms: BTreeMap<String, u64>,
+}
+
+/// Walks the whole cache to work out how its data is distributed: entry
+/// sizes, content ages, and which hash algorithms are in use. Useful for
+/// tuning size thresholds and the like against what a cache actually holds.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let histograms = cacache_sync::histograms("./my-cache")?;
+///     for (bound, count) in &histograms.entry_sizes.buckets {
+///         println!("<= {} bytes: {}", bound, count);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn histograms<P: AsRef<Path>>(cache: P) -> Result<CacheHistograms> {
+    let cache = cache.as_ref();
+    let config = config::load(cache)?;
+    let now = SystemTime::now();
+    let mut histograms = CacheHistograms::default();
+    let mut sizes = HashMap::<PathBuf, u64>::new();
+    for dir in path::content_dirs(&config, cache) {
+        for file in WalkDir::new(&dir).into_iter().filter_map(|file| file.ok()) {
+            if !file.file_type().is_file() {
+                continue;
+            }
+            let meta = file.metadata().to_internal()?;
+            let age = meta
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .map_or(0, |age| age.as_secs());
+            histograms.content_ages.record(age);
+            if let Some((algo, _)) = path::parse_content_path(&dir, file.path()) {
+                *histograms.algorithms.entry(algo).or_default() += 1;
+            }
+            sizes.insert(file.into_path(), meta.len());
+        }
+    }
+    // A missing or unreadable index just means there's nothing to count.
+    for entry in index::ls(cache).filter_map(|entry| entry.ok()) {
+        // No data, so no size to speak of.
+        if entry.metadata_only {
+            continue;
+        }
+        let size = match (entry.size, &entry.external) {
+            (0, None) => {
+                // Sizes aren't always recorded, but the content file knows.
+                let cpath = path::content_path_with(&config, cache, &entry.integrity);
+                sizes.get(&cpath).copied().unwrap_or(0)
+            }
+            (size, _) => size as u64,
+        };
+        histograms.entry_sizes.record(size);
+    }
+    Ok(histograms)
+}
+
+/// Outcome of the last [`crate::verify_cache`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct VerifySummary {
+    /// When it finished, in unix milliseconds.
+    pub time: u128,
+    /// Number of live entries it checked.
+    pub entries: u64,
+    /// Keys of the entries whose index line or data failed their check, or
+    /// whose data was missing.
+    pub damaged: Vec<String>,
+}
+
+/// Returns the summary recorded by the last [`crate::verify_cache`], if the
+/// cache was ever verified.
+pub fn last_verify<P: AsRef<Path>>(cache: P) -> Result<Option<VerifySummary>> {
+    let verify_path = cache.as_ref().join(VERIFY_FILE);
+    match fs::read(&verify_path) {
+        Ok(data) => Ok(serde_json::from_slice(&data).ok()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err)
+            .with_context(|| format!("Failed to read verify summary at {:?}", verify_path))?,
+    }
+}
+
+pub(crate) fn record_verify(cache: &Path, summary: &VerifySummary) -> Result<()> {
+    let tmp_path = cache.join("tmp");
+    DirBuilder::new()
+        .recursive(true)
+        .create(&tmp_path)
+        .with_context(|| format!("Failed to create tmp directory at {:?}", tmp_path))?;
+    let mut tmpfile = NamedTempFile::new_in(&tmp_path).to_internal()?;
+    serde_json::to_writer(tmpfile.as_file_mut(), summary).with_context(|| {
+        format!(
+            "Failed to serialize verify summary for cache at {:?}",
+            cache
+        )
+    })?;
+    tmpfile
+        .persist(cache.join(VERIFY_FILE))
+        .with_context(|| format!("Failed to write verify summary for cache at {:?}", cache))?;
+    Ok(())
+}
+
+/// Version of the document produced by [`stats_json`]. Bumped whenever a
+/// field is removed or changes meaning. New fields may be added without
+/// bumping it.
+pub const STATS_JSON_VERSION: u32 = 1;
+
+/// Produces a JSON document describing the health of a cache, for external
+/// monitoring to ingest without linking against this crate. Walks the whole
+/// cache, like [`histograms`]. The document looks like this, with every
+/// count and size an integer:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "generated_at": 1700000000000,
+///   "entries": 2,
+///   "content_objects": 2,
+///   "content_bytes": 11,
+///   "index_bytes": 612,
+///   "histograms": {
+///     "entry_sizes": [{ "le": 8, "count": 2 }],
+///     "content_ages": [{ "le": 64, "count": 2 }],
+///     "algorithms": { "sha256": 2 }
+///   },
+///   "quarantine": { "objects": 0, "bytes": 0, "last_time": null },
+///   "last_verify": { "time": 1700000000000, "entries": 2, "damaged": 0 }
+/// }
+/// ```
+///
+/// - `version` is [`STATS_JSON_VERSION`].
+/// - `generated_at`, `quarantine.last_time` and `last_verify.time` are unix
+///   milliseconds.
+/// - `entries`, `content_objects` and `content_bytes` are as in
+///   [`CacheStats`].
+/// - `index_bytes` is the size of the index on disk.
+/// - Each histogram from [`CacheHistograms`] is a list of buckets, in
+///   increasing order of their upper bound `le`. Entry sizes are in bytes,
+///   content ages in seconds.
+/// - `quarantine` summarizes the content that failed verification and was
+///   set aside, see [`crate::list_quarantine`], with the time of the most
+///   recent, if any.
+/// - `last_verify` summarizes the last [`crate::verify_cache`], like
+///   [`last_verify`], with the number of damaged entries it found. It's
+///   `null` if the cache was never verified.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     std::fs::write("cache-health.json", cacache_sync::stats_json("./my-cache")?)
+///         .expect("Failed to write report");
+///     Ok(())
+/// }
+/// ```
+pub fn stats_json<P: AsRef<Path>>(cache: P) -> Result<String> {
+    let cache = cache.as_ref();
+    let stats = compute(cache)?;
+    let histograms = histograms(cache)?;
+    let mut index_bytes = 0;
+    for file in WalkDir::new(index::index_dir(cache))
+        .into_iter()
+        .filter_map(|file| file.ok())
+    {
+        if file.file_type().is_file() {
+            index_bytes += file.metadata().to_internal()?.len();
+        }
+    }
+    let quarantined = crate::list_quarantine(cache)?;
+    let verified = last_verify(cache)?;
+    let buckets = |histogram: &Histogram| {
+        histogram
+            .buckets
+            .iter()
+            .map(|(le, count)| serde_json::json!({ "le": le, "count": count }))
+            .collect::<Vec<_>>()
+    };
+    let document = serde_json::json!({
+        "version": STATS_JSON_VERSION,
+        "generated_at": index::now() as u64,
+        "entries": stats.entries,
+        "content_objects": stats.content_objects,
+        "content_bytes": stats.content_bytes,
+        "index_bytes": index_bytes,
+        "histograms": {
+            "entry_sizes": buckets(&histograms.entry_sizes),
+            "content_ages": buckets(&histograms.content_ages),
+            "algorithms": histograms.algorithms,
+        },
+        "quarantine": {
+            "objects": quarantined.len(),
+            "bytes": quarantined.iter().map(|report| report.size).sum::<u64>(),
+            "last_time": quarantined.last().map(|report| report.time as u64),
+        },
+        "last_verify": verified.map(|summary| serde_json::json!({
+            "time": summary.time as u64,
+            "entries": summary.entries,
+            "damaged": summary.damaged.len(),
+        })),
+    });
+    serde_json::to_string_pretty(&document)
+        .with_context(|| format!("Failed to serialize stats for cache at {:?}", cache))
+        .map_err(Into::into)
+}
+
+/// Recomputes the stats of a cache from scratch by walking it, and stores the
+/// result if the cache is configured with `track_stats`. Useful if the stats
+/// file has drifted, for example after a process crashed halfway through an
+/// update, or after the cache was modified by hand.
+pub fn rebuild_stats<P: AsRef<Path>>(cache: P) -> Result<CacheStats> {
+    let cache = cache.as_ref();
+    if !config::load(cache)?.track_stats {
+        return compute(cache);
+    }
+    let _lock = Lock::acquire(&cache.join(STATS_LOCK))?;
+    rebuild(cache)
+}
+
+/// A change to apply to the stats of a cache.
+#[derive(Default)]
+pub(crate) struct StatsDelta {
+    pub entries: i64,
+    pub content_objects: i64,
+    pub content_bytes: i64,
+}
+
+/// Applies `delta` to the stats file, if the cache tracks stats. Must be
+/// called after the change it describes has been made on disk.
+pub(crate) fn record(cache: &Path, delta: StatsDelta) -> Result<()> {
+    if !config::load(cache)?.track_stats {
+        return Ok(());
+    }
+    let _lock = Lock::acquire(&cache.join(STATS_LOCK))?;
+    match read_stats(cache)? {
+        Some(mut stats) => {
+            stats.entries = apply(stats.entries, delta.entries);
+            stats.content_objects = apply(stats.content_objects, delta.content_objects);
+            stats.content_bytes = apply(stats.content_bytes, delta.content_bytes);
+            write_stats(cache, &stats)
+        }
+        // A fresh count already includes this change.
+        None => rebuild(cache).map(|_| ()),
+    }
+}
+
+/// Whether the stats of a cache are being tracked, so callers can skip work
+/// that's only needed to compute a delta.
+pub(crate) fn tracking(cache: &Path) -> Result<bool> {
+    Ok(config::load(cache)?.track_stats)
+}
+
+fn apply(value: u64, delta: i64) -> u64 {
+    if delta < 0 {
+        value.saturating_sub(delta.unsigned_abs())
+    } else {
+        value.saturating_add(delta as u64)
+    }
+}
+
+fn rebuild(cache: &Path) -> Result<CacheStats> {
+    let stats = compute(cache)?;
+    write_stats(cache, &stats)?;
+    Ok(stats)
+}
+
+fn compute(cache: &Path) -> Result<CacheStats> {
+    let mut stats = CacheStats {
+        // A missing or unreadable index just means there's nothing to count.
+        entries: index::ls(cache).filter(|entry| entry.is_ok()).count() as u64,
+        ..Default::default()
+    };
+    let config = config::load(cache)?;
+    for dir in path::content_dirs(&config, cache) {
+        for file in WalkDir::new(dir).into_iter().filter_map(|file| file.ok()) {
+            if file.file_type().is_file() {
+                stats.content_objects += 1;
+                stats.content_bytes += file.metadata().to_internal()?.len();
+            }
+        }
+    }
+    Ok(stats)
+}
+
+fn read_stats(cache: &Path) -> Result<Option<CacheStats>> {
+    let stats_path = cache.join(STATS_FILE);
+    match fs::read(&stats_path) {
+        Ok(data) => Ok(serde_json::from_slice(&data).ok()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => {
+            Err(err).with_context(|| format!("Failed to read stats file at {:?}", stats_path))?
+        }
+    }
+}
+
+fn write_stats(cache: &Path, stats: &CacheStats) -> Result<()> {
+    let tmp_path = cache.join("tmp");
+    DirBuilder::new()
+        .recursive(true)
+        .create(&tmp_path)
+        .with_context(|| format!("Failed to create tmp directory at {:?}", tmp_path))?;
+    let mut tmpfile = NamedTempFile::new_in(&tmp_path).to_internal()?;
+    serde_json::to_writer(tmpfile.as_file_mut(), stats)
+        .with_context(|| format!("Failed to serialize stats for cache at {:?}", cache))?;
+    tmpfile
+        .persist(cache.join(STATS_FILE))
+        .with_context(|| format!("Failed to write stats for cache at {:?}", cache))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CacheConfig;
+
+    #[test]
+    fn stats_untracked() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "a", b"hello").unwrap();
+        crate::write(&dir, "b", b"hello").unwrap();
+        crate::write(&dir, "c", b"world!").unwrap();
+        let expected = CacheStats {
+            entries: 3,
+            content_objects: 2,
+            content_bytes: 11,
+        };
+        assert_eq!(stats(&dir).unwrap(), expected);
+        assert!(!dir.join(STATS_FILE).exists());
+    }
+
+    #[test]
+    fn stats_as_json() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "a", b"hello").unwrap();
+        let sri = crate::write(&dir, "b", b"world!").unwrap();
+        std::fs::write(crate::content_path(&dir, &sri).unwrap(), b"broken").unwrap();
+        crate::quarantine_hash(&dir, &sri).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&stats_json(&dir).unwrap()).unwrap();
+        assert_eq!(json["version"], STATS_JSON_VERSION);
+        assert_eq!(json["entries"], 2);
+        assert_eq!(json["content_objects"], 1);
+        assert_eq!(json["content_bytes"], 5);
+        assert!(json["index_bytes"].as_u64().unwrap() > 0);
+        assert_eq!(
+            json["histograms"]["entry_sizes"],
+            serde_json::json!([{ "le": 0, "count": 1 }, { "le": 8, "count": 1 }])
+        );
+        assert_eq!(json["histograms"]["algorithms"]["sha256"], 1);
+        assert_eq!(json["quarantine"]["objects"], 1);
+        assert_eq!(json["quarantine"]["bytes"], 6);
+        assert!(json["quarantine"]["last_time"].is_u64());
+        assert!(json["last_verify"].is_null());
+
+        let summary = crate::verify_cache(&dir).unwrap();
+        assert_eq!(summary.entries, 2);
+        assert_eq!(summary.damaged, ["b"]);
+        assert_eq!(last_verify(&dir).unwrap(), Some(summary.clone()));
+        let json: serde_json::Value = serde_json::from_str(&stats_json(&dir).unwrap()).unwrap();
+        assert_eq!(json["last_verify"]["time"], summary.time as u64);
+        assert_eq!(json["last_verify"]["entries"], 2);
+        assert_eq!(json["last_verify"]["damaged"], 1);
+    }
+
+    #[test]
+    fn stats_tracked() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "a", b"hello").unwrap();
+        crate::configure(&dir, CacheConfig::new().track_stats(true)).unwrap();
+        assert!(dir.join(STATS_FILE).exists());
+
+        crate::write(&dir, "a", b"hello").unwrap();
+        crate::write(&dir, "b", b"hello").unwrap();
+        let sri = crate::write(&dir, "c", b"world!").unwrap();
+        crate::remove(&dir, "a").unwrap();
+        crate::remove(&dir, "missing").unwrap();
+        crate::remove_hash(&dir, &sri).unwrap();
+
+        let expected = CacheStats {
+            entries: 2,
+            content_objects: 1,
+            content_bytes: 5,
+        };
+        assert_eq!(stats(&dir).unwrap(), expected);
+        assert_eq!(rebuild_stats(&dir).unwrap(), expected);
+
+        crate::clear(&dir).unwrap();
+        assert_eq!(stats(&dir).unwrap(), CacheStats::default());
+    }
+
+    #[test]
+    fn cache_histograms() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        assert_eq!(histograms(&dir).unwrap(), CacheHistograms::default());
+
+        crate::write(&dir, "a", b"").unwrap();
+        crate::write(&dir, "b", b"hello").unwrap();
+        crate::write(&dir, "c", b"hello").unwrap();
+        crate::WriteOpts::new()
+            .algorithm(ssri::Algorithm::Sha1)
+            .open(&dir, "d")
+            .unwrap()
+            .commit()
+            .unwrap();
+        crate::write(&dir, "e", vec![1; 1000]).unwrap();
+        crate::write_metadata_only(&dir, "f", "no data").unwrap();
+
+        let histograms = histograms(&dir).unwrap();
+        let sizes = histograms.entry_sizes.buckets;
+        assert_eq!(sizes, [(0, 2), (8, 2), (1024, 1)].into_iter().collect());
+        assert_eq!(histograms.content_ages.buckets.values().sum::<u64>(), 4);
+        let algorithms = [("sha1".to_string(), 1), ("sha256".to_string(), 3)];
+        assert_eq!(histograms.algorithms, algorithms.into_iter().collect());
+    }
+}
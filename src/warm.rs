@@ -0,0 +1,137 @@
+//! Functions for pulling a working set into the page cache ahead of time.
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::content::read;
+use crate::errors::{Internal, Result};
+use crate::index;
+
+/// Summary of what [`warm`] and [`warm_index`] loaded.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WarmReport {
+    /// Number of keys that had an entry. Metadata-only entries count, though
+    /// they have no content to read.
+    pub found: usize,
+    /// Keys that had no entry, or whose content was missing.
+    pub missing: Vec<String>,
+    /// Total size in bytes of the content that was read.
+    pub bytes: u64,
+}
+
+/// Reads the index buckets and content for `keys`, so they're in the OS page
+/// cache by the time they're needed. Useful at startup, to take the cost of
+/// faulting in the hot working set before traffic arrives rather than on the
+/// first requests. Nothing is verified, so this is no slower than it has to
+/// be, and reads made afterwards still check the data as usual.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     let report = cacache_sync::warm("./my-cache", ["index.html", "app.js"])?;
+///     println!("warmed {} keys, {} bytes", report.found, report.bytes);
+///     Ok(())
+/// }
+/// ```
+pub fn warm<P, I, K>(cache: P, keys: I) -> Result<WarmReport>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = K>,
+    K: AsRef<str>,
+{
+    warm_keys(cache.as_ref(), keys, true)
+}
+
+/// Like [`warm`], but only reads the index buckets for `keys`, leaving their
+/// content alone. Enough to make lookups fast, for caches whose content is
+/// too large to keep in memory.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache_sync::Result<()> {
+///     cacache_sync::warm_index("./my-cache", ["index.html", "app.js"])?;
+///     Ok(())
+/// }
+/// ```
+pub fn warm_index<P, I, K>(cache: P, keys: I) -> Result<WarmReport>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = K>,
+    K: AsRef<str>,
+{
+    warm_keys(cache.as_ref(), keys, false)
+}
+
+fn warm_keys<I, K>(cache: &Path, keys: I, content: bool) -> Result<WarmReport>
+where
+    I: IntoIterator<Item = K>,
+    K: AsRef<str>,
+{
+    let mut report = WarmReport::default();
+    for key in keys {
+        let key = key.as_ref();
+        // Looking the key up is what reads its bucket in.
+        let entry = match index::find(cache, key)? {
+            Some(entry) => entry,
+            None => {
+                report.missing.push(key.to_owned());
+                continue;
+            }
+        };
+        if content && !entry.metadata_only {
+            let cpath = read::entry_file(cache, &entry)?;
+            let mut fd = match File::open(&cpath) {
+                Ok(fd) => fd,
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    report.missing.push(key.to_owned());
+                    continue;
+                }
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Failed to open content at {:?}", cpath))?
+                }
+            };
+            report.bytes += std::io::copy(&mut fd, &mut std::io::sink())
+                .with_context(|| format!("Failed to read content at {:?}", cpath))?;
+        }
+        report.found += 1;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warms_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        crate::write(dir, "a", b"hello").unwrap();
+        let sri = crate::write(dir, "b", b"world!").unwrap();
+        crate::remove_hash(dir, &sri).unwrap();
+
+        let report = warm(dir, ["a", "b", "c"]).unwrap();
+        assert_eq!(report.found, 1);
+        assert_eq!(report.missing, ["b", "c"]);
+        assert_eq!(report.bytes, 5);
+
+        let report = warm_index(dir, ["a", "b", "c"]).unwrap();
+        assert_eq!(report.found, 2);
+        assert_eq!(report.missing, ["c"]);
+        assert_eq!(report.bytes, 0);
+    }
+
+    #[test]
+    fn warms_metadata_only_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        crate::write_metadata_only(dir, "nothing", "no data").unwrap();
+        crate::write(dir, "a", b"hello").unwrap();
+
+        let report = warm(dir, ["nothing", "a"]).unwrap();
+        assert_eq!(report.found, 2);
+        assert!(report.missing.is_empty());
+        assert_eq!(report.bytes, 5);
+    }
+}
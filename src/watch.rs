@@ -0,0 +1,220 @@
+//! Functions for watching a cache for changes made by other processes.
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use walkdir::WalkDir;
+
+use crate::cache::Event;
+use crate::config;
+use crate::errors::{Internal, Result};
+use crate::index;
+
+/// Watches a cache for index changes, as returned by [`watch`]. Watching stops
+/// when this is dropped.
+pub struct CacheWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl std::fmt::Debug for CacheWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CacheWatcher").finish()
+    }
+}
+
+/// Calls `callback` with an [`Event::Written`] or [`Event::Removed`] for every
+/// index entry written to `cache` from now on, by any process, including this
+/// one. The callback runs on a background thread.
+///
+/// Events are delivered as the OS reports file changes, so they may arrive
+/// slightly after the write. Buckets rewritten whole, like by
+/// [`crate::repack`], are read again, and only entries that changed since
+/// are reported. Clearing the cache removes the watched directory, which ends
+/// the watch.
+///
+/// ## Example
+/// ```no_run
+/// use cacache_sync::Event;
+///
+/// fn main() -> cacache_sync::Result<()> {
+///     let _watcher = cacache_sync::watch("./my-cache", |event| {
+///         if let Event::Written { key, .. } = event {
+///             println!("{} was cached", key);
+///         }
+///     })?;
+///     std::thread::park();
+///     Ok(())
+/// }
+/// ```
+pub fn watch<P, F>(cache: P, mut callback: F) -> Result<CacheWatcher>
+where
+    P: AsRef<Path>,
+    F: FnMut(Event) + Send + 'static,
+{
+    let index_dir = index::index_dir(cache.as_ref());
+    let format = config::load(cache.as_ref())?.index_format;
+    fs::create_dir_all(&index_dir)
+        .with_context(|| format!("Failed to create index directory at {:?}", index_dir))?;
+    // Only report entries written after this point.
+    let started = index::now();
+    let mut seen = HashMap::new();
+    for entry in WalkDir::new(&index_dir).into_iter().filter_map(|e| e.ok()) {
+        if entry.file_type().is_file() {
+            let meta = entry.metadata().to_internal()?;
+            seen.insert(entry.path().to_path_buf(), Seen::at_end(&meta));
+        }
+    }
+    // What was last reported for each key, for telling what's new in
+    // buckets that were rewritten.
+    let mut latest = HashMap::new();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(_) => return,
+        };
+        let mut buckets = Vec::new();
+        for path in event.paths {
+            if path.is_dir() {
+                // Buckets can be written to a new directory before it's being
+                // watched, so look for them directly.
+                buckets.extend(
+                    WalkDir::new(&path)
+                        .into_iter()
+                        .filter_map(|e| e.ok())
+                        .filter(|e| e.file_type().is_file())
+                        .map(|e| e.into_path()),
+                );
+            } else {
+                buckets.push(path);
+            }
+        }
+        for bucket in buckets {
+            let opened = fs::File::open(&bucket).and_then(|fd| {
+                let meta = fd.metadata()?;
+                Ok((fd, meta))
+            });
+            let (fd, meta) = match opened {
+                Ok(opened) => opened,
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    seen.remove(&bucket);
+                    continue;
+                }
+                // Try again on the next change to this bucket.
+                Err(_) => continue,
+            };
+            let id = index::file_id(&meta);
+            // Buckets are only appended to, unless they're rewritten whole,
+            // like by compaction, which shrinks them or replaces the file.
+            let (offset, rewritten) = match seen.get(&bucket) {
+                Some(seen) if seen.id == id && seen.offset <= meta.len() => (seen.offset, false),
+                Some(_) => (0, true),
+                None => (0, false),
+            };
+            let (entries, offset) = match index::appended_entries(fd, offset, format) {
+                Ok(appended) => appended,
+                Err(_) => continue,
+            };
+            seen.insert(bucket, Seen { offset, id });
+            for (key, integrity, time) in entries {
+                // A rewritten bucket is read again from the start, and
+                // only what's changed since is reported.
+                let changed = match latest.get(&key) {
+                    Some(reported) => *reported != integrity,
+                    None => !rewritten || time > started,
+                };
+                if !changed && rewritten {
+                    continue;
+                }
+                latest.insert(key.clone(), integrity.clone());
+                callback(match integrity {
+                    Some(integrity) => Event::Written { key, integrity },
+                    None => Event::Removed { key },
+                });
+            }
+        }
+    })
+    .to_internal()?;
+    watcher
+        .watch(&index_dir, RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch index directory at {:?}", index_dir))?;
+    Ok(CacheWatcher { _watcher: watcher })
+}
+
+/// How far into a bucket file the watcher has read, and which file that
+/// was.
+struct Seen {
+    offset: u64,
+    id: Option<u128>,
+}
+
+impl Seen {
+    fn at_end(meta: &fs::Metadata) -> Seen {
+        Seen {
+            offset: meta.len(),
+            id: index::file_id(meta),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn reports_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "before", b"hello").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let _watcher = watch(&dir, move |event| tx.send(event).unwrap()).unwrap();
+
+        let sri = crate::write(&dir, "key", b"hello").unwrap();
+        let timeout = Duration::from_secs(10);
+        assert_eq!(
+            rx.recv_timeout(timeout).unwrap(),
+            Event::Written {
+                key: "key".into(),
+                integrity: sri
+            }
+        );
+        crate::remove(&dir, "before").unwrap();
+        assert_eq!(
+            rx.recv_timeout(timeout).unwrap(),
+            Event::Removed {
+                key: "before".into()
+            }
+        );
+    }
+
+    #[test]
+    fn rescans_rewritten_buckets() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        for data in [&b"one"[..], b"two", b"three"] {
+            crate::write(&dir, "key", data).unwrap();
+        }
+        let (tx, rx) = mpsc::channel();
+        let _watcher = watch(&dir, move |event| tx.send(event).unwrap()).unwrap();
+
+        // Compaction shrinks the bucket, so the next write lands before
+        // where the watcher had read up to. Entries are timed to the
+        // millisecond, so make sure the write's is after the watch began.
+        std::thread::sleep(Duration::from_millis(10));
+        index::compact(&dir).unwrap();
+        let sri = crate::write(&dir, "key", b"four").unwrap();
+        let timeout = Duration::from_secs(10);
+        assert_eq!(
+            rx.recv_timeout(timeout).unwrap(),
+            Event::Written {
+                key: "key".into(),
+                integrity: sri
+            }
+        );
+        // Nothing that was there before is reported again.
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+}